@@ -0,0 +1,253 @@
+//! C-compatible interface to the tax lot engine.
+//!
+//! The header for this interface is generated by cbindgen into `include/taxlot.h`, see `cbindgen.toml`. A typical
+//! embedding creates a collection, applies each lot operation line, iterates the remaining lots and
+//! then frees both the iterator and the collection.
+
+use std::{
+    ffi::{c_char, CStr},
+    str::FromStr,
+    vec,
+};
+
+use rust_decimal::Decimal;
+
+use taxlot::{Lot, LotCollection, LotOperation, SelectionAlgorithm};
+
+/// Length of the nul-terminated decimal strings in `TaxLotRecord`. A decimal has at most 29 digits,
+/// which leaves room for the sign, the decimal point and the terminator.
+pub const TAXLOT_DECIMAL_LEN: usize = 32;
+
+/// Selection algorithm of `taxlot_collection_new` that sells the lot bought first.
+pub const TAXLOT_FIFO: u32 = 0;
+/// Selection algorithm of `taxlot_collection_new` that sells the lot with the highest price first.
+pub const TAXLOT_HIFO: u32 = 1;
+/// Selection algorithm of `taxlot_collection_new` that sells the long-term lots by highest price first.
+pub const TAXLOT_LT_HIFO: u32 = 2;
+/// Selection algorithm of `taxlot_collection_new` that sells the lot with the largest loss first.
+pub const TAXLOT_MAX_LOSS: u32 = 3;
+/// Selection algorithm of `taxlot_collection_new` that sells the long-term lots by lowest price first.
+pub const TAXLOT_MAX_GAIN: u32 = 4;
+
+/// Status codes returned by the C interface.
+#[repr(C)]
+#[derive(Debug, PartialEq, Eq)]
+pub enum Status {
+    Ok = 0,
+    /// A required pointer argument was null.
+    NullPointer,
+    /// The lot operation was not valid UTF-8.
+    InvalidUtf8,
+    /// The lot operation could not be parsed or applied to the collection.
+    InvalidOperation,
+}
+
+/// C representation of a tax lot. `price` and `quantity` are nul-terminated decimal strings so that
+/// no precision is lost crossing the boundary.
+#[repr(C)]
+pub struct LotRecord {
+    pub id: u64,
+    pub year: i32,
+    pub month: u32,
+    pub day: u32,
+    pub price: [c_char; TAXLOT_DECIMAL_LEN],
+    pub quantity: [c_char; TAXLOT_DECIMAL_LEN],
+}
+
+impl From<&Lot> for LotRecord {
+    fn from(lot: &Lot) -> Self {
+        use chrono::Datelike;
+
+        LotRecord {
+            id: lot.id(),
+            year: lot.date().year(),
+            month: lot.date().month(),
+            day: lot.date().day(),
+            price: decimal_to_c_string(lot.price()),
+            quantity: decimal_to_c_string(lot.quantity()),
+        }
+    }
+}
+
+/// A collection of tax lots, created by `taxlot_collection_new` and only used through pointers.
+pub struct Collection {
+    lot_collection: LotCollection,
+}
+
+/// Iterator over a snapshot of the lots in a `TaxLotCollection`, in the order they would be sold.
+/// Applying operations to the collection does not affect an existing iterator.
+pub struct LotIter {
+    records: vec::IntoIter<LotRecord>,
+}
+
+/// Copies the string representation of `value` into a nul-terminated C buffer.
+fn decimal_to_c_string(value: Decimal) -> [c_char; TAXLOT_DECIMAL_LEN] {
+    let mut buffer = [0; TAXLOT_DECIMAL_LEN];
+    let value = value.to_string();
+    for (dst, src) in buffer.iter_mut().zip(value.bytes().take(TAXLOT_DECIMAL_LEN - 1)) {
+        *dst = src as c_char;
+    }
+    buffer
+}
+
+/// Creates an empty lot collection that sells with `selection_algo`, one of the `TAXLOT_FIFO` to
+/// `TAXLOT_MAX_GAIN` constants. Returns null if `selection_algo` is not one of them. The collection
+/// must be released with `taxlot_collection_free`.
+#[no_mangle]
+pub extern "C" fn taxlot_collection_new(selection_algo: u32) -> *mut Collection {
+    match SelectionAlgorithm::try_from(selection_algo) {
+        Ok(selection_algo) => Box::into_raw(Box::new(Collection {
+            lot_collection: LotCollection::new(selection_algo),
+        })),
+        Err(_) => std::ptr::null_mut(),
+    }
+}
+
+/// Parses `lot_operation`, a single line in the same format as the CLI input, and applies it to the
+/// collection.
+///
+/// # Safety
+///
+/// `collection` must be null or a pointer returned by `taxlot_collection_new` that has not been freed.
+/// `lot_operation` must be null or point to a nul-terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn taxlot_collection_apply(
+    collection: *mut Collection,
+    lot_operation: *const c_char,
+) -> Status {
+    let Some(collection) = collection.as_mut() else {
+        return Status::NullPointer;
+    };
+    if lot_operation.is_null() {
+        return Status::NullPointer;
+    }
+
+    let Ok(lot_operation) = CStr::from_ptr(lot_operation).to_str() else {
+        return Status::InvalidUtf8;
+    };
+
+    match LotOperation::from_str(lot_operation)
+        .and_then(|lot_operation| collection.lot_collection.apply_lot_operation(lot_operation))
+    {
+        Ok(()) => Status::Ok,
+        Err(_) => Status::InvalidOperation,
+    }
+}
+
+/// Frees a collection created by `taxlot_collection_new`. Passing null is a no-op.
+///
+/// # Safety
+///
+/// `collection` must be null or a pointer returned by `taxlot_collection_new` that has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn taxlot_collection_free(collection: *mut Collection) {
+    if !collection.is_null() {
+        drop(Box::from_raw(collection));
+    }
+}
+
+/// Creates an iterator over a snapshot of the remaining lots in the collection. Returns null if
+/// `collection` is null. The iterator must be released with `taxlot_lot_iter_free`.
+///
+/// # Safety
+///
+/// `collection` must be null or a pointer returned by `taxlot_collection_new` that has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn taxlot_lot_iter_new(collection: *const Collection) -> *mut LotIter {
+    let Some(collection) = collection.as_ref() else {
+        return std::ptr::null_mut();
+    };
+
+    let records: Vec<LotRecord> = collection.lot_collection.lots().map(|lot| LotRecord::from(&*lot)).collect();
+    Box::into_raw(Box::new(LotIter {
+        records: records.into_iter(),
+    }))
+}
+
+/// Writes the next lot into `record` and returns true, or returns false once the iterator is exhausted.
+///
+/// # Safety
+///
+/// `iter` must be null or a pointer returned by `taxlot_lot_iter_new` that has not been freed, and
+/// `record` must be null or point to writable memory for a `TaxLotRecord`.
+#[no_mangle]
+pub unsafe extern "C" fn taxlot_lot_iter_next(iter: *mut LotIter, record: *mut LotRecord) -> bool {
+    let Some(iter) = iter.as_mut() else {
+        return false;
+    };
+    if record.is_null() {
+        return false;
+    }
+
+    match iter.records.next() {
+        Some(next) => {
+            record.write(next);
+            true
+        }
+        None => false,
+    }
+}
+
+/// Frees an iterator created by `taxlot_lot_iter_new`. Passing null is a no-op.
+///
+/// # Safety
+///
+/// `iter` must be null or a pointer returned by `taxlot_lot_iter_new` that has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn taxlot_lot_iter_free(iter: *mut LotIter) {
+    if !iter.is_null() {
+        drop(Box::from_raw(iter));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        ffi::{CStr, CString},
+        mem::MaybeUninit,
+    };
+
+    use super::*;
+
+    #[test]
+    fn test_apply_and_iterate_through_c_interface() {
+        let collection = taxlot_collection_new(TAXLOT_FIFO);
+        let buy = CString::new("2021-01-01,buy,10000.00,1.00000000").expect("Failed to create CString");
+        let sell = CString::new("2021-02-01,sell,20000.00,0.25000000").expect("Failed to create CString");
+        let invalid = CString::new("2021-01-01,invalid,10000.00,1.00000000").expect("Failed to create CString");
+
+        unsafe {
+            assert_eq!(taxlot_collection_apply(collection, buy.as_ptr()), Status::Ok);
+            assert_eq!(taxlot_collection_apply(collection, sell.as_ptr()), Status::Ok);
+            assert_eq!(taxlot_collection_apply(collection, invalid.as_ptr()), Status::InvalidOperation);
+            assert_eq!(taxlot_collection_apply(collection, std::ptr::null()), Status::NullPointer);
+
+            let iter = taxlot_lot_iter_new(collection);
+            let mut record = MaybeUninit::<LotRecord>::uninit();
+            assert!(taxlot_lot_iter_next(iter, record.as_mut_ptr()));
+            let record = record.assume_init();
+            assert_eq!(record.id, 1);
+            assert_eq!((record.year, record.month, record.day), (2021, 1, 1));
+            assert_eq!(CStr::from_ptr(record.price.as_ptr()).to_str(), Ok("10000.00"));
+            assert_eq!(CStr::from_ptr(record.quantity.as_ptr()).to_str(), Ok("0.75000000"));
+
+            let mut record = MaybeUninit::<LotRecord>::uninit();
+            assert!(!taxlot_lot_iter_next(iter, record.as_mut_ptr()));
+
+            taxlot_lot_iter_free(iter);
+            taxlot_collection_free(collection);
+        }
+    }
+    #[test]
+    fn test_collection_new_takes_every_selection_algorithm() {
+        let algorithms = [TAXLOT_FIFO, TAXLOT_HIFO, TAXLOT_LT_HIFO, TAXLOT_MAX_LOSS, TAXLOT_MAX_GAIN];
+        for (selection_algo, expected) in algorithms.into_iter().zip(SelectionAlgorithm::ALL) {
+            assert_eq!(SelectionAlgorithm::try_from(selection_algo).ok(), Some(expected));
+            let collection = taxlot_collection_new(selection_algo);
+            assert!(!collection.is_null());
+            unsafe { taxlot_collection_free(collection) };
+        }
+        assert!(taxlot_collection_new(5).is_null());
+        assert!(taxlot_collection_new(u32::MAX).is_null());
+    }
+}
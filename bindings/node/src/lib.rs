@@ -0,0 +1,176 @@
+//! Node.js bindings for the tax lot engine.
+//!
+//! Exposes a `TaxLotEngine` class backed by the same `LotCollection` as the CLI. Large batches of
+//! operations can be applied off the JavaScript main thread with `applyOperations`, which resolves
+//! once the whole batch has been applied.
+
+use std::{
+    str::FromStr,
+    sync::{Arc, Mutex, MutexGuard},
+};
+
+use napi::{bindgen_prelude::AsyncTask, Env, Error, Result, Task};
+use napi_derive::napi;
+
+use taxlot::{Lot, LotCollection, LotOperation, SelectionAlgorithm, TaxLotError};
+
+/// A tax lot as seen from JavaScript. `price` and `quantity` are decimal strings so that no
+/// precision is lost converting to a JavaScript number.
+#[napi(object, js_name = "Lot")]
+pub struct JsLot {
+    pub id: i64,
+    pub date: String,
+    pub price: String,
+    pub quantity: String,
+    pub tags: Vec<String>,
+    pub memo: Option<String>,
+}
+
+impl From<&Lot> for JsLot {
+    fn from(lot: &Lot) -> Self {
+        JsLot {
+            id: lot.id() as i64,
+            date: lot.date().to_string(),
+            price: lot.price().to_string(),
+            quantity: lot.quantity().to_string(),
+            tags: lot.tags().map(str::to_string).collect(),
+            memo: lot.memo().map(str::to_string),
+        }
+    }
+}
+
+/// Maps a `TaxLotError` to a JavaScript `Error` with the same message.
+fn to_js_error(error: TaxLotError) -> Error {
+    Error::from_reason(error.to_string())
+}
+
+/// Parses a single lot operation line and applies it to the collection.
+fn apply(collection: &mut LotCollection, lot_operation: &str) -> Result<()> {
+    LotOperation::from_str(lot_operation)
+        .and_then(|lot_operation| collection.apply_lot_operation(lot_operation))
+        .map_err(to_js_error)
+}
+
+/// A collection of tax lots that operations can be applied to from JavaScript.
+#[napi]
+pub struct TaxLotEngine {
+    collection: Arc<Mutex<LotCollection>>,
+}
+
+#[napi]
+impl TaxLotEngine {
+    /// Creates an empty engine. `selectionAlgo` is one of the algorithms accepted by the CLI, e.g. "fifo".
+    #[napi(constructor)]
+    pub fn new(selection_algo: String) -> Result<Self> {
+        let selection_algo = SelectionAlgorithm::from_str(&selection_algo).map_err(to_js_error)?;
+        Ok(TaxLotEngine {
+            collection: Arc::new(Mutex::new(LotCollection::new(selection_algo))),
+        })
+    }
+
+    /// Applies a single lot operation line, in the same format as the CLI input.
+    #[napi]
+    pub fn apply_operation(&self, lot_operation: String) -> Result<()> {
+        apply(&mut *self.lock()?, &lot_operation)
+    }
+
+    /// Applies a batch of lot operation lines on the libuv thread pool. The promise rejects with the
+    /// first error; operations before it stay applied.
+    #[napi(ts_return_type = "Promise<void>")]
+    pub fn apply_operations(&self, lot_operations: Vec<String>) -> AsyncTask<ApplyOperations> {
+        AsyncTask::new(ApplyOperations {
+            collection: Arc::clone(&self.collection),
+            lot_operations,
+        })
+    }
+
+    /// Returns the remaining lots in the order they would be sold.
+    #[napi]
+    pub fn get_lots(&self) -> Result<Vec<JsLot>> {
+        Ok(self.lock()?.lots().map(|lot| JsLot::from(&*lot)).collect())
+    }
+
+    /// Returns the total realized gain of all sells so far as a decimal string.
+    #[napi]
+    pub fn realized_gains(&self) -> Result<String> {
+        Ok(self.lock()?.realized_gains().to_string())
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, LotCollection>> {
+        lock(&self.collection)
+    }
+}
+
+/// Locks the collection, mapping a poisoned lock to a JavaScript error.
+fn lock(collection: &Mutex<LotCollection>) -> Result<MutexGuard<'_, LotCollection>> {
+    collection
+        .lock()
+        .map_err(|_| Error::from_reason("Tax lot engine is poisoned by an earlier panic"))
+}
+
+/// Background task behind `TaxLotEngine.applyOperations`.
+pub struct ApplyOperations {
+    collection: Arc<Mutex<LotCollection>>,
+    lot_operations: Vec<String>,
+}
+
+impl Task for ApplyOperations {
+    type Output = ();
+    type JsValue = ();
+
+    fn compute(&mut self) -> Result<Self::Output> {
+        let mut collection = lock(&self.collection)?;
+        for lot_operation in &self.lot_operations {
+            apply(&mut collection, lot_operation)?;
+        }
+        Ok(())
+    }
+
+    fn resolve(&mut self, _env: Env, output: Self::Output) -> Result<Self::JsValue> {
+        Ok(output)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use napi::{Result, Task};
+
+    use super::{ApplyOperations, TaxLotEngine};
+
+    /// Runs `applyOperations` on the calling thread, as the thread pool would.
+    fn apply_operations(engine: &TaxLotEngine, lot_operations: &[&str]) -> Result<()> {
+        let lot_operations = lot_operations.iter().map(|lot_operation| lot_operation.to_string()).collect();
+        ApplyOperations { collection: Arc::clone(&engine.collection), lot_operations }.compute()
+    }
+
+    #[test]
+    fn test_engine_applies_operations() -> Result<()> {
+        let engine = TaxLotEngine::new("hifo".to_string())?;
+        engine.apply_operation("2021-01-01,buy,10000.00,1.00000000".to_string())?;
+        apply_operations(&engine, &["2021-01-02,buy,20000.00,1.00000000", "2021-02-01,sell,25000.00,0.50000000"])?;
+
+        let lots = engine.get_lots()?;
+        assert_eq!(lots.len(), 2);
+        assert_eq!((lots[0].id, lots[0].quantity.as_str()), (2, "0.50000000"));
+        assert_eq!(lots[1].date, "2021-01-01");
+        assert_eq!(engine.realized_gains()?, "2500.0000000000");
+        Ok(())
+    }
+
+    #[test]
+    fn test_engine_rejects_invalid_input() -> Result<()> {
+        assert!(TaxLotEngine::new("newest".to_string()).is_err());
+
+        let engine = TaxLotEngine::new("fifo".to_string())?;
+        let error = engine.apply_operation("2021-01-01,hold,10000.00,1".to_string()).expect_err("Applied a hold");
+        assert!(error.reason.contains("Lot Type"), "{}", error.reason);
+
+        // A batch stops at the first error, and the operations before it stay applied.
+        let batch = ["2021-01-01,buy,10000.00,1", "2021-13-01,buy,10000.00,1", "2021-01-03,buy,10000.00,1"];
+        apply_operations(&engine, &batch).expect_err("Applied an invalid date");
+        assert_eq!(engine.get_lots()?.len(), 1);
+        Ok(())
+    }
+}
@@ -53,4 +53,426 @@ if [ $return_code -ne 0 ]; then
 fi
 echo "FIFO half Year test successful"
 
+# Verify `lt-hifo` sells the long-term lot before the short-term lot with a higher price
+output=$(echo -e "2020-01-01,buy,10000.00,1.00000000\n2021-01-01,buy,20000.00,1.00000000\n2021-03-01,sell,20000.00,1.00000000" | ./target/debug/taxlot lt-hifo)
+if [ "$output" != "2,2021-01-01,20000.00,1.00000000" ]; then
+    echo "Error: expected the short-term lot to remain, got: $output"
+    exit 1
+fi
+echo "LT-HIFO test successful"
+
+# Verify `max-loss` sells the lot with a loss before the lot with a smaller gain
+output=$(echo -e "2021-01-01,buy,30000.00,1.00000000\n2021-01-02,buy,19000.00,1.00000000\n2021-02-01,sell,20000.00,1.00000000" | ./target/debug/taxlot max-loss)
+if [ "$output" != "2,2021-01-02,19000.00,1.00000000" ]; then
+    echo "Error: expected the lot with a gain to remain, got: $output"
+    exit 1
+fi
+echo "Max loss test successful"
+
+# Verify `max-gain` sells the lowest price lot first
+output=$(echo -e "2021-01-01,buy,10000.00,1.00000000\n2021-01-02,buy,20000.00,1.00000000\n2021-02-01,sell,20000.00,1.00000000" | ./target/debug/taxlot max-gain)
+if [ "$output" != "2,2021-01-02,20000.00,1.00000000" ]; then
+    echo "Error: expected the highest price lot to remain, got: $output"
+    exit 1
+fi
+echo "Max gain test successful"
+
+# Verify negative quantities are sells with `--input-dialect signed`
+output=$(echo -e "2021-01-01,10000.00,1.00000000\n2021-02-01,20000.00,-0.5" | ./target/debug/taxlot fifo --input-dialect signed)
+if [ "$output" != "1,2021-01-01,10000.00,0.50000000" ]; then
+    echo "Error: expected half of the lot to be sold, got: $output"
+    exit 1
+fi
+echo "Signed input dialect test successful"
+
+# Verify fixed-width lines are parsed according to the layout
+output=$(printf '2021-01-01 BUY   10000.00    1.00000000\n2021-02-01 SELL  20000.00    0.50000000\n' | ./target/debug/taxlot fifo --fixed-width date=0:10,type=11:4,price=15:10,quantity=25:14)
+if [ "$output" != "1,2021-01-01,10000.00,0.50000000" ]; then
+    echo "Error: expected half of the lot to be sold, got: $output"
+    exit 1
+fi
+echo "Fixed-width input test successful"
+
+# Verify records of an XML document are mapped to lot operations
+output=$(echo '<trades><trade date="2021-01-01"><side>buy</side><price>10000.00</price><qty>1</qty></trade><trade date="2021-02-01"><side>sell</side><price>20000.00</price><qty>0.5</qty></trade></trades>' | ./target/debug/taxlot fifo --xml 'record=//trade,date=@date,type=side,price=price,quantity=qty')
+if [ "$output" != "1,2021-01-01,10000.00,0.50000000" ]; then
+    echo "Error: expected half of the lot to be sold, got: $output"
+    exit 1
+fi
+echo "XML input test successful"
+
+# Verify the gains realized in each year are written to the gains report
+gains_report=$(mktemp)
+echo -e "2021-01-01,buy,10000.00,1.00000000\n2021-06-01,buy,20000.00,1.00000000\n2021-12-31,sell,15000.00,0.50000000\n2022-03-01,sell,25000.00,1.00000000" | ./target/debug/taxlot fifo --gains-report "$gains_report" > /dev/null
+output=$(cat "$gains_report")
+rm "$gains_report"
+if [ "$output" != "$(echo -e "2021,2500.00,0.00,2500.00\n2022,2500.00,7500.00,10000.00")" ]; then
+    echo "Error: expected short-term and long-term gains per year, got: $output"
+    exit 1
+fi
+echo "Gains report test successful"
+
+# Verify the Australian rules discount the gains of lots held for more than 12 months by income year
+gains_report=$(mktemp)
+disposals_report=$(mktemp)
+echo -e "2021-03-15,buy,10000.00,2.00000000\n2022-03-15,sell,15000.00,1.00000000\n2022-03-16,sell,15000.00,1.00000000" | ./target/debug/taxlot fifo --jurisdiction au --gains-report "$gains_report" --disposals-report "$disposals_report" > /dev/null
+output=$(cat "$gains_report" "$disposals_report")
+rm "$gains_report" "$disposals_report"
+if [ "$output" != "$(echo -e "2022,5000.00,5000.00,0.00,0.00,2500.00,7500.00,0.00\n1,2021-03-15,2022-03-15,1.00000000,10000.00,15000.00,5000.00,short,,other\n1,2021-03-15,2022-03-16,1.00000000,10000.00,15000.00,5000.00,long,,discount")" ]; then
+    echo "Error: expected the CGT discount and a method per disposal, got: $output"
+    exit 1
+fi
+echo "Australian gains report test successful"
+
+# Verify Section 1256 contracts are marked to market at year end, with their gains split 60/40 in the gains report
+gains_report=$(mktemp)
+mark_prices=$(mktemp)
+echo -e "date,price\n2021-12-31,4800.00\n2022-12-30,4500.00" > "$mark_prices"
+echo -e "2021-01-01,buy,10000.00,1.00000000\n2021-11-01,buy,4700.00,2.00000000,tags=es\n2022-03-01,sell,4900.00,1.00000000,tags=es\n2022-06-01,sell,15000.00,1.00000000\n2023-02-01,sell,4600.00,1.00000000,tags=es" | ./target/debug/taxlot fifo --section-1256-tag es --mark-prices "$mark_prices" --gains-report "$gains_report" > /dev/null
+output=$(cat "$gains_report")
+rm "$gains_report" "$mark_prices"
+if [ "$output" != "$(echo -e "2021,80.00,120.00,200.00\n2022,-80.00,4880.00,4800.00\n2023,40.00,60.00,100.00")" ]; then
+    echo "Error: expected the marked gains of the contracts split 60/40, got: $output"
+    exit 1
+fi
+echo "Section 1256 gains report test successful"
+
+# Verify the gains realized in each quarter are written to the quarterly report with year-to-date totals
+quarterly_report=$(mktemp)
+echo -e "2021-01-01,buy,10000.00,3.00000000\n2021-02-01,sell,11000.00,1.00000000\n2021-04-01,sell,9000.00,1.00000000\n2022-10-01,sell,15000.00,1.00000000" | ./target/debug/taxlot fifo --quarterly-report "$quarterly_report" > /dev/null
+output=$(cat "$quarterly_report")
+rm "$quarterly_report"
+if [ "$output" != "$(echo -e "2021,1,1000.00,0.00,1000.00,1000.00\n2021,2,-1000.00,0.00,-1000.00,0.00\n2022,4,0.00,5000.00,5000.00,5000.00")" ]; then
+    echo "Error: expected gains per quarter with year-to-date totals, got: $output"
+    exit 1
+fi
+echo "Quarterly report test successful"
+
+# Verify dividend and interest income is written to the income report
+income_report=$(mktemp)
+echo -e "2021-01-01,buy,10000.00,2.00000000\n2021-03-31,dividend,0.25,2.00000000\n2021-06-30,interest,12.50,1" | ./target/debug/taxlot fifo --income-report "$income_report" > /dev/null
+output=$(cat "$income_report")
+rm "$income_report"
+if [ "$output" != "$(echo -e "2021,dividend,0.50\n2021,interest,12.50")" ]; then
+    echo "Error: expected dividend and interest totals, got: $output"
+    exit 1
+fi
+echo "Income report test successful"
+
+# Verify memos are kept on lots and joined when lots are merged
+output=$(echo -e "2021-01-01,buy,10000.00,1.00000000,dca\n2021-01-01,buy,12000.00,1.00000000,bought the dip, again" | ./target/debug/taxlot fifo)
+if [ "$output" != "1,2021-01-01,11000.00,2.00000000,dca; bought the dip, again" ]; then
+    echo "Error: expected merged memo, got: $output"
+    exit 1
+fi
+echo "Memo test successful"
+
+# Verify lots can be filtered with a filter expression
+output=$(echo -e "2021-01-01,buy,10000.00,1.00000000\n2021-06-01,buy,20000.00,1.00000000\n2022-01-01,buy,30000.00,1.00000000" | ./target/debug/taxlot fifo --filter "price > 10000 && date < 2022-01-01")
+if [ "$output" != "2,2021-06-01,20000.00,1.00000000" ]; then
+    echo "Error: expected only the lot matching the filter, got: $output"
+    exit 1
+fi
+echo "Filter expression test successful"
+
+# Verify lots can be filtered by tag
+output=$(echo -e "2021-01-01,buy,10000.00,1.00000000,tags=ira\n2021-01-02,buy,12000.00,1.00000000,tags=taxable" | ./target/debug/taxlot fifo --exclude-tag ira)
+if [ "$output" != "2,2021-01-02,12000.00,1.00000000,tags=taxable" ]; then
+    echo "Error: expected only the taxable lot, got: $output"
+    exit 1
+fi
+echo "Tag filter test successful"
+
+# Verify buys on the same date are not merged with `--merge-policy keep-separate`
+output=$(echo -e "2021-01-01,buy,10000.00,1.00000000\n2021-01-01,buy,20000.00,1.00000000" | ./target/debug/taxlot hifo --merge-policy keep-separate)
+if [ "$output" != "$(echo -e "2,2021-01-01,20000.00,1.00000000\n1,2021-01-01,10000.00,1.00000000")" ]; then
+    echo "Error: expected two separate lots, got: $output"
+    exit 1
+fi
+echo "Merge policy test successful"
+
+# Verify buys in the same month are merged with `--merge-window month`
+output=$(echo -e "2021-01-15,buy,10000.00,1.00000000\n2021-01-03,buy,20000.00,1.00000000" | ./target/debug/taxlot fifo --merge-window month)
+if [ "$output" != "1,2021-01-03,15000.00,2.00000000" ]; then
+    echo "Error: expected one lot for January, got: $output"
+    exit 1
+fi
+echo "Merge window test successful"
+
+# Verify lot ids can start at another number and be formatted
+output=$(echo -e "2021-01-01,buy,10000.00,1.00000000" | ./target/debug/taxlot fifo --start-id 123 --id-prefix LOT- --id-width 6)
+if [ "$output" != "LOT-000123,2021-01-01,10000.00,1.00000000" ]; then
+    echo "Error: expected formatted lot id, got: $output"
+    exit 1
+fi
+echo "Lot id format test successful"
+
+# Verify cancelled operations are removed when the journal is enabled
+output=$(echo -e "2021-01-01,buy,10000.00,1.00000000,txn=1\n2021-01-02,buy,20000.00,1.00000000,txn=2\n2021-01-05,cancel,,,txn=1" | ./target/debug/taxlot fifo --journal)
+if [ "$output" != "1,2021-01-02,20000.00,1.00000000" ]; then
+    echo "Error: expected only the second buy to remain, got: $output"
+    exit 1
+fi
+echo "Cancel test successful"
+
+# Verify a backdated operation is inserted before later sells
+output=$(echo -e "2021-01-01,buy,10000.00,1.00000000\n2021-01-10,buy,20000.00,1.00000000\n2021-02-01,sell,25000.00,1.00000000" | ./target/debug/taxlot insert hifo 2021-01-05,buy,30000.00,1.00000000 2> /dev/null)
+expected='"2021-01-05,buy,30000.00,1.00000000",lot,added,,3,,2021-01-10,,,20000.00,,1.00000000,,
+"2021-01-05,buy,30000.00,1.00000000",disposal,rematched,2,2,2021-01-10,2021-01-05,2021-02-01,,,1.00000000,1.00000000,5000.00,-5000.00'
+if [ "$output" != "$expected" ]; then
+    echo "Error: expected the sell to be re-matched to the inserted lot, got: $output"
+    exit 1
+fi
+echo "Insert test successful"
+
+# Verify a run resumed from a checkpoint only applies the operations after it
+checkpoint_dir=$(mktemp -d)
+echo -e "2021-01-01,buy,10000.00,1.00000000\n2021-01-02,buy,20000.00,1.00000000" > "$checkpoint_dir/input.txt"
+./target/debug/taxlot fifo --input "$checkpoint_dir/input.txt" --checkpoint "$checkpoint_dir/checkpoint" > /dev/null
+echo "2021-02-01,sell,20000.00,1.50000000" >> "$checkpoint_dir/input.txt"
+output=$(./target/debug/taxlot fifo --input "$checkpoint_dir/input.txt" --checkpoint "$checkpoint_dir/checkpoint")
+expected=$(./target/debug/taxlot fifo --input "$checkpoint_dir/input.txt")
+rm -r "$checkpoint_dir"
+if [ "$output" != "$expected" ]; then
+    echo "Error: expected resumed run to match a full run, got: $output"
+    exit 1
+fi
+echo "Checkpoint test successful"
+
+# Verify reading from a memory-mapped file gives the same result as stdin
+stdin_output=$(cat test_data.txt | ./target/debug/taxlot hifo)
+file_output=$(./target/debug/taxlot hifo --input test_data.txt)
+if [ "$stdin_output" != "$file_output" ]; then
+    echo "Error: expected --input to match stdin output"
+    exit 1
+fi
+
+# Verify returns exit code 1 when the input file does not exist
+./target/debug/taxlot hifo --input does_not_exist.txt
+
+return_code="$?"
+if [ $return_code -ne 1 ]; then
+    echo "Error: expected return code 1"
+    exit 1
+fi
+echo "File input test successful"
+
+# Verify locked lots are not sold
+output=$(echo -e "2021-01-01,buy,10000.00,1.00000000\n2021-01-02,buy,20000.00,1.00000000\n2021-02-01,sell,20000.00,2.00000000" | ./target/debug/taxlot fifo --lock 1)
+if [ "$output" != "1,2021-01-01,10000.00,1.00000000" ]; then
+    echo "Error: expected locked lot 1 to remain, got: $output"
+    exit 1
+fi
+echo "Lot locking test successful"
+
+# Verify options can be set with environment variables
+output=$(echo -e "2021-01-01,buy,10000.00,1.00000000\n2021-01-02,buy,20000.00,1.00000000\n2021-02-01,sell,20000.00,2.00000000" | TAXLOT_LOCK=1 ./target/debug/taxlot fifo)
+if [ "$output" != "1,2021-01-01,10000.00,1.00000000" ]; then
+    echo "Error: expected TAXLOT_LOCK to lock lot 1, got: $output"
+    exit 1
+fi
+echo "Environment variable test successful"
+
+# Verify options can be set in a config file, with the environment taking precedence
+config=$(mktemp)
+echo -e "# locked lots\nlock = 2\nalert-empty-position = 0" > "$config"
+output=$(echo -e "2021-01-01,buy,10000.00,1.00000000\n2021-01-02,buy,20000.00,1.00000000\n2021-02-01,sell,20000.00,1.00000000" | ./target/debug/taxlot fifo --config "$config")
+if [ "$output" != "2,2021-01-02,20000.00,1.00000000" ]; then
+    echo "Error: expected the config file to lock lot 2, got: $output"
+    exit 1
+fi
+output=$(echo -e "2021-01-01,buy,10000.00,1.00000000\n2021-01-02,buy,20000.00,1.00000000\n2021-02-01,sell,20000.00,1.00000000" | TAXLOT_LOCK=1 TAXLOT_CONFIG="$config" ./target/debug/taxlot fifo)
+rm "$config"
+if [ "$output" != "1,2021-01-01,10000.00,1.00000000" ]; then
+    echo "Error: expected TAXLOT_LOCK to take precedence over the config file, got: $output"
+    exit 1
+fi
+echo "Config file test successful"
+
+# Verify the disposals report has a row per lot that a sell deducted from
+disposals=$(mktemp)
+echo -e "2020-01-01,buy,10000.00,1.00000000\n2021-06-01,buy,20000.00,1.00000000\n2021-07-01,sell,25000.00,1.50000000,txn=s1" | ./target/debug/taxlot fifo --disposals-report "$disposals" > /dev/null
+expected="1,2020-01-01,2021-07-01,1.00000000,10000.00,25000.00,15000.00,long,s1
+2,2021-06-01,2021-07-01,0.50000000,10000.00,12500.00,2500.00,short,s1"
+output=$(cat "$disposals")
+rm "$disposals"
+if [ "$output" != "$expected" ]; then
+    echo "Error: expected the disposals of the sell, got: $output"
+    exit 1
+fi
+output=$(echo -e "2020-01-01,buy,10000.00,1.00000000\n2021-06-01,buy,20000.00,1.00000000\n2021-07-01,sell,25000.00,1.50000000,txn=s1" | ./target/debug/taxlot fifo --disposals-report /dev/stdout --spill-disposals 1 | tail -n 2)
+if [ "$output" != "$expected" ]; then
+    echo "Error: expected spilled disposals to be read back, got: $output"
+    exit 1
+fi
+input="2020-01-01,buy,10000.00,1.00000000\n2020-02-01,buy,30000.00,1.00000000\n2020-03-01,buy,20000.00,1.00000000\n2020-04-01,buy,40000.00,1.00000000\n2021-07-01,sell,25000.00,1.50000000"
+expected="2,2020-02-01,30000.00,0.50000000
+3,2020-03-01,20000.00,1.00000000
+1,2020-01-01,10000.00,1.00000000"
+output=$(echo -e "$input" | ./target/debug/taxlot hifo --spill-lots 1)
+if [ "$output" != "$expected" ]; then
+    echo "Error: expected spilled lots to be sold like lots in memory, got: $output"
+    exit 1
+fi
+echo "Disposals report test successful"
+
+# Verify sells fail instead of selling lots held for too few days
+echo -e "2021-01-01,buy,10000.00,1.00000000\n2021-01-20,buy,20000.00,1.00000000\n2021-02-01,sell,20000.00,1.50000000" | ./target/debug/taxlot hifo --min-holding-days 30 > /dev/null 2>&1
+if [ $? -ne 1 ]; then
+    echo "Error: expected exit code 1 for a sell that breaks the minimum holding period"
+    exit 1
+fi
+echo "Minimum holding period test successful"
+
+# Verify the lots can be written as JSON
+output=$(echo "2021-01-01,buy,10000.00,1.00000000,tags=ira,first" | ./target/debug/taxlot fifo --format json)
+if [ "$output" != "$(echo -e '{"schema":"lots","version":2,"rows":[\n{"id":"1","date":"2021-01-01","price":10000.00,"quantity":1.00000000,"tags":["ira"],"memo":"first","purchases":[{"date":"2021-01-01","price":10000.00,"quantity":1.00000000}]}\n]}')" ]; then
+    echo "Error: expected lots as JSON, got: $output"
+    exit 1
+fi
+echo "JSON output test successful"
+
+# Verify the schema version header is written before the lots
+output=$(echo "2021-01-01,buy,10000.00,1.00000000" | ./target/debug/taxlot fifo --schema-version 1)
+if [ "$output" != "$(echo -e "# lots schema 1: id,date,price,quantity[,tags=<tags>][,memo]\n1,2021-01-01,10000.00,1.00000000")" ]; then
+    echo "Error: expected schema header before the lots, got: $output"
+    exit 1
+fi
+echo "Schema version test successful"
+
+# Verify alerts run the alert command when they fire
+output=$(echo -e "2021-01-01,buy,10000.00,2.00000000\n2021-02-01,sell,12000.00,1.00000000\n2021-03-01,sell,12000.00,1.00000000" | ./target/debug/taxlot fifo --alert-short-term-gains 3000 --alert-empty-position --alert-exec 'echo "$TAXLOT_ALERT"')
+if [ "$output" != "$(echo -e "short-term-gains\nempty-position")" ]; then
+    echo "Error: expected short-term gains and empty position alerts, got: $output"
+    exit 1
+fi
+output=$(echo -e "2021-01-01,buy,10000.00,1.00000000\n2021-02-01,sell,12000.00,2.00000000" | ./target/debug/taxlot fifo --alert-oversell --alert-exec 'echo "$TAXLOT_ALERT"')
+if [ "$output" != "oversell" ]; then
+    echo "Error: expected an oversell alert, got: $output"
+    exit 1
+fi
+echo "Alert test successful"
+
+# Verify input beyond the limits fails
+for limit in "--max-line-length 38" "--max-fields 4" "--max-operations 1" "--max-open-lots 1"; do
+    echo -e "2021-01-01,buy,10000.00,1.00000000\n2021-01-02,buy,20000.00,1.00000000,robo" | ./target/debug/taxlot fifo $limit > /dev/null 2>&1
+    if [ $? -ne 1 ]; then
+        echo "Error: expected exit code 1 for input beyond $limit"
+        exit 1
+    fi
+done
+echo "Input limit test successful"
+
+# Verify input with a byte order mark, in UTF-16 or in latin-1 is decoded
+for encode in "sed 1s/^/\xef\xbb\xbf/" "iconv -f UTF-8 -t UTF-16" "iconv -f UTF-8 -t UTF-16LE" "iconv -f UTF-8 -t LATIN1"; do
+    output=$(echo "2021-01-01,buy,10000.00,1.00000000,café" | $encode | ./target/debug/taxlot fifo)
+    if [ "$output" != "1,2021-01-01,10000.00,1.00000000,café" ]; then
+        echo "Error: expected the input to be decoded after $encode, got: $output"
+        exit 1
+    fi
+done
+echo "2021-01-01,buy,10000.00,1.00000000,café" | iconv -f UTF-8 -t LATIN1 | ./target/debug/taxlot fifo --encoding utf8 > /dev/null 2>&1
+if [ $? -ne 1 ]; then
+    echo "Error: expected exit code 1 for latin-1 input with --encoding utf8"
+    exit 1
+fi
+echo "Encoding test successful"
+
+# Verify blank lines and comments are skipped
+output=$(echo -e "# Brokerage account\n2021-01-01,buy,10000.00,1.00000000\n\n// moved from the old account\n2021-01-02,buy,20000.00,1.00000000" | ./target/debug/taxlot fifo --comment-prefix //)
+if [ "$output" != "$(echo -e "1,2021-01-01,10000.00,1.00000000\n2,2021-01-02,20000.00,1.00000000")" ]; then
+    echo "Error: expected blank lines and comments to be skipped, got: $output"
+    exit 1
+fi
+echo "Comment test successful"
+
+# Verify check schema writes the errors in each column
+schema_file=$(mktemp)
+echo -e "2021-01-01,buy,10000.00,1.00000000\n2021-13-01,buy,10000.00,1.00000000\n2021-01-02,hold,abc" > "$schema_file"
+output=$(./target/debug/taxlot check schema "$schema_file" 2>/dev/null)
+status=$?
+rm -f "$schema_file"
+if [ $status -ne 1 ] || [ "$output" != "$(echo -e "date,1,line 2: \`2021-13-01\` is not a date in the format YYYY-MM-DD\ntype,1,line 3: \`hold\` is not an operation type\nprice,1,line 3: \`abc\` is not a number\nquantity,1,line 3: missing\nfields,0,")" ]; then
+    echo "Error: expected exit code 1 and the errors in each column, got $status: $output"
+    exit 1
+fi
+echo "Schema check test successful"
+
+# Verify check schema validates fixed-width lines with the layout
+schema_file=$(mktemp)
+echo -e "2021-01-01 buy   10000.00    1\n2021-01-01 hold  10000.00" > "$schema_file"
+output=$(./target/debug/taxlot --fixed-width date=0:10,type=11:4,price=15:10,quantity=25:14 check schema "$schema_file" 2>/dev/null)
+status=$?
+rm -f "$schema_file"
+if [ $status -ne 1 ] || [ "$output" != "$(echo -e "date,0,\ntype,1,line 2: \`hold\` is not an operation type\nprice,0,\nquantity,1,line 2: missing")" ]; then
+    echo "Error: expected exit code 1 and the errors in each fixed-width column, got $status: $output"
+    exit 1
+fi
+echo "Fixed-width schema check test successful"
+
+# Verify a sell can override the selection algorithm
+output=$(echo -e "2021-01-01,buy,10000.00,1.00000000\n2021-01-02,buy,20000.00,1.00000000\n2021-02-01,sell,25000.00,1.00000000,method=fifo" | ./target/debug/taxlot hifo)
+if [ "$output" != "2,2021-01-02,20000.00,1.00000000" ]; then
+    echo "Error: expected the sell to use fifo, got: $output"
+    exit 1
+fi
+echo "Sell method test successful"
+
+# Verify add appends an operation once its columns are valid and it is confirmed
+add_file=$(mktemp)
+echo -e "2021-13-01\n2021-01-01\nbuy\n10000.00\n1.00000000\n\ny" | ./target/debug/taxlot add "$add_file" 2>/dev/null
+output=$(cat "$add_file")
+rm -f "$add_file"
+if [ "$output" != "2021-01-01,buy,10000.00,1.00000000" ]; then
+    echo "Error: expected add to append the operation, got: $output"
+    exit 1
+fi
+echo "Add test successful"
+
+# Verify the unrealized gains report values the lots at the latest mark price
+marks_file=$(mktemp)
+unrealized_file=$(mktemp)
+echo -e "date,price\n2022-12-01,16000\n2022-11-01,20000" > "$marks_file"
+echo -e "2021-01-01,buy,10000.00,1.00000000\n2022-06-01,buy,30000.00,1.00000000" | ./target/debug/taxlot fifo --unrealized-report "$unrealized_file" --mark-prices "$marks_file" > /dev/null
+output=$(cat "$unrealized_file")
+rm -f "$marks_file" "$unrealized_file"
+if [ "$output" != "$(echo -e "1,2021-01-01,1.00000000,10000.00,2022-12-01,16000.00,16000.00,6000.00,long\n2,2022-06-01,1.00000000,30000.00,2022-12-01,16000.00,16000.00,-14000.00,short")" ]; then
+    echo "Error: expected the lots valued at the mark price, got: $output"
+    exit 1
+fi
+echo "Unrealized gains report test successful"
+
+# Verify the performance report has the returns up to the mark price
+performance_file=$(mktemp)
+echo -e "2021-01-01,buy,100.00,10.00000000" | ./target/debug/taxlot fifo --performance-report "$performance_file" --mark-price 110 > /dev/null
+output=$(cut -d, -f1,3-5 "$performance_file")
+rm -f "$performance_file"
+if [ "$output" != "2021-01-01,1000.00,0.00,1100.00" ]; then
+    echo "Error: expected the cash flows of the performance report, got: $output"
+    exit 1
+fi
+echo "Performance report test successful"
+
+# Verify a URL input fails without the https feature
+echo -n "" | ./target/debug/taxlot fifo --input "https://example.com/trades.csv" 2>/dev/null
+if [ $? -ne 1 ]; then
+    echo "Error: expected exit code 1 for a URL input without the https feature"
+    exit 1
+fi
+echo "URL input test successful"
+
+# Verify diff writes the lots that differ between two inputs
+old_input=$(mktemp)
+new_input=$(mktemp)
+echo -e "2021-01-01,buy,100.00,1.00000000\n2021-01-02,buy,200.00,2.00000000" > "$old_input"
+echo -e "2021-01-01,buy,100.00,1.00000000\n2021-01-02,buy,200.00,1.00000000\n2021-01-03,buy,150.00,1.00000000" > "$new_input"
+output=$(./target/debug/taxlot diff fifo "$old_input" "$new_input")
+expected=$(echo -e "changed,2,2021-01-02,2021-01-02,200.00,200.00,2.00000000,1.00000000\nadded,3,,2021-01-03,,150.00,,1.00000000")
+if [ "$output" != "$expected" ]; then
+    echo "Error: expected the changed and added lots, got: $output"
+    exit 1
+fi
+rm "$old_input" "$new_input"
+echo "Diff test successful"
+
 echo "Successfully finished integration test"
\ No newline at end of file
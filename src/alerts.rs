@@ -0,0 +1,279 @@
+//! Alerts that run a command or call a webhook when a condition is met while the input is processed, e.g. to be
+//! notified during a long ingest.
+
+use std::{collections::HashSet, process::Command};
+
+use clap::{builder::BoolishValueParser, Args};
+use rust_decimal::Decimal;
+use taxlot::LotCollection;
+
+#[cfg(feature = "https")]
+use crate::output::json_string;
+
+/// Arguments that an alert must be given with, at least one of them.
+#[cfg(feature = "https")]
+pub const ALERT_SINKS: &str = "'--alert-exec <COMMAND>' or '--alert-webhook <URL>'";
+#[cfg(not(feature = "https"))]
+pub const ALERT_SINKS: &str = "'--alert-exec <COMMAND>'";
+
+/// Represents the alerts that are checked after every input operation is applied.
+///
+/// `exec`: Command run with `sh -c` when an alert fires. The alert, e.g. `short-term-gains`, is in the
+/// `TAXLOT_ALERT` environment variable of the command, and a description of it in `TAXLOT_ALERT_MESSAGE`.
+/// `webhook`: URL that a JSON object `{"alert": ..., "message": ...}` is posted to when an alert fires. Requires
+/// the `https` feature.
+/// `short_term_gains`: Fire once per year when the net short-term gains realized in it exceed this amount.
+/// `empty_position`: Fire when an operation sells the last of the lots.
+/// `oversell`: Fire when a sell has more quantity than the lots it can sell from.
+#[derive(Args, Debug, Clone, Default)]
+pub struct AlertOpts {
+    #[clap(long = "alert-exec", value_name = "COMMAND", env = "TAXLOT_ALERT_EXEC", global = true)]
+    pub exec: Option<String>,
+
+    #[cfg(feature = "https")]
+    #[clap(long = "alert-webhook", value_name = "URL", env = "TAXLOT_ALERT_WEBHOOK", global = true)]
+    pub webhook: Option<String>,
+
+    #[clap(
+        long = "alert-short-term-gains",
+        value_name = "AMOUNT",
+        env = "TAXLOT_ALERT_SHORT_TERM_GAINS",
+        global = true
+    )]
+    pub short_term_gains: Option<Decimal>,
+
+    #[clap(
+        long = "alert-empty-position",
+        value_parser = BoolishValueParser::new(),
+        env = "TAXLOT_ALERT_EMPTY_POSITION",
+        global = true
+    )]
+    pub empty_position: bool,
+
+    #[clap(
+        long = "alert-oversell",
+        value_parser = BoolishValueParser::new(),
+        env = "TAXLOT_ALERT_OVERSELL",
+        global = true
+    )]
+    pub oversell: bool,
+}
+
+impl AlertOpts {
+    /// Returns the argument of the first alert that is given, if any.
+    pub fn first_alert(&self) -> Option<&'static str> {
+        [
+            (self.short_term_gains.is_some(), "--alert-short-term-gains <AMOUNT>"),
+            (self.empty_position, "--alert-empty-position"),
+            (self.oversell, "--alert-oversell"),
+        ]
+        .into_iter()
+        .find_map(|(given, argument)| given.then_some(argument))
+    }
+
+    /// Returns true if fired alerts are sent anywhere.
+    pub fn has_sink(&self) -> bool {
+        #[cfg(feature = "https")]
+        if self.webhook.is_some() {
+            return true;
+        }
+        self.exec.is_some()
+    }
+}
+
+/// Checks the alerts of `opts` against a lot collection as operations are applied to it.
+///
+/// `realized_gains`: Realized gains when the alerts were last checked, so that the yearly gains are only
+/// looked at after a sell realized something.
+/// `had_lots`: Whether there were lots when the alerts were last checked.
+/// `oversold`: Quantity that sells could not find lots for when the alerts were last checked.
+/// `alerted_years`: Years whose short-term gains alert has fired.
+#[derive(Default)]
+pub struct Alerts {
+    opts: AlertOpts,
+    realized_gains: Decimal,
+    had_lots: bool,
+    oversold: Decimal,
+    alerted_years: HashSet<i32>,
+}
+
+impl Alerts {
+    /// Returns the alerts of `opts`, starting from the current state of `lot_collection`, e.g. after a checkpoint
+    /// was restored, so that conditions that were already met do not fire again.
+    pub fn new(opts: AlertOpts, lot_collection: &LotCollection) -> Self {
+        let alerted_years = match opts.short_term_gains {
+            Some(threshold) => lot_collection
+                .realized_gains_by_year()
+                .filter(|(_, gains)| gains.short_term > threshold)
+                .map(|(year, _)| year)
+                .collect(),
+            None => HashSet::new(),
+        };
+        Alerts {
+            opts,
+            realized_gains: lot_collection.realized_gains(),
+            had_lots: lot_collection.lots().next().is_some(),
+            oversold: lot_collection.oversold(),
+            alerted_years,
+        }
+    }
+
+    /// Checks the alerts after an operation was applied to `lot_collection`, running the command and calling the
+    /// webhook for each one that fires.
+    pub fn check(&mut self, lot_collection: &LotCollection) {
+        if !self.opts.has_sink() {
+            return;
+        }
+
+        if let Some(threshold) = self.opts.short_term_gains {
+            if lot_collection.realized_gains() != self.realized_gains {
+                self.realized_gains = lot_collection.realized_gains();
+                for (year, gains) in lot_collection.realized_gains_by_year() {
+                    if gains.short_term > threshold && self.alerted_years.insert(year) {
+                        let message = format!(
+                            "Net short-term gains realized in {year} are {:.2}, over {threshold}",
+                            gains.short_term
+                        );
+                        self.fire("short-term-gains", &message);
+                    }
+                }
+            }
+        }
+
+        if self.opts.empty_position {
+            let has_lots = lot_collection.lots().next().is_some();
+            if self.had_lots && !has_lots {
+                self.fire("empty-position", "No lots are left");
+            }
+            self.had_lots = has_lots;
+        }
+
+        if self.opts.oversell && lot_collection.oversold() != self.oversold {
+            let unsold = lot_collection.oversold() - self.oversold;
+            self.oversold = lot_collection.oversold();
+            // An amend or undo can lower the oversold quantity, which is not an oversell.
+            if unsold > Decimal::ZERO {
+                self.fire("oversell", &format!("A sell had {unsold} more than the lots it could sell from"));
+            }
+        }
+    }
+
+    /// Runs the command and calls the webhook for `alert`. One that fails is reported on stderr without stopping
+    /// the processing of the input.
+    fn fire(&self, alert: &str, message: &str) {
+        if let Some(exec) = &self.opts.exec {
+            run(exec, alert, message);
+        }
+        #[cfg(feature = "https")]
+        if let Some(url) = &self.opts.webhook {
+            post(url, alert, message);
+        }
+    }
+}
+
+/// Runs the alert command for `alert` and waits for it.
+fn run(exec: &str, alert: &str, message: &str) {
+    let status = Command::new("sh")
+        .arg("-c")
+        .arg(exec)
+        .env("TAXLOT_ALERT", alert)
+        .env("TAXLOT_ALERT_MESSAGE", message)
+        .status();
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => eprintln!("Alert command for {alert} failed: {status}"),
+        Err(e) => eprintln!("Could not run alert command for {alert}: {e}"),
+    }
+}
+
+/// Posts `alert` and `message` to the webhook at `url` as a JSON object and waits for the response.
+#[cfg(feature = "https")]
+fn post(url: &str, alert: &str, message: &str) {
+    let body = format!("{{\"alert\":{},\"message\":{}}}", json_string(alert), json_string(message));
+    if let Err(e) = ureq::post(url).content_type("application/json").send(&body) {
+        eprintln!("Could not call the alert webhook for {alert}: {e}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, str::FromStr};
+
+    use rust_decimal::Decimal;
+    use taxlot::{LotCollection, LotOperation, SelectionAlgorithm, TaxLotError};
+
+    use super::{AlertOpts, Alerts};
+
+    /// Applies `operations` to a new lot collection, checking `opts` after each one, and returns the alerts that
+    /// fired, in order.
+    fn fired_alerts(opts: AlertOpts, operations: &[&str]) -> Result<Vec<String>, TaxLotError> {
+        let fired = tempfile::NamedTempFile::new()?;
+        let opts = AlertOpts {
+            exec: Some(format!("echo \"$TAXLOT_ALERT\" >> '{}'", fired.path().display())),
+            ..opts
+        };
+        let mut lot_collection = LotCollection::new(SelectionAlgorithm::Fifo);
+        let mut alerts = Alerts::new(opts, &lot_collection);
+        for operation in operations {
+            lot_collection.apply_lot_operation(LotOperation::from_str(operation)?)?;
+            alerts.check(&lot_collection);
+        }
+        Ok(fs::read_to_string(fired.path())?.lines().map(str::to_string).collect())
+    }
+
+    #[test]
+    fn test_short_term_gains_alert_fires_once_per_year_over_the_threshold() -> Result<(), TaxLotError> {
+        let opts = AlertOpts { short_term_gains: Some(Decimal::from(1000)), ..AlertOpts::default() };
+        let operations = [
+            "2021-01-01,buy,10000.00,3.00000000",
+            // Gains at the threshold are not over it.
+            "2021-02-01,sell,11000.00,1.00000000",
+            "2021-03-01,sell,10000.01,1.00000000",
+            "2021-04-01,sell,12000.00,1.00000000",
+            "2021-12-01,buy,10000.00,1.00000000",
+            "2022-01-02,sell,11000.01,1.00000000",
+        ];
+        let fired = fired_alerts(opts, &operations)?;
+        assert_eq!(fired, ["short-term-gains", "short-term-gains"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_short_term_gains_alert_ignores_long_term_gains() -> Result<(), TaxLotError> {
+        let opts = AlertOpts { short_term_gains: Some(Decimal::from(1000)), ..AlertOpts::default() };
+        // The lot is sold a year and a day after it was bought, so the gain is long-term.
+        let operations = ["2021-01-01,buy,10000.00,1.00000000", "2022-01-02,sell,20000.00,1.00000000"];
+        assert!(fired_alerts(opts.clone(), &operations)?.is_empty());
+
+        // A year to the day is still short-term.
+        let operations = ["2021-01-01,buy,10000.00,1.00000000", "2022-01-01,sell,20000.00,1.00000000"];
+        assert_eq!(fired_alerts(opts, &operations)?, ["short-term-gains"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_empty_position_and_oversell_alerts() -> Result<(), TaxLotError> {
+        let opts = AlertOpts { empty_position: true, oversell: true, ..AlertOpts::default() };
+        let operations = [
+            "2021-01-01,buy,10000.00,2.00000000",
+            "2021-02-01,sell,10000.00,1.00000000",
+            "2021-03-01,sell,10000.00,2.00000000",
+            "2021-04-01,buy,10000.00,1.00000000",
+            "2021-05-01,sell,10000.00,1.00000000",
+        ];
+        let fired = fired_alerts(opts, &operations)?;
+        assert_eq!(fired, ["empty-position", "oversell", "empty-position"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_alerts_without_a_sink_do_not_fire() -> Result<(), TaxLotError> {
+        let opts = AlertOpts { empty_position: true, ..AlertOpts::default() };
+        let mut lot_collection = LotCollection::new(SelectionAlgorithm::Fifo);
+        let mut alerts = Alerts::new(opts, &lot_collection);
+        lot_collection.apply_lot_operation(LotOperation::from_str("2021-01-01,buy,10000.00,1.00000000")?)?;
+        alerts.check(&lot_collection);
+        assert!(!alerts.had_lots);
+        Ok(())
+    }
+}
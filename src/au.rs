@@ -0,0 +1,216 @@
+//! Australian capital gains tax reporting with `--jurisdiction au`: the 50% CGT discount of disposals of assets held
+//! for at least 12 months, applied in the gains report by income year, and the method of each disposal.
+//!
+//! The 12 months exclude the day a lot was acquired and the day it was sold, so a lot is eligible for the discount
+//! when it is sold after its first anniversary. Capital losses, with the net capital loss carried forward from earlier
+//! income years, are applied to the gains that cannot be discounted first, which leaves the most to discount. The
+//! discount is half of the discountable gains that are left. Income years run from 1 July to 30 June and are named by
+//! the year they end in.
+//!
+//! The indexation method is not chosen for any disposal. It only applies to assets acquired before 21 September
+//! 1999, and needs consumer price index figures that the input does not have. The discount method is available for
+//! those assets too.
+
+use std::{collections::BTreeMap, fmt::Display, io};
+
+use chrono::{Datelike, Months, NaiveDate};
+use rust_decimal::Decimal;
+use taxlot::Disposal;
+
+/// Represents the method that the capital gain of a disposal is worked out with. `Other` is the gain without a
+/// discount, which is also the method of every loss.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CgtMethod {
+    Other,
+    Discount,
+}
+
+impl Display for CgtMethod {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CgtMethod::Other => write!(f, "other"),
+            CgtMethod::Discount => write!(f, "discount"),
+        }
+    }
+}
+
+/// Returns true if an asset acquired on `acquired` and sold on `sold` was held for at least 12 months.
+pub fn is_discount_eligible(acquired: NaiveDate, sold: NaiveDate) -> bool {
+    acquired.checked_add_months(Months::new(12)).is_some_and(|anniversary| sold > anniversary)
+}
+
+/// Returns the method of the capital gain of `disposal`.
+pub fn method(disposal: &Disposal) -> CgtMethod {
+    if disposal.gain > Decimal::ZERO && is_discount_eligible(disposal.acquired, disposal.sold) {
+        CgtMethod::Discount
+    } else {
+        CgtMethod::Other
+    }
+}
+
+/// Returns the income year that `date` is in.
+pub fn income_year(date: NaiveDate) -> i32 {
+    if date.month() >= 7 {
+        date.year() + 1
+    } else {
+        date.year()
+    }
+}
+
+/// Represents the capital gains of an income year.
+///
+/// `year`: Income year, named by the year it ends in.
+/// `other_gains`: Gains that cannot be discounted.
+/// `discountable_gains`: Gains of assets held for at least 12 months, before the discount.
+/// `losses`: Capital losses of the year.
+/// `carried_losses`: Net capital loss carried forward from earlier years and applied in this one.
+/// `discount`: Half of the discountable gains that are left after the losses.
+/// `net_gain`: Net capital gain of the year.
+/// `carried_forward`: Net capital loss carried forward to later years.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct IncomeYearGains {
+    pub year: i32,
+    pub other_gains: Decimal,
+    pub discountable_gains: Decimal,
+    pub losses: Decimal,
+    pub carried_losses: Decimal,
+    pub discount: Decimal,
+    pub net_gain: Decimal,
+    pub carried_forward: Decimal,
+}
+
+/// Returns the capital gains of each income year with disposals, oldest first.
+pub fn income_years(disposals: impl Iterator<Item = io::Result<Disposal>>) -> io::Result<Vec<IncomeYearGains>> {
+    let mut years: BTreeMap<i32, IncomeYearGains> = BTreeMap::new();
+    for disposal in disposals {
+        let disposal = disposal?;
+        let year = income_year(disposal.sold);
+        let gains = years.entry(year).or_insert(IncomeYearGains { year, ..IncomeYearGains::default() });
+        match method(&disposal) {
+            _ if disposal.gain < Decimal::ZERO => gains.losses -= disposal.gain,
+            CgtMethod::Discount => gains.discountable_gains += disposal.gain,
+            CgtMethod::Other => gains.other_gains += disposal.gain,
+        }
+    }
+
+    let mut carried_forward = Decimal::ZERO;
+    let mut years: Vec<_> = years.into_values().collect();
+    for gains in &mut years {
+        let mut losses = gains.losses;
+        let other_gains = apply_losses(gains.other_gains, &mut losses);
+        let discountable_gains = apply_losses(gains.discountable_gains, &mut losses);
+        // A loss carried forward is applied only after the losses of the year, and only to what they leave.
+        let carried = carried_forward;
+        let other_gains = apply_losses(other_gains, &mut carried_forward);
+        let discountable_gains = apply_losses(discountable_gains, &mut carried_forward);
+        gains.carried_losses = carried - carried_forward;
+        gains.discount = discountable_gains / Decimal::TWO;
+        gains.net_gain = other_gains + discountable_gains - gains.discount;
+        carried_forward += losses;
+        gains.carried_forward = carried_forward;
+    }
+    Ok(years)
+}
+
+/// Returns what is left of `gains` after applying as much of `losses` to them as they can take, and leaves the rest
+/// of the losses in `losses`.
+fn apply_losses(gains: Decimal, losses: &mut Decimal) -> Decimal {
+    let applied = gains.min(*losses);
+    *losses -= applied;
+    gains - applied
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chrono::NaiveDate;
+    use rust_decimal::Decimal;
+    use taxlot::{Disposal, TaxLotError};
+
+    use super::{income_year, income_years, is_discount_eligible, method, CgtMethod, IncomeYearGains};
+
+    fn date(date: &str) -> NaiveDate {
+        NaiveDate::from_str(date).expect("Invalid date")
+    }
+
+    fn disposal(acquired: &str, sold: &str, gain: i64) -> Disposal {
+        Disposal {
+            lot_id: 1,
+            acquired: date(acquired),
+            sold: date(sold),
+            quantity: Decimal::ONE,
+            cost_basis: Decimal::from(1000),
+            proceeds: Decimal::from(1000 + gain),
+            gain: Decimal::from(gain),
+            long_term: false,
+            txn_id: None,
+        }
+    }
+
+    #[test]
+    fn test_discount_needs_12_months_between_acquiring_and_selling() {
+        assert!(!is_discount_eligible(date("2021-03-15"), date("2022-03-15")));
+        assert!(is_discount_eligible(date("2021-03-15"), date("2022-03-16")));
+        // The anniversary of 29 February is 28 February.
+        assert!(!is_discount_eligible(date("2020-02-29"), date("2021-02-28")));
+        assert!(is_discount_eligible(date("2020-02-29"), date("2021-03-01")));
+
+        assert_eq!(method(&disposal("2021-03-15", "2022-03-16", 100)), CgtMethod::Discount);
+        assert_eq!(method(&disposal("2021-03-15", "2022-03-15", 100)), CgtMethod::Other);
+        assert_eq!(method(&disposal("2021-03-15", "2022-03-16", -100)), CgtMethod::Other);
+    }
+
+    #[test]
+    fn test_income_years_end_on_30_june() {
+        assert_eq!(income_year(date("2022-06-30")), 2022);
+        assert_eq!(income_year(date("2022-07-01")), 2023);
+    }
+
+    #[test]
+    fn test_losses_are_applied_before_the_discount() -> Result<(), TaxLotError> {
+        let disposals = [
+            // 2022: the loss is applied to the gain that cannot be discounted first.
+            disposal("2020-01-01", "2021-08-01", 1000),
+            disposal("2021-08-01", "2021-09-01", 300),
+            disposal("2021-08-01", "2022-06-30", -500),
+            // 2023: a loss larger than the gains is carried forward.
+            disposal("2021-01-01", "2022-07-01", 200),
+            disposal("2022-07-01", "2022-08-01", -700),
+            // 2024: the loss carried forward is applied.
+            disposal("2022-01-01", "2023-07-01", 1000),
+        ];
+        let years = income_years(disposals.into_iter().map(Ok))?;
+        let decimal = Decimal::from;
+        assert_eq!(
+            years,
+            [
+                IncomeYearGains {
+                    year: 2022,
+                    other_gains: decimal(300),
+                    discountable_gains: decimal(1000),
+                    losses: decimal(500),
+                    discount: decimal(400),
+                    net_gain: decimal(400),
+                    ..IncomeYearGains::default()
+                },
+                IncomeYearGains {
+                    year: 2023,
+                    discountable_gains: decimal(200),
+                    losses: decimal(700),
+                    carried_forward: decimal(500),
+                    ..IncomeYearGains::default()
+                },
+                IncomeYearGains {
+                    year: 2024,
+                    discountable_gains: decimal(1000),
+                    carried_losses: decimal(500),
+                    discount: decimal(250),
+                    net_gain: decimal(250),
+                    ..IncomeYearGains::default()
+                },
+            ]
+        );
+        Ok(())
+    }
+}
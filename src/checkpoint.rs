@@ -0,0 +1,508 @@
+//! Checkpoints of the lots of a `LotCollection`, so that an interrupted ingest can resume from the last
+//! checkpoint instead of applying every operation again.
+//!
+//! A checkpoint is a text file. The first line is `taxlot-checkpoint,<algorithm>,<operations>,<realized
+//! gains>,<next id>`, where `operations` is the number of input operations applied before the checkpoint
+//! was written. It is followed by a line `gains,<year>,<quarter>,<short-term>,<long-term>` for the gains
+//! realized in every quarter, a line `income,<year>,<type>,<amount>` for every income total, and a line
+//! `oversold,<quantity>` if sells could not find lots for some of their quantity. Every other
+//! line is a lot, `id,date,price,quantity[,tags=a;b][,memo=<memo>][,espp=<offering date>;<offering fmv>;<purchase
+//! fmv>;<discount>]`, with the exact price and quantity rather than the rounded ones that are printed, followed by
+//! a line `purchase,<date>,<price>,<quantity>` for each of its purchases. Backslashes, commas and line endings in
+//! tags and memos are escaped with a backslash, e.g. `\,` and `\n`, since memos from XML input can span lines. If
+//! disposals are recorded, each of them is a line
+//! `disposal,<lot id>,<acquired>,<sold>,<quantity>,<cost basis>,<proceeds>,<gain>,<long|short>[,txn=<id>]`.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    io::{self, Write},
+    str::FromStr,
+    sync::atomic::Ordering,
+};
+
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+
+use crate::{
+    Disposal, EsppPurchase, IncomeType, Lot, LotCollection, LotOperation, Purchase, RealizedGains, SelectionAlgorithm,
+    TaxLotError,
+};
+
+/// First field of the header line of a checkpoint.
+const CHECKPOINT_MAGIC: &str = "taxlot-checkpoint";
+
+/// First field of the quarterly gains lines of a checkpoint.
+const GAINS_PREFIX: &str = "gains,";
+
+/// First field of the income lines of a checkpoint.
+const INCOME_PREFIX: &str = "income,";
+
+/// First field of the oversold quantity line of a checkpoint.
+const OVERSOLD_PREFIX: &str = "oversold,";
+
+/// First field of the purchase lines of a checkpoint.
+const PURCHASE_PREFIX: &str = "purchase,";
+
+/// First field of the disposal lines of a checkpoint.
+const DISPOSAL_PREFIX: &str = "disposal,";
+
+/// Prefixes of the tags, memo and ESPP purchase fields of the lot lines, and of the transaction id field of the
+/// disposal lines, of a checkpoint.
+const TAGS_PREFIX: &str = "tags=";
+const MEMO_PREFIX: &str = "memo=";
+const ESPP_PREFIX: &str = "espp=";
+const TXN_PREFIX: &str = "txn=";
+
+impl LotCollection {
+    /// Writes the lots, realized gains of every quarter, income, oversold quantity, disposals and next sequential id
+    /// to `out`, along with the number of input `operations` applied so far. Locked lots, settings, the journal and
+    /// the undo log are not included.
+    pub fn write_checkpoint(&self, operations: u64, mut out: impl Write) -> io::Result<()> {
+        writeln!(
+            out,
+            "{CHECKPOINT_MAGIC},{},{operations},{},{}",
+            self.selection_algorithm,
+            self.realized_gains,
+            self.id_generator.load(Ordering::SeqCst)
+        )?;
+        for ((year, quarter), gains) in &self.quarterly_gains {
+            writeln!(out, "{GAINS_PREFIX}{year},{quarter},{},{}", gains.short_term, gains.long_term)?;
+        }
+        for ((year, income_type), amount) in &self.income {
+            writeln!(out, "{INCOME_PREFIX}{year},{income_type},{amount}")?;
+        }
+        if !self.oversold.is_zero() {
+            writeln!(out, "{OVERSOLD_PREFIX}{}", self.oversold)?;
+        }
+        for lot in self.lots() {
+            write_lot(&lot, &mut out)?;
+        }
+        for disposal in self.disposals() {
+            write!(out, "{DISPOSAL_PREFIX}")?;
+            write_disposal(&disposal?, &mut out)?;
+            writeln!(out)?;
+        }
+        out.flush()
+    }
+
+    /// Replaces the lots, realized gains of every quarter, income, oversold quantity and next sequential id with the
+    /// ones in a checkpoint written by `write_checkpoint`, and returns the number of input operations that had been
+    /// applied. The operations after them can then be applied to continue where the checkpoint left off. The
+    /// checkpoint must have been written with the same selection algorithm. The disposals are only restored if
+    /// they are recorded, see `enable_disposals`.
+    pub fn restore_checkpoint(&mut self, checkpoint: &str) -> Result<u64, TaxLotError> {
+        let mut lines = checkpoint.lines();
+        let header = lines.next().unwrap_or_default();
+        let mut fields = header.split(',');
+        if fields.next() != Some(CHECKPOINT_MAGIC) {
+            return Err(TaxLotError::InvalidCheckpoint("missing header".to_string()));
+        }
+        let selection_algorithm = SelectionAlgorithm::from_str(LotOperation::next_field(&mut fields, "Algorithm")?)?;
+        if selection_algorithm != self.selection_algorithm {
+            return Err(TaxLotError::InvalidCheckpoint(format!(
+                "written with {selection_algorithm}, not {}",
+                self.selection_algorithm
+            )));
+        }
+        let operations = parse_u64(LotOperation::next_field(&mut fields, "Operations")?)?;
+        let realized_gains = Decimal::from_str(LotOperation::next_field(&mut fields, "Realized Gains")?)?;
+        let next_id = parse_u64(LotOperation::next_field(&mut fields, "Next Id")?)?;
+
+        let mut quarterly_gains = BTreeMap::new();
+        let mut income = BTreeMap::new();
+        let mut oversold = Decimal::ZERO;
+        let mut lots = Vec::new();
+        let mut disposals = Vec::new();
+        for line in lines {
+            if let Some(line) = line.strip_prefix(GAINS_PREFIX) {
+                let mut fields = line.split(',');
+                let year = parse_year(LotOperation::next_field(&mut fields, "Year")?)?;
+                let quarter = LotOperation::next_field(&mut fields, "Quarter")?;
+                let quarter = quarter
+                    .parse()
+                    .ok()
+                    .filter(|quarter| (1..=4).contains(quarter))
+                    .ok_or_else(|| TaxLotError::InvalidCheckpoint(format!("`{quarter}` is not a quarter")))?;
+                let short_term = Decimal::from_str(LotOperation::next_field(&mut fields, "Short-Term Gains")?)?;
+                let long_term = Decimal::from_str(LotOperation::next_field(&mut fields, "Long-Term Gains")?)?;
+                quarterly_gains.insert((year, quarter), RealizedGains { short_term, long_term });
+            } else if let Some(line) = line.strip_prefix(INCOME_PREFIX) {
+                let mut fields = line.split(',');
+                let year = parse_year(LotOperation::next_field(&mut fields, "Year")?)?;
+                let income_type = IncomeType::from_str(LotOperation::next_field(&mut fields, "Income Type")?)?;
+                let amount = Decimal::from_str(LotOperation::next_field(&mut fields, "Amount")?)?;
+                income.insert((year, income_type), amount);
+            } else if let Some(quantity) = line.strip_prefix(OVERSOLD_PREFIX) {
+                oversold = Decimal::from_str(quantity)?;
+            } else if let Some(line) = line.strip_prefix(PURCHASE_PREFIX) {
+                let lot: &mut Lot = lots
+                    .last_mut()
+                    .ok_or_else(|| TaxLotError::InvalidCheckpoint("purchase before the first lot".to_string()))?;
+                lot.purchases.push(parse_purchase(line)?);
+            } else if let Some(line) = line.strip_prefix(DISPOSAL_PREFIX) {
+                disposals.push(parse_disposal(line)?);
+            } else {
+                lots.push(parse_lot(line, self.selection_algorithm)?);
+            }
+        }
+
+        // The disposals are replaced first, since spilling them can fail.
+        if let Some(recorded) = &mut self.disposals {
+            recorded.replace(disposals)?;
+        }
+        self.lots.clear();
+        self.lot_ids.clear();
+        self.date_index.clear();
+        for lot in lots {
+            self.insert_lot(lot);
+        }
+        self.realized_gains = realized_gains;
+        self.quarterly_gains = quarterly_gains;
+        self.income = income;
+        self.oversold = oversold;
+        self.id_generator.store(next_id, Ordering::SeqCst);
+        Ok(operations)
+    }
+}
+
+/// Writes a lot line of a checkpoint and the purchase lines after it.
+pub(crate) fn write_lot(lot: &Lot, out: &mut impl Write) -> io::Result<()> {
+    write!(out, "{},{},{},{}", lot.id, lot.date, lot.price, lot.quantity)?;
+    let tags: Vec<_> = lot.tags().map(escape_tag).collect();
+    if !tags.is_empty() {
+        write!(out, ",{TAGS_PREFIX}{}", escape(&tags.join(";")))?;
+    }
+    if let Some(memo) = &lot.memo {
+        write!(out, ",{MEMO_PREFIX}{}", escape(memo))?;
+    }
+    if let Some(EsppPurchase { offering_date, offering_fmv, purchase_fmv, discount }) = &lot.espp {
+        write!(out, ",{ESPP_PREFIX}{offering_date};{offering_fmv};{purchase_fmv};{discount}")?;
+    }
+    writeln!(out)?;
+    for purchase in &lot.purchases {
+        writeln!(out, "{PURCHASE_PREFIX}{},{},{}", purchase.date, purchase.price, purchase.quantity)?;
+    }
+    Ok(())
+}
+
+/// Parses the lines that `write_lot` writes for a lot, e.g. of a spilled lot.
+pub(crate) fn parse_lot_lines(lines: &str, selection_algo: SelectionAlgorithm) -> Result<Lot, TaxLotError> {
+    let mut lines = lines.lines();
+    let mut lot = parse_lot(lines.next().unwrap_or_default(), selection_algo)?;
+    for line in lines {
+        let purchase = line
+            .strip_prefix(PURCHASE_PREFIX)
+            .ok_or_else(|| TaxLotError::InvalidCheckpoint(format!("`{line}` is not a purchase")))?;
+        lot.purchases.push(parse_purchase(purchase)?);
+    }
+    Ok(lot)
+}
+
+/// Parses a purchase line of a checkpoint, without its prefix.
+fn parse_purchase(line: &str) -> Result<Purchase, TaxLotError> {
+    let mut fields = line.split(',');
+    let date = NaiveDate::parse_from_str(LotOperation::next_field(&mut fields, "Date")?, "%Y-%m-%d")?;
+    let price = Decimal::from_str(LotOperation::next_field(&mut fields, "Price")?)?;
+    let quantity = Decimal::from_str(LotOperation::next_field(&mut fields, "Quantity")?)?;
+    Ok(Purchase { date, price, quantity })
+}
+
+/// Parses a lot line of a checkpoint. Its purchases are on the lines after it.
+fn parse_lot(line: &str, selection_algo: SelectionAlgorithm) -> Result<Lot, TaxLotError> {
+    let fields = split_escaped(line)?;
+    let mut parts = fields.iter().map(String::as_str);
+    let id = parse_u64(LotOperation::next_field(&mut parts, "Lot Id")?)?;
+    let date = NaiveDate::parse_from_str(LotOperation::next_field(&mut parts, "Date")?, "%Y-%m-%d")?;
+    let price = Decimal::from_str(LotOperation::next_field(&mut parts, "Price")?)?;
+    let quantity = Decimal::from_str(LotOperation::next_field(&mut parts, "Quantity")?)?;
+    let mut tags = BTreeSet::new();
+    let mut memo = None;
+    let mut espp = None;
+    for field in parts {
+        if let Some(value) = field.strip_prefix(TAGS_PREFIX) {
+            tags.extend(split_tags(value)?);
+        } else if let Some(value) = field.strip_prefix(MEMO_PREFIX) {
+            memo = Some(value.to_string());
+        } else if let Some(value) = field.strip_prefix(ESPP_PREFIX) {
+            espp = Some(parse_espp(value)?);
+        } else {
+            return Err(TaxLotError::InvalidCheckpoint(format!("unknown lot field `{field}`")));
+        }
+    }
+
+    Ok(Lot {
+        id,
+        date,
+        price,
+        quantity,
+        selection_algo,
+        tags,
+        memo,
+        espp,
+        purchases: Vec::new(),
+    })
+}
+
+/// Parses the `<offering date>;<offering fmv>;<purchase fmv>;<discount>` value of the ESPP field of a lot line.
+fn parse_espp(value: &str) -> Result<EsppPurchase, TaxLotError> {
+    let mut parts = value.split(';');
+    let offering_date = NaiveDate::parse_from_str(LotOperation::next_field(&mut parts, "Offering Date")?, "%Y-%m-%d")?;
+    let offering_fmv = Decimal::from_str(LotOperation::next_field(&mut parts, "Offering FMV")?)?;
+    let purchase_fmv = Decimal::from_str(LotOperation::next_field(&mut parts, "Purchase FMV")?)?;
+    let discount = Decimal::from_str(LotOperation::next_field(&mut parts, "Discount")?)?;
+    Ok(EsppPurchase { offering_date, offering_fmv, purchase_fmv, discount })
+}
+
+/// Writes the fields of a disposal line of a checkpoint, without its prefix and line ending.
+pub(crate) fn write_disposal(disposal: &Disposal, out: &mut impl Write) -> io::Result<()> {
+    let Disposal { lot_id, acquired, sold, quantity, cost_basis, proceeds, gain, .. } = disposal;
+    let term = if disposal.long_term { "long" } else { "short" };
+    write!(out, "{lot_id},{acquired},{sold},{quantity},{cost_basis},{proceeds},{gain},{term}")?;
+    if let Some(txn_id) = &disposal.txn_id {
+        write!(out, ",{TXN_PREFIX}{}", escape(txn_id))?;
+    }
+    Ok(())
+}
+
+/// Parses a disposal line of a checkpoint, without its prefix.
+pub(crate) fn parse_disposal(line: &str) -> Result<Disposal, TaxLotError> {
+    let fields = split_escaped(line)?;
+    let mut parts = fields.iter().map(String::as_str);
+    let lot_id = parse_u64(LotOperation::next_field(&mut parts, "Lot Id")?)?;
+    let acquired = NaiveDate::parse_from_str(LotOperation::next_field(&mut parts, "Acquired")?, "%Y-%m-%d")?;
+    let sold = NaiveDate::parse_from_str(LotOperation::next_field(&mut parts, "Sold")?, "%Y-%m-%d")?;
+    let quantity = Decimal::from_str(LotOperation::next_field(&mut parts, "Quantity")?)?;
+    let cost_basis = Decimal::from_str(LotOperation::next_field(&mut parts, "Cost Basis")?)?;
+    let proceeds = Decimal::from_str(LotOperation::next_field(&mut parts, "Proceeds")?)?;
+    let gain = Decimal::from_str(LotOperation::next_field(&mut parts, "Gain")?)?;
+    let long_term = match LotOperation::next_field(&mut parts, "Term")? {
+        "long" => true,
+        "short" => false,
+        term => return Err(TaxLotError::InvalidCheckpoint(format!("`{term}` is not long or short"))),
+    };
+    let txn_id = match parts.next() {
+        Some(field) => match field.strip_prefix(TXN_PREFIX) {
+            Some(txn_id) => Some(txn_id.to_string()),
+            None => return Err(TaxLotError::InvalidCheckpoint(format!("unknown disposal field `{field}`"))),
+        },
+        None => None,
+    };
+
+    Ok(Disposal {
+        lot_id,
+        acquired,
+        sold,
+        quantity,
+        cost_basis,
+        proceeds,
+        gain,
+        long_term,
+        txn_id,
+    })
+}
+
+/// Escapes the backslashes, commas and line endings of a tag, memo or transaction id, so that it stays in one field
+/// of its line.
+fn escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            ',' => escaped.push_str("\\,"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Escapes the backslashes and semicolons of a tag, so that it stays one tag of the `;` separated tags field. The
+/// field is escaped with `escape` after the tags are joined.
+fn escape_tag(tag: &str) -> String {
+    tag.replace('\\', "\\\\").replace(';', "\\;")
+}
+
+/// Splits the value of the tags field at the semicolons that are not escaped, and unescapes every tag.
+fn split_tags(value: &str) -> Result<Vec<String>, TaxLotError> {
+    let mut tags = vec![String::new()];
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        let tag = tags.last_mut().expect("There is always a tag");
+        match c {
+            ';' => tags.push(String::new()),
+            '\\' => match chars.next() {
+                Some(c @ ('\\' | ';')) => tag.push(c),
+                _ => return Err(TaxLotError::InvalidCheckpoint(format!("invalid escape in tags `{value}`"))),
+            },
+            c => tag.push(c),
+        }
+    }
+    Ok(tags)
+}
+
+/// Splits a line at the commas that are not escaped, and unescapes every field.
+fn split_escaped(line: &str) -> Result<Vec<String>, TaxLotError> {
+    let mut fields = vec![String::new()];
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        let field = fields.last_mut().expect("There is always a field");
+        match c {
+            ',' => fields.push(String::new()),
+            '\\' => match chars.next() {
+                Some('n') => field.push('\n'),
+                Some('r') => field.push('\r'),
+                Some(c @ ('\\' | ',')) => field.push(c),
+                _ => return Err(TaxLotError::InvalidCheckpoint(format!("invalid escape in `{line}`"))),
+            },
+            c => field.push(c),
+        }
+    }
+    Ok(fields)
+}
+
+/// Parses the year of a quarterly gains or income line.
+fn parse_year(s: &str) -> Result<i32, TaxLotError> {
+    s.parse()
+        .map_err(|_| TaxLotError::InvalidCheckpoint(format!("`{s}` is not a year")))
+}
+
+/// Parses a lot id or operation count.
+fn parse_u64(s: &str) -> Result<u64, TaxLotError> {
+    s.parse()
+        .map_err(|_| TaxLotError::InvalidCheckpoint(format!("`{s}` is not a number")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::{
+        test_helpers::{apply, lots},
+        LotCollection, LotOperation, SelectionAlgorithm, TaxLotError,
+    };
+
+    #[test]
+    fn test_restored_checkpoint_continues_ingest() -> Result<(), TaxLotError> {
+        let head = [
+            "2021-01-01,buy,10000.00,1.00000000,tags=ira;dca,first, of two",
+            "2021-01-02,buy,20000.00,3.00000000",
+            "2021-01-02,buy,20001.00,1.00000000",
+            "2021-02-01,sell,15000.00,0.33333333,txn=s1",
+            "2021-02-01,dividend,0.25,3.66666667",
+            "2021-02-01,espp,85.00,2,offering=2020-08-01,offering_fmv=100.00,fmv=110.00",
+        ];
+        let tail = ["2021-02-02,buy,30000.00,1.00000000", "2021-02-03,sell,25000.00,1.50000000"];
+
+        let mut lot_collection = LotCollection::new(SelectionAlgorithm::Hifo);
+        lot_collection.enable_disposals();
+        apply(&mut lot_collection, &head)?;
+        let mut checkpoint = Vec::new();
+        lot_collection.write_checkpoint(head.len() as u64, &mut checkpoint)?;
+        apply(&mut lot_collection, &tail)?;
+
+        let mut restored = LotCollection::new(SelectionAlgorithm::Hifo);
+        restored.enable_disposals();
+        let checkpoint = String::from_utf8(checkpoint).expect("Checkpoint is not UTF-8");
+        assert_eq!(restored.restore_checkpoint(&checkpoint)?, head.len() as u64);
+        apply(&mut restored, &tail)?;
+
+        // The exact prices and quantities are restored, so the result is the same as applying every operation.
+        assert_eq!(lots(&restored), lots(&lot_collection));
+        let purchases = |lot_collection: &LotCollection| -> Vec<_> {
+            lot_collection.lots().map(|lot| lot.purchases().to_vec()).collect()
+        };
+        assert_eq!(purchases(&restored), purchases(&lot_collection));
+        let espp =
+            |lot_collection: &LotCollection| lot_collection.lots().map(|lot| lot.espp().copied()).collect::<Vec<_>>();
+        assert_eq!(espp(&restored), espp(&lot_collection));
+        assert_eq!(restored.realized_gains(), lot_collection.realized_gains());
+        assert!(restored.realized_gains_by_quarter().eq(lot_collection.realized_gains_by_quarter()));
+        assert!(restored.income().eq(lot_collection.income()));
+        let disposals = |lot_collection: &LotCollection| lot_collection.disposals().collect::<Result<Vec<_>, _>>();
+        assert_eq!(disposals(&restored)?, disposals(&lot_collection)?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_checkpoint_requires_same_algorithm() -> Result<(), TaxLotError> {
+        let mut lot_collection = LotCollection::new(SelectionAlgorithm::Hifo);
+        apply(&mut lot_collection, &["2021-01-01,buy,10000.00,1.00000000"])?;
+        let mut checkpoint = Vec::new();
+        lot_collection.write_checkpoint(1, &mut checkpoint)?;
+        let checkpoint = String::from_utf8(checkpoint).expect("Checkpoint is not UTF-8");
+
+        let mut restored = LotCollection::new(SelectionAlgorithm::Fifo);
+        restored
+            .restore_checkpoint(&checkpoint)
+            .expect_err("Restored a checkpoint written with another algorithm");
+        restored.restore_checkpoint("").expect_err("Restored an empty checkpoint");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_checkpoint_escapes_tags_and_memos() -> Result<(), TaxLotError> {
+        // Memos from XML input can span lines, and a memo can look like a field.
+        let memos = ["tags=ira,txn=1", "two\nlines\r\n", "back\\slash, \\n and comma"];
+        let mut lot_collection = LotCollection::new(SelectionAlgorithm::Fifo);
+        for (i, memo) in memos.into_iter().enumerate() {
+            let mut lot_operation = LotOperation::from_str(&format!("2021-01-0{},buy,10000.00,1.00000000", i + 1))?;
+            lot_operation.tags.insert(format!("acct,{i}\\"));
+            lot_operation.memo = Some(memo.to_string());
+            lot_collection.apply_lot_operation(lot_operation)?;
+        }
+        let mut checkpoint = Vec::new();
+        lot_collection.write_checkpoint(memos.len() as u64, &mut checkpoint)?;
+        let checkpoint = String::from_utf8(checkpoint).expect("Checkpoint is not UTF-8");
+        assert_eq!(checkpoint.lines().count(), 1 + 2 * memos.len());
+
+        let mut restored = LotCollection::new(SelectionAlgorithm::Fifo);
+        restored.restore_checkpoint(&checkpoint)?;
+        let restored_memos: Vec<_> = restored.lots().map(|lot| lot.memo().map(str::to_string)).collect();
+        assert_eq!(restored_memos, memos.map(|memo| Some(memo.to_string())));
+        assert_eq!(lots(&restored), lots(&lot_collection));
+
+        restored
+            .restore_checkpoint("taxlot-checkpoint,fifo,1,0,2\n1,2021-01-01,1,1,memo=bad \\escape")
+            .expect_err("Restored a memo with an invalid escape");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_checkpoint_round_trips_tags_with_semicolons() -> Result<(), TaxLotError> {
+        let tags = ["a;b", "a", "b", "c\\;", ";"];
+        let mut lot_collection = LotCollection::new(SelectionAlgorithm::Fifo);
+        let mut lot_operation = LotOperation::from_str("2021-01-01,buy,10000.00,1.00000000")?;
+        lot_operation.tags.extend(tags.map(str::to_string));
+        lot_collection.apply_lot_operation(lot_operation)?;
+        let mut checkpoint = Vec::new();
+        lot_collection.write_checkpoint(1, &mut checkpoint)?;
+        let checkpoint = String::from_utf8(checkpoint).expect("Checkpoint is not UTF-8");
+
+        let mut restored = LotCollection::new(SelectionAlgorithm::Fifo);
+        restored.restore_checkpoint(&checkpoint)?;
+        let restored_tags: Vec<_> =
+            restored.lots().flat_map(|lot| lot.tags().map(str::to_string).collect::<Vec<_>>()).collect();
+        let mut expected_tags = tags.to_vec();
+        expected_tags.sort();
+        assert_eq!(restored_tags, expected_tags);
+
+        restored
+            .restore_checkpoint("taxlot-checkpoint,fifo,1,0,2\n1,2021-01-01,1,1,tags=bad\\\\escape")
+            .expect_err("Restored a tag with an invalid escape");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_checkpoint_rejects_unknown_income_type() {
+        let mut restored = LotCollection::new(SelectionAlgorithm::Fifo);
+        let error = restored
+            .restore_checkpoint("taxlot-checkpoint,fifo,1,0,1\nincome,2021,royalty,1.00")
+            .expect_err("Restored an unknown income type");
+        assert!(matches!(error, TaxLotError::ParseIncomeTypeError), "Unexpected error {error:?}");
+    }
+}
@@ -0,0 +1,250 @@
+//! Columnar output of the lots and reports as Arrow record batches, so that large results can be loaded into e.g.
+//! Polars or DuckDB without parsing text.
+//!
+//! Every column of a row becomes an Arrow column. Text is `Utf8`, lists are lists of `Utf8`, and numbers are
+//! `Int64` if every number in the column is a whole number, or else `Decimal128` with as many decimals as the
+//! number with the most of them, so that no digit of a price or quantity is lost. Nested records, e.g. the
+//! purchases of a lot, are a JSON array in a `Utf8` column. With the `parquet` feature, the record batches can also
+//! be written as Parquet files, which keep the Arrow types.
+
+use std::{
+    collections::HashMap,
+    io::{self, Write},
+    str::FromStr,
+    sync::Arc,
+};
+
+use arrow_array::{
+    builder::{Decimal128Builder, Int64Builder, ListBuilder, StringBuilder},
+    Array, ArrayRef, RecordBatch,
+};
+use arrow_ipc::writer::FileWriter;
+use arrow_schema::{ArrowError, DataType, Field, Schema};
+#[cfg(feature = "parquet")]
+use parquet::{arrow::ArrowWriter, basic::Compression, file::properties::WriterProperties};
+use rust_decimal::Decimal;
+
+use crate::output::{write_json_object, Row, SchemaVersion, Value};
+
+/// Largest number of digits of a `Decimal128` column.
+const DECIMAL_PRECISION: u8 = 38;
+
+/// Represents the Arrow type of a column. Numbers have the most decimals of any number in the column.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Text,
+    Number(i8),
+    List,
+}
+
+impl Kind {
+    fn of(value: &Value) -> Kind {
+        match value {
+            Value::Text(_) | Value::Records(_) => Kind::Text,
+            Value::Number(number) => {
+                Kind::Number(number.split_once('.').map_or(0, |(_, decimals)| decimals.len() as i8))
+            }
+            Value::List(_) => Kind::List,
+        }
+    }
+
+    /// Returns the kind of a column with values of both kinds, or `None` if they cannot be in the same column.
+    fn merge(self, other: Kind) -> Option<Kind> {
+        match (self, other) {
+            (Kind::Number(scale), Kind::Number(other_scale)) => Some(Kind::Number(scale.max(other_scale))),
+            (kind, other) => (kind == other).then_some(kind),
+        }
+    }
+}
+
+/// Returns the rows as a record batch, with the name and version of the output in the metadata of its schema.
+pub fn record_batch<R: Row>(
+    rows: impl Iterator<Item = R>,
+    schema_version: SchemaVersion,
+) -> Result<RecordBatch, ArrowError> {
+    let columns = R::columns(schema_version);
+    let mut values: Vec<Vec<Option<Value>>> = columns.iter().map(|_| Vec::new()).collect();
+    for row in rows {
+        for (column, value) in values.iter_mut().zip(row.values(schema_version)) {
+            column.push(value);
+        }
+    }
+
+    let mut fields = Vec::with_capacity(columns.len());
+    let mut arrays = Vec::with_capacity(columns.len());
+    for (&name, column) in columns.iter().zip(values) {
+        let (data_type, array) = array(name, column)?;
+        fields.push(Field::new(name, data_type, true));
+        arrays.push(array);
+    }
+    let metadata = HashMap::from([
+        ("schema".to_string(), R::OUTPUT.to_string()),
+        ("version".to_string(), schema_version.number().to_string()),
+    ]);
+    RecordBatch::try_new(Arc::new(Schema::new_with_metadata(fields, metadata)), arrays)
+}
+
+/// Writes a record batch to `out` as an Arrow IPC file, which is also a Feather v2 file.
+pub fn write_ipc(batch: &RecordBatch, out: impl Write) -> io::Result<()> {
+    let mut writer = FileWriter::try_new(out, &batch.schema()).map_err(io::Error::other)?;
+    writer.write(batch).map_err(io::Error::other)?;
+    writer.finish().map_err(io::Error::other)
+}
+
+/// Writes a record batch to `out` as a Parquet file compressed with Snappy. The file is built in memory, since the
+/// Parquet writer needs an output it can send between threads.
+#[cfg(feature = "parquet")]
+pub fn write_parquet(batch: &RecordBatch, mut out: impl Write) -> io::Result<()> {
+    let properties = WriterProperties::builder().set_compression(Compression::SNAPPY).build();
+    let mut writer = ArrowWriter::try_new(Vec::new(), batch.schema(), Some(properties)).map_err(io::Error::other)?;
+    writer.write(batch).map_err(io::Error::other)?;
+    out.write_all(&writer.into_inner().map_err(io::Error::other)?)
+}
+
+/// Returns the type and array of the column `name`, with a null for every missing value.
+fn array(name: &str, column: Vec<Option<Value>>) -> Result<(DataType, ArrayRef), ArrowError> {
+    let mismatch = || ArrowError::InvalidArgumentError(format!("column {name} has values of different types"));
+    let mut kind = None;
+    for value in column.iter().flatten() {
+        let value_kind = Kind::of(value);
+        kind = Some(kind.map_or(Some(value_kind), |kind: Kind| kind.merge(value_kind)).ok_or_else(mismatch)?);
+    }
+
+    match kind.unwrap_or(Kind::Text) {
+        Kind::Text => {
+            let mut builder = StringBuilder::new();
+            for value in column {
+                match value {
+                    Some(Value::Text(text)) => builder.append_value(text),
+                    Some(Value::Records(records)) => {
+                        let mut json = Vec::new();
+                        write!(json, "[")?;
+                        for (i, record) in records.into_iter().enumerate() {
+                            write!(json, "{}", if i > 0 { "," } else { "" })?;
+                            write_json_object(record.into_iter(), &mut json)?;
+                        }
+                        write!(json, "]")?;
+                        builder.append_value(String::from_utf8_lossy(&json));
+                    }
+                    _ => builder.append_null(),
+                }
+            }
+            Ok((DataType::Utf8, Arc::new(builder.finish())))
+        }
+        Kind::Number(0) => {
+            let mut builder = Int64Builder::new();
+            for value in column {
+                match value {
+                    Some(Value::Number(number)) => builder.append_value(number.parse().map_err(|_| mismatch())?),
+                    _ => builder.append_null(),
+                }
+            }
+            Ok((DataType::Int64, Arc::new(builder.finish())))
+        }
+        Kind::Number(scale) => {
+            let mut builder = Decimal128Builder::new();
+            for value in column {
+                match value {
+                    Some(Value::Number(number)) => {
+                        let mut number = Decimal::from_str(&number).map_err(|_| mismatch())?;
+                        number.rescale(scale as u32);
+                        builder.append_value(number.mantissa());
+                    }
+                    _ => builder.append_null(),
+                }
+            }
+            let data_type = DataType::Decimal128(DECIMAL_PRECISION, scale);
+            Ok((data_type.clone(), Arc::new(builder.finish().with_data_type(data_type))))
+        }
+        Kind::List => {
+            let mut builder = ListBuilder::new(StringBuilder::new());
+            for value in column {
+                match value {
+                    Some(Value::List(items)) => builder.append_value(items.into_iter().map(Some)),
+                    _ => builder.append_null(),
+                }
+            }
+            let array = builder.finish();
+            Ok((array.data_type().clone(), Arc::new(array)))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use arrow_array::{cast::AsArray, types::Decimal128Type, types::Int64Type, Array};
+    use arrow_ipc::reader::FileReader;
+    use arrow_schema::DataType;
+
+    use super::{record_batch, write_ipc};
+    use crate::output::{Row, SchemaVersion, Value};
+
+    struct TestRow(&'static str, &'static str, Option<&'static str>);
+
+    impl Row for TestRow {
+        const OUTPUT: &'static str = "test";
+        fn columns(_: SchemaVersion) -> &'static [&'static str] {
+            &["name", "year", "amount"]
+        }
+
+        fn values(&self, _: SchemaVersion) -> Vec<Option<Value>> {
+            vec![
+                Some(Value::Text(self.0.to_string())),
+                Some(Value::Number(self.1.to_string())),
+                self.2.map(|amount| Value::Number(amount.to_string())),
+            ]
+        }
+    }
+
+    #[test]
+    fn test_ipc_round_trips_numbers_exactly() {
+        let rows = [
+            TestRow("a", "2021", Some("1.5")),
+            TestRow("b", "2022", Some("0.00000001")),
+            TestRow("c", "2023", None),
+        ];
+        let batch = record_batch(rows.into_iter(), SchemaVersion::V1).expect("Could not build the record batch");
+        let mut ipc = Vec::new();
+        write_ipc(&batch, &mut ipc).expect("Could not write the IPC file");
+
+        let mut reader = FileReader::try_new(Cursor::new(ipc), None).expect("Could not read the IPC file");
+        assert_eq!(reader.schema().metadata()["schema"], "test");
+        let read = reader.next().expect("No record batch").expect("Could not read the record batch");
+        assert_eq!(read, batch);
+        assert_eq!(read.column(1).as_primitive::<Int64Type>().values(), &[2021, 2022, 2023]);
+        let amounts = read.column(2).as_primitive::<Decimal128Type>();
+        assert_eq!(amounts.data_type(), &DataType::Decimal128(38, 8));
+        assert_eq!(amounts.value(0), 150_000_000);
+        assert_eq!(amounts.value(1), 1);
+        assert!(amounts.is_null(2));
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn test_parquet_round_trips_numbers_exactly() {
+        use std::io::{Seek, Write};
+
+        use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+        let rows = [
+            TestRow("a", "2021", Some("1.5")),
+            TestRow("b", "2022", Some("0.00000001")),
+            TestRow("c", "2023", None),
+        ];
+        let batch = record_batch(rows.into_iter(), SchemaVersion::V1).expect("Could not build the record batch");
+        let mut file = tempfile::tempfile().expect("Could not create a file");
+        super::write_parquet(&batch, &mut file).expect("Could not write the Parquet file");
+        file.flush().expect("Could not flush the Parquet file");
+        file.rewind().expect("Could not rewind the Parquet file");
+
+        let builder = ParquetRecordBatchReaderBuilder::try_new(file).expect("Could not read the Parquet file");
+        assert_eq!(builder.schema().metadata()["schema"], "test");
+        let mut reader = builder.build().expect("Could not read the Parquet file");
+        let read = reader.next().expect("No record batch").expect("Could not read the record batch");
+        // The batches that are read have the metadata in the schema of the file rather than in their own.
+        assert_eq!(read.columns(), batch.columns());
+        assert_eq!(read.column(2).as_primitive::<Decimal128Type>().value(1), 1);
+    }
+}
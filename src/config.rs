@@ -0,0 +1,62 @@
+//! Config files of options, layered under the environment and the command line so that a container can ship
+//! its settings as a file and still override single options with `TAXLOT_` variables.
+
+use std::{env, ffi::OsString, fs, path::PathBuf};
+
+use clap::Command;
+use taxlot::TaxLotError;
+
+/// Environment variable naming the config file, like `--config`.
+const CONFIG_ENV: &str = "TAXLOT_CONFIG";
+
+/// Reads the config file given with `--config` in `args` or in `TAXLOT_CONFIG`, if any, and sets the
+/// environment variable of every option it sets that is not already set. The command line is parsed after this,
+/// so it takes precedence over the environment, which takes precedence over the config file.
+///
+/// The file has an option per line as `name = value`, named like on the command line without the leading
+/// dashes, e.g. `min-holding-days = 365`. Blank lines and lines starting with `#` are skipped.
+pub fn load(command: &Command, args: &[OsString]) -> Result<(), TaxLotError> {
+    let Some(path) = config_path(args) else {
+        return Ok(());
+    };
+    let config = fs::read_to_string(&path)
+        .map_err(|e| TaxLotError::InvalidConfig(format!("could not read {}: {e}", path.display())))?;
+    for (number, line) in config.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let invalid = |message: &str| TaxLotError::InvalidConfig(format!("line {}: {message}", number + 1));
+        let (name, value) = line.split_once('=').ok_or_else(|| invalid("expected `name = value`"))?;
+        let name = name.trim().replace('_', "-");
+        let variable = option_env(command, &name).ok_or_else(|| invalid(&format!("no option named {name}")))?;
+        if env::var_os(&variable).is_none() {
+            env::set_var(variable, value.trim());
+        }
+    }
+    Ok(())
+}
+
+/// Returns the config file given with `--config FILE` or `--config=FILE` in `args`, or in `TAXLOT_CONFIG`.
+fn config_path(args: &[OsString]) -> Option<PathBuf> {
+    let mut args = args.iter().skip(1);
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            break;
+        } else if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        } else if let Some(path) = arg.to_str().and_then(|arg| arg.strip_prefix("--config=")) {
+            return Some(PathBuf::from(path));
+        }
+    }
+    env::var_os(CONFIG_ENV).map(PathBuf::from)
+}
+
+/// Returns the environment variable of the option with the long name `name` in `command` or its subcommands.
+fn option_env(command: &Command, name: &str) -> Option<OsString> {
+    let arg = command.get_arguments().find(|arg| arg.get_long() == Some(name) && name != "config");
+    match arg.and_then(|arg| arg.get_env()) {
+        Some(variable) => Some(variable.to_os_string()),
+        None => command.get_subcommands().find_map(|subcommand| option_env(subcommand, name)),
+    }
+}
@@ -0,0 +1,310 @@
+//! Disposal records of the parts of lots that sells deduct, so that every sale can be reported with its own
+//! cost basis, proceeds and holding period rather than only as a total.
+//!
+//! A lot collection with tens of millions of sells would keep as many disposals in memory, so they can be spilled
+//! to a temporary file once there are more than a limit of them, see `LotCollection::spill_disposals`. The open lots
+//! are spilled separately, with `LotCollection::spill_lots`.
+
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader, BufWriter, Seek, SeekFrom, Write},
+};
+
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+use tempfile::NamedTempFile;
+
+use crate::{
+    checkpoint::{parse_disposal, write_disposal},
+    LotCollection, TaxLotError,
+};
+
+/// Represents the part of a lot that a sell deducted.
+///
+/// `lot_id`: Id of the lot that was deducted from.
+/// `acquired`: Date of the lot.
+/// `sold`: Date of the sell.
+/// `quantity`: Quantity deducted from the lot.
+/// `cost_basis`: Price of the lot times the quantity.
+/// `proceeds`: Price of the sell times the quantity.
+/// `gain`: Gain realized by the disposal, or loss if negative.
+/// `long_term`: Whether the lot had been held for more than one year on the date of the sell.
+/// `txn_id`: Transaction id of the sell, if it had one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Disposal {
+    pub lot_id: u64,
+    pub acquired: NaiveDate,
+    pub sold: NaiveDate,
+    pub quantity: Decimal,
+    pub cost_basis: Decimal,
+    pub proceeds: Decimal,
+    pub gain: Decimal,
+    pub long_term: bool,
+    pub txn_id: Option<String>,
+}
+
+/// The disposals recorded by a `LotCollection`, oldest first. If `memory_limit` is set, the disposals in memory are
+/// spilled to a temporary file whenever there are more than that many of them.
+///
+/// `recent`: Disposals after the spilled ones, in memory.
+/// `spill`: File of the disposals before the `recent` ones, once any were spilled.
+#[derive(Debug, Default)]
+pub(crate) struct DisposalLog {
+    recent: Vec<Disposal>,
+    memory_limit: Option<usize>,
+    spill: Option<Spill>,
+}
+
+/// Represents a temporary file of spilled disposals, one checkpoint disposal line each. It is deleted when dropped.
+///
+/// `records`: Number of disposals in the file that are recorded. Reverting sells only lowers it, since reverting
+/// cannot fail, so the file can have lines after them until it is cut at the next spill.
+/// `chunks`: Number of records and length of the file after each spill, so the file can be cut without reading
+/// all of it.
+#[derive(Debug)]
+struct Spill {
+    file: NamedTempFile,
+    records: usize,
+    chunks: Vec<(usize, u64)>,
+}
+
+impl DisposalLog {
+    /// Returns an empty disposal log with the same memory limit.
+    pub(crate) fn cleared(&self) -> DisposalLog {
+        DisposalLog {
+            memory_limit: self.memory_limit,
+            ..DisposalLog::default()
+        }
+    }
+
+    /// Returns the number of disposals, including the spilled ones.
+    pub(crate) fn len(&self) -> usize {
+        self.spill.as_ref().map_or(0, |spill| spill.records) + self.recent.len()
+    }
+
+    /// Adds the disposals of a sell. If spilling fails, the log is left unchanged.
+    pub(crate) fn extend(&mut self, disposals: Vec<Disposal>) -> Result<(), TaxLotError> {
+        let recent = self.recent.len();
+        self.recent.extend(disposals);
+        if self.memory_limit.is_some_and(|limit| self.recent.len() > limit) {
+            if let Err(e) = self.spill_recent() {
+                self.recent.truncate(recent);
+                return Err(TaxLotError::DisposalSpillError(e));
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces every disposal with `disposals`, e.g. the ones of a checkpoint.
+    pub(crate) fn replace(&mut self, disposals: Vec<Disposal>) -> Result<(), TaxLotError> {
+        let mut replaced = self.cleared();
+        replaced.extend(disposals)?;
+        *self = replaced;
+        Ok(())
+    }
+
+    /// Keeps only the first `len` disposals, e.g. to revert a sell.
+    pub(crate) fn truncate(&mut self, len: usize) {
+        match &mut self.spill {
+            Some(spill) if len < spill.records => {
+                spill.records = len;
+                self.recent.clear();
+            }
+            Some(spill) => self.recent.truncate(len - spill.records),
+            None => self.recent.truncate(len),
+        }
+    }
+
+    /// Returns every disposal, oldest first, reading the spilled ones back from their file.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = io::Result<Disposal>> + '_ {
+        let spilled = self.spill.iter().flat_map(|spill| {
+            // The file is opened again, so that reading it does not move the position that spills write at.
+            let (lines, error) = match File::open(spill.file.path()) {
+                Ok(file) => (Some(BufReader::new(file).lines().take(spill.records)), None),
+                Err(e) => (None, Some(Err(e))),
+            };
+            error.into_iter().chain(lines.into_iter().flatten().map(|line| {
+                parse_disposal(&line?).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            }))
+        });
+        spilled.chain(self.recent.iter().cloned().map(Ok))
+    }
+
+    /// Appends the disposals in memory to the spill file, creating it if there is none.
+    fn spill_recent(&mut self) -> io::Result<()> {
+        let spill = match &mut self.spill {
+            Some(spill) => spill,
+            None => self.spill.insert(Spill {
+                file: NamedTempFile::new()?,
+                records: 0,
+                chunks: Vec::new(),
+            }),
+        };
+        let start = spill.cut()?;
+        let file = spill.file.as_file_mut();
+        file.seek(SeekFrom::Start(start))?;
+        let mut out = BufWriter::new(&mut *file);
+        let written = self.recent.iter().try_for_each(|disposal| {
+            write_disposal(disposal, &mut out)?;
+            writeln!(out)
+        });
+        let end = written.and_then(|()| out.flush()).and_then(|()| out.stream_position());
+        drop(out);
+        let end = match end {
+            Ok(end) => end,
+            Err(e) => {
+                // The records are still in memory, so the spill is left as it was.
+                let _ = file.set_len(start);
+                return Err(e);
+            }
+        };
+        spill.records += self.recent.len();
+        spill.chunks.push((spill.records, end));
+        self.recent.clear();
+        Ok(())
+    }
+}
+
+impl Spill {
+    /// Cuts the file after its first `records` lines, and returns its length.
+    fn cut(&mut self) -> io::Result<u64> {
+        self.chunks.retain(|&(records, _)| records <= self.records);
+        let (mut records, mut len) = self.chunks.last().copied().unwrap_or_default();
+        if records < self.records {
+            let mut lines = BufReader::new(File::open(self.file.path())?);
+            lines.seek(SeekFrom::Start(len))?;
+            let mut line = Vec::new();
+            while records < self.records {
+                line.clear();
+                len += lines.read_until(b'\n', &mut line)? as u64;
+                records += 1;
+            }
+            self.chunks.push((records, len));
+        }
+        self.file.as_file().set_len(len)?;
+        Ok(len)
+    }
+}
+
+impl LotCollection {
+    /// Records a `Disposal` for every lot that the sells applied from now on deduct from, see `disposals`. The
+    /// records are kept in memory, unless they are spilled with `spill_disposals`.
+    pub fn enable_disposals(&mut self) {
+        self.disposals.get_or_insert_with(DisposalLog::default);
+    }
+
+    /// Records disposals like `enable_disposals`, but keeps at most `limit` of them in memory. Whenever there are
+    /// more, the ones in memory are appended to a temporary file, which is deleted when the lot collection is
+    /// dropped. A sell that cannot spill its disposals fails with `TaxLotError::DisposalSpillError`.
+    pub fn spill_disposals(&mut self, limit: usize) {
+        self.disposals.get_or_insert_with(DisposalLog::default).memory_limit = Some(limit);
+    }
+
+    /// Returns the disposals of the sells applied since `enable_disposals`, in the order they were sold. Spilled
+    /// disposals are read back from their file, which fails if it cannot be read.
+    pub fn disposals(&self) -> impl Iterator<Item = io::Result<Disposal>> + '_ {
+        self.disposals.iter().flat_map(DisposalLog::iter)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chrono::NaiveDate;
+    use rust_decimal::Decimal;
+
+    use crate::{test_helpers::apply, Disposal, LotCollection, SelectionAlgorithm, TaxLotError};
+
+    #[test]
+    fn test_sell_records_disposal_per_lot() -> Result<(), TaxLotError> {
+        let mut lot_collection = LotCollection::new(SelectionAlgorithm::Fifo);
+        lot_collection.enable_disposals();
+        apply(
+            &mut lot_collection,
+            &[
+                "2020-01-01,buy,10000.00,1.00000000",
+                "2021-06-01,buy,20000.00,1.00000000",
+                "2021-07-01,sell,25000.00,1.50000000,txn=s1",
+            ],
+        )?;
+
+        let disposal = |lot_id, acquired: &str, quantity: &str, cost_basis: &str, proceeds: &str, long_term| {
+            let quantity = Decimal::from_str(quantity).unwrap();
+            let cost_basis = Decimal::from_str(cost_basis).unwrap();
+            let proceeds = Decimal::from_str(proceeds).unwrap();
+            Disposal {
+                lot_id,
+                acquired: NaiveDate::from_str(acquired).unwrap(),
+                sold: NaiveDate::from_ymd_opt(2021, 7, 1).unwrap(),
+                quantity,
+                cost_basis,
+                proceeds,
+                gain: proceeds - cost_basis,
+                long_term,
+                txn_id: Some("s1".to_string()),
+            }
+        };
+        let disposals = lot_collection.disposals().collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(
+            disposals,
+            [
+                disposal(1, "2020-01-01", "1", "10000", "25000", true),
+                disposal(2, "2021-06-01", "0.5", "10000", "12500", false),
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_undo_and_amend_remove_disposals() -> Result<(), TaxLotError> {
+        let mut lot_collection = LotCollection::new(SelectionAlgorithm::Fifo);
+        lot_collection.enable_journal();
+        lot_collection.enable_undo(10);
+        lot_collection.enable_disposals();
+        apply(
+            &mut lot_collection,
+            &[
+                "2021-01-01,buy,10000.00,2.00000000,txn=b1",
+                "2021-02-01,sell,15000.00,1.00000000,txn=s1",
+                "2021-03-01,sell,15000.00,1.00000000,txn=s2",
+            ],
+        )?;
+        assert_eq!(lot_collection.undo(1), 1);
+        assert_eq!(lot_collection.disposals().count(), 1);
+
+        // Replaying the journal records the disposals again, with the amended quantity.
+        apply(&mut lot_collection, &["2021-03-05,amend,15000.00,0.50000000,txn=s1"])?;
+        let disposals = lot_collection.disposals().collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(disposals.len(), 1);
+        assert_eq!(disposals[0].quantity, Decimal::from_str("0.5").unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn test_spilled_disposals_are_read_back_and_reverted() -> Result<(), TaxLotError> {
+        let mut in_memory = LotCollection::new(SelectionAlgorithm::Fifo);
+        in_memory.enable_disposals();
+        let mut spilled = LotCollection::new(SelectionAlgorithm::Fifo);
+        spilled.enable_undo(10);
+        spilled.spill_disposals(2);
+        let mut lines = vec!["2021-01-01,buy,10000.00,10.00000000".to_string()];
+        lines.extend((1..=9).map(|day| format!("2021-02-{day:02},sell,{day}0000.00,1.00000000,txn=s{day}")));
+        let lines: Vec<_> = lines.iter().map(String::as_str).collect();
+        apply(&mut spilled, &lines)?;
+
+        // Reverting sells whose disposals were spilled, and selling again, cuts the spill file.
+        assert_eq!(spilled.undo(6), 6);
+        apply(&mut in_memory, &lines[..4])?;
+        let more = ["2021-03-01,sell,1.00,2.00000000,txn=a", "2021-03-02,sell,2.00,1.00000000,txn=b"];
+        apply(&mut spilled, &more)?;
+        apply(&mut in_memory, &more)?;
+
+        let disposals = |lot_collection: &LotCollection| lot_collection.disposals().collect::<Result<Vec<_>, _>>();
+        let spilled = disposals(&spilled)?;
+        assert_eq!(spilled, disposals(&in_memory)?);
+        let txn_ids: Vec<_> = spilled.iter().filter_map(|disposal| disposal.txn_id.as_deref()).collect();
+        assert_eq!(txn_ids, ["s1", "s2", "s3", "a", "b"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,104 @@
+//! Character encodings of the input, e.g. UTF-16 exports of spreadsheets, which are decoded to UTF-8 before
+//! lot operations are parsed from them.
+
+use std::{borrow::Cow, io};
+
+use clap::ValueEnum;
+
+use crate::TaxLotError;
+
+const UTF8_BOM: &[u8] = b"\xEF\xBB\xBF";
+const UTF16LE_BOM: &[u8] = b"\xFF\xFE";
+
+/// Represents the character encoding of the input.
+///
+/// auto: detected from the start of the input. A UTF-8 or UTF-16LE byte order mark is skipped, and input that
+/// starts with an ASCII character followed by a zero byte is UTF-16LE. Otherwise every line is UTF-8, or latin-1
+/// if it is not valid UTF-8, as in the exports of older spreadsheets.
+/// utf8: UTF-8, failing on invalid UTF-8. A byte order mark is skipped.
+/// utf16le: UTF-16 little-endian, e.g. Excel's "Unicode Text". A byte order mark is skipped.
+/// latin1: ISO-8859-1, where every byte is a character.
+#[derive(Debug, ValueEnum, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Encoding {
+    #[default]
+    Auto,
+    Utf8,
+    Utf16le,
+    Latin1,
+}
+
+impl Encoding {
+    /// Returns the encoding of an input that starts with `start`, and the length of its byte order mark. With
+    /// `auto`, this is `utf8` after a UTF-8 byte order mark and `utf16le` for UTF-16LE input, and `auto`
+    /// otherwise, since whether a line is latin-1 is only known once it is decoded.
+    pub fn detect(self, start: &[u8]) -> (Encoding, usize) {
+        match self {
+            Encoding::Auto if start.starts_with(UTF8_BOM) => (Encoding::Utf8, UTF8_BOM.len()),
+            Encoding::Auto if start.starts_with(UTF16LE_BOM) => (Encoding::Utf16le, UTF16LE_BOM.len()),
+            Encoding::Auto if matches!(start, [first, 0, ..] if first.is_ascii() && *first != 0) => {
+                (Encoding::Utf16le, 0)
+            }
+            Encoding::Utf8 if start.starts_with(UTF8_BOM) => (Encoding::Utf8, UTF8_BOM.len()),
+            Encoding::Utf16le if start.starts_with(UTF16LE_BOM) => (Encoding::Utf16le, UTF16LE_BOM.len()),
+            encoding => (encoding, 0),
+        }
+    }
+
+    /// Decodes `bytes` in this encoding without its byte order mark, e.g. a line or a whole document. UTF-8 is
+    /// returned without copying it.
+    pub fn decode(self, bytes: &[u8]) -> Result<Cow<'_, str>, TaxLotError> {
+        match self {
+            Encoding::Auto => Ok(std::str::from_utf8(bytes).map_or_else(|_| latin1(bytes), Cow::Borrowed)),
+            Encoding::Utf8 => Ok(Cow::Borrowed(std::str::from_utf8(bytes).map_err(invalid_data)?)),
+            Encoding::Utf16le => {
+                let (units, rest) = bytes.as_chunks::<2>();
+                if !rest.is_empty() {
+                    return Err(invalid_data("UTF-16 input has an odd number of bytes"));
+                }
+                let units = units.iter().map(|&unit| u16::from_le_bytes(unit));
+                Ok(Cow::Owned(char::decode_utf16(units).collect::<Result<_, _>>().map_err(invalid_data)?))
+            }
+            Encoding::Latin1 => Ok(latin1(bytes)),
+        }
+    }
+}
+
+/// Decodes latin-1 `bytes`, whose bytes are the first 256 Unicode characters.
+fn latin1(bytes: &[u8]) -> Cow<'_, str> {
+    Cow::Owned(bytes.iter().map(|&byte| char::from(byte)).collect())
+}
+
+/// Returns an error for input that is not valid in its encoding.
+fn invalid_data(error: impl Into<Box<dyn std::error::Error + Send + Sync>>) -> TaxLotError {
+    io::Error::new(io::ErrorKind::InvalidData, error).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Encoding, TaxLotError};
+
+    #[test]
+    fn test_detect_and_decode() -> Result<(), TaxLotError> {
+        let line = "2021-01-01,buy,10000.00,1.00000000,café";
+        let utf16le: Vec<u8> = [0xFEFF].into_iter().chain(line.encode_utf16()).flat_map(u16::to_le_bytes).collect();
+        let latin1 = b"2021-01-01,buy,10000.00,1.00000000,caf\xE9";
+        for (input, detected) in [
+            ([b"\xEF\xBB\xBF", line.as_bytes()].concat(), Encoding::Utf8),
+            (utf16le.clone(), Encoding::Utf16le),
+            (utf16le[2..].to_vec(), Encoding::Utf16le),
+            (latin1.to_vec(), Encoding::Auto),
+            (line.as_bytes().to_vec(), Encoding::Auto),
+        ] {
+            let (encoding, bom) = Encoding::Auto.detect(&input);
+            assert_eq!(encoding, detected);
+            assert_eq!(encoding.decode(&input[bom..])?, line);
+        }
+
+        // An encoding that is forced is not detected.
+        assert_eq!(Encoding::Latin1.detect(&utf16le), (Encoding::Latin1, 0));
+        Encoding::Utf8.decode(latin1).expect_err("Decoded latin-1 as UTF-8");
+        Encoding::Utf16le.decode(&utf16le[1..]).expect_err("Decoded an odd number of UTF-16 bytes");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,150 @@
+//! Guided entry of lot operations for the `add` subcommand, for trades that are kept by hand rather than
+//! exported, so that a mistyped column is caught as it is entered instead of when the file is processed.
+
+use std::{
+    fs::OpenOptions,
+    io::{self, BufRead, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use chrono::Local;
+use taxlot::{Column, InputDialect, TaxLotError};
+
+/// Prompts on `prompts` for every column of a line in `dialect`, reading the answers from `input`. An answer
+/// with a problem is asked for again. The line is appended to the file at `path` once it is confirmed. Returns
+/// false if it was not confirmed.
+pub fn add(
+    path: &Path,
+    dialect: InputDialect,
+    mut input: impl BufRead,
+    mut prompts: impl Write,
+) -> Result<bool, TaxLotError> {
+    let today = Local::now().date_naive().to_string();
+    let mut values: Vec<String> = Vec::new();
+    for &column in dialect.columns() {
+        loop {
+            write!(prompts, "{}: ", prompt(column, dialect, &today))?;
+            prompts.flush()?;
+            let answer = read_answer(&mut input)?;
+            let value = match answer.as_str() {
+                "" if column == Column::Date => today.clone(),
+                answer => answer.to_string(),
+            };
+            if column != Column::Fields && value.contains(',') {
+                writeln!(prompts, "  `{value}` contains a comma, which separates the columns")?;
+                continue;
+            }
+
+            // Only the columns entered so far are in the line, so the later ones are missing.
+            let line = values.iter().chain([&value]).cloned().collect::<Vec<_>>().join(",");
+            match dialect.check(&line).into_iter().find(|error| error.column == column) {
+                Some(error) => writeln!(prompts, "  {}", error.message)?,
+                None => {
+                    values.push(value);
+                    break;
+                }
+            }
+        }
+    }
+
+    if values.last().is_some_and(String::is_empty) {
+        values.pop();
+    }
+    let line = values.join(",");
+    // The columns are valid on their own, but e.g. a cancel also needs a transaction id.
+    dialect.parse(&line)?;
+    write!(prompts, "Append {line} to {}? [y/N] ", path.display())?;
+    prompts.flush()?;
+    if !matches!(read_answer(&mut input)?.to_ascii_lowercase().as_str(), "y" | "yes") {
+        writeln!(prompts, "Nothing was appended")?;
+        return Ok(false);
+    }
+
+    let mut file = OpenOptions::new().read(true).append(true).create(true).open(path)?;
+    // Start a new line if the last line of the file has no line ending.
+    let mut last = [0];
+    if file.seek(SeekFrom::End(0))? > 0 {
+        file.seek(SeekFrom::End(-1))?;
+        file.read_exact(&mut last)?;
+        if last[0] != b'\n' {
+            writeln!(file)?;
+        }
+    }
+    writeln!(file, "{line}")?;
+    Ok(true)
+}
+
+/// Returns the prompt for `column`, with the values it takes.
+fn prompt(column: Column, dialect: InputDialect, today: &str) -> String {
+    match column {
+        Column::Date => format!("date (YYYY-MM-DD) [{today}]"),
+        Column::Type => "type (buy, sell, dividend, interest, drip, vest, espp, amend, cancel)".to_string(),
+        Column::Price => "price".to_string(),
+        Column::Quantity if dialect == InputDialect::Signed => "quantity (negative for a sell)".to_string(),
+        Column::Quantity => "quantity".to_string(),
+        Column::Fields => "tags=, txn= and method= fields and memo (optional)".to_string(),
+    }
+}
+
+/// Reads an answer from `input` without the line ending, failing if the input ended.
+fn read_answer(input: &mut impl BufRead) -> Result<String, TaxLotError> {
+    let mut answer = String::new();
+    if input.read_line(&mut answer)? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "input ended before the operation was entered").into());
+    }
+    Ok(answer.trim().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use taxlot::{InputDialect, TaxLotError};
+
+    use super::add;
+
+    /// Enters the lines of `answers` for a line in the standard dialect appended to a file holding `existing`.
+    /// Returns whether the line was appended, the prompts and the file.
+    fn enter(existing: &str, answers: &str) -> Result<(bool, String, String), TaxLotError> {
+        let file = tempfile::NamedTempFile::new()?;
+        fs::write(file.path(), existing)?;
+        let mut prompts = Vec::new();
+        let appended = add(file.path(), InputDialect::Standard, answers.as_bytes(), &mut prompts)?;
+        let prompts = String::from_utf8(prompts).expect("Prompts are not UTF-8");
+        Ok((appended, prompts, fs::read_to_string(file.path())?))
+    }
+
+    #[test]
+    fn test_add_appends_a_valid_entry() -> Result<(), TaxLotError> {
+        let answers = "2021-01-01\nbuy\n10000.00\n1.00000000\ntags=ira\ny\n";
+        let (appended, prompts, file) = enter("2020-12-01,buy,9000.00,1.00000000", answers)?;
+        assert!(appended);
+        assert_eq!(prompts.matches("date (YYYY-MM-DD)").count(), 1);
+        assert_eq!(file, "2020-12-01,buy,9000.00,1.00000000\n2021-01-01,buy,10000.00,1.00000000,tags=ira\n");
+
+        // Nothing is appended if the line is not confirmed.
+        let (appended, prompts, file) = enter("", "2021-01-01\nbuy\n10000.00\n1.00000000\n\nn\n")?;
+        assert!(!appended);
+        assert!(prompts.ends_with("Nothing was appended\n"), "Unexpected prompts {prompts}");
+        assert_eq!(file, "");
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_asks_again_for_a_bad_date_price_or_quantity() -> Result<(), TaxLotError> {
+        let answers = "2021-13-01\n2021-01-01\nbuy\nten\n10000.00\n1,5\n-1\n1.5\n\ny\n";
+        let (appended, prompts, file) = enter("", answers)?;
+        assert!(appended);
+        assert_eq!(prompts.matches("date (YYYY-MM-DD)").count(), 2);
+        assert_eq!(prompts.matches("price: ").count(), 2);
+        assert_eq!(prompts.matches("quantity: ").count(), 3);
+        assert!(prompts.contains("`1,5` contains a comma"), "Unexpected prompts {prompts}");
+        assert_eq!(file, "2021-01-01,buy,10000.00,1.5\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_add_fails_if_the_input_ends() {
+        enter("", "2021-01-01\nbuy\n").expect_err("Entered an operation without a price");
+    }
+}
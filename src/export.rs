@@ -0,0 +1,318 @@
+//! Exports of the results of a run to a database, which is easier to query than several reports, or feeds an
+//! existing reporting database directly.
+//!
+//! In SQLite, prices, quantities and gains are exported as exact decimal text without trailing zeros, rather than
+//! floating point numbers. SQLite converts them when they are used in arithmetic, e.g. `SELECT SUM(gain) FROM
+//! disposals`. In PostgreSQL they are `NUMERIC`.
+
+#[cfg(feature = "sqlite")]
+use std::path::Path;
+
+#[cfg(feature = "postgres")]
+use clap::Args;
+#[cfg(feature = "postgres")]
+use postgres::{Client, NoTls};
+#[cfg(feature = "sqlite")]
+use rusqlite::{params, Connection};
+#[cfg(feature = "sqlite")]
+use rust_decimal::Decimal;
+use taxlot::{Disposal, LotCollection, TaxLotError};
+
+/// Statements that replace the tables of an earlier export with empty ones.
+#[cfg(feature = "sqlite")]
+const SQLITE_SCHEMA: &str = "
+    DROP TABLE IF EXISTS lots;
+    DROP TABLE IF EXISTS disposals;
+    DROP TABLE IF EXISTS realized_gains;
+    CREATE TABLE lots (
+        id INTEGER PRIMARY KEY,
+        date TEXT NOT NULL,
+        price TEXT NOT NULL,
+        quantity TEXT NOT NULL,
+        tags TEXT NOT NULL,
+        memo TEXT
+    );
+    CREATE TABLE disposals (
+        lot_id INTEGER NOT NULL,
+        acquired TEXT NOT NULL,
+        sold TEXT NOT NULL,
+        quantity TEXT NOT NULL,
+        cost_basis TEXT NOT NULL,
+        proceeds TEXT NOT NULL,
+        gain TEXT NOT NULL,
+        term TEXT NOT NULL,
+        txn TEXT
+    );
+    CREATE TABLE realized_gains (
+        year INTEGER PRIMARY KEY,
+        short_term TEXT NOT NULL,
+        long_term TEXT NOT NULL,
+        total TEXT NOT NULL
+    );
+";
+
+/// Writes the remaining lots, the disposals and the gains realized in each year to the `lots`, `disposals` and
+/// `realized_gains` tables of the SQLite database at `path`, creating it if it does not exist. Tables of an
+/// earlier export are replaced. Everything is written in one transaction, so a failed export leaves the database
+/// as it was.
+#[cfg(feature = "sqlite")]
+pub fn sqlite(lot_collection: &LotCollection, path: &Path) -> Result<(), TaxLotError> {
+    let mut connection = Connection::open(path).map_err(sqlite_error)?;
+    let transaction = connection.transaction().map_err(sqlite_error)?;
+    transaction.execute_batch(SQLITE_SCHEMA).map_err(sqlite_error)?;
+    {
+        let mut insert = transaction
+            .prepare("INSERT INTO lots (id, date, price, quantity, tags, memo) VALUES (?1, ?2, ?3, ?4, ?5, ?6)")
+            .map_err(sqlite_error)?;
+        for lot in lot_collection.lots() {
+            let tags = lot.tags().collect::<Vec<_>>().join(";");
+            let row = params![
+                lot_id(lot.id())?,
+                lot.date().to_string(),
+                decimal(lot.price()),
+                decimal(lot.quantity()),
+                tags,
+                lot.memo(),
+            ];
+            insert.execute(row).map_err(sqlite_error)?;
+        }
+
+        let mut insert = transaction
+            .prepare(
+                "INSERT INTO disposals (lot_id, acquired, sold, quantity, cost_basis, proceeds, gain, term, txn)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)",
+            )
+            .map_err(sqlite_error)?;
+        for disposal in lot_collection.disposals() {
+            let disposal = read_disposal(disposal)?;
+            let term = term(&disposal);
+            let row = params![
+                lot_id(disposal.lot_id)?,
+                disposal.acquired.to_string(),
+                disposal.sold.to_string(),
+                decimal(disposal.quantity),
+                decimal(disposal.cost_basis),
+                decimal(disposal.proceeds),
+                decimal(disposal.gain),
+                term,
+                disposal.txn_id,
+            ];
+            insert.execute(row).map_err(sqlite_error)?;
+        }
+
+        let mut insert = transaction
+            .prepare("INSERT INTO realized_gains (year, short_term, long_term, total) VALUES (?1, ?2, ?3, ?4)")
+            .map_err(sqlite_error)?;
+        for (year, gains) in lot_collection.realized_gains_by_year() {
+            let row = params![
+                year,
+                decimal(gains.short_term),
+                decimal(gains.long_term),
+                decimal(gains.total()),
+            ];
+            insert.execute(row).map_err(sqlite_error)?;
+        }
+    }
+    transaction.commit().map_err(sqlite_error)
+}
+
+/// Represents the PostgreSQL tables that lots and disposals are upserted into.
+///
+/// `export`: Connection string of the database, e.g. `host=localhost user=taxlot dbname=reports`.
+/// `lots_table`: Table of the remaining lots, by lot id.
+/// `disposals_table`: Table of the disposals, by transaction id of the sell and lot id.
+#[cfg(feature = "postgres")]
+#[derive(Args, Debug, Clone)]
+pub struct PostgresOpts {
+    #[clap(long = "export-postgres", value_name = "CONNINFO", env = "TAXLOT_EXPORT_POSTGRES", global = true)]
+    pub export: Option<String>,
+
+    #[clap(
+        long = "postgres-lots-table",
+        value_name = "TABLE",
+        default_value = "taxlot_lots",
+        env = "TAXLOT_POSTGRES_LOTS_TABLE",
+        global = true
+    )]
+    pub lots_table: String,
+
+    #[clap(
+        long = "postgres-disposals-table",
+        value_name = "TABLE",
+        default_value = "taxlot_disposals",
+        env = "TAXLOT_POSTGRES_DISPOSALS_TABLE",
+        global = true
+    )]
+    pub disposals_table: String,
+}
+
+/// Upserts the remaining lots into the lots table of `opts` by lot id, and the disposals into the disposals table
+/// by the transaction id of their sell and their lot id, creating the tables if they do not exist. Lots that were
+/// sold completely are deleted from the lots table, so it has the open lots of every run that wrote to it. A
+/// disposal of a sell without a transaction id is keyed by its position among the disposals, e.g. `#3`, which
+/// only stays the same if the input does. Everything is written in one transaction.
+#[cfg(feature = "postgres")]
+pub fn postgres(lot_collection: &LotCollection, conninfo: &str, opts: &PostgresOpts) -> Result<(), TaxLotError> {
+    let lots_table = table_name(&opts.lots_table)?;
+    let disposals_table = table_name(&opts.disposals_table)?;
+    let mut client = Client::connect(conninfo, NoTls).map_err(postgres_error)?;
+    let mut transaction = client.transaction().map_err(postgres_error)?;
+    transaction
+        .batch_execute(&format!(
+            "CREATE TABLE IF NOT EXISTS {lots_table} (
+                id BIGINT PRIMARY KEY,
+                date DATE NOT NULL,
+                price NUMERIC NOT NULL,
+                quantity NUMERIC NOT NULL,
+                tags TEXT[] NOT NULL,
+                memo TEXT
+            );
+            CREATE TABLE IF NOT EXISTS {disposals_table} (
+                txn TEXT NOT NULL,
+                lot_id BIGINT NOT NULL,
+                acquired DATE NOT NULL,
+                sold DATE NOT NULL,
+                quantity NUMERIC NOT NULL,
+                cost_basis NUMERIC NOT NULL,
+                proceeds NUMERIC NOT NULL,
+                gain NUMERIC NOT NULL,
+                term TEXT NOT NULL,
+                PRIMARY KEY (txn, lot_id)
+            );"
+        ))
+        .map_err(postgres_error)?;
+
+    let upsert = transaction
+        .prepare(&format!(
+            "INSERT INTO {lots_table} (id, date, price, quantity, tags, memo) VALUES ($1, $2, $3, $4, $5, $6)
+            ON CONFLICT (id) DO UPDATE SET date = EXCLUDED.date, price = EXCLUDED.price,
+                quantity = EXCLUDED.quantity, tags = EXCLUDED.tags, memo = EXCLUDED.memo"
+        ))
+        .map_err(postgres_error)?;
+    let mut open_lots = Vec::new();
+    for lot in lot_collection.lots() {
+        let id = lot_id(lot.id())?;
+        let tags: Vec<&str> = lot.tags().collect();
+        let row: [&(dyn postgres::types::ToSql + Sync); 6] =
+            [&id, &lot.date(), &lot.price(), &lot.quantity(), &tags, &lot.memo()];
+        transaction.execute(&upsert, &row).map_err(postgres_error)?;
+        open_lots.push(id);
+    }
+
+    let upsert = transaction
+        .prepare(&format!(
+            "INSERT INTO {disposals_table}
+                (txn, lot_id, acquired, sold, quantity, cost_basis, proceeds, gain, term)
+                VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+            ON CONFLICT (txn, lot_id) DO UPDATE SET acquired = EXCLUDED.acquired, sold = EXCLUDED.sold,
+                quantity = EXCLUDED.quantity, cost_basis = EXCLUDED.cost_basis, proceeds = EXCLUDED.proceeds,
+                gain = EXCLUDED.gain, term = EXCLUDED.term"
+        ))
+        .map_err(postgres_error)?;
+    let mut closed_lots = Vec::new();
+    for (i, disposal) in lot_collection.disposals().enumerate() {
+        let disposal = read_disposal(disposal)?;
+        let txn_id = disposal.txn_id.clone().unwrap_or_else(|| format!("#{}", i + 1));
+        let id = lot_id(disposal.lot_id)?;
+        let row: [&(dyn postgres::types::ToSql + Sync); 9] = [
+            &txn_id,
+            &id,
+            &disposal.acquired,
+            &disposal.sold,
+            &disposal.quantity,
+            &disposal.cost_basis,
+            &disposal.proceeds,
+            &disposal.gain,
+            &term(&disposal),
+        ];
+        transaction.execute(&upsert, &row).map_err(postgres_error)?;
+        if !open_lots.contains(&id) {
+            closed_lots.push(id);
+        }
+    }
+    transaction
+        .execute(&format!("DELETE FROM {lots_table} WHERE id = ANY($1)"), &[&closed_lots])
+        .map_err(postgres_error)?;
+    transaction.commit().map_err(postgres_error)
+}
+
+/// Returns `name` if it can be put in a statement as a table name, optionally qualified by a schema.
+#[cfg(feature = "postgres")]
+fn table_name(name: &str) -> Result<&str, TaxLotError> {
+    let is_identifier = |part: &str| {
+        part.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_')
+            && part.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+    };
+    match name.split('.').all(is_identifier) {
+        true => Ok(name),
+        false => Err(TaxLotError::ExportError(format!("`{name}` is not a table name"))),
+    }
+}
+
+#[cfg(feature = "postgres")]
+fn postgres_error(e: postgres::Error) -> TaxLotError {
+    TaxLotError::ExportError(format!("PostgreSQL: {e}"))
+}
+
+/// Returns a disposal that was read back, e.g. from the spill file.
+fn read_disposal(disposal: std::io::Result<Disposal>) -> Result<Disposal, TaxLotError> {
+    disposal.map_err(|e| TaxLotError::ExportError(format!("could not read disposals: {e}")))
+}
+
+/// Returns the holding period of a disposal, `long` or `short`.
+fn term(disposal: &Disposal) -> &'static str {
+    if disposal.long_term {
+        "long"
+    } else {
+        "short"
+    }
+}
+
+/// Returns a lot id as a signed 64-bit integer, the type of integer columns. Only sequential ids can be larger.
+fn lot_id(id: u64) -> Result<i64, TaxLotError> {
+    i64::try_from(id).map_err(|_| TaxLotError::ExportError(format!("lot id {id} does not fit in an integer column")))
+}
+
+/// Returns a decimal as text without trailing zeros, e.g. `0.5` rather than `0.50000000`.
+#[cfg(feature = "sqlite")]
+fn decimal(value: Decimal) -> String {
+    value.normalize().to_string()
+}
+
+#[cfg(feature = "sqlite")]
+fn sqlite_error(e: rusqlite::Error) -> TaxLotError {
+    TaxLotError::ExportError(format!("SQLite: {e}"))
+}
+
+#[cfg(all(test, feature = "sqlite"))]
+mod tests {
+    use std::str::FromStr;
+
+    use rusqlite::Connection;
+    use taxlot::{LotCollection, LotOperation, SelectionAlgorithm, TaxLotError};
+
+    #[test]
+    fn test_sqlite_export_replaces_tables() -> Result<(), TaxLotError> {
+        let mut lot_collection = LotCollection::new(SelectionAlgorithm::Fifo);
+        lot_collection.enable_disposals();
+        for line in [
+            "2020-01-01,buy,10000.00,1.00000000,tags=ira",
+            "2021-06-01,buy,20000.00,1.00000000",
+            "2021-07-01,sell,25000.00,1.50000000,txn=s1",
+        ] {
+            lot_collection.apply_lot_operation(LotOperation::from_str(line)?)?;
+        }
+        let database = tempfile::NamedTempFile::new()?;
+        super::sqlite(&lot_collection, database.path())?;
+        // Exporting again replaces the rows instead of adding to them.
+        super::sqlite(&lot_collection, database.path())?;
+
+        let connection = Connection::open(database.path()).expect("Could not open the database");
+        let query = |sql: &str| -> rusqlite::Result<String> { connection.query_row(sql, [], |row| row.get(0)) };
+        assert_eq!(query("SELECT group_concat(id || ':' || quantity) FROM lots").unwrap(), "2:0.5");
+        assert_eq!(query("SELECT group_concat(term || ':' || txn) FROM disposals").unwrap(), "long:s1,short:s1");
+        assert_eq!(query("SELECT CAST(SUM(gain) AS TEXT) FROM disposals").unwrap(), "17500");
+        assert_eq!(query("SELECT year || ':' || total FROM realized_gains").unwrap(), "2021:17500");
+        Ok(())
+    }
+}
@@ -0,0 +1,306 @@
+//! Filter expressions over the fields of lots, e.g. to only print some of the remaining lots.
+
+use std::{
+    fmt::Display,
+    iter::Peekable,
+    str::{Chars, FromStr},
+};
+
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+
+use crate::{Lot, TaxLotError};
+
+/// Represents a filter expression that lots either match or not, e.g. `price > 10000 && date < 2022-01-01`.
+///
+/// A comparison is a field, an operator and a value. The fields are `id`, `date`, `price` and `quantity`,
+/// compared with `==`, `!=`, `<`, `<=`, `>` and `>=`, and `tag` and `memo`, compared with `==` and `!=`.
+/// `tag == ira` matches lots with the tag and `tag != ira` lots without it. Dates are written as
+/// `YYYY-MM-DD`, and values with spaces or operators in them are quoted with `"`. Comparisons are negated with
+/// `!` and combined with `&&`, which binds tighter, and `||`, and can be grouped with parentheses. Prices and
+/// quantities are compared exactly rather than as they are printed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LotFilter {
+    expression: Expression,
+}
+
+/// Represents a parsed filter expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expression {
+    Or(Box<Expression>, Box<Expression>),
+    And(Box<Expression>, Box<Expression>),
+    Not(Box<Expression>),
+    Compare(Comparison),
+}
+
+/// Represents a comparison of a field of a lot with a value.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Comparison {
+    Id(Operator, Decimal),
+    Date(Operator, NaiveDate),
+    Price(Operator, Decimal),
+    Quantity(Operator, Decimal),
+    Tag(bool, String),
+    Memo(bool, String),
+}
+
+/// Represents the comparison operators.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operator {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+impl Operator {
+    /// Returns true if `left` compares to `right` according to the operator.
+    fn compare<T: PartialOrd>(self, left: T, right: T) -> bool {
+        match self {
+            Operator::Eq => left == right,
+            Operator::Ne => left != right,
+            Operator::Lt => left < right,
+            Operator::Le => left <= right,
+            Operator::Gt => left > right,
+            Operator::Ge => left >= right,
+        }
+    }
+}
+
+/// Represents the tokens of a filter expression.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    LeftParen,
+    RightParen,
+    And,
+    Or,
+    Not,
+    Operator(Operator),
+    /// A field name or value, either bare or quoted.
+    Word(String),
+}
+
+impl Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let operator = |operator| match operator {
+            Operator::Eq => "==",
+            Operator::Ne => "!=",
+            Operator::Lt => "<",
+            Operator::Le => "<=",
+            Operator::Gt => ">",
+            Operator::Ge => ">=",
+        };
+        match self {
+            Token::LeftParen => write!(f, "`(`"),
+            Token::RightParen => write!(f, "`)`"),
+            Token::And => write!(f, "`&&`"),
+            Token::Or => write!(f, "`||`"),
+            Token::Not => write!(f, "`!`"),
+            Token::Operator(op) => write!(f, "`{}`", operator(*op)),
+            Token::Word(word) => write!(f, "`{word}`"),
+        }
+    }
+}
+
+impl FromStr for LotFilter {
+    type Err = TaxLotError;
+
+    fn from_str(s: &str) -> Result<Self, TaxLotError> {
+        let tokens = tokenize(s)?;
+        let mut parser = Parser { tokens: tokens.into_iter().peekable() };
+        let expression = parser.or()?;
+        match parser.tokens.next() {
+            Some(token) => Err(TaxLotError::InvalidFilter(format!("unexpected {token}"))),
+            None => Ok(LotFilter { expression }),
+        }
+    }
+}
+
+impl LotFilter {
+    /// Returns true if `lot` matches the filter.
+    pub fn matches(&self, lot: &Lot) -> bool {
+        self.expression.matches(lot)
+    }
+}
+
+impl Expression {
+    fn matches(&self, lot: &Lot) -> bool {
+        match self {
+            Expression::Or(left, right) => left.matches(lot) || right.matches(lot),
+            Expression::And(left, right) => left.matches(lot) && right.matches(lot),
+            Expression::Not(expression) => !expression.matches(lot),
+            Expression::Compare(comparison) => match comparison {
+                Comparison::Id(operator, id) => operator.compare(Decimal::from(lot.id), *id),
+                Comparison::Date(operator, date) => operator.compare(lot.date, *date),
+                Comparison::Price(operator, price) => operator.compare(lot.price, *price),
+                Comparison::Quantity(operator, quantity) => operator.compare(lot.quantity, *quantity),
+                Comparison::Tag(equal, tag) => lot.has_tag(tag) == *equal,
+                Comparison::Memo(equal, memo) => (lot.memo() == Some(memo.as_str())) == *equal,
+            },
+        }
+    }
+}
+
+/// Parses tokens into an expression by recursive descent, one method per level of precedence.
+struct Parser {
+    tokens: Peekable<std::vec::IntoIter<Token>>,
+}
+
+impl Parser {
+    fn or(&mut self) -> Result<Expression, TaxLotError> {
+        let mut expression = self.and()?;
+        while self.tokens.next_if_eq(&Token::Or).is_some() {
+            expression = Expression::Or(Box::new(expression), Box::new(self.and()?));
+        }
+        Ok(expression)
+    }
+
+    fn and(&mut self) -> Result<Expression, TaxLotError> {
+        let mut expression = self.unary()?;
+        while self.tokens.next_if_eq(&Token::And).is_some() {
+            expression = Expression::And(Box::new(expression), Box::new(self.unary()?));
+        }
+        Ok(expression)
+    }
+
+    fn unary(&mut self) -> Result<Expression, TaxLotError> {
+        match self.tokens.next() {
+            Some(Token::Not) => Ok(Expression::Not(Box::new(self.unary()?))),
+            Some(Token::LeftParen) => {
+                let expression = self.or()?;
+                match self.tokens.next() {
+                    Some(Token::RightParen) => Ok(expression),
+                    _ => Err(TaxLotError::InvalidFilter("missing `)`".to_string())),
+                }
+            }
+            Some(Token::Word(field)) => self.comparison(&field),
+            Some(token) => Err(TaxLotError::InvalidFilter(format!("unexpected {token}"))),
+            None => Err(TaxLotError::InvalidFilter("unexpected end of the filter".to_string())),
+        }
+    }
+
+    fn comparison(&mut self, field: &str) -> Result<Expression, TaxLotError> {
+        let (Some(Token::Operator(operator)), Some(Token::Word(value))) = (self.tokens.next(), self.tokens.next())
+        else {
+            let message = format!("`{field}` is not followed by an operator and a value");
+            return Err(TaxLotError::InvalidFilter(message));
+        };
+        let invalid_value = || TaxLotError::InvalidFilter(format!("`{value}` is not a valid {field}"));
+        let number = || Decimal::from_str(&value).map_err(|_| invalid_value());
+        let equal = || match operator {
+            Operator::Eq => Ok(true),
+            Operator::Ne => Ok(false),
+            _ => Err(TaxLotError::InvalidFilter(format!("{field} can only be compared with == and !="))),
+        };
+        let comparison = match field {
+            "id" => Comparison::Id(operator, number()?),
+            "date" => Comparison::Date(
+                operator,
+                NaiveDate::parse_from_str(&value, "%Y-%m-%d").map_err(|_| invalid_value())?,
+            ),
+            "price" => Comparison::Price(operator, number()?),
+            "quantity" => Comparison::Quantity(operator, number()?),
+            "tag" => Comparison::Tag(equal()?, value.clone()),
+            "memo" => Comparison::Memo(equal()?, value.clone()),
+            _ => return Err(TaxLotError::InvalidFilter(format!("unknown field `{field}`"))),
+        };
+        Ok(Expression::Compare(comparison))
+    }
+}
+
+/// Splits a filter expression into tokens.
+fn tokenize(s: &str) -> Result<Vec<Token>, TaxLotError> {
+    let mut tokens = Vec::new();
+    let mut chars = s.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::LeftParen,
+            ')' => Token::RightParen,
+            '&' | '|' => {
+                if chars.next() != Some(c) {
+                    return Err(TaxLotError::InvalidFilter(format!("`{c}` is not followed by `{c}`")));
+                }
+                if c == '&' {
+                    Token::And
+                } else {
+                    Token::Or
+                }
+            }
+            '=' if chars.next_if_eq(&'=').is_some() => Token::Operator(Operator::Eq),
+            '!' if chars.next_if_eq(&'=').is_some() => Token::Operator(Operator::Ne),
+            '!' => Token::Not,
+            '<' if chars.next_if_eq(&'=').is_some() => Token::Operator(Operator::Le),
+            '<' => Token::Operator(Operator::Lt),
+            '>' if chars.next_if_eq(&'=').is_some() => Token::Operator(Operator::Ge),
+            '>' => Token::Operator(Operator::Gt),
+            '"' => Token::Word(quoted(&mut chars)?),
+            c if is_word_char(c) => {
+                let mut word = c.to_string();
+                while let Some(c) = chars.next_if(|&c| is_word_char(c)) {
+                    word.push(c);
+                }
+                Token::Word(word)
+            }
+            c => return Err(TaxLotError::InvalidFilter(format!("unexpected `{c}`"))),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+/// Returns true if `c` can be part of a bare field name or value.
+fn is_word_char(c: char) -> bool {
+    !c.is_whitespace() && !"()&|=!<>\"".contains(c)
+}
+
+/// Returns the rest of a quoted value after its opening `"`, up to the closing `"`.
+fn quoted(chars: &mut Peekable<Chars>) -> Result<String, TaxLotError> {
+    let mut value = String::new();
+    for c in chars.by_ref() {
+        if c == '"' {
+            return Ok(value);
+        }
+        value.push(c);
+    }
+    Err(TaxLotError::InvalidFilter("missing closing `\"`".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::{test_helpers::apply, LotCollection, LotFilter, SelectionAlgorithm, TaxLotError};
+
+    /// Returns the ids of the lots of `lines` that match `filter`.
+    fn matching(lines: &[&str], filter: &str) -> Result<Vec<u64>, TaxLotError> {
+        let mut lot_collection = LotCollection::new(SelectionAlgorithm::Fifo);
+        apply(&mut lot_collection, lines)?;
+        let filter = LotFilter::from_str(filter)?;
+        Ok(lot_collection.lots().filter(|lot| filter.matches(lot)).map(|lot| lot.id()).collect())
+    }
+
+    #[test]
+    fn test_filter_matches_lots() -> Result<(), TaxLotError> {
+        let lines = [
+            "2021-01-01,buy,10000.00,1.00000000,tags=ira",
+            "2021-06-01,buy,20000.00,2.00000000,dip buy",
+            "2022-01-01,buy,30000.00,0.50000000,tags=ira;dca",
+        ];
+        assert_eq!(matching(&lines, "price > 10000 && date < 2022-01-01")?, [2]);
+        assert_eq!(matching(&lines, "tag == ira || quantity >= 2")?, [1, 2, 3]);
+        assert_eq!(matching(&lines, "!(tag == ira) || id == 3")?, [2, 3]);
+        assert_eq!(matching(&lines, r#"memo == "dip buy""#)?, [2]);
+        assert_eq!(matching(&lines, "tag != dca && price <= 10000.001")?, [1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_invalid_filter() {
+        for filter in ["price >", "price > cheap", "tag < ira", "size > 1", "(price > 1", "price > 1 &", "price > 1 id"] {
+            LotFilter::from_str(filter).expect_err(filter);
+        }
+    }
+}
@@ -0,0 +1,147 @@
+//! Parsing of fixed-width lines, e.g. from brokerage statements whose columns are not delimited.
+
+use std::{ops::Range, str::FromStr};
+
+use chrono::NaiveDate;
+
+use crate::{schema::check_record, Column, ColumnError, LotOperation, LotType, TaxLotError};
+
+/// Represents where each field of a lot operation is on a fixed-width line, as character offsets.
+///
+/// A layout is written as comma-separated `field=offset:width` entries, e.g.
+/// `date=0:10,type=10:6,price=16:12,quantity=28:14,memo=42`. The `date`, `type`, `price` and `quantity`
+/// fields are required. The optional `memo` field is parsed like the rest of a delimited line after the
+/// quantity, so it can also hold `tags=` and `txn=` fields. A field without a width extends to the end of
+/// the line, and fields past the end of a line are empty. Values are trimmed of surrounding spaces.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FixedWidthLayout {
+    date: Range<usize>,
+    lot_type: Range<usize>,
+    price: Range<usize>,
+    quantity: Range<usize>,
+    memo: Option<Range<usize>>,
+}
+
+impl FromStr for FixedWidthLayout {
+    type Err = TaxLotError;
+
+    fn from_str(s: &str) -> Result<Self, TaxLotError> {
+        let (mut date, mut lot_type, mut price, mut quantity, mut memo) = (None, None, None, None, None);
+        for entry in s.split(',') {
+            let invalid = || TaxLotError::InvalidFixedWidthLayout(entry.to_string());
+            let (name, columns) = entry.split_once('=').ok_or_else(invalid)?;
+            let (offset, width) = columns.split_once(':').unwrap_or((columns, ""));
+            let offset: usize = offset.trim().parse().map_err(|_| invalid())?;
+            let end = match width.trim() {
+                "" => usize::MAX,
+                width => offset.saturating_add(width.parse().map_err(|_| invalid())?),
+            };
+            let field = match name.trim() {
+                "date" => &mut date,
+                "type" => &mut lot_type,
+                "price" => &mut price,
+                "quantity" => &mut quantity,
+                "memo" => &mut memo,
+                _ => return Err(invalid()),
+            };
+            *field = Some(offset..end);
+        }
+
+        let required = |field: Option<Range<usize>>, name: &str| {
+            field.ok_or_else(|| TaxLotError::InvalidFixedWidthLayout(format!("missing {name} field")))
+        };
+        Ok(FixedWidthLayout {
+            date: required(date, "date")?,
+            lot_type: required(lot_type, "type")?,
+            price: required(price, "price")?,
+            quantity: required(quantity, "quantity")?,
+            memo,
+        })
+    }
+}
+
+impl FixedWidthLayout {
+    /// Parses a fixed-width line into a lot operation according to the layout.
+    pub fn parse(&self, line: &str) -> Result<LotOperation, TaxLotError> {
+        let date = NaiveDate::parse_from_str(field(line, &self.date), "%Y-%m-%d")?;
+        let lot_type = LotType::from_str(field(line, &self.lot_type))?;
+        let memo = self.memo.as_ref().map_or("", |memo| field(line, memo));
+        LotOperation::from_fields(
+            date,
+            lot_type,
+            field(line, &self.price),
+            field(line, &self.quantity),
+            memo,
+        )
+    }
+
+    /// Returns the columns of lines in this layout. The memo is the `Fields` column.
+    pub fn columns(&self) -> &'static [Column] {
+        match self.memo {
+            Some(_) => &[Column::Date, Column::Type, Column::Price, Column::Quantity, Column::Fields],
+            None => &[Column::Date, Column::Type, Column::Price, Column::Quantity],
+        }
+    }
+
+    /// Returns a problem with each column of a fixed-width line that keeps it from being parsed, in the order of the
+    /// columns, like `InputDialect::check`. A blank field, e.g. one past the end of the line, is missing.
+    pub fn check(&self, line: &str) -> Vec<ColumnError> {
+        let value = |columns| Some(field(line, columns)).filter(|value| !value.is_empty());
+        check_record(&[
+            (Column::Date, value(&self.date)),
+            (Column::Type, value(&self.lot_type)),
+            (Column::Price, value(&self.price)),
+            (Column::Quantity, value(&self.quantity)),
+            (Column::Fields, self.memo.as_ref().and_then(value)),
+        ])
+    }
+}
+
+/// Returns the trimmed characters of `line` in `columns`, or an empty `&str` past the end of the line.
+fn field<'a>(line: &'a str, columns: &Range<usize>) -> &'a str {
+    let byte_offset = |chars: usize| line.char_indices().nth(chars).map_or(line.len(), |(offset, _)| offset);
+    let start = byte_offset(columns.start);
+    let end = if columns.end == usize::MAX { line.len() } else { byte_offset(columns.end) };
+    line[start..end].trim()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use rust_decimal::{prelude::FromPrimitive, Decimal};
+
+    use crate::{Column, FixedWidthLayout, LotType, TaxLotError};
+
+    #[test]
+    fn test_parse_fixed_width_line() -> Result<(), TaxLotError> {
+        let layout = FixedWidthLayout::from_str("date=0:10,type=11:4,price=15:10,quantity=25:14,memo=39")?;
+        let lot_operation = layout.parse("2021-01-01 SELL  10000.00    0.50000000 tags=ira,rebalânce")?;
+        assert_eq!(lot_operation.lot_type, LotType::Sell);
+        assert_eq!(lot_operation.price, Decimal::from_f64(10000.0).expect("Failed to parse price"));
+        assert_eq!(lot_operation.quantity, Decimal::from_f64(0.5).expect("Failed to parse quantity"));
+        assert_eq!(lot_operation.tags.len(), 1);
+        assert_eq!(lot_operation.memo.as_deref(), Some("rebalânce"));
+
+        // A line that ends before the optional memo has no memo.
+        let lot_operation = layout.parse("2021-01-01 buy   10000.00    1")?;
+        assert_eq!(lot_operation.lot_type, LotType::Buy);
+        assert_eq!(lot_operation.memo, None);
+
+        // A line that ends before the quantity is missing a required field.
+        layout.parse("2021-01-01 buy   10000.00").expect_err("Successfully parsed a line without a quantity");
+        let columns: Vec<_> = layout.check("2021-01-01 hold  10000.00").into_iter().map(|error| error.column).collect();
+        assert_eq!(columns, [Column::Type, Column::Quantity]);
+        assert_eq!(layout.check("2021-01-01 buy   10000.00    1          rebalance"), []);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_fixed_width_layout() {
+        FixedWidthLayout::from_str("date=0:10,type=10:4,price=14:10").expect_err("Parsed a layout without a quantity");
+        FixedWidthLayout::from_str("date=0:10,type=10:4,price=14:10,quantity=24:x")
+            .expect_err("Parsed a layout with an invalid width");
+        FixedWidthLayout::from_str("date=0:10,side=10:4,price=14:10,quantity=24:10")
+            .expect_err("Parsed a layout with an unknown field");
+    }
+}
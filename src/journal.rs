@@ -0,0 +1,444 @@
+//! Journal of the buys and sells applied to a `LotCollection`, which lets `amend` and `cancel`
+//! operations correct an earlier operation by its transaction id, and backdated operations be inserted
+//! among the earlier operations by date.
+//!
+//! A correction or insertion edits the journal and replays it from an empty collection, so the lots and realized
+//! gains are always exactly what they would be had the corrected operations been applied in the first
+//! place. Replaying is O(N) in the number of journaled operations.
+
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::atomic::AtomicU64,
+};
+
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+
+use crate::{
+    disposal::DisposalLog, undo::UndoLog, Disposal, Lot, LotCollection, LotOperation, LotType, TaxLotError,
+};
+
+/// Disposals grouped by the date and transaction id of their sell.
+type DisposalsBySell = BTreeMap<(NaiveDate, Option<String>), Vec<Disposal>>;
+
+/// Every operation applied to a `LotCollection` since the journal was enabled, except for corrections, in order.
+#[derive(Debug, Default)]
+pub(crate) struct Journal {
+    operations: Vec<LotOperation>,
+
+    // Transaction ids of the journaled operations, to reject duplicates without searching `operations`.
+    txn_ids: HashSet<String>,
+}
+
+/// Represents how a lot differs after a backdated operation was inserted, matched by lot id.
+#[derive(Debug, Clone, PartialEq)]
+pub enum LotChange {
+    Added(Lot),
+    Removed(Lot),
+    Changed { before: Lot, after: Lot },
+}
+
+/// Represents how the disposals differ after a backdated operation was inserted. Disposals are matched by the date
+/// and transaction id of their sell, and a sell whose disposals differ has them paired in order as re-matched, e.g.
+/// when it now sells from another lot. The ones left over were added or removed.
+#[derive(Debug, Clone, PartialEq)]
+pub enum DisposalChange {
+    Added(Disposal),
+    Removed(Disposal),
+    Rematched { before: Disposal, after: Disposal },
+}
+
+/// Represents what changed when a backdated operation was inserted.
+///
+/// `lots`: Lots that changed, in order of their ids.
+/// `disposals`: Disposals that changed, in order of the date of their sell. Empty unless disposals are recorded.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InsertChanges {
+    pub lots: Vec<LotChange>,
+    pub disposals: Vec<DisposalChange>,
+}
+
+impl Journal {
+    /// Returns an error if `lot_operation` has a transaction id that is already in the journal, since
+    /// an amend or cancel could not tell the two operations apart.
+    pub(crate) fn check_txn_id(&self, lot_operation: &LotOperation) -> Result<(), TaxLotError> {
+        match &lot_operation.txn_id {
+            Some(txn_id) if self.txn_ids.contains(txn_id) => Err(TaxLotError::DuplicateTxnId(txn_id.clone())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Removes the last operation from the journal, e.g. when it is undone.
+    pub(crate) fn pop(&mut self) {
+        if let Some(txn_id) = self.operations.pop().and_then(|lot_operation| lot_operation.txn_id) {
+            self.txn_ids.remove(&txn_id);
+        }
+    }
+
+    /// Adds an applied lot operation to the end of the journal.
+    pub(crate) fn push(&mut self, lot_operation: LotOperation) {
+        if let Some(txn_id) = &lot_operation.txn_id {
+            self.txn_ids.insert(txn_id.clone());
+        }
+        self.operations.push(lot_operation);
+    }
+
+    /// Returns the index of the operation with the transaction id `txn_id`.
+    fn position(&self, txn_id: &str) -> Result<usize, TaxLotError> {
+        self.operations
+            .iter()
+            .position(|lot_operation| lot_operation.txn_id.as_deref() == Some(txn_id))
+            .ok_or_else(|| TaxLotError::UnknownTxnId(txn_id.to_string()))
+    }
+}
+
+impl LotCollection {
+    /// Keeps every buy and sell applied from now on in a journal, so that later `amend` and `cancel`
+    /// operations can correct them. Since corrections replay the journal from an empty collection, this
+    /// must be enabled before applying any lot operations.
+    ///
+    /// With the `sequential` id scheme, correcting an operation can change the ids of the lots created
+    /// after it. The `hash` id scheme keeps them stable.
+    pub fn enable_journal(&mut self) {
+        self.journal.get_or_insert_with(Journal::default);
+    }
+
+    /// Applies an `amend` or `cancel` lot operation by correcting the journaled operation with the same
+    /// transaction id and replaying the journal. An amend replaces the price and quantity of the operation,
+    /// while a cancel removes it. The undo log is cleared, since the operations before the correction are
+    /// replayed and can no longer be reverted one by one.
+    pub(crate) fn correct(&mut self, lot_operation: LotOperation) -> Result<(), TaxLotError> {
+        let journal = self.journal.as_ref().ok_or(TaxLotError::JournalDisabled)?;
+        let txn_id = lot_operation
+            .txn_id
+            .as_deref()
+            .ok_or_else(|| TaxLotError::FieldDoesntExist("Transaction Id".to_string()))?;
+        let index = journal.position(txn_id)?;
+
+        let mut operations = journal.operations.clone();
+        if lot_operation.lot_type == LotType::Cancel {
+            operations.remove(index);
+        } else {
+            operations[index].price = lot_operation.price;
+            operations[index].quantity = lot_operation.quantity;
+        }
+
+        self.replay(operations)
+    }
+
+    /// Inserts a backdated buy or sell into the journal after the last operation on or before its date, and
+    /// recomputes every lot from there as if it had been applied in date order. Amend and cancel operations
+    /// are applied as usual. Returns the lots and disposals that changed; the realized gains can be compared
+    /// with `realized_gains` before and after. The disposals are compared in memory, including spilled ones. If
+    /// replaying fails, the lots are left unchanged.
+    pub fn insert_lot_operation(&mut self, lot_operation: LotOperation) -> Result<InsertChanges, TaxLotError> {
+        let journal = self.journal.as_ref().ok_or(TaxLotError::JournalDisabled)?;
+        let before: BTreeMap<u64, Lot> = self.lots().map(|lot| (lot.id, lot.into_owned())).collect();
+        let disposals_before = self.disposals_by_sell()?;
+
+        if !lot_operation.lot_type.is_correction() {
+            journal.check_txn_id(&lot_operation)?;
+            let index = journal
+                .operations
+                .iter()
+                .rposition(|journaled| journaled.date <= lot_operation.date)
+                .map_or(0, |index| index + 1);
+            let mut operations = journal.operations.clone();
+            operations.insert(index, lot_operation);
+            self.replay(operations)?;
+        } else {
+            self.apply_lot_operation(lot_operation)?;
+        }
+
+        let mut after: BTreeMap<u64, Lot> = self.lots().map(|lot| (lot.id, lot.into_owned())).collect();
+        let mut changes = Vec::new();
+        for (id, lot) in before {
+            match after.remove(&id) {
+                Some(after) if after != lot => changes.push(LotChange::Changed { before: lot, after }),
+                Some(_) => {}
+                None => changes.push(LotChange::Removed(lot)),
+            }
+        }
+        changes.extend(after.into_values().map(LotChange::Added));
+        changes.sort_by_key(|change| match change {
+            LotChange::Added(lot) | LotChange::Removed(lot) | LotChange::Changed { before: lot, .. } => lot.id,
+        });
+
+        let mut disposals_after = self.disposals_by_sell()?;
+        let mut disposal_changes = Vec::new();
+        for (sell, mut before) in disposals_before {
+            let mut after = disposals_after.remove(&sell).unwrap_or_default();
+            // Disposals that are the same before and after did not change, wherever they are among those of the sell.
+            before.retain(|disposal| match after.iter().position(|after| after == disposal) {
+                Some(index) => {
+                    after.remove(index);
+                    false
+                }
+                None => true,
+            });
+            let mut after = after.into_iter();
+            for before in before {
+                disposal_changes.push(match after.next() {
+                    Some(after) => DisposalChange::Rematched { before, after },
+                    None => DisposalChange::Removed(before),
+                });
+            }
+            disposal_changes.extend(after.map(DisposalChange::Added));
+        }
+        disposal_changes.extend(disposals_after.into_values().flatten().map(DisposalChange::Added));
+        disposal_changes.sort_by_key(|change| match change {
+            DisposalChange::Added(disposal)
+            | DisposalChange::Removed(disposal)
+            | DisposalChange::Rematched { after: disposal, .. } => disposal.sold,
+        });
+
+        Ok(InsertChanges { lots: changes, disposals: disposal_changes })
+    }
+
+    /// Returns the recorded disposals grouped by the date and transaction id of their sell, in the order they were
+    /// sold.
+    fn disposals_by_sell(&self) -> Result<DisposalsBySell, TaxLotError> {
+        let mut sells = DisposalsBySell::new();
+        for disposal in self.disposals() {
+            let disposal = disposal.map_err(TaxLotError::DisposalSpillError)?;
+            sells.entry((disposal.sold, disposal.txn_id.clone())).or_default().push(disposal);
+        }
+        Ok(sells)
+    }
+
+    /// Replaces the lots with the result of applying `operations` to an empty collection with the same
+    /// settings, including the sell strategy, and an empty undo log. If any operation fails, e.g. because a
+    /// correction left a later sell unable to meet the minimum holding period, the error is returned and the
+    /// collection is left unchanged.
+    fn replay(&mut self, operations: Vec<LotOperation>) -> Result<(), TaxLotError> {
+        let mut replayed = LotCollection {
+            lots: self.lots.cleared(),
+            date_index: HashMap::new(),
+            merge_policy: self.merge_policy,
+            merge_window: self.merge_window,
+            id_generator: AtomicU64::new(self.start_id),
+            start_id: self.start_id,
+            id_scheme: self.id_scheme,
+            id_seed: self.id_seed,
+            lot_ids: HashSet::new(),
+            selection_algorithm: self.selection_algorithm,
+            realized_gains: Decimal::ZERO,
+            quarterly_gains: BTreeMap::new(),
+            oversold: Decimal::ZERO,
+            income: BTreeMap::new(),
+            locked_lots: self.locked_lots.clone(),
+            min_holding_days: self.min_holding_days,
+            max_open_lots: self.max_open_lots,
+            disposals: self.disposals.as_ref().map(DisposalLog::cleared),
+            journal: Some(Journal::default()),
+            undo_log: None,
+            sell_strategy: self.sell_strategy.take(),
+        };
+        for lot_operation in operations {
+            if let Err(e) = replayed.apply_lot_operation(lot_operation) {
+                // The strategy cannot be cloned, so it is handed back.
+                self.sell_strategy = replayed.sell_strategy.take();
+                return Err(e);
+            }
+        }
+        replayed.undo_log = self.undo_log.as_ref().map(UndoLog::cleared);
+
+        *self = replayed;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use rust_decimal::{prelude::FromPrimitive, Decimal};
+
+    use crate::{
+        test_helpers::{apply, lots},
+        Disposal, DisposalChange, LotChange, LotCollection, LotOperation, SelectionAlgorithm, TaxLotError,
+    };
+
+    #[test]
+    fn test_amend_recomputes_lots() -> Result<(), TaxLotError> {
+        let mut lot_collection = LotCollection::new(SelectionAlgorithm::Hifo);
+        lot_collection.enable_journal();
+        apply(
+            &mut lot_collection,
+            &[
+                "2021-01-01,buy,10000.00,1.00000000,txn=a",
+                "2021-01-02,buy,20000.00,1.00000000,txn=b",
+                "2021-02-01,sell,25000.00,1.00000000,txn=c",
+            ],
+        )?;
+        assert_eq!(lots(&lot_collection), ["1,2021-01-01,10000.00,1.00000000"]);
+
+        // Once the first buy is corrected to the higher price, the sell deducts from it instead.
+        apply(&mut lot_collection, &["2021-02-05,amend,30000.00,2.00000000,txn=a"])?;
+        assert_eq!(
+            lots(&lot_collection),
+            ["1,2021-01-01,30000.00,1.00000000", "2,2021-01-02,20000.00,1.00000000"]
+        );
+        assert_eq!(lot_collection.realized_gains(), Decimal::from_f64(-5000.0).expect("Failed to parse gain"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_cancel_removes_operation() -> Result<(), TaxLotError> {
+        let mut lot_collection = LotCollection::new(SelectionAlgorithm::Fifo);
+        lot_collection.enable_journal();
+        apply(
+            &mut lot_collection,
+            &[
+                "2021-01-01,buy,10000.00,1.00000000,txn=a",
+                "2021-01-02,buy,20000.00,1.00000000,txn=b",
+                "2021-02-01,sell,25000.00,1.00000000,txn=c",
+                "2021-02-05,cancel,,,txn=c",
+            ],
+        )?;
+        assert_eq!(lots(&lot_collection).len(), 2);
+
+        // A cancelled transaction id can be used again.
+        apply(&mut lot_collection, &["2021-02-06,sell,25000.00,0.50000000,txn=c"])?;
+        assert_eq!(
+            lots(&lot_collection),
+            ["1,2021-01-01,10000.00,0.50000000", "2,2021-01-02,20000.00,1.00000000"]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_correction_leaves_lots_unchanged() -> Result<(), TaxLotError> {
+        let mut lot_collection = LotCollection::new(SelectionAlgorithm::Fifo);
+        lot_collection.enable_journal();
+        lot_collection.set_min_holding_days(30);
+        apply(
+            &mut lot_collection,
+            &[
+                "2021-01-01,buy,10000.00,1.00000000,txn=a",
+                "2021-01-20,buy,20000.00,1.00000000,txn=b",
+                "2021-02-10,sell,25000.00,1.00000000,txn=c",
+            ],
+        )?;
+
+        // Without the first buy, the sell could only be filled from a lot held for 21 days.
+        apply(&mut lot_collection, &["2021-02-15,cancel,,,txn=a"])
+            .expect_err("Cancelled a buy that a later sell depends on");
+        assert_eq!(lots(&lot_collection), ["2,2021-01-20,20000.00,1.00000000"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_reports_changed_lots() -> Result<(), TaxLotError> {
+        let mut lot_collection = LotCollection::new(SelectionAlgorithm::Hifo);
+        lot_collection.enable_journal();
+        apply(
+            &mut lot_collection,
+            &[
+                "2021-01-01,buy,10000.00,1.00000000",
+                "2021-01-10,buy,20000.00,1.00000000",
+                "2021-02-01,sell,25000.00,1.00000000",
+            ],
+        )?;
+
+        // Bought before the sell, the lot with the highest price is the one that is sold.
+        let changes = lot_collection.insert_lot_operation(LotOperation::from_str("2021-01-05,buy,30000.00,1.00000000")?)?;
+        assert_eq!(lots(&lot_collection), ["3,2021-01-10,20000.00,1.00000000", "1,2021-01-01,10000.00,1.00000000"]);
+        assert!(changes.disposals.is_empty());
+        let added: Vec<_> = changes
+            .lots
+            .iter()
+            .map(|change| match change {
+                LotChange::Added(lot) => lot.to_string(),
+                change => panic!("Unexpected change {change:?}"),
+            })
+            .collect();
+        assert_eq!(added, ["3,2021-01-10,20000.00,1.00000000"]);
+        assert_eq!(lot_collection.realized_gains(), Decimal::from_f64(-5000.0).expect("Failed to parse gain"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_insert_reports_changed_disposals() -> Result<(), TaxLotError> {
+        let mut lot_collection = LotCollection::new(SelectionAlgorithm::Hifo);
+        lot_collection.enable_journal();
+        lot_collection.enable_disposals();
+        apply(
+            &mut lot_collection,
+            &[
+                "2021-01-01,buy,10000.00,1.00000000",
+                "2021-01-10,buy,20000.00,1.00000000",
+                "2021-02-01,sell,25000.00,1.00000000,txn=s",
+            ],
+        )?;
+        let sold = |disposal: &Disposal| {
+            (disposal.lot_id, disposal.acquired.to_string(), disposal.gain.normalize().to_string())
+        };
+
+        // The sell is re-matched to the inserted lot with the higher price.
+        let buy = LotOperation::from_str("2021-01-05,buy,30000.00,1.00000000")?;
+        let changes = lot_collection.insert_lot_operation(buy)?;
+        let rematched: Vec<_> = changes
+            .disposals
+            .iter()
+            .map(|change| match change {
+                DisposalChange::Rematched { before, after } => (sold(before), sold(after)),
+                change => panic!("Unexpected change {change:?}"),
+            })
+            .collect();
+        assert_eq!(
+            rematched,
+            [(
+                (2, "2021-01-10".to_string(), "5000".to_string()),
+                (2, "2021-01-05".to_string(), "-5000".to_string())
+            )]
+        );
+
+        // A backdated sell adds a disposal, and moves the later sell to the next lot.
+        let changes = lot_collection.insert_lot_operation(LotOperation::from_str("2021-01-20,sell,20000.00,0.5")?)?;
+        let changed: Vec<_> = changes
+            .disposals
+            .iter()
+            .map(|change| match change {
+                DisposalChange::Added(after) => (None, Some(sold(after))),
+                DisposalChange::Removed(before) => (Some(sold(before)), None),
+                DisposalChange::Rematched { before, after } => (Some(sold(before)), Some(sold(after))),
+            })
+            .collect();
+        assert_eq!(
+            changed,
+            [
+                (None, Some((2, "2021-01-05".to_string(), "-5000".to_string()))),
+                (
+                    Some((2, "2021-01-05".to_string(), "-5000".to_string())),
+                    Some((2, "2021-01-05".to_string(), "-2500".to_string()))
+                ),
+                (None, Some((3, "2021-01-10".to_string(), "2500".to_string()))),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_corrections_require_known_txn_id() -> Result<(), TaxLotError> {
+        let mut lot_collection = LotCollection::new(SelectionAlgorithm::Fifo);
+        apply(&mut lot_collection, &["2021-01-01,buy,10000.00,1.00000000,txn=a"])?;
+        apply(&mut lot_collection, &["2021-01-02,cancel,,,txn=a"]).expect_err("Cancelled without a journal");
+
+        lot_collection = LotCollection::new(SelectionAlgorithm::Fifo);
+        lot_collection.enable_journal();
+        apply(&mut lot_collection, &["2021-01-01,buy,10000.00,1.00000000,txn=a"])?;
+        apply(&mut lot_collection, &["2021-01-02,buy,10000.00,1.00000000,txn=a"])
+            .expect_err("Applied a duplicate transaction id");
+        apply(&mut lot_collection, &["2021-01-02,cancel,,,txn=b"]).expect_err("Cancelled an unknown transaction id");
+        apply(&mut lot_collection, &["2021-01-02,cancel,,"]).expect_err("Cancelled without a transaction id");
+        assert_eq!(lots(&lot_collection), ["1,2021-01-01,10000.00,1.00000000"]);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,3215 @@
+use std::{
+    borrow::Cow,
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt::Display,
+    str::FromStr,
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+use chrono::{Datelike, Days, Months, NaiveDate, ParseError};
+use clap::{Subcommand, ValueEnum};
+use rust_decimal::Decimal;
+use thiserror::Error;
+
+pub use disposal::Disposal;
+use disposal::DisposalLog;
+pub use encoding::Encoding;
+pub use filter::LotFilter;
+pub use fixed_width::FixedWidthLayout;
+use journal::Journal;
+use lot_store::LotStore;
+pub use xml::XmlMapping;
+pub use schema::{Column, ColumnError};
+pub use journal::{DisposalChange, InsertChanges, LotChange};
+pub use strategy::SellStrategy;
+use undo::{Undo, UndoLog};
+
+mod checkpoint;
+mod disposal;
+mod encoding;
+mod filter;
+mod fixed_width;
+mod journal;
+mod lot_store;
+mod schema;
+mod strategy;
+mod undo;
+mod xml;
+#[cfg(feature = "async")]
+mod stream;
+
+const INITIAL_TAX_LOT_ID: u64 = 1;
+
+/// Hash ids are limited to 63 bits so that they also fit in a signed 64-bit integer, e.g. in JavaScript bindings.
+const HASH_ID_MASK: u64 = i64::MAX as u64;
+
+/// Increment of the SplitMix64 generator that random ids are drawn from.
+const SPLITMIX_GAMMA: u64 = 0x9e37_79b9_7f4a_7c15;
+
+/// Offset basis and prime of the 64-bit FNV-1a hash.
+const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Central enum for errors that can occur when processing tax lots.
+#[derive(Debug, Error)]
+pub enum TaxLotError {
+    #[error("Error reading input: {0}")]
+    InputReadError(#[from] std::io::Error),
+    #[error("Could not parse date. Format: YYYY-mm-DD")]
+    DateParseError(#[from] ParseError),
+    #[error("Could not parse lot operation. {0} field does not exist")]
+    FieldDoesntExist(String),
+    #[error("Could not parse Lot Type. Options: buy, sell, amend, cancel, dividend, interest, drip, vest, espp")]
+    ParseLotTypeError,
+    #[error("Could not parse income type. Options: dividend, interest, compensation")]
+    ParseIncomeTypeError,
+    #[error("Could not parse selection algorithm. Options: fifo, hifo, lt-hifo, max-loss, max-gain")]
+    ParseSelectionAlgorithmError,
+    #[error("Could not parse sell method. Options: fifo, hifo, lt-hifo, max-loss, max-gain, lot:<id>")]
+    ParseSellMethodError,
+    #[error("Could not sell {0} from lot {1} on {2}: there is no unlocked lot with that id and that many shares")]
+    SpecificLotUnavailable(Decimal, u64, NaiveDate),
+    #[error("Could not parse Decimal")]
+    DecimalParseError(#[from] rust_decimal::Error),
+    #[error("Overflow occurred while {0}")]
+    DecimalOverflow(String),
+    #[error("Underflow occurred while {0}")]
+    DecimalUnderflow(String),
+    #[error("Could not parse price: price cannot be negative")]
+    NegativePrice,
+    #[error("Could not parse quantity: quantity cannot be negative")]
+    NegativeQuantity,
+    #[error("Could not sell {0} on {1}: not enough shares have been held for more than {2} days")]
+    HoldingPeriodNotMet(Decimal, NaiveDate, u32),
+    #[error("Amend, cancel and insert operations require the journal to be enabled")]
+    JournalDisabled,
+    #[error("No operation with transaction id {0}")]
+    UnknownTxnId(String),
+    #[error("Duplicate transaction id {0}")]
+    DuplicateTxnId(String),
+    #[error("Invalid checkpoint: {0}")]
+    InvalidCheckpoint(String),
+    #[error("Invalid fixed-width layout: {0}. Format: field=offset:width,...")]
+    InvalidFixedWidthLayout(String),
+    #[error("Invalid XML mapping: {0}. Format: record=path,field=path,...")]
+    InvalidXmlMapping(String),
+    #[error("Could not parse XML: {0}")]
+    XmlParseError(String),
+    #[error("Invalid filter: {0}")]
+    InvalidFilter(String),
+    #[error("Limit exceeded: {0}")]
+    LimitExceeded(String),
+    #[error("Invalid config file: {0}")]
+    InvalidConfig(String),
+    #[error("Parsing the input failed unexpectedly: {0}")]
+    InputParserPanicked(String),
+    #[error("Could not spill disposals to disk: {0}")]
+    DisposalSpillError(std::io::Error),
+    #[error("Could not spill lots to disk: {0}")]
+    LotSpillError(std::io::Error),
+    #[error("Could not export the results: {0}")]
+    ExportError(String),
+    #[error("Could not fetch the input: {0}")]
+    RemoteInputError(String),
+    #[error("Could not get the mark price: {0}")]
+    PriceError(String),
+    #[error("Invalid ESPP purchase: {0}. Fields: offering=<date>,offering_fmv=<price>,fmv=<price>")]
+    InvalidEsppPurchase(String),
+    #[error("Sell strategy failed: {0}")]
+    StrategyError(String),
+    #[error("Script error: {0}")]
+    ScriptError(String),
+    #[error("{1} of {0} rows have errors")]
+    SchemaErrors(usize, usize),
+}
+
+/// Represents the selection algorithm for how the tax lots are sold.
+/// 
+/// fifo: tax lot that is bought first is also sold first
+/// hifo: tax lot with the highest price is sold first.
+/// lt-hifo: long-term tax lot with the highest price is sold first, then short-term lots by highest price.
+/// max-loss: tax lot with the largest loss at the sell price is sold first, whether it is short-term or long-term,
+/// followed by the lots with the smallest gain, long-term gains before short-term gains.
+/// max-gain: long-term tax lot with the lowest price is sold first, then short-term lots by lowest price.
+#[derive(Debug, Subcommand, Clone, Copy, PartialEq, Eq)]
+pub enum SelectionAlgorithm {
+    #[clap(name = "fifo")]
+    Fifo,
+
+    #[clap(name = "hifo")]
+    Hifo,
+
+    #[clap(name = "lt-hifo")]
+    LtHifo,
+
+    #[clap(name = "max-loss")]
+    MaxLoss,
+
+    #[clap(name = "max-gain")]
+    MaxGain,
+}
+
+impl SelectionAlgorithm {
+    /// Every selection algorithm, e.g. for benchmarking each of them.
+    pub const ALL: [SelectionAlgorithm; 5] = [
+        SelectionAlgorithm::Fifo,
+        SelectionAlgorithm::Hifo,
+        SelectionAlgorithm::LtHifo,
+        SelectionAlgorithm::MaxLoss,
+        SelectionAlgorithm::MaxGain,
+    ];
+}
+
+impl Display for SelectionAlgorithm {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // Display the same name that is used on the command line.
+        match self {
+            SelectionAlgorithm::Fifo => write!(f, "fifo"),
+            SelectionAlgorithm::Hifo => write!(f, "hifo"),
+            SelectionAlgorithm::LtHifo => write!(f, "lt-hifo"),
+            SelectionAlgorithm::MaxLoss => write!(f, "max-loss"),
+            SelectionAlgorithm::MaxGain => write!(f, "max-gain"),
+        }
+    }
+}
+
+impl FromStr for SelectionAlgorithm {
+    type Err = TaxLotError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().trim() {
+            "fifo" => Ok(SelectionAlgorithm::Fifo),
+            "hifo" => Ok(SelectionAlgorithm::Hifo),
+            "lt-hifo" => Ok(SelectionAlgorithm::LtHifo),
+            "max-loss" => Ok(SelectionAlgorithm::MaxLoss),
+            "max-gain" => Ok(SelectionAlgorithm::MaxGain),
+            _ => Err(TaxLotError::ParseSelectionAlgorithmError),
+        }
+    }
+}
+
+impl TryFrom<u32> for SelectionAlgorithm {
+    type Error = TaxLotError;
+
+    /// Returns the algorithm numbered `value` in the order of `SelectionAlgorithm::ALL`, e.g. as the C interface
+    /// takes it.
+    fn try_from(value: u32) -> Result<Self, Self::Error> {
+        usize::try_from(value)
+            .ok()
+            .and_then(|index| SelectionAlgorithm::ALL.get(index).copied())
+            .ok_or(TaxLotError::ParseSelectionAlgorithmError)
+    }
+}
+
+impl SelectionAlgorithm {
+    /// Returns true if lots are sorted in the same order by both algorithms, i.e. they have the same kind of
+    /// `LotKey`.
+    fn sorts_like(self, other: SelectionAlgorithm) -> bool {
+        let order = |algorithm| match algorithm {
+            SelectionAlgorithm::Fifo => 0,
+            SelectionAlgorithm::Hifo | SelectionAlgorithm::LtHifo | SelectionAlgorithm::MaxLoss => 1,
+            SelectionAlgorithm::MaxGain => 2,
+        };
+        order(self) == order(other)
+    }
+
+    /// Returns the number of passes over the lots the algorithm makes when selling, see `sell_pass`.
+    fn passes(self) -> usize {
+        match self {
+            SelectionAlgorithm::Fifo | SelectionAlgorithm::Hifo => 1,
+            SelectionAlgorithm::LtHifo | SelectionAlgorithm::MaxGain => 2,
+            SelectionAlgorithm::MaxLoss => 3,
+        }
+    }
+
+    /// Returns the pass over the lots in which `lot` is sold by a sell on `date` at `price`. Within a pass, lots
+    /// are sold in the order of their keys.
+    ///
+    /// `lt-hifo`: the long-term lots, then the short-term lots.
+    /// `max-loss`: the losses, the long-term gains, then the short-term gains. Since the lots are sorted by
+    /// highest price, the pass over losses sells the largest loss, `lot price - sell price`, first and each pass
+    /// over gains sells the smallest gain first.
+    /// `max-gain`: the long-term lots, then the short-term lots.
+    fn sell_pass(self, lot: &Lot, date: NaiveDate, price: Decimal) -> usize {
+        match self {
+            SelectionAlgorithm::Fifo | SelectionAlgorithm::Hifo => 0,
+            SelectionAlgorithm::LtHifo | SelectionAlgorithm::MaxGain => usize::from(!lot.is_long_term(date)),
+            SelectionAlgorithm::MaxLoss if lot.price > price => 0,
+            SelectionAlgorithm::MaxLoss => 1 + usize::from(!lot.is_long_term(date)),
+        }
+    }
+}
+
+/// Represents how a single sell selects the lots it sells from, instead of the selection algorithm of the
+/// collection, from a `method=` field after the quantity.
+///
+/// Algorithm: the lots are sold in the order of another selection algorithm, e.g. `method=fifo`.
+/// Lot: only the lot with this id is sold from, e.g. `method=lot:42`, like a specific lot instruction to a
+/// broker. The sell fails if the lot is locked or holds fewer shares.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SellMethod {
+    Algorithm(SelectionAlgorithm),
+    Lot(u64),
+}
+
+impl FromStr for SellMethod {
+    type Err = TaxLotError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().strip_prefix("lot:") {
+            Some(id) => id.trim().parse().map(SellMethod::Lot).map_err(|_| TaxLotError::ParseSellMethodError),
+            None => SelectionAlgorithm::from_str(s)
+                .map(SellMethod::Algorithm)
+                .map_err(|_| TaxLotError::ParseSellMethodError),
+        }
+    }
+}
+
+/// Represents what happens when a lot is bought on a date that already has a lot.
+///
+/// weighted-average: the buy is merged into the existing lot, which takes the weighted average price.
+/// keep-separate: every buy creates its own lot, e.g. to be able to identify specific lots later.
+#[derive(Debug, ValueEnum, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MergePolicy {
+    #[default]
+    WeightedAverage,
+    KeepSeparate,
+}
+
+/// Represents the period that buys are merged within with the `weighted-average` merge policy.
+///
+/// day: buys on the same date are merged.
+/// week: buys in the same week, starting on Monday, are merged.
+/// month: buys in the same calendar month are merged.
+#[derive(Debug, ValueEnum, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MergeWindow {
+    #[default]
+    Day,
+    Week,
+    Month,
+}
+
+impl MergeWindow {
+    /// Returns the first date of the window that `date` falls in.
+    fn start(self, date: NaiveDate) -> NaiveDate {
+        match self {
+            MergeWindow::Day => date,
+            MergeWindow::Week => date - Days::new(u64::from(date.weekday().num_days_from_monday())),
+            MergeWindow::Month => date - Days::new(u64::from(date.day0())),
+        }
+    }
+}
+
+/// Represents how ids are assigned to new tax lots.
+///
+/// sequential: lots are numbered in the order they are created, starting at 1.
+/// uuid: every lot gets a random 63-bit id, so that the ids of lots from different files do not collide when
+/// they are merged. Ids are drawn from a seed picked when the collection is created, so replaying the journal
+/// after a correction gives the lots the same ids again.
+/// hash: the id is derived from the date, price and quantity of the buy that created the lot, so it stays the
+/// same when the input is re-processed, even if other operations are added or reordered.
+#[derive(Debug, ValueEnum, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IdScheme {
+    #[default]
+    Sequential,
+    Uuid,
+    Hash,
+}
+
+/// Represents the format of the lines that lot operations are parsed from.
+///
+/// standard: `date,buy|sell,price,quantity`, followed by the optional fields and memo.
+/// signed: `date,price,quantity` without the type, where a negative quantity is a sell, as in the trade
+/// exports of several exchanges. The optional fields and memo can follow as in the standard format.
+#[derive(Debug, ValueEnum, Clone, Copy, Default, PartialEq, Eq)]
+pub enum InputDialect {
+    #[default]
+    Standard,
+    Signed,
+}
+
+impl InputDialect {
+    /// Parses a line in this format into a lot operation.
+    pub fn parse(self, line: &str) -> Result<LotOperation, TaxLotError> {
+        match self {
+            InputDialect::Standard => LotOperation::from_str(line),
+            InputDialect::Signed => LotOperation::from_signed_str(line),
+        }
+    }
+}
+
+/// Represents the type of operation that can be applied to the tax lots.
+/// 
+/// Buy: create a new tax lot if no date currently exists or merge with existing tax lot.
+/// Sell: Deduct the shares from the tax lots according to the selection algorithm.
+/// Amend: Correct the price and quantity of an earlier operation with the same transaction id.
+/// Cancel: Remove an earlier operation with the same transaction id.
+/// Dividend: Record dividend income of price * quantity, e.g. the dividend per share times the shares held.
+/// Interest: Record interest income of price * quantity.
+/// Drip: Reinvest a dividend, recording dividend income of price * quantity and buying the quantity at the price.
+/// Vest: Vest restricted stock units, recording compensation income of price * quantity, where the price is the
+/// fair market value at vesting, and creating a lot with that price as its basis.
+/// Espp: Buy shares through an employee stock purchase plan at the discounted price, creating a lot that is never
+/// merged and keeps the terms of the purchase, see `EsppPurchase`.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum LotType {
+    Buy,
+    Sell,
+    Amend,
+    Cancel,
+    Dividend,
+    Interest,
+    Drip,
+    Vest,
+    Espp,
+}
+
+impl LotType {
+    /// Returns true if this type corrects an earlier operation instead of being journaled itself.
+    fn is_correction(self) -> bool {
+        matches!(self, LotType::Amend | LotType::Cancel)
+    }
+}
+
+impl Display for LotType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LotType::Buy => write!(f, "buy"),
+            LotType::Sell => write!(f, "sell"),
+            LotType::Amend => write!(f, "amend"),
+            LotType::Cancel => write!(f, "cancel"),
+            LotType::Dividend => write!(f, "dividend"),
+            LotType::Interest => write!(f, "interest"),
+            LotType::Drip => write!(f, "drip"),
+            LotType::Vest => write!(f, "vest"),
+            LotType::Espp => write!(f, "espp"),
+        }
+    }
+}
+
+/// Lots in the order that a sell deducts from them, with their keys, see `LotCollection::sell_order`.
+type SellOrder<'a> = Box<dyn Iterator<Item = (LotKey, Cow<'a, Lot>)> + 'a>;
+
+/// Represents the lots that a sell deducts from, the realized gains after it and, if they are recorded, its
+/// disposals, see `lots_to_sell`.
+struct SellPlan {
+    deductions: Vec<(LotKey, Decimal)>,
+    unfilled: Decimal,
+    realized_gains: Decimal,
+    quarter: (i32, u32),
+    quarter_gains: RealizedGains,
+    disposals: Vec<Disposal>,
+    compensation: Decimal,
+}
+
+/// Represents the kinds of income that are totalled per year.
+///
+/// Compensation is ordinary income from equity compensation, e.g. vested restricted stock units or the discount of
+/// ESPP shares sold in a disqualifying disposition.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum IncomeType {
+    Dividend,
+    Interest,
+    Compensation,
+}
+
+impl Display for IncomeType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IncomeType::Dividend => write!(f, "dividend"),
+            IncomeType::Interest => write!(f, "interest"),
+            IncomeType::Compensation => write!(f, "compensation"),
+        }
+    }
+}
+
+impl FromStr for IncomeType {
+    type Err = TaxLotError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dividend" => Ok(IncomeType::Dividend),
+            "interest" => Ok(IncomeType::Interest),
+            "compensation" => Ok(IncomeType::Compensation),
+            _ => Err(TaxLotError::ParseIncomeTypeError),
+        }
+    }
+}
+
+/// Represents the gains (or losses, if negative) realized in a year or quarter, split by holding period.
+///
+/// `short_term`: Gains realized by selling lots held for one year or less.
+/// `long_term`: Gains realized by selling lots held for more than one year.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RealizedGains {
+    pub short_term: Decimal,
+    pub long_term: Decimal,
+}
+
+impl RealizedGains {
+    /// Returns the short-term and long-term gains together.
+    pub fn total(&self) -> Decimal {
+        self.short_term + self.long_term
+    }
+}
+
+/// Represents the terms of a purchase through an employee stock purchase plan, which decide how much of the gain of
+/// selling its shares is ordinary income. The price of the purchase is what was paid after the discount.
+///
+/// `offering_date`: First day of the offering period, from an `offering=` field.
+/// `offering_fmv`: Fair market value of a share on the offering date, from an `offering_fmv=` field.
+/// `purchase_fmv`: Fair market value of a share on the purchase date, from an `fmv=` field.
+/// `discount`: Purchase fair market value minus the price paid per share.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EsppPurchase {
+    pub offering_date: NaiveDate,
+    pub offering_fmv: Decimal,
+    pub purchase_fmv: Decimal,
+    pub discount: Decimal,
+}
+
+impl EsppPurchase {
+    /// Returns true if selling shares bought on `purchased` on `sold` is a disqualifying disposition, i.e. it is
+    /// within two years of the offering date or within one year of the purchase. The discount of the shares sold in
+    /// a disqualifying disposition is ordinary income, and is added to their cost basis.
+    pub fn is_disqualifying(&self, purchased: NaiveDate, sold: NaiveDate) -> bool {
+        let within = |date: NaiveDate, months| {
+            date.checked_add_months(Months::new(months)).is_none_or(|end| sold <= end)
+        };
+        within(self.offering_date, 24) || within(purchased, 12)
+    }
+}
+
+/// Represents the gain (or loss, if negative) that selling what is left of a lot at a mark price would realize.
+///
+/// `cost_basis`: What the remaining quantity of the lot was bought for.
+/// `market_value`: What the remaining quantity of the lot is worth at the mark price.
+/// `gain`: The market value minus the cost basis.
+/// `long_term`: Whether the lot has been held for more than one year on the date of the mark price.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnrealizedGain {
+    pub cost_basis: Decimal,
+    pub market_value: Decimal,
+    pub gain: Decimal,
+    pub long_term: bool,
+}
+
+impl FromStr for LotType {
+    type Err = TaxLotError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // Compare without lowercasing into a new `String`, since this is parsed for every line.
+        let s = s.trim();
+        if s.eq_ignore_ascii_case("buy") {
+            Ok(LotType::Buy)
+        } else if s.eq_ignore_ascii_case("sell") {
+            Ok(LotType::Sell)
+        } else if s.eq_ignore_ascii_case("amend") {
+            Ok(LotType::Amend)
+        } else if s.eq_ignore_ascii_case("cancel") {
+            Ok(LotType::Cancel)
+        } else if s.eq_ignore_ascii_case("dividend") {
+            Ok(LotType::Dividend)
+        } else if s.eq_ignore_ascii_case("interest") {
+            Ok(LotType::Interest)
+        } else if s.eq_ignore_ascii_case("drip") {
+            Ok(LotType::Drip)
+        } else if s.eq_ignore_ascii_case("vest") {
+            Ok(LotType::Vest)
+        } else if s.eq_ignore_ascii_case("espp") {
+            Ok(LotType::Espp)
+        } else {
+            Err(TaxLotError::ParseLotTypeError)
+        }
+    }
+}
+
+/// Represents an operation that can be applied to the tax lots. These lot operations
+/// are parsed from stdin.
+///
+/// `tags`: Optional labels from a `tags=ira;robo` field after the quantity, e.g. the account the trade was made in.
+/// `txn_id`: Optional transaction id from a `txn=` field after the quantity, which amends and cancels refer to.
+/// `method`: Optional sell method from a `method=` field after the quantity, overriding the selection algorithm for
+/// a single sell. Other operations ignore it.
+/// `espp`: Terms of an `espp` purchase from its `offering=`, `offering_fmv=` and `fmv=` fields after the quantity,
+/// which it requires. Other operations ignore them.
+/// `memo`: Optional free-form note from the rest of the line after the quantity and fields, e.g. why the trade was made.
+#[derive(Debug, Clone)]
+pub struct LotOperation {
+    date: NaiveDate,
+    lot_type: LotType,
+    price: Decimal,
+    quantity: Decimal,
+    tags: BTreeSet<String>,
+    txn_id: Option<String>,
+    method: Option<SellMethod>,
+    espp: Option<EsppPurchase>,
+    memo: Option<String>,
+}
+
+/// Represents the optional fields and memo of a line after the quantity, see `LotOperation`. `offering`,
+/// `offering_fmv` and `fmv` are the fields of an ESPP purchase.
+struct OptionalFields {
+    tags: BTreeSet<String>,
+    txn_id: Option<String>,
+    method: Option<SellMethod>,
+    offering: Option<NaiveDate>,
+    offering_fmv: Option<Decimal>,
+    fmv: Option<Decimal>,
+    memo: Option<String>,
+}
+
+impl FromStr for LotOperation {
+    type Err = TaxLotError;
+
+    fn from_str(s: &str) -> Result<Self, TaxLotError> {
+        // The memo is the rest of the line, so it may contain commas.
+        let mut parts = s.splitn(5, ',');
+        let date = NaiveDate::parse_from_str(
+            LotOperation::next_field(&mut parts, "Date")?,
+            "%Y-%m-%d",
+        )?;
+        let lot_type = LotType::from_str(LotOperation::next_field(&mut parts, "Lot Type")?)?;
+        let price = LotOperation::next_field(&mut parts, "Price")?;
+        let quantity = LotOperation::next_field(&mut parts, "Quantity")?;
+        LotOperation::from_fields(date, lot_type, price, quantity, parts.next().unwrap_or_default())
+    }
+}
+
+impl LotOperation {
+    pub fn date(&self) -> NaiveDate {
+        self.date
+    }
+
+    pub fn lot_type(&self) -> LotType {
+        self.lot_type
+    }
+
+    pub fn price(&self) -> Decimal {
+        self.price
+    }
+
+    pub fn quantity(&self) -> Decimal {
+        self.quantity
+    }
+
+    pub fn tags(&self) -> impl Iterator<Item = &str> {
+        self.tags.iter().map(String::as_str)
+    }
+
+    pub fn memo(&self) -> Option<&str> {
+        self.memo.as_deref()
+    }
+
+    /// Replaces the price and quantity, e.g. to apply a fee to every buy before it is applied, with the same checks
+    /// as when they are parsed. The discount of an ESPP purchase is the fair market value minus the new price.
+    pub fn set_price_and_quantity(&mut self, price: Decimal, quantity: Decimal) -> Result<(), TaxLotError> {
+        if price <= Decimal::ZERO {
+            return Err(TaxLotError::NegativePrice);
+        }
+        if quantity <= Decimal::ZERO {
+            return Err(TaxLotError::NegativeQuantity);
+        }
+        if let Some(espp) = &mut self.espp {
+            if espp.purchase_fmv < price {
+                let message = format!("the price {price} is more than the fair market value {}", espp.purchase_fmv);
+                return Err(TaxLotError::InvalidEsppPurchase(message));
+            }
+            espp.discount = espp.purchase_fmv - price;
+        }
+        self.price = price;
+        self.quantity = quantity;
+        Ok(())
+    }
+
+    /// Replaces the tags and memo.
+    pub fn set_tags_and_memo(&mut self, tags: impl IntoIterator<Item = String>, memo: Option<String>) {
+        self.tags = tags.into_iter().collect();
+        self.memo = memo;
+    }
+
+    /// Parses a line of the `signed` input dialect, `date,price,quantity`, where a negative quantity is a sell.
+    fn from_signed_str(s: &str) -> Result<Self, TaxLotError> {
+        let mut parts = s.splitn(4, ',');
+        let date = NaiveDate::parse_from_str(
+            LotOperation::next_field(&mut parts, "Date")?,
+            "%Y-%m-%d",
+        )?;
+        let price = LotOperation::next_field(&mut parts, "Price")?;
+        let quantity = LotOperation::next_field(&mut parts, "Quantity")?;
+        let (lot_type, quantity) = match quantity.strip_prefix('-') {
+            Some(quantity) => (LotType::Sell, quantity),
+            None => (LotType::Buy, quantity.strip_prefix('+').unwrap_or(quantity)),
+        };
+        LotOperation::from_fields(date, lot_type, price, quantity, parts.next().unwrap_or_default())
+    }
+
+    /// Validates the price and quantity of a parsed line and splits the `rest` of the line after them into
+    /// the optional fields and memo.
+    fn from_fields(
+        date: NaiveDate,
+        lot_type: LotType,
+        price: &str,
+        quantity: &str,
+        rest: &str,
+    ) -> Result<Self, TaxLotError> {
+        // A cancel only refers to an earlier operation by its transaction id, so its price and quantity are
+        // left empty.
+        let (price, quantity) = if lot_type == LotType::Cancel {
+            (Decimal::ZERO, Decimal::ZERO)
+        } else {
+            let price = Decimal::from_str(price)?;
+            if price <= Decimal::ZERO {
+                return Err(TaxLotError::NegativePrice);
+            }
+            let quantity = Decimal::from_str(quantity)?;
+            if quantity <= Decimal::ZERO {
+                return Err(TaxLotError::NegativeQuantity);
+            }
+            (price, quantity)
+        };
+        let OptionalFields { tags, txn_id, method, offering, offering_fmv, fmv, memo } =
+            LotOperation::parse_fields_and_memo(rest)?;
+        let espp = match (lot_type, offering, offering_fmv, fmv) {
+            (LotType::Espp, Some(offering_date), Some(offering_fmv), Some(purchase_fmv)) => {
+                if purchase_fmv < price {
+                    let message = format!("the price {price} is more than the fair market value {purchase_fmv}");
+                    return Err(TaxLotError::InvalidEsppPurchase(message));
+                }
+                Some(EsppPurchase { offering_date, offering_fmv, purchase_fmv, discount: purchase_fmv - price })
+            }
+            (LotType::Espp, ..) => return Err(TaxLotError::InvalidEsppPurchase("missing field".to_string())),
+            _ => None,
+        };
+
+        Ok(LotOperation {
+            date,
+            lot_type,
+            price,
+            quantity,
+            tags,
+            txn_id,
+            method,
+            espp,
+            memo,
+        })
+    }
+
+    /// Create a new lot from a lot operation. A new lot should be created when the `LotCollection` 
+    /// does not have a lot for the date of the `LotOperation`.
+    fn create_new_lot(self, id: u64, selection_algo: SelectionAlgorithm) -> Lot {
+        Lot {
+            id,
+            date: self.date,
+            price: self.price,
+            quantity: self.quantity,
+            selection_algo,
+            tags: self.tags,
+            memo: self.memo,
+            espp: self.espp,
+            purchases: vec![Purchase {
+                date: self.date,
+                price: self.price,
+                quantity: self.quantity,
+            }],
+        }
+    }
+
+    /// Returns the id of a lot created by this operation with the `hash` id scheme. Price and quantity are
+    /// normalized first, so e.g. `10000.00` and `10000` give the same id.
+    fn content_hash(&self) -> u64 {
+        let content = format!("{},{},{}", self.date, self.price.normalize(), self.quantity.normalize());
+        let hash = content
+            .bytes()
+            .fold(FNV_OFFSET_BASIS, |hash, byte| (hash ^ u64::from(byte)).wrapping_mul(FNV_PRIME));
+        hash & HASH_ID_MASK
+    }
+
+    /// Splits the rest of a line after the quantity into the tags, transaction id, sell method and ESPP purchase
+    /// terms from its leading `tags=`, `txn=`, `method=`, `offering=`, `offering_fmv=` and `fmv=` fields, in any
+    /// order, and the memo after them.
+    fn parse_fields_and_memo(mut rest: &str) -> Result<OptionalFields, TaxLotError> {
+        let mut tags = BTreeSet::new();
+        let mut txn_id = None;
+        let mut method = None;
+        let (mut offering, mut offering_fmv, mut fmv) = (None, None, None);
+        let fair_market_value = |value: &str| match Decimal::from_str(value.trim()) {
+            Ok(value) if value > Decimal::ZERO => Ok(value),
+            _ => Err(TaxLotError::InvalidEsppPurchase(format!("`{value}` is not a fair market value"))),
+        };
+        loop {
+            let (field, remainder) = rest.split_once(',').unwrap_or((rest, ""));
+            if let Some(value) = field.trim().strip_prefix("tags=") {
+                let values = value.split(';').map(str::trim).filter(|tag| !tag.is_empty());
+                tags.extend(values.map(str::to_string));
+            } else if let Some(value) = field.trim().strip_prefix("txn=") {
+                txn_id = Some(value.to_string()).filter(|txn_id| !txn_id.is_empty());
+            } else if let Some(value) = field.trim().strip_prefix("method=") {
+                method = Some(SellMethod::from_str(value)?);
+            } else if let Some(value) = field.trim().strip_prefix("offering=") {
+                offering = Some(NaiveDate::parse_from_str(value.trim(), "%Y-%m-%d")?);
+            } else if let Some(value) = field.trim().strip_prefix("offering_fmv=") {
+                offering_fmv = Some(fair_market_value(value)?);
+            } else if let Some(value) = field.trim().strip_prefix("fmv=") {
+                fmv = Some(fair_market_value(value)?);
+            } else {
+                break;
+            }
+            rest = remainder;
+        }
+        let memo = Some(rest.trim()).filter(|memo| !memo.is_empty()).map(str::to_string);
+        Ok(OptionalFields { tags, txn_id, method, offering, offering_fmv, fmv, memo })
+    }
+
+    /// Returns the next `&str` from the iterator over the fields of a line. Performs error
+    /// checking to validate that the field exists. Iterating avoids allocating a vector of
+    /// fields for every line that is parsed.
+    fn next_field<'a>(parts: &mut impl Iterator<Item = &'a str>, field_name: &str) -> Result<&'a str, TaxLotError> {
+        match parts.next() {
+            Some(field) => Ok(field),
+            None => Err(TaxLotError::FieldDoesntExist(field_name.to_string()))
+        }
+    }
+}
+
+/// Returns the `n`th random id of the `uuid` id scheme drawn from `seed`, the `n`th output of a SplitMix64
+/// generator. Like hash ids, the ids are limited to 63 bits.
+fn random_id(seed: u64, n: u64) -> u64 {
+    let mut z = seed.wrapping_add(n.wrapping_add(1).wrapping_mul(SPLITMIX_GAMMA));
+    z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    (z ^ (z >> 31)) & HASH_ID_MASK
+}
+
+/// Checked addition operation that maps an `Option` to a `Result` in case the operation
+/// overflows.
+fn checked_add(left: Decimal, right: Decimal) -> Result<Decimal, TaxLotError> {
+    match left.checked_add(right) {
+        Some(result) => Ok(result),
+        None => Err(TaxLotError::DecimalOverflow("adding".to_string()))
+    }
+}
+
+/// Checked multiplication operation that maps an `Option` to a `Result` in case the operation
+/// overflows.
+fn checked_mul(left: Decimal, right: Decimal) -> Result<Decimal, TaxLotError> {
+    match left.checked_mul(right) {
+        Some(result) => Ok(result),
+        None => Err(TaxLotError::DecimalOverflow("multiplying".to_string()))
+    }
+}
+
+/// Checked division operation that maps an `Option` to a `Result` in case the operation
+/// underflows.
+fn checked_div(left: Decimal, right: Decimal) -> Result<Decimal, TaxLotError> {
+    match left.checked_div(right) {
+        Some(result) => Ok(result),
+        None => Err(TaxLotError::DecimalUnderflow("dividing".to_string()))
+    }
+}
+
+/// Checked subtraction operation that maps an `Option` to a `Result` in case the operation
+/// underflows.
+fn checked_sub(left: Decimal, right: Decimal) -> Result<Decimal, TaxLotError> {
+    match left.checked_sub(right) {
+        Some(result) => Ok(result),
+        None => Err(TaxLotError::DecimalUnderflow("subtracting".to_string()))
+    }
+}
+
+/// Represents a TaxLot that can be sold. `LotOperation`s can be merged if they have been bought on the
+/// same date.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Lot {
+    id: u64,
+    date: NaiveDate,
+    price: Decimal,
+    quantity: Decimal,
+    selection_algo: SelectionAlgorithm,
+    tags: BTreeSet<String>,
+    memo: Option<String>,
+    espp: Option<EsppPurchase>,
+    purchases: Vec<Purchase>,
+}
+
+/// Represents a buy that a tax lot is made of, with the price and quantity it was bought at. The weighted
+/// average price of a merged lot does not show its buys, so they are kept for audits.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Purchase {
+    pub date: NaiveDate,
+    pub price: Decimal,
+    pub quantity: Decimal,
+}
+
+/// Position of a lot in the sell order of a `LotCollection`. The derived ordering sorts lots in the
+/// order they are sold. Lots with the same price are sorted by date, oldest first, and lots with the same
+/// date by id, lowest first, so the order never depends on the order of the lots in memory. The lot id
+/// also keeps keys unique.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum LotKey {
+    /// `fifo`: sorted by date, oldest first.
+    Date(NaiveDate, u64),
+    /// `hifo`, `lt-hifo` and `max-loss`: sorted by price, highest first.
+    HighestPrice(Reverse<Decimal>, NaiveDate, u64),
+    /// `max-gain`: sorted by price, lowest first.
+    LowestPrice(Decimal, NaiveDate, u64),
+}
+
+/// Represents how the id of a lot is displayed, e.g. `LOT-000123`.
+///
+/// `prefix`: Text displayed before every id.
+/// `width`: Minimum number of digits of the id, padded with leading zeros.
+#[derive(Debug, Clone, Default)]
+pub struct IdFormat {
+    pub prefix: String,
+    pub width: usize,
+}
+
+impl IdFormat {
+    /// Displays ids as plain numbers.
+    const PLAIN: IdFormat = IdFormat { prefix: String::new(), width: 0 };
+
+    /// Returns `id` as it is displayed, e.g. `LOT-000123`.
+    pub fn format_id(&self, id: u64) -> String {
+        format!("{}{:0width$}", self.prefix, id, width = self.width)
+    }
+}
+
+/// Displays a lot with its id formatted according to an `IdFormat`. Created with `Lot::display`.
+pub struct LotDisplay<'a> {
+    lot: &'a Lot,
+    id_format: &'a IdFormat,
+}
+
+impl Display for Lot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.display(&IdFormat::PLAIN).fmt(f)
+    }
+}
+
+impl Display for LotDisplay<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let LotDisplay { lot, id_format } = self;
+        // Display price with two decimals and quantity with 8 decimals.
+        write!(
+            f,
+            "{}{:0width$},{},{:.2},{:.8}",
+            id_format.prefix, lot.id, lot.date, lot.price, lot.quantity,
+            width = id_format.width
+        )?;
+        // Tags and the memo are only displayed if there are any, so other lots keep the original format.
+        let mut tags = lot.tags.iter();
+        if let Some(first) = tags.next() {
+            write!(f, ",tags={first}")?;
+            for tag in tags {
+                write!(f, ";{tag}")?;
+            }
+        }
+        if let Some(memo) = &lot.memo {
+            write!(f, ",{memo}")?;
+        }
+        Ok(())
+    }
+}
+
+impl Lot {
+    /// Returns a `Display` of the lot with its id formatted according to `id_format`.
+    pub fn display<'a>(&'a self, id_format: &'a IdFormat) -> LotDisplay<'a> {
+        LotDisplay { lot: self, id_format }
+    }
+
+    /// Returns the id of the tax lot.
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Returns the date the tax lot was bought on.
+    pub fn date(&self) -> NaiveDate {
+        self.date
+    }
+
+    /// Returns the weighted average price of the tax lot.
+    pub fn price(&self) -> Decimal {
+        self.price
+    }
+
+    /// Returns the remaining quantity of the tax lot.
+    pub fn quantity(&self) -> Decimal {
+        self.quantity
+    }
+
+    /// Returns the tags of the buys that make up the tax lot, in sorted order.
+    pub fn tags(&self) -> impl Iterator<Item = &str> {
+        self.tags.iter().map(String::as_str)
+    }
+
+    /// Returns true if any of the buys that make up the tax lot was tagged with `tag`.
+    pub fn has_tag(&self, tag: &str) -> bool {
+        self.tags.contains(tag)
+    }
+
+    /// Returns the memos of the buys that make up the tax lot, separated by "; ".
+    pub fn memo(&self) -> Option<&str> {
+        self.memo.as_deref()
+    }
+
+    /// Returns the terms of the ESPP purchase of the tax lot, if it was bought through an employee stock purchase
+    /// plan.
+    pub fn espp(&self) -> Option<&EsppPurchase> {
+        self.espp.as_ref()
+    }
+
+    /// Returns the buys that make up the tax lot, in the order they were applied, with the quantities they
+    /// were bought with rather than what is left of them.
+    pub fn purchases(&self) -> &[Purchase] {
+        &self.purchases
+    }
+
+    /// Returns the gain or loss of selling the remaining quantity of the lot at `price` on `date`.
+    pub fn unrealized_gain(&self, price: Decimal, date: NaiveDate) -> Result<UnrealizedGain, TaxLotError> {
+        let cost_basis = checked_mul(self.price, self.quantity)?;
+        let market_value = checked_mul(price, self.quantity)?;
+        Ok(UnrealizedGain {
+            cost_basis,
+            market_value,
+            gain: checked_sub(market_value, cost_basis)?,
+            long_term: self.is_long_term(date),
+        })
+    }
+
+    /// Returns true if the lot has been held for more than one year on `date`, so that selling it
+    /// realizes a long-term gain or loss.
+    fn is_long_term(&self, date: NaiveDate) -> bool {
+        self.date
+            .checked_add_months(Months::new(12))
+            .is_some_and(|anniversary| date > anniversary)
+    }
+
+    /// Returns the key of the lot according to its selection algorithm. The key changes when a merge
+    /// changes the price of a `hifo` lot, so the lot must be re-inserted into the collection.
+    fn key(&self) -> LotKey {
+        self.key_for(self.selection_algo)
+    }
+
+    /// Returns the key the lot would have in a collection with `selection_algo`.
+    fn key_for(&self, selection_algo: SelectionAlgorithm) -> LotKey {
+        match selection_algo {
+            SelectionAlgorithm::Fifo => LotKey::Date(self.date, self.id),
+            SelectionAlgorithm::Hifo | SelectionAlgorithm::LtHifo | SelectionAlgorithm::MaxLoss => {
+                LotKey::HighestPrice(Reverse(self.price), self.date, self.id)
+            }
+            SelectionAlgorithm::MaxGain => LotKey::LowestPrice(self.price, self.date, self.id),
+        }
+    }
+
+    /// "Merge" takes a lot operation in the same merge window as the lot,
+    /// computes the aggregate quantity, and then computes the weighted average
+    /// price. The lot keeps the earlier of both dates and the tags of both, and the memo
+    /// of the lot operation is appended to its memo. If the merge fails, the lot is left unchanged.
+    fn merge(&mut self, lot_operation: LotOperation) -> Result<(), TaxLotError> {
+        // Verify that the operation is a buy operation
+        assert!(lot_operation.lot_type == LotType::Buy);
+
+        let left = checked_mul(self.price, self.quantity)?;
+        let right = checked_mul(lot_operation.price, lot_operation.quantity)?;
+
+        let quantity = checked_add(self.quantity, lot_operation.quantity)?;
+
+        let total = checked_add(left, right)?;
+        self.price = checked_div(total, quantity)?;
+        self.quantity = quantity;
+
+        self.date = self.date.min(lot_operation.date);
+        self.purchases.push(Purchase {
+            date: lot_operation.date,
+            price: lot_operation.price,
+            quantity: lot_operation.quantity,
+        });
+        self.tags.extend(lot_operation.tags);
+        self.memo = match (self.memo.take(), lot_operation.memo) {
+            (Some(memo), Some(other)) => Some(format!("{memo}; {other}")),
+            (memo, other) => memo.or(other),
+        };
+
+        Ok(())
+    }
+}
+
+/// Represents a collection of tax lots. These lots can be sold, added to, or merged with an existing lot.
+/// 
+/// A `BTreeMap` ordered by `LotKey` is used for efficient access to the "first" item, where the first item
+/// is dictated by the `selection_algorithm`. If we're using `fifo`, the `lots` are sorted by date and the
+/// first item will be the oldest tax lot. If we're using `hifo`, the `lots` are sorted by price and the first
+/// item will be the highest price tax lot.
+/// 
+/// A `date_index` maps the first date of each `merge_window` to the key of the lot bought in it, so a buy
+/// can find the lot to merge with without searching `lots`, even when they are sorted by price. With the
+/// `keep-separate` merge policy, buys never merge and the index stays empty.
+/// 
+/// If undo is enabled, the last buys and sells can be reverted.
+/// 
+/// If the journal is enabled, every operation other than corrections is kept so that later `amend` and `cancel` operations can
+/// correct them by replaying the journal.
+/// 
+/// Lots can be locked by id, in which case sells skip over them as if they were not in the collection. Sells
+/// can also be restricted to lots that have been held for more than a minimum number of days.
+/// 
+/// Buy Operation: O(1) to find lot with the same date and O(log N) to insert or re-insert it.
+/// Sell Operation: O(log N) per lot sold, plus O(1) for each locked or too recent lot that is skipped. With
+/// the algorithms that make several passes over the lots, every lot that a pass does not sell is also skipped.
+pub struct LotCollection {
+    // Keeps the lots sorted according to the `selection_algorithm`, with the tail of the order spilled to disk if
+    // `spill_lots` is set.
+    lots: LotStore,
+
+    // Maps the start of the merge window of every lot in `lots` that buys merge into to its key.
+    date_index: HashMap<NaiveDate, LotKey>,
+
+    // Determines which buys are merged into the same lot with the `weighted-average` merge policy.
+    merge_window: MergeWindow,
+
+    // Determines whether buys on the same date are merged into one lot.
+    merge_policy: MergePolicy,
+
+    // Generates ids for tax lots starting at `start_id`.
+    id_generator: AtomicU64,
+
+    // Id of the first tax lot, which the `id_generator` is reset to when the journal is replayed.
+    start_id: u64,
+
+    // Determines how ids are assigned to new tax lots.
+    id_scheme: IdScheme,
+
+    // Seed of the random ids of the `uuid` id scheme.
+    id_seed: u64,
+
+    // Ids of every lot in `lots`, so that a new lot never takes the id of another one.
+    lot_ids: HashSet<u64>,
+
+    // Determines how the tax lots are sorted in `lots`.
+    selection_algorithm: SelectionAlgorithm,
+
+    // Total gain (or loss, if negative) realized by all sell operations so far.
+    realized_gains: Decimal,
+
+    // Gains realized by the sell operations in each quarter, by year and quarter.
+    quarterly_gains: BTreeMap<(i32, u32), RealizedGains>,
+
+    // Total quantity that sell operations could not find lots to deduct from.
+    oversold: Decimal,
+
+    // Ids of the lots that sells must not deduct from.
+    locked_lots: HashSet<u64>,
+
+    // If set, sells only deduct from lots held for more than this many days.
+    min_holding_days: Option<u32>,
+
+    // If set, buys fail instead of creating a lot when this many lots are open.
+    max_open_lots: Option<usize>,
+
+    // Total dividend and interest income received in each year.
+    income: BTreeMap<(i32, IncomeType), Decimal>,
+
+    // If enabled, a record of every lot deducted from by the sells so far.
+    disposals: Option<DisposalLog>,
+
+    // If enabled, every operation applied so far, so that they can be amended or cancelled.
+    journal: Option<Journal>,
+
+    // If enabled, how to revert the last buys and sells.
+    undo_log: Option<UndoLog>,
+
+    // If set, the order sells without a sell method deduct from lots in, instead of the `selection_algorithm`.
+    sell_strategy: Option<Box<dyn SellStrategy>>,
+}
+
+impl LotCollection {
+    pub fn new(selection_algorithm: SelectionAlgorithm) -> Self {
+        LotCollection {
+            lots: LotStore::new(selection_algorithm),
+            date_index: HashMap::new(),
+            merge_policy: MergePolicy::default(),
+            merge_window: MergeWindow::default(),
+            id_generator: AtomicU64::new(INITIAL_TAX_LOT_ID),
+            start_id: INITIAL_TAX_LOT_ID,
+            id_scheme: IdScheme::default(),
+            id_seed: rand::random(),
+            lot_ids: HashSet::new(),
+            selection_algorithm,
+            realized_gains: Decimal::ZERO,
+            quarterly_gains: BTreeMap::new(),
+            oversold: Decimal::ZERO,
+            income: BTreeMap::new(),
+            locked_lots: HashSet::new(),
+            min_holding_days: None,
+            max_open_lots: None,
+            disposals: None,
+            journal: None,
+            undo_log: None,
+            sell_strategy: None,
+        }
+    }
+
+    /// Locks the lot with the given id so that sells skip it. The lot does not have to exist yet,
+    /// which allows locking lots by the id they will be assigned when the input is processed.
+    pub fn lock_lot(&mut self, id: u64) {
+        self.locked_lots.insert(id);
+    }
+
+    /// Restricts sells to lots held for more than `days` days on the date of the sell, e.g. to avoid
+    /// realizing short-term gains. A sell that can only be filled by also selling younger lots fails with
+    /// `TaxLotError::HoldingPeriodNotMet` and leaves the lots unchanged.
+    pub fn set_min_holding_days(&mut self, days: u32) {
+        self.min_holding_days = Some(days);
+    }
+
+    /// Limits the number of open lots to `max`, e.g. so that untrusted input cannot use up the memory. A buy
+    /// that would create a lot beyond it fails with `TaxLotError::LimitExceeded`, while buys that merge into an
+    /// open lot still succeed.
+    pub fn set_max_open_lots(&mut self, max: usize) {
+        self.max_open_lots = Some(max);
+    }
+
+    /// Sells lots in the order of `sell_strategy` instead of the selection algorithm, e.g. a policy loaded from a
+    /// plugin, unless a sell has its own sell method. The lots are still sorted by the selection algorithm, which
+    /// is the order the strategy is given them in.
+    pub fn set_sell_strategy(&mut self, sell_strategy: Box<dyn SellStrategy>) {
+        self.sell_strategy = Some(sell_strategy);
+    }
+
+    /// Sets whether buys on the same date are merged into one lot. This only affects buys applied after it
+    /// is set, so it should be set before applying any lot operations.
+    pub fn set_merge_policy(&mut self, merge_policy: MergePolicy) {
+        self.merge_policy = merge_policy;
+    }
+
+    /// Sets the period that buys are merged within, e.g. to keep one lot per month. The merged lot has the
+    /// date of the earliest buy in it. Like the merge policy, this should be set before applying any lot
+    /// operations.
+    pub fn set_merge_window(&mut self, merge_window: MergeWindow) {
+        self.merge_window = merge_window;
+    }
+
+    /// Sets the id of the next tax lot created with the `sequential` id scheme, e.g. to continue after
+    /// the ids of another system. Later lots are numbered up from it.
+    pub fn set_start_id(&mut self, id: u64) {
+        self.start_id = id;
+        self.id_generator.store(id, Ordering::SeqCst);
+    }
+
+    /// Sets how ids are assigned to tax lots created after it is set.
+    pub fn set_id_scheme(&mut self, id_scheme: IdScheme) {
+        self.id_scheme = id_scheme;
+    }
+
+    /// Unlocks a lot previously locked with `lock_lot`. Returns false if the lot was not locked.
+    pub fn unlock_lot(&mut self, id: u64) -> bool {
+        self.locked_lots.remove(&id)
+    }
+
+    /// Returns the total gain realized by the sell operations applied so far. Each lot that is
+    /// sold from realizes `(sell price - lot price) * quantity sold`, so a negative value is a loss.
+    pub fn realized_gains(&self) -> Decimal {
+        self.realized_gains
+    }
+
+    /// Returns the total quantity that sells could not find lots to deduct from, e.g. because more was sold than
+    /// was bought. The unsold quantity of a sell is otherwise ignored.
+    pub fn oversold(&self) -> Decimal {
+        self.oversold
+    }
+
+    /// Returns the gains realized in each year that had sells, by year. The totals of every year add up to
+    /// `realized_gains`.
+    pub fn realized_gains_by_year(&self) -> impl Iterator<Item = (i32, RealizedGains)> {
+        let mut yearly_gains = BTreeMap::<i32, RealizedGains>::new();
+        for (&(year, _), gains) in &self.quarterly_gains {
+            let year_gains = yearly_gains.entry(year).or_default();
+            year_gains.short_term += gains.short_term;
+            year_gains.long_term += gains.long_term;
+        }
+        yearly_gains.into_iter()
+    }
+
+    /// Returns the gains realized in each calendar quarter that had sells, by year and quarter (1 to 4).
+    pub fn realized_gains_by_quarter(&self) -> impl Iterator<Item = (i32, u32, RealizedGains)> + '_ {
+        self.quarterly_gains.iter().map(|(&(year, quarter), &gains)| (year, quarter, gains))
+    }
+
+    /// Returns the total income of each type received in each year, by year and type.
+    pub fn income(&self) -> impl Iterator<Item = (i32, IncomeType, Decimal)> + '_ {
+        self.income.iter().map(|(&(year, income_type), &amount)| (year, income_type, amount))
+    }
+
+    /// Returns the remaining tax lots in the order they would be sold. Spilled lots are read from their file, see
+    /// `spill_lots`.
+    pub fn lots(&self) -> impl Iterator<Item = Cow<'_, Lot>> {
+        self.lots.iter().map(|(_, lot)| lot)
+    }
+
+    /// Applies a lot operation to the lot collection. `amend` and `cancel` operations require the journal,
+    /// see `enable_journal`.
+    ///
+    /// Applying an operation is transactional: if it fails, the lot collection is left unchanged.
+    pub fn apply_lot_operation(&mut self, lot_operation: LotOperation) -> Result<(), TaxLotError> {
+        // Spilling moves lots between memory and disk without changing them, so it can fail before the operation.
+        self.lots.balance().map_err(TaxLotError::LotSpillError)?;
+        let journal_entry = match &self.journal {
+            Some(journal) if !lot_operation.lot_type.is_correction() => {
+                journal.check_txn_id(&lot_operation)?;
+                Some(lot_operation.clone())
+            }
+            _ => None,
+        };
+
+        let undo = match lot_operation.lot_type {
+            LotType::Buy => self.buy(lot_operation)?,
+            LotType::Sell => self.sell(lot_operation)?,
+            LotType::Dividend => self.receive_income(IncomeType::Dividend, lot_operation)?,
+            LotType::Interest => self.receive_income(IncomeType::Interest, lot_operation)?,
+            LotType::Drip => self.buy_with_income(IncomeType::Dividend, lot_operation)?,
+            LotType::Vest => self.buy_with_income(IncomeType::Compensation, lot_operation)?,
+            LotType::Espp => self.buy(lot_operation)?,
+            LotType::Amend | LotType::Cancel => return self.correct(lot_operation),
+        };
+
+        if let (Some(undo_log), Some(undo)) = (&mut self.undo_log, undo) {
+            undo_log.push(undo);
+        }
+        if let (Some(journal), Some(journal_entry)) = (&mut self.journal, journal_entry) {
+            journal.push(journal_entry);
+        }
+        Ok(())
+    }
+
+    /// Gets the key of the lot that a buy on `date` merges into from the lot collection, according
+    /// to the `merge_window`. The `date_index` is used so that we don't have to search the lots when
+    /// they are sorted by price.
+    fn get_lot(&self, date: NaiveDate) -> Option<LotKey> {
+        match self.merge_policy {
+            MergePolicy::WeightedAverage => self.date_index.get(&self.merge_window.start(date)).copied(),
+            MergePolicy::KeepSeparate => None,
+        }
+    }
+
+    /// Adds a lot to `lots` and its id to `lot_ids`, and, if buys can merge into it, to the `date_index`. Buys never
+    /// merge into an ESPP lot, so that it keeps the terms of its purchase.
+    fn insert_lot(&mut self, lot: Lot) {
+        if self.merge_policy == MergePolicy::WeightedAverage && lot.espp.is_none() {
+            self.date_index.insert(self.merge_window.start(lot.date), lot.key());
+        }
+        self.lot_ids.insert(lot.id);
+        self.lots.insert(lot.key(), lot);
+    }
+
+    /// Removes the lot with `key` from `lots`, `lot_ids` and the `date_index`.
+    fn remove_lot(&mut self, key: &LotKey) -> Option<Lot> {
+        let lot = self.lots.remove(key)?;
+        self.lot_ids.remove(&lot.id);
+        let window_start = self.merge_window.start(lot.date);
+        if self.date_index.get(&window_start) == Some(key) {
+            self.date_index.remove(&window_start);
+        }
+        Some(lot)
+    }
+
+    /// Adds the income of a dividend or interest operation, price * quantity, to the total of its year. No
+    /// lots are created or sold. Returns how to revert it if undo is enabled.
+    fn receive_income(
+        &mut self,
+        income_type: IncomeType,
+        lot_operation: LotOperation,
+    ) -> Result<Option<Undo>, TaxLotError> {
+        let (key, amount, total) = self.income_after(income_type, &lot_operation)?;
+        self.income.insert(key, total);
+        Ok(self.undo_log.is_some().then_some(Undo::Income { key, amount }))
+    }
+
+    /// Records the income of a drip or vest operation and buys its quantity at its price, as if an income
+    /// operation and a buy had been applied. Returns how to revert both if undo is enabled.
+    fn buy_with_income(
+        &mut self,
+        income_type: IncomeType,
+        lot_operation: LotOperation,
+    ) -> Result<Option<Undo>, TaxLotError> {
+        // The income is computed before buying, so that neither is applied if either fails.
+        let (key, amount, total) = self.income_after(income_type, &lot_operation)?;
+        let bought = self.buy(LotOperation {
+            lot_type: LotType::Buy,
+            ..lot_operation
+        })?;
+        self.income.insert(key, total);
+        Ok(bought.map(|bought| Undo::BoughtWithIncome {
+            bought: Box::new(bought),
+            key,
+            amount,
+        }))
+    }
+
+    /// Returns the key of the income total that `lot_operation` adds to, the amount it adds and the new total.
+    fn income_after(
+        &self,
+        income_type: IncomeType,
+        lot_operation: &LotOperation,
+    ) -> Result<((i32, IncomeType), Decimal, Decimal), TaxLotError> {
+        let amount = checked_mul(lot_operation.price, lot_operation.quantity)?;
+        let key = (lot_operation.date.year(), income_type);
+        let total = checked_add(self.income.get(&key).copied().unwrap_or_default(), amount)?;
+        Ok((key, amount, total))
+    }
+
+    /// Buy creates a new tax lot if there is no tax lot in the merge window of the `lot_operation` date.
+    /// Buy merges `lot_operation` with an existing lot if the `lot_collection` already
+    /// has a `lot` in that window, unless the merge policy is `keep-separate`. An ESPP purchase always creates a
+    /// new tax lot. Returns how to revert the buy if undo is enabled.
+    fn buy(&mut self, lot_operation: LotOperation) -> Result<Option<Undo>, TaxLotError> {
+        let record_undo = self.undo_log.is_some();
+        let next_id = self.id_generator.load(Ordering::SeqCst);
+        let merge_into = self.get_lot(lot_operation.date).filter(|_| lot_operation.espp.is_none());
+        let (lot, undo) = match merge_into.and_then(|key| self.lots.remove(&key)) {
+            Some(mut existing_lot) => {
+                // merge with an existing lot since the `lot_collection` already has a lot
+                // for this date. The lot is re-inserted below since merging can change its price.
+                let previous = record_undo.then(|| existing_lot.clone());
+                if let Err(e) = existing_lot.merge(lot_operation) {
+                    self.lots.insert(existing_lot.key(), existing_lot);
+                    return Err(e);
+                }
+                let undo = previous.map(|previous| Undo::Merged { key: existing_lot.key(), previous });
+                (existing_lot, undo)
+            }
+            None => {
+                // create a new lot since `lot_collection` does not have a lot for this date.
+                if let Some(max) = self.max_open_lots.filter(|&max| self.lots.len() >= max) {
+                    let message = format!("buying on {} would open more than {max} lots", lot_operation.date);
+                    return Err(TaxLotError::LimitExceeded(message));
+                }
+                let id = match self.id_scheme {
+                    IdScheme::Sequential => self.id_generator.fetch_add(1, Ordering::SeqCst),
+                    IdScheme::Uuid => random_id(self.id_seed, self.id_generator.fetch_add(1, Ordering::SeqCst)),
+                    IdScheme::Hash => lot_operation.content_hash(),
+                };
+                let mut lot = lot_operation.create_new_lot(id, self.selection_algorithm);
+                // Identical buys that are kept as separate lots hash to the same id, and a sequential id can be
+                // taken by a hash id or a restored lot, so the lot takes the next free id. The largest id wraps
+                // around to 0 to stay within `HASH_ID_MASK`.
+                while self.lot_ids.contains(&lot.id) {
+                    lot.id = lot.id.wrapping_add(1) & HASH_ID_MASK;
+                }
+                let undo = record_undo.then(|| Undo::Created { key: lot.key(), next_id });
+                (lot, undo)
+            }
+        };
+        self.insert_lot(lot);
+
+        Ok(undo)
+    }
+
+    /// Returns the lots for which `predicate` is true, in the order of `lots`.
+    fn lots_where<'a>(
+        &'a self,
+        predicate: impl Fn(&Lot) -> bool + 'a,
+    ) -> impl Iterator<Item = (LotKey, Cow<'a, Lot>)> + 'a {
+        self.lots.iter().filter(move |(_, lot)| predicate(lot))
+    }
+
+    /// Returns every lot in the order that `lot_operation` deducts from them, according to its sell method or the
+    /// `selection_algorithm`. For `fifo` and `hifo` this is the order of `lots`. The other algorithms depend on
+    /// how long each lot has been held on the date of the sell, or on its gain at the sell price, so they make
+    /// several passes over `lots`, see `SelectionAlgorithm::sell_pass`. A sell with an algorithm that sorts the
+    /// lots differently than the `selection_algorithm` sorts a copy of them instead. Without a sell method, the
+    /// `sell_strategy` orders the lots if it is set, see `strategy_order`.
+    fn sell_order<'a>(&'a self, lot_operation: &LotOperation) -> Result<SellOrder<'a>, TaxLotError> {
+        let date = lot_operation.date;
+        let price = lot_operation.price;
+        let algorithm = match (lot_operation.method, &self.sell_strategy) {
+            (Some(SellMethod::Lot(id)), _) => return Ok(Box::new(self.lots_where(move |lot| lot.id == id))),
+            (Some(SellMethod::Algorithm(algorithm)), _) => algorithm,
+            (None, Some(sell_strategy)) => {
+                return Ok(Box::new(self.strategy_order(sell_strategy.as_ref(), lot_operation)?.into_iter()))
+            }
+            (None, None) => self.selection_algorithm,
+        };
+        if algorithm.sorts_like(self.selection_algorithm) {
+            Ok(Box::new((0..algorithm.passes()).flat_map(move |pass| {
+                self.lots_where(move |lot| algorithm.sell_pass(lot, date, price) == pass)
+            })))
+        } else {
+            let mut lots: Vec<_> = self.lots.iter().collect();
+            lots.sort_by_cached_key(|(_, lot)| (algorithm.sell_pass(lot, date, price), lot.key_for(algorithm)));
+            Ok(Box::new(lots.into_iter()))
+        }
+    }
+
+    /// Returns the unlocked lots in the order that `sell_strategy` sells them in for `lot_operation`. Fails if the
+    /// strategy fails, or returns an id that is not one of the lots or is returned more than once.
+    fn strategy_order<'a>(
+        &'a self,
+        sell_strategy: &dyn SellStrategy,
+        lot_operation: &LotOperation,
+    ) -> Result<Vec<(LotKey, Cow<'a, Lot>)>, TaxLotError> {
+        let unlocked: Vec<_> = self.lots_where(|lot| !self.locked_lots.contains(&lot.id)).collect();
+        let lots: Vec<&Lot> = unlocked.iter().map(|(_, lot)| lot.as_ref()).collect();
+        let order = sell_strategy.sell_order(lot_operation, &lots)?;
+        let mut unlocked: HashMap<u64, (LotKey, Cow<Lot>)> =
+            unlocked.into_iter().map(|(key, lot)| (lot.id, (key, lot))).collect();
+        let mut sold = HashSet::new();
+        order
+            .into_iter()
+            .map(|id| match (unlocked.remove(&id), sold.insert(id)) {
+                (Some(lot), _) => Ok(lot),
+                (None, false) => Err(TaxLotError::StrategyError(format!("lot {id} is sold from more than once"))),
+                (None, true) => {
+                    Err(TaxLotError::StrategyError(format!("lot {id} is not one of the lots to sell from")))
+                }
+            })
+            .collect()
+    }
+
+    /// Returns true if `lot` has not been held for more than `min_holding_days` on `date`.
+    fn is_held_too_short(&self, lot: &Lot, date: NaiveDate) -> bool {
+        self.min_holding_days
+            .is_some_and(|days| (date - lot.date).num_days() <= i64::from(days))
+    }
+
+    /// Returns the keys of the lots that a sell deducts from, in the order they are sold, with the quantity
+    /// deducted from each of them. Locked lots and lots that have not been held for long enough are skipped.
+    /// If there are not enough shares, every lot that can be sold is returned, unless shares are left in lots
+    /// that were skipped for being too recent. Also returns the realized gains, and those of the quarter of the
+    /// sell, after the sell, the quantity left unsold, the disposals of the sell if they are recorded, and the
+    /// compensation income of the ESPP shares it sells in a disqualifying disposition. Their cost basis is their
+    /// fair market value at purchase, since their discount is taxed as compensation.
+    fn lots_to_sell(&self, lot_operation: &LotOperation) -> Result<SellPlan, TaxLotError> {
+        let mut deductions = Vec::new();
+        let mut total = Decimal::ZERO;
+        let mut realized_gains = self.realized_gains;
+        let quarter = (lot_operation.date.year(), lot_operation.date.month0() / 3 + 1);
+        let mut quarter_gains = self.quarterly_gains.get(&quarter).copied().unwrap_or_default();
+        let mut skipped_too_recent = false;
+        let mut disposals = Vec::new();
+        let mut compensation = Decimal::ZERO;
+
+        let unlocked_lots = self
+            .sell_order(lot_operation)?
+            .filter(|(_, lot)| !self.locked_lots.contains(&lot.id));
+        for (key, lot) in unlocked_lots {
+            if total >= lot_operation.quantity {
+                break;
+            }
+            if self.is_held_too_short(&lot, lot_operation.date) {
+                skipped_too_recent = true;
+                continue;
+            }
+            let deducted = checked_sub(lot_operation.quantity, total)?.min(lot.quantity);
+            let basis = match lot.espp {
+                Some(espp) if espp.is_disqualifying(lot.date, lot_operation.date) => {
+                    compensation = checked_add(compensation, checked_mul(espp.discount, deducted)?)?;
+                    espp.purchase_fmv
+                }
+                _ => lot.price,
+            };
+            let gain = checked_mul(checked_sub(lot_operation.price, basis)?, deducted)?;
+            realized_gains = checked_add(realized_gains, gain)?;
+            if lot.is_long_term(lot_operation.date) {
+                quarter_gains.long_term = checked_add(quarter_gains.long_term, gain)?;
+            } else {
+                quarter_gains.short_term = checked_add(quarter_gains.short_term, gain)?;
+            }
+            deductions.push((key, deducted));
+            if self.disposals.is_some() {
+                disposals.push(Disposal {
+                    lot_id: lot.id,
+                    acquired: lot.date,
+                    sold: lot_operation.date,
+                    quantity: deducted,
+                    cost_basis: checked_mul(basis, deducted)?,
+                    proceeds: checked_mul(lot_operation.price, deducted)?,
+                    gain,
+                    long_term: lot.is_long_term(lot_operation.date),
+                    txn_id: lot_operation.txn_id.clone(),
+                });
+            }
+            total = checked_add(total, lot.quantity)?;
+        }
+
+        if let (true, Some(days)) = (skipped_too_recent && total < lot_operation.quantity, self.min_holding_days) {
+            return Err(TaxLotError::HoldingPeriodNotMet(lot_operation.quantity, lot_operation.date, days));
+        }
+        if let (true, Some(SellMethod::Lot(id))) = (total < lot_operation.quantity, lot_operation.method) {
+            return Err(TaxLotError::SpecificLotUnavailable(lot_operation.quantity, id, lot_operation.date));
+        }
+
+        Ok(SellPlan {
+            deductions,
+            unfilled: checked_sub(lot_operation.quantity, total.min(lot_operation.quantity))?,
+            realized_gains,
+            quarter,
+            quarter_gains,
+            disposals,
+            compensation,
+        })
+    }
+
+    /// Sell deducts "shares" from tax lots according to the `selection_algorithm`. Since `lots` is sorted
+    /// according to the `selection_algorithm`, this just needs to take the first unlocked tax lots in `sell_order` and
+    /// deduct shares from each lot until there are no more tax lots or we have sold the number of shares specified.
+    ///
+    /// The deductions and realized gains are computed before any lot is changed, so a sell that fails leaves
+    /// the lots unchanged. Returns how to revert the sell if undo is enabled.
+    fn sell(&mut self, lot_operation: LotOperation) -> Result<Option<Undo>, TaxLotError> {
+        let record_undo = self.undo_log.is_some();
+
+        // If we run out of lots to sell, the remaining quantity is only added to the oversold quantity.
+        let SellPlan {
+            deductions,
+            unfilled,
+            realized_gains,
+            quarter,
+            quarter_gains,
+            disposals,
+            compensation,
+        } = self.lots_to_sell(&lot_operation)?;
+        let income_key = (lot_operation.date.year(), IncomeType::Compensation);
+        let income_total = match compensation.is_zero() {
+            true => None,
+            false => Some(checked_add(self.income.get(&income_key).copied().unwrap_or_default(), compensation)?),
+        };
+        let oversold = checked_add(self.oversold, unfilled)?;
+
+        // The disposals are recorded first, since spilling them can fail.
+        let undo_disposals = self.disposals.as_ref().map_or(0, DisposalLog::len);
+        if let Some(recorded) = &mut self.disposals {
+            recorded.extend(disposals)?;
+        }
+        if let Some(total) = income_total {
+            self.income.insert(income_key, total);
+        }
+        let undo_gains = std::mem::replace(&mut self.realized_gains, realized_gains);
+        let undo_oversold = std::mem::replace(&mut self.oversold, oversold);
+        let undo_quarter_gains = if deductions.is_empty() {
+            // A sell with no lots to deduct from does not realize anything, so no quarter is added for it.
+            self.quarterly_gains.get(&quarter).copied()
+        } else {
+            self.quarterly_gains.insert(quarter, quarter_gains)
+        };
+        let mut deducted_lots = Vec::new();
+        let mut removed_lots = Vec::new();
+        for (key, deducted) in deductions {
+            let Some(lot) = self.lots.get_mut(&key) else {
+                continue;
+            };
+            if deducted < lot.quantity {
+                // The deducted quantity is less than the lot's quantity, so this cannot underflow.
+                lot.quantity -= deducted;
+                if record_undo {
+                    deducted_lots.push((key, deducted));
+                }
+            } else if let Some(lot) = self.remove_lot(&key) {
+                if record_undo {
+                    removed_lots.push(lot);
+                }
+            }
+        }
+
+        Ok(record_undo.then_some(Undo::Sold {
+            realized_gains: undo_gains,
+            oversold: undo_oversold,
+            quarter,
+            quarter_gains: undo_quarter_gains,
+            deducted: deducted_lots,
+            removed: removed_lots,
+            disposals: undo_disposals,
+            compensation: income_total.map(|_| (income_key, compensation)),
+        }))
+    }
+}
+
+/// Helpers shared by the tests of every module.
+#[cfg(test)]
+mod test_helpers {
+    use std::str::FromStr;
+
+    use crate::{LotCollection, LotOperation, TaxLotError};
+
+    /// Applies each line as a lot operation, stopping at the first error.
+    pub(crate) fn apply(lot_collection: &mut LotCollection, lines: &[&str]) -> Result<(), TaxLotError> {
+        for line in lines {
+            lot_collection.apply_lot_operation(LotOperation::from_str(line)?)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the displayed lots of the collection in sell order.
+    pub(crate) fn lots(lot_collection: &LotCollection) -> Vec<String> {
+        lot_collection.lots().map(|lot| lot.to_string()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeSet, str::FromStr};
+
+    use chrono::NaiveDate;
+    use rust_decimal::{prelude::FromPrimitive, Decimal};
+
+    use crate::{test_helpers::apply, IdFormat, IdScheme, HASH_ID_MASK, IncomeType, InputDialect, LotCollection, LotOperation, LotType, MergePolicy, MergeWindow, Purchase, RealizedGains, SelectionAlgorithm, TaxLotError, Lot, UnrealizedGain};
+
+    fn get_by_date(lot_collection: &LotCollection, date: &str) -> Result<Lot, TaxLotError> {
+        let naive_date = NaiveDate::from_str(date)?;
+        Ok(lot_collection
+            .lots()
+            .find(|lot| lot.date == naive_date)
+            .expect("No date found")
+            .into_owned())
+    }
+
+    #[test]
+    fn test_parse_lot_operation() -> Result<(), TaxLotError> {
+        LotOperation::from_str("2021-01-01,Buy,10000.00,1.00000000").expect("Failed to parse valid lot operation");
+        LotOperation::from_str("2021-01-01,sell,10000.00,1.00000000").expect("Failed to parse valid lot operation");
+        LotOperation::from_str("2021-01-01,sell,1,4").expect("Failed to parse valid lot operation");
+
+        // invalid date
+        LotOperation::from_str("2021-13-01,buy,10000.00,1.00000000").expect_err("Successfully parsed an invalid date");
+
+        // invalid lot type
+        LotOperation::from_str("2021-01-01,invalid,10000.00,1.00000000").expect_err("Successfully parsed an invalid lot type");
+
+        // invalid price
+        LotOperation::from_str("2021-01-01,buy,-10000.00,1.00000000").expect_err("Successfully parsed an invalid price");
+        LotOperation::from_str("2021-01-01,buy,0.0,1.00000000").expect_err("Successfully parsed an invalid price");
+        LotOperation::from_str("2021-01-01,buy,invalid,1.00000000").expect_err("Successfully parsed an invalid price");
+
+        // invalid quantity
+        LotOperation::from_str("2021-01-01,buy,10000.00,-1.00000000").expect_err("Successfully parsed an invalid quantity");
+        LotOperation::from_str("2021-01-01,buy,10000.00,invalid").expect_err("Successfully parsed an invalid quantity");
+        LotOperation::from_str("2021-01-01,buy,10000.00,0").expect_err("Successfully parsed an invalid quantity");
+
+        // no quantity
+        LotOperation::from_str("2021-01-01,buy,10000.00").expect_err("Successfully parsed lot opration with no quantity");
+
+        // no price
+        LotOperation::from_str("2021-01-01,buy").expect_err("Successfully parsed lot operation with no price");
+
+        // memo
+        let lot_operation = LotOperation::from_str("2021-01-01,buy,10000.00,1.00000000, rebalance, quarterly ")?;
+        assert_eq!(lot_operation.memo.as_deref(), Some("rebalance, quarterly"));
+        let lot_operation = LotOperation::from_str("2021-01-01,buy,10000.00,1.00000000,")?;
+        assert_eq!(lot_operation.memo, None);
+
+        // tags
+        let lot_operation = LotOperation::from_str("2021-01-01,buy,10000.00,1.00000000,tags=ira; robo,rebalance")?;
+        assert_eq!(lot_operation.tags, BTreeSet::from(["ira".to_string(), "robo".to_string()]));
+        assert_eq!(lot_operation.memo.as_deref(), Some("rebalance"));
+        let lot_operation = LotOperation::from_str("2021-01-01,buy,10000.00,1.00000000,tags=ira")?;
+        assert_eq!(lot_operation.tags, BTreeSet::from(["ira".to_string()]));
+        assert_eq!(lot_operation.memo, None);
+
+        // transaction id
+        let lot_operation = LotOperation::from_str("2021-01-01,buy,10000.00,1.00000000,txn=42,tags=ira,dca")?;
+        assert_eq!(lot_operation.txn_id.as_deref(), Some("42"));
+        assert_eq!(lot_operation.tags, BTreeSet::from(["ira".to_string()]));
+        assert_eq!(lot_operation.memo.as_deref(), Some("dca"));
+
+        // cancel
+        let lot_operation = LotOperation::from_str("2021-01-01,cancel,,,txn=42")?;
+        assert_eq!(lot_operation.txn_id.as_deref(), Some("42"));
+        LotOperation::from_str("2021-01-01,amend,,,txn=42").expect_err("Successfully parsed an amend without a price");
+
+        // no type
+        LotOperation::from_str("2021-01-01").expect_err("Successfully parsed lot operation with no lot type");
+
+        // no date
+        LotOperation::from_str("").expect_err("Successfully parsed lot operation with no date");
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_signed_lot_operation() -> Result<(), TaxLotError> {
+        let lot_operation = InputDialect::Signed.parse("2021-01-01,10000.00,-0.5")?;
+        assert_eq!(lot_operation.lot_type, LotType::Sell);
+        assert_eq!(lot_operation.quantity, Decimal::from_f64(0.5).expect("Failed to parse quantity"));
+
+        let lot_operation = InputDialect::Signed.parse("2021-01-01,10000.00,+1.5,tags=ira,dca")?;
+        assert_eq!(lot_operation.lot_type, LotType::Buy);
+        assert_eq!(lot_operation.quantity, Decimal::from_f64(1.5).expect("Failed to parse quantity"));
+        assert_eq!(lot_operation.tags, BTreeSet::from(["ira".to_string()]));
+        assert_eq!(lot_operation.memo.as_deref(), Some("dca"));
+
+        InputDialect::Signed.parse("2021-01-01,10000.00,0").expect_err("Successfully parsed a zero quantity");
+        InputDialect::Signed.parse("2021-01-01,10000.00,--1").expect_err("Successfully parsed a double negative quantity");
+        InputDialect::Signed.parse("2021-01-01,buy,10000.00,1").expect_err("Successfully parsed a standard line");
+        Ok(())
+    }
+
+    #[test]
+    fn test_lot_displays_proper_formatting() -> Result<(), TaxLotError> {
+        let lot = Lot {
+            date: NaiveDate::from_str("2021-01-01")?,
+            id: 1,
+            price: Decimal::from_f64(10000.0).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.0).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            memo: None,
+            espp: None,
+            selection_algo: SelectionAlgorithm::Fifo,
+            purchases: Vec::new(),
+        };
+
+        let lot_string = lot.to_string();
+
+        assert_eq!(lot_string, "1,2021-01-01,10000.00,1.00000000");
+
+        let id_format = IdFormat { prefix: "LOT-".to_string(), width: 6 };
+        assert_eq!(lot.display(&id_format).to_string(), "LOT-000001,2021-01-01,10000.00,1.00000000");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_uses_weighted_average() -> Result<(), TaxLotError> {
+        let mut lot = Lot {
+            date: NaiveDate::from_str("2021-01-01")?,
+            id: 1,
+            price: Decimal::from_f64(10000.0).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.0).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            memo: None,
+            espp: None,
+            selection_algo: SelectionAlgorithm::Fifo,
+            purchases: Vec::new(),
+        };
+
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-01-01")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(20000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(3.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+
+        lot.merge(lot_operation)?;
+
+        assert_eq!(lot.id, 1);
+        assert_eq!(lot.price, Decimal::from_f64(17500.0).expect("Failed to parse price"));
+        assert_eq!(lot.quantity, Decimal::from_f64(4.0).expect("Failed to parse quantity"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_buy_creates_new_lot() -> Result<(), TaxLotError> {
+        let selection_algo = SelectionAlgorithm::Fifo;
+        let mut lot_collection = LotCollection::new(selection_algo);
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-01-01")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(10000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-01-02")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(20000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(2.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        assert_eq!(lot_collection.lots.len(), 2);
+        let lot1 = get_by_date(&lot_collection, "2021-01-01")?;
+        let lot2 = get_by_date(&lot_collection, "2021-01-02")?;
+        assert_eq!(lot1.id, 1);
+        assert_eq!(lot2.id, 2);
+        assert_eq!(lot1.price, Decimal::from_f64(10000.00).expect("Failed to parse price"));
+        assert_eq!(lot2.price, Decimal::from_f64(20000.00).expect("Failed to parse price"));
+        assert_eq!(lot1.quantity, Decimal::from_f64(1.00000000).expect("Failed to parse quantity"));
+        assert_eq!(lot2.quantity, Decimal::from_f64(2.00000000).expect("Failed to parse quantity"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_buy_merges_with_existing_lot() -> Result<(), TaxLotError> {
+        let selection_algo = SelectionAlgorithm::Fifo;
+        let mut lot_collection = LotCollection::new(selection_algo);
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-01-01")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(10000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-01-01")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(20000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(3.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        assert_eq!(lot_collection.lots.len(), 1);
+        let lot1 = get_by_date(&lot_collection, "2021-01-01")?;
+        assert_eq!(lot1.id, 1);
+        assert_eq!(lot1.price, Decimal::from_f64(17500.00).expect("Failed to parse price"));
+        assert_eq!(lot1.quantity, Decimal::from_f64(4.00000000).expect("Failed to parse quantity"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sell_deducts_only_lot() -> Result<(), TaxLotError> {
+        let selection_algo = SelectionAlgorithm::Fifo;
+        let mut lot_collection = LotCollection::new(selection_algo);
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-01-01")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(10000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-02-01")?,
+            lot_type: crate::LotType::Sell,
+            price: Decimal::from_f64(5000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(0.50000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.sell(lot_operation)?;
+
+        assert_eq!(lot_collection.lots.len(), 1);
+        let lot1 = get_by_date(&lot_collection, "2021-01-01")?;
+        assert_eq!(lot1.price, Decimal::from_f64(10000.00).expect("Failed to parse price"));
+        assert_eq!(lot1.quantity, Decimal::from_f64(0.50000000).expect("Failed to parse quantity"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sell_deducts_from_multiple_lots_fifo() -> Result<(), TaxLotError> {
+        let selection_algo = SelectionAlgorithm::Fifo;
+        let mut lot_collection = LotCollection::new(selection_algo);
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-01-01")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(10000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-01-02")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(20000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(3.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-01-03")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(15000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(10.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-02-01")?,
+            lot_type: crate::LotType::Sell,
+            price: Decimal::from_f64(5000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(7.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.sell(lot_operation)?;
+
+        assert_eq!(lot_collection.lots.len(), 1);
+        let lot1 = get_by_date(&lot_collection, "2021-01-03")?;
+        assert_eq!(lot1.price, Decimal::from_f64(15000.00).expect("Failed to parse price"));
+        assert_eq!(lot1.quantity, Decimal::from_f64(7.00000000).expect("Failed to parse quantity"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sell_deducts_from_multiple_lots_hifo() -> Result<(), TaxLotError> {
+        let selection_algo = SelectionAlgorithm::Hifo;
+        let mut lot_collection = LotCollection::new(selection_algo);
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-01-01")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(10000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-01-02")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(20000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(3.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-01-03")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(15000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(10.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-02-01")?,
+            lot_type: crate::LotType::Sell,
+            price: Decimal::from_f64(5000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(7.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.sell(lot_operation)?;
+
+        assert_eq!(lot_collection.lots.len(), 2);
+
+        let lot1 = get_by_date(&lot_collection, "2021-01-03")?;
+        assert_eq!(lot1.price, Decimal::from_f64(15000.00).expect("Failed to parse price"));
+        assert_eq!(lot1.quantity, Decimal::from_f64(6.00000000).expect("Failed to parse quantity"));
+
+        let lot2 = get_by_date(&lot_collection, "2021-01-01")?;
+        assert_eq!(lot2.price, Decimal::from_f64(10000.00).expect("Failed to parse price"));
+        assert_eq!(lot2.quantity, Decimal::from_f64(1.00000000).expect("Failed to parse quantity"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sell_runs_out_of_lots() -> Result<(), TaxLotError> {
+        let selection_algo = SelectionAlgorithm::Hifo;
+        let mut lot_collection = LotCollection::new(selection_algo);
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-01-01")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(10000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-01-02")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(20000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(3.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-01-03")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(15000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(10.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-02-01")?,
+            lot_type: crate::LotType::Sell,
+            price: Decimal::from_f64(5000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(15.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.sell(lot_operation)?;
+
+        // We have sold all of the lots, because the sell specified 15 shares and we have only bought (1+3+10)=14 shares
+        assert_eq!(lot_collection.lots.len(), 0);
+        assert_eq!(lot_collection.oversold(), Decimal::ONE);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sell_with_no_lots() -> Result<(), TaxLotError> {
+        let selection_algo = SelectionAlgorithm::Hifo;
+        let mut lot_collection = LotCollection::new(selection_algo);
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-02-01")?,
+            lot_type: crate::LotType::Sell,
+            price: Decimal::from_f64(5000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(15.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+
+        // The sell operation does not fail if there's no tax lots to sell, it will return success without changing the lot collection
+        lot_collection.sell(lot_operation)?;
+        assert_eq!(lot_collection.lots.len(), 0);
+        assert_eq!(lot_collection.oversold(), Decimal::from(15));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sell_accumulates_realized_gains() -> Result<(), TaxLotError> {
+        let selection_algo = SelectionAlgorithm::Fifo;
+        let mut lot_collection = LotCollection::new(selection_algo);
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-01-01")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(10000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-01-02")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(20000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-02-01")?,
+            lot_type: crate::LotType::Sell,
+            price: Decimal::from_f64(15000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.50000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.sell(lot_operation)?;
+
+        // The first lot realizes a 5000 gain on 1 share and the second a 5000 loss on half a share.
+        assert_eq!(lot_collection.realized_gains(), Decimal::from_f64(2500.00).expect("Failed to parse gain"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_hifo_merge_reorders_lots() -> Result<(), TaxLotError> {
+        let selection_algo = SelectionAlgorithm::Hifo;
+        let mut lot_collection = LotCollection::new(selection_algo);
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-01-01")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(10000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-01-02")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(15000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-01-03")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(15000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        // Merging raises the price of the first lot to 20000, so it must now be sold first.
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-01-01")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(30000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        // Lots with equal prices are both kept.
+        let ids: Vec<u64> = lot_collection.lots().map(|lot| lot.id).collect();
+        assert_eq!(ids, vec![1, 2, 3]);
+        let lot1 = get_by_date(&lot_collection, "2021-01-01")?;
+        assert_eq!(lot1.price, Decimal::from_f64(20000.00).expect("Failed to parse price"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_fifo_merges_out_of_order_buys() -> Result<(), TaxLotError> {
+        let selection_algo = SelectionAlgorithm::Fifo;
+        let mut lot_collection = LotCollection::new(selection_algo);
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-01-02")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(10000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-01-01")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(15000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        // This buy is not for the most recent date, but must still merge with the first lot.
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-01-02")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(20000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        assert_eq!(lot_collection.lots.len(), 2);
+        let ids: Vec<u64> = lot_collection.lots().map(|lot| lot.id).collect();
+        assert_eq!(ids, vec![2, 1]);
+        let lot1 = get_by_date(&lot_collection, "2021-01-02")?;
+        assert_eq!(lot1.price, Decimal::from_f64(15000.00).expect("Failed to parse price"));
+        assert_eq!(lot1.quantity, Decimal::from_f64(2.00000000).expect("Failed to parse quantity"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_buy_after_lot_sold_creates_new_lot() -> Result<(), TaxLotError> {
+        let selection_algo = SelectionAlgorithm::Hifo;
+        let mut lot_collection = LotCollection::new(selection_algo);
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-01-01")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(10000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-01-01")?,
+            lot_type: crate::LotType::Sell,
+            price: Decimal::from_f64(12000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.sell(lot_operation)?;
+        assert!(lot_collection.date_index.is_empty());
+
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-01-01")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(11000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(2.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        assert_eq!(lot_collection.lots.len(), 1);
+        let lot1 = get_by_date(&lot_collection, "2021-01-01")?;
+        assert_eq!(lot1.id, 2);
+        assert_eq!(lot1.price, Decimal::from_f64(11000.00).expect("Failed to parse price"));
+        assert_eq!(lot1.quantity, Decimal::from_f64(2.00000000).expect("Failed to parse quantity"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sell_skips_locked_lots() -> Result<(), TaxLotError> {
+        let selection_algo = SelectionAlgorithm::Fifo;
+        let mut lot_collection = LotCollection::new(selection_algo);
+        lot_collection.lock_lot(1);
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-01-01")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(10000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-01-02")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(20000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-02-01")?,
+            lot_type: crate::LotType::Sell,
+            price: Decimal::from_f64(15000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.50000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.sell(lot_operation)?;
+
+        // The locked first lot is untouched, and the sell runs out of unlocked lots after the second one.
+        assert_eq!(lot_collection.lots.len(), 1);
+        let lot1 = get_by_date(&lot_collection, "2021-01-01")?;
+        assert_eq!(lot1.quantity, Decimal::from_f64(1.00000000).expect("Failed to parse quantity"));
+
+        // Once unlocked, the first lot can be sold again.
+        assert!(lot_collection.unlock_lot(1));
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-02-02")?,
+            lot_type: crate::LotType::Sell,
+            price: Decimal::from_f64(15000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(0.50000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.sell(lot_operation)?;
+        let lot1 = get_by_date(&lot_collection, "2021-01-01")?;
+        assert_eq!(lot1.quantity, Decimal::from_f64(0.50000000).expect("Failed to parse quantity"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sell_respects_min_holding_days() -> Result<(), TaxLotError> {
+        let selection_algo = SelectionAlgorithm::Hifo;
+        let mut lot_collection = LotCollection::new(selection_algo);
+        lot_collection.set_min_holding_days(30);
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-01-01")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(10000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-01-20")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(20000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        // The highest price lot has only been held for 12 days, so the older lot is sold instead.
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-02-01")?,
+            lot_type: crate::LotType::Sell,
+            price: Decimal::from_f64(15000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(0.50000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.sell(lot_operation)?;
+        let lot1 = get_by_date(&lot_collection, "2021-01-01")?;
+        assert_eq!(lot1.quantity, Decimal::from_f64(0.50000000).expect("Failed to parse quantity"));
+
+        // Selling more than the older lot holds fails without changing any lot.
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-02-01")?,
+            lot_type: crate::LotType::Sell,
+            price: Decimal::from_f64(15000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.sell(lot_operation).expect_err("Sold a lot that was not held long enough");
+        let lot1 = get_by_date(&lot_collection, "2021-01-01")?;
+        assert_eq!(lot1.quantity, Decimal::from_f64(0.50000000).expect("Failed to parse quantity"));
+        let lot2 = get_by_date(&lot_collection, "2021-01-20")?;
+        assert_eq!(lot2.quantity, Decimal::from_f64(1.00000000).expect("Failed to parse quantity"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_lt_hifo_sells_long_term_lots_first() -> Result<(), TaxLotError> {
+        let selection_algo = SelectionAlgorithm::LtHifo;
+        let mut lot_collection = LotCollection::new(selection_algo);
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2020-01-01")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(10000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2020-02-01")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(15000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-01-01")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(20000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        // Both 2020 lots are long-term, so the highest price of them is sold before the short-term lot with
+        // an even higher price, and the remaining shares come from the next long-term lot.
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-03-01")?,
+            lot_type: crate::LotType::Sell,
+            price: Decimal::from_f64(25000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.50000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.sell(lot_operation)?;
+        assert_eq!(lot_collection.lots.len(), 2);
+        let lot1 = get_by_date(&lot_collection, "2020-01-01")?;
+        assert_eq!(lot1.quantity, Decimal::from_f64(0.50000000).expect("Failed to parse quantity"));
+
+        // Once the long-term lots are exhausted, the short-term lot is sold.
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-03-01")?,
+            lot_type: crate::LotType::Sell,
+            price: Decimal::from_f64(25000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.sell(lot_operation)?;
+        assert_eq!(lot_collection.lots.len(), 1);
+        let lot3 = get_by_date(&lot_collection, "2021-01-01")?;
+        assert_eq!(lot3.quantity, Decimal::from_f64(0.50000000).expect("Failed to parse quantity"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_loss_sells_losses_before_gains() -> Result<(), TaxLotError> {
+        let selection_algo = SelectionAlgorithm::MaxLoss;
+        let mut lot_collection = LotCollection::new(selection_algo);
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2020-01-01")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(30000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2020-02-01")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(18000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-01-01")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(25000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-02-01")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(19000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        // The long-term loss is sold before the smaller short-term loss.
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-03-01")?,
+            lot_type: crate::LotType::Sell,
+            price: Decimal::from_f64(20000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.sell(lot_operation)?;
+        assert_eq!(lot_collection.lots.len(), 3);
+        assert!(lot_collection.lots().all(|lot| lot.price != Decimal::from(30000)));
+        get_by_date(&lot_collection, "2021-01-01")?;
+
+        // Then the short-term loss, followed by the long-term gain even though the short-term gain is smaller.
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-03-01")?,
+            lot_type: crate::LotType::Sell,
+            price: Decimal::from_f64(20000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.50000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.sell(lot_operation)?;
+        assert_eq!(lot_collection.lots.len(), 2);
+        let lot2 = get_by_date(&lot_collection, "2020-02-01")?;
+        assert_eq!(lot2.quantity, Decimal::from_f64(0.50000000).expect("Failed to parse quantity"));
+        let lot4 = get_by_date(&lot_collection, "2021-02-01")?;
+        assert_eq!(lot4.quantity, Decimal::from_f64(1.00000000).expect("Failed to parse quantity"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_gain_sells_lowest_price_long_term_lots_first() -> Result<(), TaxLotError> {
+        let selection_algo = SelectionAlgorithm::MaxGain;
+        let mut lot_collection = LotCollection::new(selection_algo);
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2020-01-01")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(15000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2020-02-01")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(12000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-01-01")?,
+            lot_type: crate::LotType::Buy,
+            price: Decimal::from_f64(10000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.buy(lot_operation)?;
+
+        // The lowest price long-term lot is sold before the short-term lot with an even lower price, and
+        // the remaining shares come from the next long-term lot.
+        let lot_operation = LotOperation {
+            date: NaiveDate::from_str("2021-03-01")?,
+            lot_type: crate::LotType::Sell,
+            price: Decimal::from_f64(20000.00).expect("Failed to parse price"),
+            quantity: Decimal::from_f64(1.50000000).expect("Failed to parse quantity"),
+            tags: BTreeSet::new(),
+            txn_id: None,
+            method: None,
+            espp: None,
+            memo: None,
+        };
+        lot_collection.sell(lot_operation)?;
+        assert_eq!(lot_collection.lots.len(), 2);
+        let lot1 = get_by_date(&lot_collection, "2020-01-01")?;
+        assert_eq!(lot1.quantity, Decimal::from_f64(0.50000000).expect("Failed to parse quantity"));
+        let lot3 = get_by_date(&lot_collection, "2021-01-01")?;
+        assert_eq!(lot3.quantity, Decimal::from_f64(1.00000000).expect("Failed to parse quantity"));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_concatenates_memos() -> Result<(), TaxLotError> {
+        let selection_algo = SelectionAlgorithm::Fifo;
+        let mut lot_collection = LotCollection::new(selection_algo);
+        lot_collection.buy(LotOperation::from_str("2021-01-01,buy,10000.00,1.00000000,dca")?)?;
+        lot_collection.buy(LotOperation::from_str("2021-01-01,buy,10000.00,1.00000000")?)?;
+        lot_collection.buy(LotOperation::from_str("2021-01-01,buy,10000.00,1.00000000,buy the dip")?)?;
+
+        let lot = get_by_date(&lot_collection, "2021-01-01")?;
+        assert_eq!(lot.memo(), Some("dca; buy the dip"));
+        assert_eq!(lot.to_string(), "1,2021-01-01,10000.00,3.00000000,dca; buy the dip");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_keeps_purchases() -> Result<(), TaxLotError> {
+        let selection_algo = SelectionAlgorithm::Fifo;
+        let mut lot_collection = LotCollection::new(selection_algo);
+        lot_collection.set_merge_window(MergeWindow::Month);
+        apply(
+            &mut lot_collection,
+            &[
+                "2021-01-05,buy,10000.00,1.00000000",
+                "2021-01-02,buy,20000.00,3.00000000",
+                "2021-02-01,sell,30000.00,2.00000000",
+            ],
+        )?;
+
+        // The purchases keep the dates, prices and quantities of the buys after the lot is merged and sold from.
+        let lot = get_by_date(&lot_collection, "2021-01-02")?;
+        assert_eq!(lot.quantity(), Decimal::from(2));
+        assert_eq!(
+            lot.purchases(),
+            [
+                Purchase {
+                    date: NaiveDate::from_str("2021-01-05")?,
+                    price: Decimal::from(10000),
+                    quantity: Decimal::from(1),
+                },
+                Purchase {
+                    date: NaiveDate::from_str("2021-01-02")?,
+                    price: Decimal::from(20000),
+                    quantity: Decimal::from(3),
+                },
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_unions_tags() -> Result<(), TaxLotError> {
+        let selection_algo = SelectionAlgorithm::Hifo;
+        let mut lot_collection = LotCollection::new(selection_algo);
+        lot_collection.buy(LotOperation::from_str("2021-01-01,buy,10000.00,1.00000000,tags=robo")?)?;
+        lot_collection.buy(LotOperation::from_str("2021-01-01,buy,10000.00,1.00000000,tags=ira;robo,dca")?)?;
+
+        let lot = get_by_date(&lot_collection, "2021-01-01")?;
+        assert!(lot.has_tag("ira"));
+        assert_eq!(lot.tags().collect::<Vec<_>>(), ["ira", "robo"]);
+        assert_eq!(lot.to_string(), "1,2021-01-01,10000.00,2.00000000,tags=ira;robo,dca");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_keep_separate_creates_lot_per_buy() -> Result<(), TaxLotError> {
+        let selection_algo = SelectionAlgorithm::Fifo;
+        let mut lot_collection = LotCollection::new(selection_algo);
+        lot_collection.set_merge_policy(MergePolicy::KeepSeparate);
+        lot_collection.buy(LotOperation::from_str("2021-01-01,buy,10000.00,1.00000000")?)?;
+        lot_collection.buy(LotOperation::from_str("2021-01-01,buy,20000.00,1.00000000")?)?;
+        assert_eq!(lot_collection.lots.len(), 2);
+
+        // The lots on the same date are sold in the order they were bought.
+        lot_collection.sell(LotOperation::from_str("2021-02-01,sell,20000.00,1.50000000")?)?;
+        let lots: Vec<_> = lot_collection.lots().map(|lot| lot.to_string()).collect();
+        assert_eq!(lots, ["2,2021-01-01,20000.00,0.50000000"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_window_merges_buys_in_same_month() -> Result<(), TaxLotError> {
+        let selection_algo = SelectionAlgorithm::Fifo;
+        let mut lot_collection = LotCollection::new(selection_algo);
+        lot_collection.set_merge_window(MergeWindow::Month);
+        lot_collection.buy(LotOperation::from_str("2021-01-15,buy,10000.00,1.00000000")?)?;
+        lot_collection.buy(LotOperation::from_str("2021-01-03,buy,20000.00,1.00000000")?)?;
+        lot_collection.buy(LotOperation::from_str("2021-01-31,buy,30000.00,1.00000000")?)?;
+        lot_collection.buy(LotOperation::from_str("2021-02-01,buy,40000.00,1.00000000")?)?;
+
+        // The January buys are merged into one lot with the date of the earliest of them.
+        let lots: Vec<_> = lot_collection.lots().map(|lot| lot.to_string()).collect();
+        assert_eq!(lots, ["1,2021-01-03,20000.00,3.00000000", "2,2021-02-01,40000.00,1.00000000"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_merge_window_start() -> Result<(), TaxLotError> {
+        // 2021-01-06 is a Wednesday.
+        let date = NaiveDate::from_str("2021-01-06")?;
+        assert_eq!(MergeWindow::Day.start(date), date);
+        assert_eq!(MergeWindow::Week.start(date), NaiveDate::from_str("2021-01-04")?);
+        assert_eq!(MergeWindow::Month.start(date), NaiveDate::from_str("2021-01-01")?);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_hifo_ties_sell_oldest_lot_first() -> Result<(), TaxLotError> {
+        let selection_algo = SelectionAlgorithm::Hifo;
+        let mut lot_collection = LotCollection::new(selection_algo);
+        lot_collection.buy(LotOperation::from_str("2021-01-02,buy,10000.00,1.00000000")?)?;
+        lot_collection.buy(LotOperation::from_str("2021-01-01,buy,10000.00,1.00000000")?)?;
+        lot_collection.buy(LotOperation::from_str("2021-01-03,buy,10000.00,1.00000000")?)?;
+
+        // All lots have the same price, so the oldest lot is sold first even though it has a higher id.
+        lot_collection.sell(LotOperation::from_str("2021-02-01,sell,20000.00,1.00000000")?)?;
+        let lots: Vec<_> = lot_collection.lots().map(|lot| lot.id()).collect();
+        assert_eq!(lots, [1, 3]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_hash_ids_do_not_depend_on_order() -> Result<(), TaxLotError> {
+        let selection_algo = SelectionAlgorithm::Fifo;
+        let mut lot_collection = LotCollection::new(selection_algo);
+        lot_collection.set_id_scheme(IdScheme::Hash);
+        lot_collection.buy(LotOperation::from_str("2021-01-01,buy,10000.00,1.00000000")?)?;
+        lot_collection.buy(LotOperation::from_str("2021-01-02,buy,20000.00,1.00000000")?)?;
+
+        let mut reordered_collection = LotCollection::new(selection_algo);
+        reordered_collection.set_id_scheme(IdScheme::Hash);
+        reordered_collection.buy(LotOperation::from_str("2021-01-02,buy,20000,1")?)?;
+        reordered_collection.buy(LotOperation::from_str("2021-01-01,buy,10000,1")?)?;
+
+        let ids: Vec<_> = lot_collection.lots().map(|lot| lot.id()).collect();
+        let reordered_ids: Vec<_> = reordered_collection.lots().map(|lot| lot.id()).collect();
+        assert_eq!(ids, reordered_ids);
+        assert_ne!(ids[0], ids[1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_hash_ids_of_identical_buys_are_unique() -> Result<(), TaxLotError> {
+        let selection_algo = SelectionAlgorithm::Hifo;
+        let mut lot_collection = LotCollection::new(selection_algo);
+        lot_collection.set_id_scheme(IdScheme::Hash);
+        lot_collection.set_merge_policy(MergePolicy::KeepSeparate);
+        lot_collection.buy(LotOperation::from_str("2021-01-01,buy,10000.00,1.00000000")?)?;
+        lot_collection.buy(LotOperation::from_str("2021-01-01,buy,10000.00,1.00000000")?)?;
+
+        assert_eq!(lot_collection.lots.len(), 2);
+        let ids: Vec<_> = lot_collection.lots().map(|lot| lot.id()).collect();
+        assert_eq!(ids[1], ids[0] + 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_ids_skip_ids_in_use() -> Result<(), TaxLotError> {
+        let selection_algo = SelectionAlgorithm::Fifo;
+        let mut lot_collection = LotCollection::new(selection_algo);
+        lot_collection.set_id_scheme(IdScheme::Hash);
+        lot_collection.buy(LotOperation::from_str("2021-01-01,buy,10000.00,1.00000000")?)?;
+        let hash_id = lot_collection.lots().map(|lot| lot.id()).next().expect("No lot was bought");
+
+        // The next sequential id is taken by a lot with another key, so the new lot takes the one after it.
+        lot_collection.set_id_scheme(IdScheme::Sequential);
+        lot_collection.set_start_id(hash_id);
+        lot_collection.buy(LotOperation::from_str("2021-01-02,buy,20000.00,1.00000000")?)?;
+        let ids: Vec<_> = lot_collection.lots().map(|lot| lot.id()).collect();
+        assert_eq!(ids, [hash_id, hash_id + 1]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_new_ids_wrap_around_after_the_largest_id() -> Result<(), TaxLotError> {
+        let selection_algo = SelectionAlgorithm::Fifo;
+        let mut lot_collection = LotCollection::new(selection_algo);
+        lot_collection.set_start_id(HASH_ID_MASK);
+        lot_collection.buy(LotOperation::from_str("2021-01-01,buy,10000.00,1.00000000")?)?;
+
+        // The new lot collides with the lot that has the largest id, so it takes the first id instead.
+        lot_collection.set_start_id(HASH_ID_MASK);
+        lot_collection.buy(LotOperation::from_str("2021-01-02,buy,20000.00,1.00000000")?)?;
+        let ids: Vec<_> = lot_collection.lots().map(|lot| lot.id()).collect();
+        assert_eq!(ids, [HASH_ID_MASK, 0]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_uuid_ids_are_unique_and_survive_replay() -> Result<(), TaxLotError> {
+        let selection_algo = SelectionAlgorithm::Fifo;
+        let mut lot_collection = LotCollection::new(selection_algo);
+        lot_collection.set_id_scheme(IdScheme::Uuid);
+        lot_collection.set_merge_policy(MergePolicy::KeepSeparate);
+        lot_collection.enable_journal();
+        apply(
+            &mut lot_collection,
+            &[
+                "2021-01-01,buy,10000.00,1.00000000,txn=a",
+                "2021-01-01,buy,10000.00,1.00000000,txn=b",
+                "2021-01-02,buy,20000.00,1.00000000,txn=c",
+            ],
+        )?;
+        let ids: Vec<_> = lot_collection.lots().map(|lot| lot.id()).collect();
+        assert_eq!(ids.iter().collect::<BTreeSet<_>>().len(), 3);
+        assert!(ids.iter().all(|&id| id > 3 && id <= i64::MAX as u64));
+
+        // The amend replays every operation, which draws the same ids again.
+        lot_collection.apply_lot_operation(LotOperation::from_str("2021-01-03,amend,30000.00,1.00000000,txn=c")?)?;
+        let replayed_ids: Vec<_> = lot_collection.lots().map(|lot| lot.id()).collect();
+        assert_eq!(replayed_ids, ids);
+
+        // Another collection draws other ids.
+        let mut other_collection = LotCollection::new(selection_algo);
+        other_collection.set_id_scheme(IdScheme::Uuid);
+        other_collection.buy(LotOperation::from_str("2021-01-01,buy,10000.00,1.00000000")?)?;
+        assert!(other_collection.lots().all(|lot| !ids.contains(&lot.id())));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_start_id() -> Result<(), TaxLotError> {
+        let selection_algo = SelectionAlgorithm::Fifo;
+        let mut lot_collection = LotCollection::new(selection_algo);
+        lot_collection.set_start_id(123);
+        lot_collection.buy(LotOperation::from_str("2021-01-01,buy,10000.00,1.00000000")?)?;
+        lot_collection.buy(LotOperation::from_str("2021-01-02,buy,10000.00,1.00000000")?)?;
+
+        let ids: Vec<_> = lot_collection.lots().map(|lot| lot.id()).collect();
+        assert_eq!(ids, [123, 124]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_income_is_totalled_per_year() -> Result<(), TaxLotError> {
+        let selection_algo = SelectionAlgorithm::Fifo;
+        let mut lot_collection = LotCollection::new(selection_algo);
+        apply(
+            &mut lot_collection,
+            &[
+                "2021-01-01,buy,10000.00,2.00000000",
+                "2021-03-31,dividend,0.25,2.00000000",
+                "2021-12-31,dividend,0.30,2.00000000",
+                "2021-06-30,interest,12.50,1",
+                "2022-03-31,dividend,0.25,2.00000000",
+                "2022-03-31,drip,20000.00,0.00002500",
+                "2022-04-01,vest,150.00,10",
+            ],
+        )?;
+
+        // Income does not create lots, but reinvested dividends and vested units do.
+        assert_eq!(lot_collection.lots.len(), 3);
+        let income: Vec<_> = lot_collection.income().collect();
+        assert_eq!(
+            income,
+            [
+                (2021, IncomeType::Dividend, Decimal::from_f64(1.1).expect("Failed to parse income")),
+                (2021, IncomeType::Interest, Decimal::from_f64(12.5).expect("Failed to parse income")),
+                (2022, IncomeType::Dividend, Decimal::from_f64(1.0).expect("Failed to parse income")),
+                (2022, IncomeType::Compensation, Decimal::from(1500)),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_espp_discount_is_compensation_in_disqualifying_disposition() -> Result<(), TaxLotError> {
+        let mut lot_collection = LotCollection::new(SelectionAlgorithm::Fifo);
+        lot_collection.enable_undo(10);
+        apply(
+            &mut lot_collection,
+            &[
+                "2021-06-30,espp,85.00,10,offering=2021-01-01,offering_fmv=100.00,fmv=110.00",
+                "2021-06-30,buy,110.00,1",
+                "2021-12-31,espp,90.00,10,offering=2021-07-01,offering_fmv=120.00,fmv=105.88",
+            ],
+        )?;
+
+        // ESPP purchases are not merged with other buys on the same date.
+        assert_eq!(lot_collection.lots.len(), 3);
+        let espp = *get_by_date(&lot_collection, "2021-12-31")?.espp().expect("No ESPP purchase");
+        assert_eq!(espp.discount, Decimal::from_str("15.88")?);
+
+        // Selling within two years of the offering is disqualifying: the discount of the shares sold is
+        // compensation, and the gain is over the fair market value at purchase.
+        apply(&mut lot_collection, &["2022-07-01,sell,130.00,4"])?;
+        assert_eq!(lot_collection.income().collect::<Vec<_>>(), [(2022, IncomeType::Compensation, Decimal::from(100))]);
+        assert_eq!(lot_collection.realized_gains(), Decimal::from(80));
+
+        lot_collection.undo(1);
+        assert_eq!(lot_collection.income().count(), 0);
+        assert_eq!(lot_collection.realized_gains(), Decimal::ZERO);
+
+        // Two years after the offering and a year after the purchase, the whole gain is a capital gain.
+        apply(&mut lot_collection, &["2023-01-02,sell,130.00,4"])?;
+        assert_eq!(lot_collection.income().count(), 0);
+        assert_eq!(lot_collection.realized_gains(), Decimal::from(180));
+
+        LotOperation::from_str("2021-06-30,espp,85.00,10,offering=2021-01-01,fmv=110.00")
+            .expect_err("Parsed an ESPP purchase without the offering fair market value");
+        LotOperation::from_str("2021-06-30,espp,85.00,10,offering=2021-01-01,offering_fmv=100.00,fmv=80.00")
+            .expect_err("Parsed an ESPP purchase above the fair market value");
+
+        Ok(())
+    }
+    #[test]
+    fn test_realized_gains_are_totalled_per_year() -> Result<(), TaxLotError> {
+        let mut lot_collection = LotCollection::new(SelectionAlgorithm::Fifo);
+        apply(
+            &mut lot_collection,
+            &[
+                "2021-01-01,buy,10000.00,1.00000000",
+                "2021-06-01,buy,20000.00,2.00000000",
+                "2021-12-31,sell,15000.00,0.50000000",
+                "2022-03-01,sell,12000.00,1.50000000",
+                "2022-03-01,sell,12000.00,5.00000000",
+                "2023-01-01,sell,10000.00,1.00000000",
+            ],
+        )?;
+
+        // The second sell realizes a long-term gain on the rest of the first lot and a short-term loss on
+        // the second lot. Sells with no lots left do not add a year.
+        let gains: Vec<_> = lot_collection.realized_gains_by_year().collect();
+        assert_eq!(
+            gains,
+            [
+                (2021, RealizedGains { short_term: Decimal::from(2500), long_term: Decimal::ZERO }),
+                (2022, RealizedGains { short_term: Decimal::from(-16000), long_term: Decimal::from(1000) }),
+            ]
+        );
+        assert_eq!(
+            gains.iter().map(|(_, gains)| gains.total()).sum::<Decimal>(),
+            lot_collection.realized_gains()
+        );
+
+        Ok(())
+    }
+    #[test]
+    fn test_realized_gains_are_totalled_per_quarter() -> Result<(), TaxLotError> {
+        let mut lot_collection = LotCollection::new(SelectionAlgorithm::Fifo);
+        apply(
+            &mut lot_collection,
+            &[
+                "2021-01-01,buy,10000.00,4.00000000",
+                "2021-02-01,sell,11000.00,1.00000000",
+                "2021-03-31,sell,12000.00,1.00000000",
+                "2021-04-01,sell,9000.00,1.00000000",
+                "2022-10-01,sell,15000.00,1.00000000",
+            ],
+        )?;
+
+        let gains: Vec<_> = lot_collection.realized_gains_by_quarter().collect();
+        assert_eq!(
+            gains,
+            [
+                (2021, 1, RealizedGains { short_term: Decimal::from(3000), long_term: Decimal::ZERO }),
+                (2021, 2, RealizedGains { short_term: Decimal::from(-1000), long_term: Decimal::ZERO }),
+                (2022, 4, RealizedGains { short_term: Decimal::ZERO, long_term: Decimal::from(5000) }),
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_unrealized_gain_at_mark_price() -> Result<(), TaxLotError> {
+        let mut lot_collection = LotCollection::new(SelectionAlgorithm::Fifo);
+        apply(
+            &mut lot_collection,
+            &["2021-01-01,buy,10000.00,2.00000000", "2021-06-01,sell,15000.00,0.50000000"],
+        )?;
+
+        let lot = get_by_date(&lot_collection, "2021-01-01")?;
+        let date = NaiveDate::from_str("2022-01-01")?;
+        assert_eq!(
+            lot.unrealized_gain(Decimal::from(8000), date)?,
+            UnrealizedGain {
+                cost_basis: Decimal::from(15000),
+                market_value: Decimal::from(12000),
+                gain: Decimal::from(-3000),
+                long_term: false,
+            }
+        );
+        assert!(lot.unrealized_gain(Decimal::from(8000), NaiveDate::from_str("2022-01-02")?)?.long_term);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_buy_fails_beyond_max_open_lots() -> Result<(), TaxLotError> {
+        let selection_algo = SelectionAlgorithm::Fifo;
+        let mut lot_collection = LotCollection::new(selection_algo);
+        lot_collection.set_max_open_lots(2);
+        apply(
+            &mut lot_collection,
+            &[
+                "2021-01-01,buy,10000.00,1.00000000",
+                "2021-01-02,buy,20000.00,1.00000000",
+                "2021-01-02,buy,30000.00,1.00000000",
+            ],
+        )?;
+
+        // A third lot cannot be opened, but it can once a lot has been sold.
+        let buy = LotOperation::from_str("2021-01-03,buy,10000.00,1.00000000")?;
+        match lot_collection.apply_lot_operation(buy.clone()) {
+            Err(TaxLotError::LimitExceeded(_)) => {}
+            result => panic!("Opened a lot beyond the limit: {result:?}"),
+        }
+        assert_eq!(lot_collection.lots.len(), 2);
+        lot_collection.apply_lot_operation(LotOperation::from_str("2021-02-01,sell,20000.00,1.00000000")?)?;
+        lot_collection.apply_lot_operation(buy)?;
+        assert_eq!(lot_collection.lots.len(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sell_method_overrides_selection_algorithm() -> Result<(), TaxLotError> {
+        let selection_algo = SelectionAlgorithm::Hifo;
+        let mut lot_collection = LotCollection::new(selection_algo);
+        apply(
+            &mut lot_collection,
+            &[
+                "2020-01-01,buy,30000.00,1.00000000",
+                "2021-01-01,buy,10000.00,1.00000000",
+                "2021-02-01,buy,20000.00,1.00000000",
+                "2021-03-01,buy,40000.00,1.00000000",
+                // Sells the oldest lot instead of the highest price lot.
+                "2021-06-01,sell,25000.00,1.00000000,method=fifo,rebalance",
+                // Sells the lowest price lot, since none of the lots is long-term.
+                "2021-06-02,sell,25000.00,0.50000000,method=max-gain",
+                // Sells from the lot with id 3 only.
+                "2021-06-03,sell,25000.00,0.50000000,method=lot:3",
+            ],
+        )?;
+        let lots: Vec<_> = lot_collection.lots().map(|lot| lot.to_string()).collect();
+        assert_eq!(
+            lots,
+            [
+                "4,2021-03-01,40000.00,1.00000000",
+                "3,2021-02-01,20000.00,0.50000000",
+                "2,2021-01-01,10000.00,0.50000000",
+            ]
+        );
+        assert_eq!(lot_collection.realized_gains(), Decimal::from(-5000 + 7500 + 2500));
+
+        // A sell from a specific lot fails if the lot does not hold enough shares, leaving the lots unchanged.
+        match lot_collection.apply_lot_operation(LotOperation::from_str("2021-06-04,sell,1,1,method=lot:3")?) {
+            Err(TaxLotError::SpecificLotUnavailable(..)) => {}
+            result => panic!("Sold more than the lot holds: {result:?}"),
+        }
+        assert_eq!(lot_collection.lots().count(), 3);
+        LotOperation::from_str("2021-06-04,sell,1,1,method=lifo").expect_err("Parsed an unknown sell method");
+
+        Ok(())
+    }
+}
@@ -0,0 +1,66 @@
+//! Limits on the size of the input, so that hostile or corrupted input fails with an error instead of using up
+//! the memory or running for as long as the input goes on.
+
+use clap::Args;
+use taxlot::TaxLotError;
+
+/// Represents the limits on the input. Every limit is unset by default.
+///
+/// `max_line_length`: Fail on an input line longer than this many bytes, without the line ending. Longer lines
+/// are not read into memory beyond the limit.
+/// `max_fields`: Fail on an input line with more comma-separated fields than this, counting the commas in a memo.
+/// `max_operations`: Fail when the input has more operations than this, including the ones restored from a
+/// checkpoint.
+/// `max_open_lots`: Fail on a buy that would open more lots than this.
+#[derive(Args, Debug, Clone, Copy, Default)]
+pub struct LimitOpts {
+    #[clap(long, value_name = "BYTES", conflicts_with = "xml", env = "TAXLOT_MAX_LINE_LENGTH", global = true)]
+    pub max_line_length: Option<usize>,
+
+    #[clap(
+        long,
+        value_name = "FIELDS",
+        conflicts_with_all = ["fixed_width", "xml"],
+        env = "TAXLOT_MAX_FIELDS",
+        global = true
+    )]
+    pub max_fields: Option<usize>,
+
+    #[clap(long, value_name = "OPERATIONS", env = "TAXLOT_MAX_OPERATIONS", global = true)]
+    pub max_operations: Option<u64>,
+
+    #[clap(long, value_name = "LOTS", env = "TAXLOT_MAX_OPEN_LOTS", global = true)]
+    pub max_open_lots: Option<usize>,
+}
+
+impl LimitOpts {
+    /// Returns the number of bytes to read of a line at most, enough to tell whether it is longer than the
+    /// maximum line length after its line ending is stripped.
+    pub fn read_limit(&self) -> u64 {
+        self.max_line_length.map_or(u64::MAX, |max| max.saturating_add(2) as u64)
+    }
+
+    /// Checks the length and fields of an input `line` without its line ending.
+    pub fn check_line(&self, line: &[u8]) -> Result<(), TaxLotError> {
+        if let Some(max) = self.max_line_length.filter(|&max| line.len() > max) {
+            return Err(TaxLotError::LimitExceeded(format!("input line is longer than {max} bytes")));
+        }
+        if let Some(max) = self.max_fields {
+            let fields = line.iter().filter(|&&byte| byte == b',').count() + 1;
+            if fields > max {
+                return Err(TaxLotError::LimitExceeded(format!("input line has more than {max} fields")));
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks that another operation can be applied after `operations` of them.
+    pub fn check_operations(&self, operations: u64) -> Result<(), TaxLotError> {
+        match self.max_operations {
+            Some(max) if operations >= max => {
+                Err(TaxLotError::LimitExceeded(format!("input has more than {max} operations")))
+            }
+            _ => Ok(()),
+        }
+    }
+}
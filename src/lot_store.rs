@@ -0,0 +1,385 @@
+//! Storage of the open lots of a `LotCollection`, in the order they are sold.
+//!
+//! A lot collection with tens of millions of open lots would keep all of them in memory, so the tail of the sell
+//! order can be spilled to a temporary file once there are more than a limit of lots in memory, see
+//! `LotCollection::spill_lots`. The head of the order stays in memory, which is where sells deduct from first.
+//! Spilled lots are paged back in when a sell deducts from them, when a buy merges into them, and whenever the head
+//! drains so that the first lot in the order is a spilled one. Only the keys of the spilled lots and where they are in
+//! the file are kept in memory. The file is memory-mapped, so the collection can still iterate over every lot in
+//! order, without paging them in, e.g. for the algorithms that make several passes over the lots.
+
+use std::{
+    borrow::Cow,
+    collections::BTreeMap,
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    iter,
+    ops::Range,
+    str,
+};
+
+use memmap2::Mmap;
+use tempfile::NamedTempFile;
+
+use crate::{
+    checkpoint::{parse_lot_lines, write_lot},
+    Lot, LotCollection, LotKey, SelectionAlgorithm,
+};
+
+/// The open lots of a `LotCollection` by their `LotKey`. If `memory_limit` is set, the lots with the last keys in
+/// memory are spilled to a temporary file whenever there are more than that many of them in memory.
+///
+/// `head`: Lots in memory, which are the first ones in the sell order unless lots were paged in out of order.
+/// `selection_algorithm`: Selection algorithm of the spilled lots, which they are read back with.
+/// `spill`: File of the lots that are not in memory, once any were spilled.
+#[derive(Debug)]
+pub(crate) struct LotStore {
+    head: BTreeMap<LotKey, Lot>,
+    selection_algorithm: SelectionAlgorithm,
+    memory_limit: Option<usize>,
+    spill: Option<Spill>,
+}
+
+/// Represents a temporary file of spilled lots, each the checkpoint lines of a lot. It is deleted when dropped.
+///
+/// `map`: Memory map of the file, as of the last spill.
+/// `index`: Where each spilled lot is in the file, by key. Lots that were paged in are left in the file until it
+/// is compacted, so the file can have more lots than the index.
+/// `live`: Total length of the lots in the index.
+#[derive(Debug)]
+struct Spill {
+    file: NamedTempFile,
+    map: Option<Mmap>,
+    index: BTreeMap<LotKey, Range<usize>>,
+    live: usize,
+}
+
+impl LotStore {
+    pub(crate) fn new(selection_algorithm: SelectionAlgorithm) -> LotStore {
+        LotStore {
+            head: BTreeMap::new(),
+            selection_algorithm,
+            memory_limit: None,
+            spill: None,
+        }
+    }
+
+    /// Returns an empty lot store with the same memory limit.
+    pub(crate) fn cleared(&self) -> LotStore {
+        LotStore {
+            memory_limit: self.memory_limit,
+            ..LotStore::new(self.selection_algorithm)
+        }
+    }
+
+    /// Returns the number of lots, including the spilled ones.
+    pub(crate) fn len(&self) -> usize {
+        self.head.len() + self.spill.as_ref().map_or(0, |spill| spill.index.len())
+    }
+
+    /// Returns every lot with its key in the order of the keys, reading the spilled ones from their file.
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (LotKey, Cow<'_, Lot>)> + '_ {
+        let mut head = self.head.iter().peekable();
+        let mut spilled = self
+            .spill
+            .iter()
+            .flat_map(|spill| spill.index.iter().map(move |(key, range)| (key, spill, range)))
+            .peekable();
+        iter::from_fn(move || {
+            let from_head = match (head.peek(), spilled.peek()) {
+                (Some((head_key, _)), Some((spilled_key, _, _))) => head_key < spilled_key,
+                (head_lot, _) => head_lot.is_some(),
+            };
+            match from_head {
+                true => head.next().map(|(&key, lot)| (key, Cow::Borrowed(lot))),
+                false => spilled
+                    .next()
+                    .map(|(&key, spill, range)| (key, Cow::Owned(spill.read(range.clone(), self.selection_algorithm)))),
+            }
+        })
+    }
+
+    /// Adds a lot to the lots in memory. It is spilled at the next `balance` if it is in the tail.
+    pub(crate) fn insert(&mut self, key: LotKey, lot: Lot) {
+        self.head.insert(key, lot);
+    }
+
+    /// Removes the lot with `key`, reading it back if it was spilled.
+    pub(crate) fn remove(&mut self, key: &LotKey) -> Option<Lot> {
+        match self.head.remove(key) {
+            Some(lot) => Some(lot),
+            None => self.spill.as_mut()?.take(key, self.selection_algorithm),
+        }
+    }
+
+    /// Returns the lot with `key`, paging it in if it was spilled.
+    pub(crate) fn get_mut(&mut self, key: &LotKey) -> Option<&mut Lot> {
+        if !self.head.contains_key(key) {
+            let lot = self.spill.as_mut()?.take(key, self.selection_algorithm)?;
+            self.head.insert(*key, lot);
+        }
+        self.head.get_mut(key)
+    }
+
+    /// Removes every lot, including the spilled ones.
+    pub(crate) fn clear(&mut self) {
+        self.head.clear();
+        self.spill = None;
+    }
+
+    /// Spills the lots with the last keys in memory if there are more than `memory_limit` of them, keeping half of
+    /// the limit. Then, if the first lot in the order is spilled, pages in as many spilled lots as half of the limit,
+    /// those with the first keys. If spilling fails, the lots are left in memory.
+    pub(crate) fn balance(&mut self) -> io::Result<()> {
+        let Some(limit) = self.memory_limit else {
+            return Ok(());
+        };
+        let keep = limit / 2;
+        if self.head.len() > limit {
+            let mut spilled = Vec::with_capacity(self.head.len() - keep);
+            while self.head.len() > keep {
+                spilled.extend(self.head.pop_last());
+            }
+            if let Err(e) = self.spill(&spilled) {
+                self.head.extend(spilled);
+                return Err(e);
+            }
+        }
+        let Some(spill) = self.spill.as_mut() else {
+            return Ok(());
+        };
+        let head_first = self.head.first_key_value().map(|(key, _)| *key);
+        let first_spilled = spill.index.first_key_value().map(|(key, _)| *key);
+        if first_spilled.is_some_and(|first| head_first.is_none_or(|head_first| first < head_first)) {
+            for _ in 0..keep.max(1) {
+                let Some((&key, _)) = spill.index.first_key_value() else {
+                    break;
+                };
+                if let Some(lot) = spill.take(&key, self.selection_algorithm) {
+                    self.head.insert(key, lot);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Appends `lots` to the spill file, creating it if there is none, and compacting it first if most of it is
+    /// lots that were paged in.
+    fn spill(&mut self, lots: &[(LotKey, Lot)]) -> io::Result<()> {
+        if self.spill.as_ref().is_some_and(|spill| spill.garbage() > spill.live) {
+            let compacted = self.spill.as_ref().map(Spill::compacted).transpose()?;
+            self.spill = compacted;
+        }
+        let spill = match &mut self.spill {
+            Some(spill) => spill,
+            None => self.spill.insert(Spill::new()?),
+        };
+        let start = spill.file.as_file_mut().seek(SeekFrom::End(0))?;
+        let ranges = match spill.append(lots, start as usize).and_then(|ranges| spill.remap().map(|()| ranges)) {
+            Ok(ranges) => ranges,
+            Err(e) => {
+                // The map is only replaced once every lot is written, so the lots in it are still where the index
+                // says, and the file is cut back to them.
+                let _ = spill.file.as_file().set_len(start);
+                return Err(e);
+            }
+        };
+        for ((key, _), range) in lots.iter().zip(ranges) {
+            spill.live += range.len();
+            spill.index.insert(*key, range);
+        }
+        Ok(())
+    }
+}
+
+impl Spill {
+    fn new() -> io::Result<Spill> {
+        Ok(Spill {
+            file: NamedTempFile::new()?,
+            map: None,
+            index: BTreeMap::new(),
+            live: 0,
+        })
+    }
+
+    /// Writes `lots` to the file, which is `start` long, and returns where each of them is.
+    fn append(&mut self, lots: &[(LotKey, Lot)], start: usize) -> io::Result<Vec<Range<usize>>> {
+        let mut out = BufWriter::new(self.file.as_file_mut());
+        let mut ranges = Vec::with_capacity(lots.len());
+        let mut end = start;
+        let mut lines = Vec::new();
+        for (_, lot) in lots {
+            lines.clear();
+            write_lot(lot, &mut lines)?;
+            out.write_all(&lines)?;
+            ranges.push(end..end + lines.len());
+            end += lines.len();
+        }
+        out.flush()?;
+        Ok(ranges)
+    }
+
+    /// Maps the file again, after lots were written to it.
+    fn remap(&mut self) -> io::Result<()> {
+        // Safety: the file is only written by the lot store, which appends to it, and only cuts it back to the
+        // length it was mapped at.
+        self.map = Some(unsafe { Mmap::map(self.file.as_file())? });
+        Ok(())
+    }
+
+    /// Returns the length of the lots in the file that are no longer spilled.
+    fn garbage(&self) -> usize {
+        self.map.as_ref().map_or(0, |map| map.len()) - self.live
+    }
+
+    /// Returns a spill with only the lots that are still spilled, in a new file.
+    fn compacted(&self) -> io::Result<Spill> {
+        let mut compacted = Spill::new()?;
+        let mut out = BufWriter::new(compacted.file.as_file_mut());
+        let mut index = BTreeMap::new();
+        let mut len = 0;
+        for (&key, range) in &self.index {
+            out.write_all(&self.bytes()[range.clone()])?;
+            index.insert(key, len..len + range.len());
+            len += range.len();
+        }
+        out.flush()?;
+        drop(out);
+        if len > 0 {
+            compacted.remap()?;
+        }
+        compacted.index = index;
+        compacted.live = len;
+        Ok(compacted)
+    }
+
+    fn bytes(&self) -> &[u8] {
+        self.map.as_deref().unwrap_or_default()
+    }
+
+    /// Reads the lot at `range` of the file.
+    fn read(&self, range: Range<usize>, selection_algorithm: SelectionAlgorithm) -> Lot {
+        let lines = str::from_utf8(&self.bytes()[range]).expect("Spilled lots are written as text");
+        parse_lot_lines(lines, selection_algorithm).expect("Spilled lots are written as checkpoint lines")
+    }
+
+    /// Removes the lot with `key` from the index and returns it.
+    fn take(&mut self, key: &LotKey, selection_algorithm: SelectionAlgorithm) -> Option<Lot> {
+        let range = self.index.remove(key)?;
+        self.live -= range.len();
+        Some(self.read(range, selection_algorithm))
+    }
+}
+
+impl LotCollection {
+    /// Keeps at most `limit` open lots in memory, those first in the sell order, and spills the rest to a temporary
+    /// file, which is deleted when the lot collection is dropped. The lots are spilled and paged back in before
+    /// each operation is applied, see `LotStore::balance`, and an operation fails with `TaxLotError::LotSpillError`
+    /// if the lots cannot be spilled. Like the merge policy, this should be set before applying any lot operations.
+    pub fn spill_lots(&mut self, limit: usize) {
+        self.lots.memory_limit = Some(limit);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{
+        test_helpers::{apply, lots},
+        LotCollection, SelectionAlgorithm, TaxLotError,
+    };
+
+    /// Returns buys of 40 lots on different dates and prices with sells in between, some of them selling more than
+    /// the lots in memory, and buys that merge into older lots.
+    fn operations() -> Vec<String> {
+        let mut operations = Vec::new();
+        for day in 0..40 {
+            let date = chrono::NaiveDate::from_ymd_opt(2020, 1, 1).unwrap() + chrono::Days::new(day * 20);
+            let price = 1000 + (day * 37) % 500;
+            operations.push(format!("{date},buy,{price}.00,1.00000000,lot {day}"));
+            if day % 7 == 6 {
+                operations.push(format!("{date},sell,{}.00,3.50000000", price + 100));
+            }
+            if day % 9 == 8 {
+                // Merges into the lot bought 8 buys earlier, which has been spilled by now.
+                let earlier = date - chrono::Days::new(160);
+                operations.push(format!("{earlier},buy,{price}.00,0.50000000,tags=merged"));
+            }
+        }
+        operations
+    }
+
+    fn collection(selection_algorithm: SelectionAlgorithm, spill: Option<usize>) -> LotCollection {
+        let mut lot_collection = LotCollection::new(selection_algorithm);
+        if let Some(limit) = spill {
+            lot_collection.spill_lots(limit);
+        }
+        lot_collection.enable_undo(100);
+        lot_collection
+    }
+
+    #[test]
+    fn test_spilled_lots_are_sold_like_lots_in_memory() -> Result<(), TaxLotError> {
+        for selection_algorithm in SelectionAlgorithm::ALL {
+            let mut in_memory = collection(selection_algorithm, None);
+            let mut spilled = collection(selection_algorithm, Some(4));
+            for operation in operations() {
+                apply(&mut in_memory, &[&operation])?;
+                apply(&mut spilled, &[&operation])?;
+                // A buy adds at most one lot in memory, and sells and merges page in the lots they change.
+                assert!(spilled.lots.head.len() <= 6, "{operation} left {} lots in memory", spilled.lots.head.len());
+                assert_eq!(lots(&spilled), lots(&in_memory), "{selection_algorithm} after {operation}");
+            }
+            assert!(spilled.lots.len() > 10);
+            assert_eq!(spilled.realized_gains(), in_memory.realized_gains());
+            let undone = spilled.undo(10);
+            assert_eq!(in_memory.undo(10), undone);
+            assert_eq!(lots(&spilled), lots(&in_memory), "{selection_algorithm} after undoing {undone} operations");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_lots_are_paged_in_as_the_head_drains() -> Result<(), TaxLotError> {
+        let mut lot_collection = collection(SelectionAlgorithm::Fifo, Some(4));
+        let buys: Vec<_> = (1..=12).map(|day| format!("2021-01-{day:02},buy,100.00,1.00000000")).collect();
+        apply(&mut lot_collection, &buys.iter().map(String::as_str).collect::<Vec<_>>())?;
+        apply(&mut lot_collection, &["2021-02-01,buy,100.00,1.00000000"])?;
+        // The later buys stay in memory until the next spill.
+        assert_eq!(lot_collection.lots.head.len(), 4);
+        assert_eq!(lot_collection.lots.len(), 13);
+
+        apply(&mut lot_collection, &["2021-03-01,sell,100.00,2.00000000"])?;
+        apply(&mut lot_collection, &["2021-03-02,sell,100.00,1.00000000"])?;
+        // The first lot in the order was spilled, so the next two spilled lots were paged in before the sell.
+        let ids: Vec<_> = lot_collection.lots.head.values().map(|lot| lot.id()).collect();
+        assert_eq!(ids, [4, 12, 13]);
+        assert_eq!(lot_collection.lots().next().map(|lot| lot.id()), Some(4));
+        Ok(())
+    }
+
+    #[test]
+    fn test_spilled_lots_are_checkpointed_and_replayed() -> Result<(), TaxLotError> {
+        let mut lot_collection = collection(SelectionAlgorithm::Hifo, Some(2));
+        lot_collection.enable_journal();
+        let operations = operations();
+        apply(&mut lot_collection, &operations.iter().map(String::as_str).collect::<Vec<_>>())?;
+        let mut checkpoint = Vec::new();
+        lot_collection.write_checkpoint(0, &mut checkpoint)?;
+        let checkpoint = String::from_utf8(checkpoint).expect("Checkpoint is not UTF-8");
+
+        let mut restored = collection(SelectionAlgorithm::Hifo, Some(2));
+        restored.restore_checkpoint(&checkpoint)?;
+        assert_eq!(lots(&restored), lots(&lot_collection));
+
+        // Cancelling a buy replays the journal into a new collection that spills its lots too.
+        let mut in_memory = collection(SelectionAlgorithm::Hifo, None);
+        in_memory.enable_journal();
+        apply(&mut in_memory, &operations.iter().map(String::as_str).collect::<Vec<_>>())?;
+        for lot_collection in [&mut lot_collection, &mut in_memory] {
+            apply(lot_collection, &["2020-01-01,buy,1000.00,1.00000000,txn=b0,lot 0"])?;
+            apply(lot_collection, &["2020-01-01,cancel,,,txn=b0"])?;
+        }
+        assert_eq!(lots(&lot_collection), lots(&in_memory));
+        assert!(lot_collection.lots.head.len() < lot_collection.lots.len());
+        Ok(())
+    }
+}
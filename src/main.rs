@@ -1,737 +1,2021 @@
+mod alerts;
+mod au;
+#[cfg(feature = "arrow")]
+mod columnar;
+mod config;
+mod entry;
+#[cfg(any(feature = "sqlite", feature = "postgres"))]
+mod export;
+mod limits;
+mod output;
+mod performance;
+#[cfg(feature = "wasm")]
+mod plugin;
+mod prices;
+#[cfg(feature = "script")]
+mod script;
+mod section1256;
+mod workload;
+
 use std::{
-    collections::VecDeque,
-    fmt::Display,
-    io,
-    str::FromStr,
-    sync::atomic::{AtomicU64, Ordering}, process,
+    borrow::Cow,
+    collections::{BTreeMap, BTreeSet},
+    env,
+    ffi::OsString,
+    fs::File,
+    io::{self, BufRead, BufWriter, Read, Write},
+    path::{Path, PathBuf},
+    process,
+    str,
+    sync::{
+        mpsc::{self, SyncSender},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-use chrono::{NaiveDate, ParseError};
-use clap::{Parser, Subcommand};
+use chrono::Local;
+use clap::{builder::BoolishValueParser, error::ErrorKind, Args, CommandFactory, Parser, Subcommand, ValueEnum};
+use memmap2::Mmap;
 use rust_decimal::Decimal;
-use thiserror::Error;
-
-const INITIAL_TAX_LOT_ID: u64 = 1;
-
-/// Represents the command line arguments
-/// 
-/// `selection_algo`: Determines how the tax lots are sold. Options: fifo, hifo
+use taxlot::{
+    Column, ColumnError, Disposal, DisposalChange, Encoding, FixedWidthLayout, IdFormat, IdScheme, IncomeType,
+    InputDialect, InsertChanges, Lot, LotChange, LotCollection, LotFilter, LotOperation, MergePolicy, MergeWindow,
+    RealizedGains, SelectionAlgorithm, TaxLotError, UnrealizedGain, XmlMapping,
+};
+use alerts::{AlertOpts, Alerts, ALERT_SINKS};
+use au::IncomeYearGains;
+use limits::LimitOpts;
+use output::{Output, OutputFormat, Row, SchemaVersion, Value};
+use performance::Performance;
+use prices::{PriceOpts, Quote};
+#[cfg(feature = "wasm")]
+use plugin::WasmStrategy;
+#[cfg(feature = "script")]
+use script::Script;
+use section1256::Section1256;
+use workload::WorkloadOpts;
+
+/// Number of lines parsed into a single batch before it is sent to the applying thread.
+const BATCH_SIZE: usize = 1024;
+
+/// Number of parsed batches that can be buffered before the reading thread blocks.
+const PIPELINE_DEPTH: usize = 16;
+
+/// Number of lines with errors in a column that `check schema` writes as examples.
+const SCHEMA_EXAMPLES: usize = 3;
+
+/// Represents the command line arguments. Every option can also be set with a `TAXLOT_` environment variable
+/// named after it, e.g. `TAXLOT_MIN_HOLDING_DAYS`, which the command line takes precedence over.
+///
+/// `command`: A selection algorithm to process the input with (e.g. fifo, hifo), or `check`/`add`/`gen`/`bench`/`diff`.
+/// `config`: File of `name = value` lines setting options, which the environment and command line take precedence
+/// over.
+/// `input`: File to read lot operations from instead of stdin. The file is memory-mapped. With the `https` feature,
+/// it can also be an `https://` URL, e.g. a published spreadsheet, which is read as it is downloaded.
+/// `bearer_token`: Token sent in the `Authorization` header when the input is a URL.
+/// `input_dialect`: Format of the input lines, e.g. `signed` for lines without a type and negative quantities for sells.
+/// `fixed_width`: Column layout of fixed-width input lines, instead of delimited lines in the input dialect.
+/// `xml`: Mapping of the records of an XML input document to lot operations, instead of reading lines.
+/// `encoding`: Character encoding of the input, detected from its start by default, e.g. for UTF-16 exports.
+/// `comment_prefix`: Also skip input lines starting with this, e.g. `//`, like blank lines and lines starting with `#`.
+/// `script`: Rhai script with a `transform(op)` function applied to every operation, a `compare(a, b, sell)` function
+/// ordering the lots sells deduct from, or both. Requires the `script` feature.
+/// `wasm_strategy`: WebAssembly plugin that orders the lots sells deduct from, taking precedence over a `compare`
+/// function of the script. Requires the `wasm` feature.
+/// `locked_lots`: Ids of lots that sells must skip, e.g. lots earmarked for donation. Repeat or comma-separate.
+/// `min_holding_days`: Only sell lots held for more than this many days, failing if a sell cannot be filled.
+/// `merge_policy`: Whether buys on the same date are merged into one lot or kept as separate lots.
+/// `merge_window`: Period that buys are merged within, e.g. one lot per month.
+/// `id_scheme`: How ids are assigned to new lots: sequentially, randomly or from the buy that created the lot.
+/// `start_id`: Id of the first lot with the sequential id scheme.
+/// `id_prefix`: Text printed before every lot id, e.g. `LOT-`.
+/// `id_width`: Minimum number of digits of printed lot ids, padded with leading zeros.
+/// `journal`: Keep every operation in memory so that later `amend` and `cancel` operations can correct them.
+/// `checkpoint`: File to periodically write the lots to, and to resume from if it exists.
+/// `checkpoint_every`: Number of operations between checkpoints.
+/// `checkpoint_secs`: Also write a checkpoint once this many seconds have passed since the last one.
+/// `gains_report`: File to write the short-term and long-term gains realized in each year to.
+/// `jurisdiction`: Tax rules of the gains and disposals reports, e.g. `au` for the CGT discount by income year.
+/// `section_1256_tag`: Tag of the operations on Section 1256 contracts, e.g. futures, which are matched apart from
+/// the other lots and marked to market at the end of each year at the quotes of `--mark-prices`, with 60/40 gains.
+/// `quarterly_report`: File to write the gains realized in each quarter to, with running totals for each year.
+/// `income_report`: File to write the dividend and interest income of each year to.
+/// `disposals_report`: File to write every part of a lot that was sold to, with its cost basis and proceeds.
+/// `spill_disposals`: Keep at most this many disposals in memory, spilling older ones to a temporary file.
+/// `spill_lots`: Keep at most this many open lots in memory, spilling the last ones in the sell order to a temporary
+/// file.
+/// `unrealized_report`: File to write the gain or loss of selling each remaining lot at the mark price to.
+/// `performance_report`: File to write the money-weighted and time-weighted returns of the buys and sells to, with
+/// the remaining lots valued at the mark price.
+/// `prices`: Where the mark price of the unrealized gains and performance reports comes from.
+/// `export_sqlite`: SQLite database to write the lots, disposals and yearly gains to. Requires the `sqlite` feature.
+/// `postgres`: PostgreSQL database and tables to upsert the lots and disposals into. Requires the `postgres` feature.
+/// `format`: Format the lots and reports are written in, e.g. `json`.
+/// `schema_version`: Version of the columns to write, starting every output with a header line naming them.
+/// `print_filter`: Which of the remaining lots are printed, by their tags or a filter expression.
+/// `alerts`: Conditions that run a command or call a webhook when they are met while the input is processed.
+/// `limits`: Limits on the size of the input, e.g. the longest line, that fail the run when exceeded.
 #[derive(Parser)]
 pub struct TaxLotOpts {
     #[clap(subcommand)]
-    selection_algo: SelectionAlgorithm,
-}
-
-/// Central enum for errors that can occur when processing tax lots.
-#[derive(Debug, Error)]
-pub enum TaxLotError {
-    #[error("Could not parse date. Format: YYYY-mm-DD")]
-    DateParseError(#[from] ParseError),
-    #[error("Could not parse lot operation. {0} field does not exist")]
-    FieldDoesntExist(String),
-    #[error("Could not parse Lot Type. Options: buy, sell")]
-    ParseLotTypeError,
-    #[error("Could not parse Decimal")]
-    DecimalParseError(#[from] rust_decimal::Error),
-    #[error("Overflow occurred while {0}")]
-    DecimalOverflow(String),
-    #[error("Underflow occurred while {0}")]
-    DecimalUnderflow(String),
-    #[error("Could not parse price: price cannot be negative")]
-    NegativePrice,
-    #[error("Could not parse quantity: quantity cannot be negative")]
-    NegativeQuantity,
-}
-
-/// Represents the selection algorithm for how the tax lots are sold.
-/// 
-/// fifo: tax lot that is bought first is also sold first
-/// hifo: tax lot with the highest price is sold first.
-#[derive(Debug, Subcommand, Clone, Copy)]
-pub enum SelectionAlgorithm {
-    #[clap(name = "fifo")]
-    Fifo,
-
-    #[clap(name = "hifo")]
-    Hifo,
-}
-
-/// Represents the type of operation that can be applied to the tax lots.
-/// 
-/// Buy: create a new tax lot if no date currently exists or merge with existing tax lot.
-/// Sell: Deduct the shares from the tax lots according to the selection algorithm.
-#[derive(Debug, Eq, PartialEq)]
-pub enum LotType {
-    Buy,
-    Sell,
-}
-
-impl FromStr for LotType {
-    type Err = TaxLotError;
-
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
-        match s.to_lowercase().trim() {
-            "buy" => Ok(LotType::Buy),
-            "sell" => Ok(LotType::Sell),
-            _ => Err(TaxLotError::ParseLotTypeError),
-        }
-    }
-}
-
-/// Represents an operation that can be applied to the tax lots. These lot operations
-/// are parsed from stdin.
-#[derive(Debug)]
-struct LotOperation {
-    date: NaiveDate,
-    lot_type: LotType,
-    price: Decimal,
-    quantity: Decimal,
-}
-
-impl FromStr for LotOperation {
-    type Err = TaxLotError;
-
-    fn from_str(s: &str) -> Result<Self, TaxLotError> {
-        let parts: Vec<&str> = s.split(',').collect();
-        let date = NaiveDate::parse_from_str(
-            LotOperation::get_field_from_parts(&parts, 0, "Date".to_string())?,
-            "%Y-%m-%d",
-        )?;
-        let lot_type = LotType::from_str(LotOperation::get_field_from_parts(&parts, 1, "Lot Type".to_string())?)?;
-        let price = Decimal::from_str(LotOperation::get_field_from_parts(&parts, 2, "Price".to_string())?)?;
-        if price <= Decimal::ZERO {
-            return Err(TaxLotError::NegativePrice);
-        }
-        let quantity =
-            Decimal::from_str(LotOperation::get_field_from_parts(&parts, 3, "Quantity".to_string())?)?;
-        if quantity <= Decimal::ZERO {
-            return Err(TaxLotError::NegativeQuantity);
-        }
-
-        Ok(LotOperation {
-            date,
-            lot_type,
-            price,
-            quantity,
-        })
+    command: Command,
+
+    #[clap(long, value_name = "FILE", env = "TAXLOT_CONFIG", global = true)]
+    config: Option<PathBuf>,
+
+    #[clap(long, env = "TAXLOT_INPUT", global = true)]
+    input: Option<PathBuf>,
+
+    #[cfg(feature = "https")]
+    #[clap(long, value_name = "TOKEN", env = "TAXLOT_BEARER_TOKEN", hide_env_values = true, global = true)]
+    bearer_token: Option<String>,
+
+    #[clap(long, value_enum, default_value_t = InputDialect::Standard, env = "TAXLOT_INPUT_DIALECT", global = true)]
+    input_dialect: InputDialect,
+
+    #[clap(long, value_name = "LAYOUT", conflicts_with = "input_dialect", env = "TAXLOT_FIXED_WIDTH", global = true)]
+    fixed_width: Option<FixedWidthLayout>,
+
+    #[clap(
+        long,
+        value_name = "MAPPING",
+        conflicts_with_all = ["input_dialect", "fixed_width"],
+        env = "TAXLOT_XML",
+        global = true
+    )]
+    xml: Option<XmlMapping>,
+
+    #[clap(long, value_enum, default_value_t = Encoding::Auto, env = "TAXLOT_ENCODING", global = true)]
+    encoding: Encoding,
+
+    #[clap(long, value_name = "PREFIX", conflicts_with = "xml", env = "TAXLOT_COMMENT_PREFIX", global = true)]
+    comment_prefix: Option<String>,
+
+    #[cfg(feature = "script")]
+    #[clap(long, value_name = "FILE", env = "TAXLOT_SCRIPT", global = true)]
+    script: Option<PathBuf>,
+
+    #[cfg(feature = "wasm")]
+    #[clap(long, value_name = "FILE", env = "TAXLOT_WASM_STRATEGY", global = true)]
+    wasm_strategy: Option<PathBuf>,
+
+    #[clap(long = "lock", value_name = "LOT_ID", value_delimiter = ',', env = "TAXLOT_LOCK", global = true)]
+    locked_lots: Vec<u64>,
+
+    #[clap(long, value_name = "DAYS", env = "TAXLOT_MIN_HOLDING_DAYS", global = true)]
+    min_holding_days: Option<u32>,
+
+    #[clap(long, value_enum, default_value_t = MergePolicy::WeightedAverage, env = "TAXLOT_MERGE_POLICY", global = true)]
+    merge_policy: MergePolicy,
+
+    #[clap(long, value_enum, default_value_t = MergeWindow::Day, env = "TAXLOT_MERGE_WINDOW", global = true)]
+    merge_window: MergeWindow,
+
+    #[clap(long, value_enum, default_value_t = IdScheme::Sequential, env = "TAXLOT_ID_SCHEME", global = true)]
+    id_scheme: IdScheme,
+
+    #[clap(long, value_name = "LOT_ID", default_value_t = 1, env = "TAXLOT_START_ID", global = true)]
+    start_id: u64,
+
+    #[clap(long, value_name = "PREFIX", default_value = "", env = "TAXLOT_ID_PREFIX", global = true)]
+    id_prefix: String,
+
+    #[clap(long, value_name = "DIGITS", default_value_t = 0, env = "TAXLOT_ID_WIDTH", global = true)]
+    id_width: usize,
+
+    #[clap(long, value_parser = BoolishValueParser::new(), env = "TAXLOT_JOURNAL", global = true)]
+    journal: bool,
+
+    #[clap(
+        long,
+        value_name = "FILE",
+        env = "TAXLOT_CHECKPOINT",
+        global = true,
+        help = "Write the lots to FILE periodically, and resume from it if it exists. On resume, as many operations \
+            as the checkpoint covers are skipped at the start of the input, so it must be the same input, possibly \
+            with operations appended. Cannot be combined with --journal, since the journal is not checkpointed."
+    )]
+    checkpoint: Option<PathBuf>,
+
+    #[clap(
+        long,
+        value_name = "OPS",
+        default_value_t = 100_000,
+        value_parser = clap::value_parser!(u64).range(1..),
+        env = "TAXLOT_CHECKPOINT_EVERY",
+        global = true
+    )]
+    checkpoint_every: u64,
+
+    #[clap(long, value_name = "SECONDS", env = "TAXLOT_CHECKPOINT_SECS", global = true)]
+    checkpoint_secs: Option<u64>,
+
+    #[clap(long, value_name = "FILE", env = "TAXLOT_GAINS_REPORT", global = true)]
+    gains_report: Option<PathBuf>,
+
+    #[clap(long, value_enum, default_value_t = Jurisdiction::Us, env = "TAXLOT_JURISDICTION", global = true)]
+    jurisdiction: Jurisdiction,
+
+    #[clap(long, value_name = "TAG", env = "TAXLOT_SECTION_1256_TAG", global = true)]
+    section_1256_tag: Option<String>,
+
+    #[clap(long, value_name = "FILE", env = "TAXLOT_QUARTERLY_REPORT", global = true)]
+    quarterly_report: Option<PathBuf>,
+
+    #[clap(long, value_name = "FILE", env = "TAXLOT_INCOME_REPORT", global = true)]
+    income_report: Option<PathBuf>,
+
+    #[clap(long, value_name = "FILE", env = "TAXLOT_DISPOSALS_REPORT", global = true)]
+    disposals_report: Option<PathBuf>,
+
+    #[clap(long, value_name = "RECORDS", env = "TAXLOT_SPILL_DISPOSALS", global = true)]
+    spill_disposals: Option<usize>,
+
+    #[clap(long, value_name = "LOTS", env = "TAXLOT_SPILL_LOTS", global = true)]
+    spill_lots: Option<usize>,
+
+    #[clap(long, value_name = "FILE", env = "TAXLOT_UNREALIZED_REPORT", global = true)]
+    unrealized_report: Option<PathBuf>,
+
+    #[clap(long, value_name = "FILE", env = "TAXLOT_PERFORMANCE_REPORT", global = true)]
+    performance_report: Option<PathBuf>,
+
+    #[clap(flatten)]
+    prices: PriceOpts,
+
+    #[cfg(feature = "sqlite")]
+    #[clap(long, value_name = "FILE", env = "TAXLOT_EXPORT_SQLITE", global = true)]
+    export_sqlite: Option<PathBuf>,
+
+    #[cfg(feature = "postgres")]
+    #[clap(flatten)]
+    postgres: export::PostgresOpts,
+
+    #[clap(long, value_enum, default_value_t = OutputFormat::Csv, env = "TAXLOT_FORMAT", global = true)]
+    format: OutputFormat,
+
+    #[clap(long, value_enum, value_name = "VERSION", env = "TAXLOT_SCHEMA_VERSION", global = true)]
+    schema_version: Option<SchemaVersion>,
+
+    #[clap(flatten)]
+    print_filter: PrintFilter,
+
+    #[clap(flatten)]
+    alerts: AlertOpts,
+
+    #[clap(flatten)]
+    limits: LimitOpts,
+}
+
+/// Represents which lots are printed according to their tags and a filter expression. Lots are still bought and
+/// sold regardless of whether they are printed, e.g. so lots in a tax-advantaged account stay part of the position.
+///
+/// `include`: If any are given, only lots with at least one of these tags are printed.
+/// `exclude`: Lots with any of these tags are not printed.
+/// `expression`: If given, only lots that match it are printed, e.g. `price > 10000 && date < 2022-01-01`.
+#[derive(Args, Default)]
+struct PrintFilter {
+    #[clap(long = "filter-tag", value_name = "TAG", value_delimiter = ',', env = "TAXLOT_FILTER_TAG", global = true)]
+    include: Vec<String>,
+
+    #[clap(long = "exclude-tag", value_name = "TAG", value_delimiter = ',', env = "TAXLOT_EXCLUDE_TAG", global = true)]
+    exclude: Vec<String>,
+
+    #[clap(long = "filter", value_name = "EXPRESSION", env = "TAXLOT_FILTER", global = true)]
+    expression: Option<LotFilter>,
+}
+
+impl PrintFilter {
+    /// Returns true if `lot` should be printed.
+    fn matches(&self, lot: &Lot) -> bool {
+        let included = self.include.is_empty() || self.include.iter().any(|tag| lot.has_tag(tag));
+        included
+            && !self.exclude.iter().any(|tag| lot.has_tag(tag))
+            && self.expression.as_ref().is_none_or(|expression| expression.matches(lot))
     }
 }
 
-impl LotOperation {
-    /// Create a new lot from a lot operation. A new lot should be created when the `LotCollection` 
-    /// does not have a lot for the date of the `LotOperation`.
-    fn create_new_lot(self, id_generator: &AtomicU64, selection_algo: SelectionAlgorithm) -> Lot {
-        Lot {
-            id: id_generator.fetch_add(1, Ordering::SeqCst),
-            date: self.date,
-            price: self.price,
-            quantity: self.quantity,
-            selection_algo,
+/// Represents the subcommands. Each selection algorithm is a subcommand that processes the
+/// lot operations from the input and prints the remaining tax lots.
+#[derive(Subcommand)]
+enum Command {
+    #[clap(flatten)]
+    Process(SelectionAlgorithm),
+
+    /// Validate an input file without processing it.
+    #[clap(subcommand)]
+    Check(Check),
+
+    /// Enter a lot operation column by column, and append it to a file in the input dialect once confirmed.
+    Add { file: PathBuf },
+
+    /// Generate random lot operations and write them to stdout.
+    Gen(WorkloadOpts),
+
+    /// Time processing generated lot operations with every selection algorithm.
+    Bench(WorkloadOpts),
+
+    /// Process the input with a selection algorithm, then insert backdated operations after the last operation on
+    /// or before their date, and write the lots and disposals that each of them changes.
+    Insert {
+        algorithm: SelectionAlgorithm,
+        #[clap(value_name = "OPERATION", required = true)]
+        operations: Vec<String>,
+    },
+
+    /// Process two input files with the same selection algorithm and settings, and write the lots that differ
+    /// between them, matched by id.
+    Diff {
+        algorithm: SelectionAlgorithm,
+        old: PathBuf,
+        new: PathBuf,
+    },
+}
+
+/// Represents the validations of the `check` subcommand.
+#[derive(Subcommand)]
+enum Check {
+    /// Validate every line or record of a file against the columns of the input format, and write the errors in each
+    /// column.
+    Schema { file: PathBuf },
+}
+
+/// Represents the tax rules that the gains and disposals reports follow.
+///
+/// us: Gains are short-term or long-term, by calendar year.
+/// au: Gains of lots held for at least 12 months are discounted by half, by income year ending 30 June, and each
+/// disposal has the method of its gain. See `au`.
+#[derive(Debug, ValueEnum, Clone, Copy, Default, PartialEq, Eq)]
+enum Jurisdiction {
+    #[default]
+    Us,
+    Au,
+}
+
+/// Represents how the input is parsed into lot operations: line by line, or as an XML document.
+#[derive(Clone)]
+enum InputFormat {
+    Delimited(InputDialect),
+    FixedWidth(FixedWidthLayout),
+    Xml(XmlMapping),
+}
+
+impl InputFormat {
+    /// Parses a single line into a lot operation. Lines given alongside an XML document, e.g. to `insert`,
+    /// are in the standard format.
+    fn parse(&self, line: &str) -> Result<LotOperation, TaxLotError> {
+        match self {
+            InputFormat::Delimited(dialect) => dialect.parse(line),
+            InputFormat::FixedWidth(layout) => layout.parse(line),
+            InputFormat::Xml(_) => InputDialect::Standard.parse(line),
+        }
+    }
+
+    /// Returns a problem with each column of a single line, like `parse` returns the first of them.
+    fn check(&self, line: &str) -> Vec<ColumnError> {
+        match self {
+            InputFormat::Delimited(dialect) => dialect.check(line),
+            InputFormat::FixedWidth(layout) => layout.check(line),
+            InputFormat::Xml(_) => InputDialect::Standard.check(line),
         }
     }
 
-    /// Returns a `&str` from the vector of string slices according to the given index. Performs error
-    /// checking to validate that the field exists. 
-    fn get_field_from_parts<'a>(parts: &'a Vec<&str>, index: usize, field_name: String) -> Result<&'a str, TaxLotError> {
-        match parts.get(index) {
-            Some(field) => Ok(field),
-            None => Err(TaxLotError::FieldDoesntExist(field_name))
+    /// Returns the columns that `check_schema` validates.
+    fn columns(&self) -> &'static [Column] {
+        match self {
+            InputFormat::Delimited(dialect) => dialect.columns(),
+            InputFormat::FixedWidth(layout) => layout.columns(),
+            InputFormat::Xml(mapping) => mapping.columns(),
         }
     }
 }
 
-/// Checked addition operation that maps an `Option` to a `Result` in case the operation
-/// overflows.
-fn checked_add(left: Decimal, right: Decimal) -> Result<Decimal, TaxLotError> {
-    match left.checked_add(right) {
-        Some(result) => Ok(result),
-        None => Err(TaxLotError::DecimalOverflow("adding".to_string()))
+/// Represents how the lot operations are read from the input.
+///
+/// `format`: How the input is parsed into lot operations.
+/// `encoding`: Character encoding of the input, which `auto` detects from the start of it.
+/// `comment_prefix`: Lines starting with it are skipped, like blank lines and lines starting with `#`.
+/// `limits`: Limits on the size of the input.
+/// `script`: Script whose `transform` is applied to every operation before it is applied.
+#[derive(Clone)]
+struct InputReader {
+    format: InputFormat,
+    encoding: Encoding,
+    comment_prefix: Option<String>,
+    limits: LimitOpts,
+    #[cfg(feature = "script")]
+    script: Option<Arc<Script>>,
+}
+
+impl InputReader {
+    /// Returns true if a decoded `line` is not a lot operation, i.e. it is blank or a comment. Comments can be
+    /// indented.
+    fn is_skipped(&self, line: &str) -> bool {
+        let line = line.trim_start();
+        line.is_empty()
+            || line.starts_with('#')
+            || self.comment_prefix.as_deref().is_some_and(|prefix| line.starts_with(prefix))
+    }
+
+    /// Returns the operation to apply for `lot_operation`, as changed by the script, or `None` if the script skips
+    /// it.
+    fn transform(&self, lot_operation: LotOperation) -> Result<Option<LotOperation>, TaxLotError> {
+        #[cfg(feature = "script")]
+        if let Some(script) = &self.script {
+            return script.transform(lot_operation);
+        }
+        Ok(Some(lot_operation))
     }
 }
 
-/// Checked multiplication operation that maps an `Option` to a `Result` in case the operation
-/// overflows.
-fn checked_mul(left: Decimal, right: Decimal) -> Result<Decimal, TaxLotError> {
-    match left.checked_mul(right) {
-        Some(result) => Ok(result),
-        None => Err(TaxLotError::DecimalOverflow("multiplying".to_string()))
+/// Represents where the lot operations are read from.
+enum Input {
+    Stdin,
+    File(Mmap),
+    Memory(Arc<[u8]>),
+    #[cfg(feature = "https")]
+    Remote(ureq::BodyReader<'static>),
+}
+
+fn main() {
+    let args: Vec<OsString> = env::args_os().collect();
+    if let Err(e) = config::load(&TaxLotOpts::command(), &args) {
+        eprintln!("{e}");
+        process::exit(1);
+    }
+    let opts = TaxLotOpts::parse_from(args);
+    if let Err(e) = check_flags(&opts) {
+        e.exit();
+    }
+    if let Err(e) = run(opts) {
+        eprintln!("{e}");
+        process::exit(1);
     }
 }
 
-/// Checked division operation that maps an `Option` to a `Result` in case the operation
-/// underflows.
-fn checked_div(left: Decimal, right: Decimal) -> Result<Decimal, TaxLotError> {
-    match left.checked_div(right) {
-        Some(result) => Ok(result),
-        None => Err(TaxLotError::DecimalUnderflow("dividing".to_string()))
+/// Checks the conflicts and requirements of the flags in `opts`. Clap cannot check them itself, because it
+/// counts a flag set to false in the environment, e.g. `TAXLOT_JOURNAL=0`, as given.
+fn check_flags(opts: &TaxLotOpts) -> Result<(), clap::Error> {
+    let mut command = TaxLotOpts::command();
+    if opts.journal && opts.checkpoint.is_some() {
+        let message = "the argument '--checkpoint <FILE>' cannot be used with '--journal'";
+        return Err(command.error(ErrorKind::ArgumentConflict, message));
+    }
+    if let (Some(alert), false) = (opts.alerts.first_alert(), opts.alerts.has_sink()) {
+        let message = format!("the argument '{alert}' requires {ALERT_SINKS}");
+        return Err(command.error(ErrorKind::MissingRequiredArgument, message));
     }
+    for (report, path) in [("unrealized", &opts.unrealized_report), ("performance", &opts.performance_report)] {
+        if path.is_some() && !opts.prices.is_set() {
+            let message = format!(
+                "the argument '--{report}-report <FILE>' requires '--mark-price <PRICE>', '--mark-prices <FILE>' \
+                or '--price-provider <PROVIDER:ID>'"
+            );
+            return Err(command.error(ErrorKind::MissingRequiredArgument, message));
+        }
+    }
+    if opts.section_1256_tag.is_some() {
+        if opts.prices.mark_prices.is_none() {
+            let message = "the argument '--section-1256-tag <TAG>' requires '--mark-prices <FILE>'";
+            return Err(command.error(ErrorKind::MissingRequiredArgument, message));
+        }
+        if opts.checkpoint.is_some() || opts.jurisdiction == Jurisdiction::Au {
+            let message = "the argument '--section-1256-tag <TAG>' cannot be used with '--checkpoint <FILE>' or \
+                '--jurisdiction au'";
+            return Err(command.error(ErrorKind::ArgumentConflict, message));
+        }
+    }
+    Ok(())
 }
 
-/// Checked subtraction operation that maps an `Option` to a `Result` in case the operation
-/// underflows.
-fn checked_sub(left: Decimal, right: Decimal) -> Result<Decimal, TaxLotError> {
-    match left.checked_sub(right) {
-        Some(result) => Ok(result),
-        None => Err(TaxLotError::DecimalUnderflow("subtracting".to_string()))
+fn run(opts: TaxLotOpts) -> Result<(), TaxLotError> {
+    let TaxLotOpts {
+        command,
+        config: _,
+        input,
+        #[cfg(feature = "https")]
+        bearer_token,
+        input_dialect,
+        fixed_width,
+        xml,
+        encoding,
+        comment_prefix,
+        #[cfg(feature = "script")]
+        script,
+        #[cfg(feature = "wasm")]
+        wasm_strategy,
+        locked_lots,
+        min_holding_days,
+        merge_policy,
+        merge_window,
+        id_scheme,
+        start_id,
+        id_prefix,
+        id_width,
+        journal,
+        checkpoint,
+        checkpoint_every,
+        checkpoint_secs,
+        gains_report,
+        jurisdiction,
+        section_1256_tag,
+        quarterly_report,
+        income_report,
+        disposals_report,
+        spill_disposals,
+        spill_lots,
+        unrealized_report,
+        performance_report,
+        prices,
+        #[cfg(feature = "sqlite")]
+        export_sqlite,
+        #[cfg(feature = "postgres")]
+        postgres,
+        format,
+        schema_version,
+        print_filter,
+        alerts,
+        limits,
+    } = opts;
+
+    #[cfg(feature = "script")]
+    let script = script.as_deref().map(Script::load).transpose()?;
+    #[cfg(feature = "wasm")]
+    let wasm_strategy = wasm_strategy.as_deref().map(WasmStrategy::load).transpose()?.map(Arc::new);
+
+    // Every lot collection of a run has the same settings, e.g. those of both inputs of `diff`.
+    let new_collection = |selection_algo| {
+        let mut lot_collection = LotCollection::new(selection_algo);
+        #[cfg(feature = "script")]
+        if let Some(sell_strategy) = script.as_ref().and_then(Script::sell_strategy) {
+            lot_collection.set_sell_strategy(sell_strategy);
+        }
+        #[cfg(feature = "wasm")]
+        if let Some(wasm_strategy) = &wasm_strategy {
+            lot_collection.set_sell_strategy(Box::new(Arc::clone(wasm_strategy)));
+        }
+        lot_collection.set_merge_policy(merge_policy);
+        lot_collection.set_merge_window(merge_window);
+        lot_collection.set_id_scheme(id_scheme);
+        lot_collection.set_start_id(start_id);
+        for &id in &locked_lots {
+            lot_collection.lock_lot(id);
+        }
+        if let Some(days) = min_holding_days {
+            lot_collection.set_min_holding_days(days);
+        }
+        if let Some(max) = limits.max_open_lots {
+            lot_collection.set_max_open_lots(max);
+        }
+        if let Some(limit) = spill_lots {
+            lot_collection.spill_lots(limit);
+        }
+        lot_collection
+    };
+    let input_format = match (fixed_width, xml) {
+        (Some(layout), _) => InputFormat::FixedWidth(layout),
+        (None, Some(mapping)) => InputFormat::Xml(mapping),
+        (None, None) => InputFormat::Delimited(input_dialect),
+    };
+    let reader = InputReader {
+        format: input_format,
+        encoding,
+        comment_prefix,
+        limits,
+        #[cfg(feature = "script")]
+        script: script.clone(),
+    };
+    let id_format = IdFormat { prefix: id_prefix, width: id_width };
+    let output = Output { format, schema_version };
+
+    // Map the input file or request the URL before processing, so that a missing
+    // file is reported before any lot operations are applied.
+    let open_input = move || -> Result<Input, TaxLotError> {
+        match input {
+            #[cfg(feature = "https")]
+            Some(path) if is_url(&path) => Ok(Input::Remote(fetch(&path, bearer_token.as_deref())?)),
+            #[cfg(not(feature = "https"))]
+            Some(path) if is_url(&path) => {
+                let message = "reading a URL requires the `https` feature";
+                Err(TaxLotError::RemoteInputError(message.to_string()))
+            }
+            Some(path) => Ok(Input::File(map_file(&path)?)),
+            None => Ok(Input::Stdin),
+        }
+    };
+
+    match command {
+        Command::Process(selection_algo) => {
+            let input = open_input()?;
+            // Likewise, look up the mark price before processing.
+            let mark = match unrealized_report.is_some() || performance_report.is_some() {
+                true => Some(prices.quote()?),
+                false => None,
+            };
+
+            let mut lot_collection = new_collection(selection_algo);
+            if journal {
+                lot_collection.enable_journal();
+            }
+            #[cfg(feature = "sqlite")]
+            let exports_disposals = export_sqlite.is_some();
+            #[cfg(not(feature = "sqlite"))]
+            let exports_disposals = false;
+            #[cfg(feature = "postgres")]
+            let exports_disposals = exports_disposals || postgres.export.is_some();
+            match spill_disposals {
+                Some(limit) => lot_collection.spill_disposals(limit),
+                None if disposals_report.is_some()
+                    || performance_report.is_some()
+                    || exports_disposals
+                    || (jurisdiction == Jurisdiction::Au && gains_report.is_some()) =>
+                {
+                    lot_collection.enable_disposals()
+                }
+                None => {}
+            }
+            let mut checkpointer = match checkpoint {
+                Some(path) => Some(Checkpointer::restore(
+                    path,
+                    checkpoint_every,
+                    checkpoint_secs.map(Duration::from_secs),
+                    &mut lot_collection,
+                )?),
+                None => None,
+            };
+            let mut contracts = match (section_1256_tag, &prices.mark_prices) {
+                (Some(tag), Some(path)) => {
+                    Some(Section1256::new(tag, &prices::read_quotes(path)?, new_collection(selection_algo)))
+                }
+                _ => None,
+            };
+            let mut alerts = Alerts::new(alerts, &lot_collection);
+            process_input(
+                input,
+                reader.clone(),
+                &mut lot_collection,
+                checkpointer.as_mut(),
+                contracts.as_mut(),
+                &mut alerts,
+            )?;
+            if let Some(checkpointer) = &mut checkpointer {
+                checkpointer.write(&lot_collection)?;
+            }
+            if let Some(contracts) = &mut contracts {
+                contracts.finish(Local::now().date_naive())?;
+            }
+            write_lots(&lot_collection, &print_filter, &id_format, &output, io::stdout().lock())?;
+            if let Some(path) = gains_report {
+                write_gains(&lot_collection, contracts.as_ref(), jurisdiction, &output, File::create(path)?)?;
+            }
+            if let Some(path) = quarterly_report {
+                write_quarterly_gains(&lot_collection, &output, File::create(path)?)?;
+            }
+            if let Some(path) = income_report {
+                write_income(&lot_collection, &output, File::create(path)?)?;
+            }
+            if let Some(path) = disposals_report {
+                write_disposals(&lot_collection, jurisdiction, &id_format, &output, File::create(path)?)?;
+            }
+            if let (Some(path), Some(mark)) = (unrealized_report, mark) {
+                write_unrealized(&lot_collection, mark, &id_format, &output, File::create(path)?)?;
+            }
+            if let (Some(path), Some(mark)) = (performance_report, mark) {
+                let performance = performance::performance(&lot_collection, mark)?;
+                output.write(performance.into_iter().map(PerformanceRow), File::create(path)?)?;
+            }
+            #[cfg(feature = "sqlite")]
+            if let Some(path) = export_sqlite {
+                export::sqlite(&lot_collection, &path)?;
+            }
+            #[cfg(feature = "postgres")]
+            if let Some(conninfo) = &postgres.export {
+                export::postgres(&lot_collection, conninfo, &postgres)?;
+            }
+        }
+        Command::Add { file } => {
+            entry::add(&file, input_dialect, io::stdin().lock(), io::stderr().lock())?;
+        }
+        Command::Gen(opts) => {
+            let mut out = BufWriter::new(io::stdout().lock());
+            workload::generate(&opts, &mut out)?;
+            out.flush()?;
+        }
+        Command::Bench(opts) => bench(&opts)?,
+        Command::Diff { algorithm, old, new } => {
+            let mut collections = Vec::with_capacity(2);
+            for path in [old, new] {
+                let mut lot_collection = new_collection(algorithm);
+                let input = Input::File(map_file(&path)?);
+                process_input(input, reader.clone(), &mut lot_collection, None, None, &mut Alerts::default())?;
+                collections.push(lot_collection);
+            }
+            write_diff(&collections[0], &collections[1], &id_format, &output, io::stdout().lock())?;
+        }
+        Command::Insert { algorithm, operations } => {
+            let input = open_input()?;
+            let mut lot_collection = new_collection(algorithm);
+            lot_collection.enable_journal();
+            match spill_disposals {
+                Some(limit) => lot_collection.spill_disposals(limit),
+                None => lot_collection.enable_disposals(),
+            }
+            process_input(input, reader.clone(), &mut lot_collection, None, None, &mut Alerts::default())?;
+            let mut inserted = Vec::with_capacity(operations.len());
+            for line in &operations {
+                let realized_gains = lot_collection.realized_gains();
+                let Some(lot_operation) = reader.transform(reader.format.parse(line)?)? else {
+                    continue;
+                };
+                let changes = lot_collection.insert_lot_operation(lot_operation)?;
+                let message = format!(
+                    "Inserted {line}: realized gains {realized_gains:.2} -> {:.2}",
+                    lot_collection.realized_gains()
+                );
+                output.write_status::<InsertChangeRow>(&message, io::stderr())?;
+                inserted.push((line.as_str(), changes));
+            }
+            write_changes(&inserted, &id_format, &output, io::stdout().lock())?;
+        }
+        Command::Check(Check::Schema { file }) => {
+            check_schema(&file, &reader, &output, io::stdout().lock(), io::stderr())?;
+        }
     }
+
+    Ok(())
 }
 
-/// Represents a TaxLot that can be sold. `LotOperation`s can be merged if they have been bought on the
-/// same date.
-#[derive(Debug)]
-struct Lot {
-    id: u64,
-    date: NaiveDate,
-    price: Decimal,
-    quantity: Decimal,
-    selection_algo: SelectionAlgorithm,
+/// Generates a workload in memory and times processing it end to end, including writing the
+/// remaining lots, with each selection algorithm.
+fn bench(opts: &WorkloadOpts) -> Result<(), TaxLotError> {
+    let mut input = Vec::new();
+    workload::generate(opts, &mut input)?;
+    let input: Arc<[u8]> = input.into();
+
+    for selection_algo in SelectionAlgorithm::ALL {
+        let start = Instant::now();
+        let mut lot_collection = LotCollection::new(selection_algo);
+        process_input(
+            Input::Memory(Arc::clone(&input)),
+            InputReader {
+                format: InputFormat::Delimited(InputDialect::Standard),
+                encoding: Encoding::default(),
+                comment_prefix: None,
+                limits: LimitOpts::default(),
+                #[cfg(feature = "script")]
+                script: None,
+            },
+            &mut lot_collection,
+            None,
+            None,
+            &mut Alerts::default(),
+        )?;
+        write_lots(&lot_collection, &PrintFilter::default(), &IdFormat::default(), &Output::default(), io::sink())?;
+        let elapsed = start.elapsed().as_secs_f64();
+
+        println!(
+            "{selection_algo}: {} operations in {elapsed:.3}s ({:.0} operations/s), {} open lots",
+            opts.ops,
+            opts.ops as f64 / elapsed,
+            lot_collection.lots().count()
+        );
+    }
+
+    Ok(())
 }
 
-impl Display for Lot {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        // Display price with two decimals and quantity with 8 decimals.
-        write!(
-            f,
-            "{},{},{:.2},{:.8}",
-            self.id, self.date, self.price, self.quantity
-        )
+/// Validates every line or record of the file at `path` against the columns of the format of the `reader`, and
+/// writes the number of errors in each column, with the first `SCHEMA_EXAMPLES` of them. The file is decoded, and
+/// blank lines and comments are skipped, as when it is processed. Fails with `SchemaErrors` if any row has errors,
+/// and otherwise writes the number of rows that were checked to `status`.
+fn check_schema(
+    path: &PathBuf,
+    reader: &InputReader,
+    output: &Output,
+    out: impl Write,
+    status: impl Write,
+) -> Result<(), TaxLotError> {
+    let mut columns: Vec<_> = reader
+        .format
+        .columns()
+        .iter()
+        .map(|&column| SchemaErrorsRow { column, errors: 0, examples: Vec::new() })
+        .collect();
+    let (mut rows, mut invalid_rows) = (0, 0);
+    let mut add = |kind: &str, number: usize, errors: Vec<ColumnError>| {
+        rows += 1;
+        if !errors.is_empty() {
+            invalid_rows += 1;
+        }
+        for error in errors {
+            if let Some(row) = columns.iter_mut().find(|row| row.column == error.column) {
+                row.errors += 1;
+                if row.examples.len() < SCHEMA_EXAMPLES {
+                    row.examples.push(format!("{kind} {number}: {}", error.message));
+                }
+            }
+        }
+    };
+
+    let input = map_file(path)?;
+    let (encoding, bom) = reader.encoding.detect(&input);
+    let input = &input[bom..];
+    match &reader.format {
+        InputFormat::Xml(mapping) => {
+            for (i, errors) in mapping.check(&encoding.decode(input)?).enumerate() {
+                add("record", i + 1, errors?);
+            }
+        }
+        format => {
+            let decoded = match encoding {
+                Encoding::Utf16le => Some(encoding.decode(input)?),
+                _ => None,
+            };
+            let (input, encoding) = match &decoded {
+                Some(decoded) => (decoded.as_bytes(), Encoding::Utf8),
+                None => (input, encoding),
+            };
+            let input = input.strip_suffix(b"\n").unwrap_or(input);
+            for (i, line) in input.split(|byte| *byte == b'\n').enumerate() {
+                let line = encoding.decode(trim_line_ending(line))?;
+                if reader.is_skipped(&line) {
+                    continue;
+                }
+                add("line", i + 1, format.check(&line));
+            }
+        }
+    }
+
+    output.write(columns.into_iter(), out)?;
+    if invalid_rows > 0 {
+        return Err(TaxLotError::SchemaErrors(rows, invalid_rows));
     }
+    output.write_status::<SchemaErrorsRow>(&format!("Checked {rows} rows without errors"), status)?;
+    Ok(())
 }
 
-impl Lot {
-    /// "Merge" takes a lot operation, verifies that the dates are the same,
-    /// computes the aggregate quantity, and then computes the weighted average
-    /// price.
-    fn merge(&mut self, lot_operation: LotOperation) -> Result<(), TaxLotError> {
-        // Verify that the dates are the same, otherwise this is an invalid operation.
-        assert!(lot_operation.date == self.date);
+/// Memory-maps the file at `path` for reading.
+fn map_file(path: &PathBuf) -> Result<Mmap, TaxLotError> {
+    let file = File::open(path)?;
+    // Safety: the mapping is only read, and the file is not expected to be modified while
+    // it is being processed.
+    Ok(unsafe { Mmap::map(&file)? })
+}
 
-        // Verify that the operation is a buy operation
-        assert!(lot_operation.lot_type == LotType::Buy);
+/// Returns true if the input `path` is an `http://` or `https://` URL rather than a file.
+fn is_url(path: &Path) -> bool {
+    path.to_str().is_some_and(|path| path.starts_with("https://") || path.starts_with("http://"))
+}
 
-        let left = checked_mul(self.price, self.quantity)?;
-        let right = checked_mul(lot_operation.price, lot_operation.quantity)?;
+/// Requests the input at `url`, sending `bearer_token` if given, and returns its body to be read as it downloads.
+/// Fails if the server does not respond with a success status.
+#[cfg(feature = "https")]
+fn fetch(url: &Path, bearer_token: Option<&str>) -> Result<ureq::BodyReader<'static>, TaxLotError> {
+    let url = url.to_str().unwrap_or_default();
+    let mut request = ureq::get(url);
+    if let Some(token) = bearer_token {
+        request = request.header("Authorization", format!("Bearer {token}"));
+    }
+    let response = request.call().map_err(|e| TaxLotError::RemoteInputError(format!("{url}: {e}")))?;
+    Ok(response.into_body().into_reader())
+}
 
-        self.quantity = checked_add(self.quantity, lot_operation.quantity)?;
+/// Periodically writes checkpoints of a lot collection while the input is processed, so that an
+/// interrupted run can resume from the last checkpoint.
+///
+/// `path`: File the checkpoints are written to. Each checkpoint is written to a temporary file first and
+/// then renamed, so the file always holds a complete checkpoint.
+/// `every`: Number of operations between checkpoints.
+/// `interval`: If set, a checkpoint is also written once this much time has passed since the last one.
+/// `operations`: Number of input operations applied so far, including the ones before the restored checkpoint.
+/// `restored`: Number of input operations that were already applied when the checkpoint was restored.
+/// `last_write`: When the last checkpoint was written.
+struct Checkpointer {
+    path: PathBuf,
+    every: u64,
+    interval: Option<Duration>,
+    operations: u64,
+    restored: u64,
+    last_write: Instant,
+}
 
-        let total = checked_add(left, right)?;
-        self.price = checked_div(total, self.quantity)?;
+impl Checkpointer {
+    /// Restores `lot_collection` from the checkpoint at `path`, if there is one.
+    fn restore(
+        path: PathBuf,
+        every: u64,
+        interval: Option<Duration>,
+        lot_collection: &mut LotCollection,
+    ) -> Result<Self, TaxLotError> {
+        let restored = match std::fs::read_to_string(&path) {
+            Ok(checkpoint) => lot_collection.restore_checkpoint(&checkpoint)?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => 0,
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Checkpointer {
+            path,
+            every,
+            interval,
+            operations: restored,
+            restored,
+            last_write: Instant::now(),
+        })
+    }
 
+    /// Returns true if the `index`th input operation was already applied before the checkpoint was restored.
+    fn is_restored(&self, index: u64) -> bool {
+        index < self.restored
+    }
+
+    /// Counts an applied operation, writing a checkpoint if one is due.
+    fn applied(&mut self, lot_collection: &LotCollection) -> io::Result<()> {
+        self.operations += 1;
+        let interval_passed = self.interval.is_some_and(|interval| self.last_write.elapsed() >= interval);
+        if self.operations.is_multiple_of(self.every) || interval_passed {
+            self.write(lot_collection)?;
+        }
+        Ok(())
+    }
+
+    /// Writes a checkpoint of `lot_collection`, replacing the previous one.
+    fn write(&mut self, lot_collection: &LotCollection) -> io::Result<()> {
+        let mut temporary_path = self.path.clone().into_os_string();
+        temporary_path.push(".tmp");
+        let mut out = BufWriter::new(File::create(&temporary_path)?);
+        lot_collection.write_checkpoint(self.operations, &mut out)?;
+        out.into_inner()?.sync_all()?;
+        std::fs::rename(&temporary_path, &self.path)?;
+        self.last_write = Instant::now();
         Ok(())
     }
 }
 
-impl Ord for Lot {
-    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
-        match &self.selection_algo {
-            SelectionAlgorithm::Fifo => self.date.cmp(&other.date),
-            // Reverse the comparison for `hifo` so that the lots are sorted from highest -> lowest.
-            SelectionAlgorithm::Hifo => other.price.cmp(&self.price),
+/// Applies every lot operation in `input`, read with `reader`, to `lot_collection`, stopping at the first error or
+/// the first of the limits of the `reader` that is exceeded. With a
+/// `checkpointer`, the operations that were applied before its checkpoint are skipped, and checkpoints are written
+/// as the remaining ones are applied. The `alerts` are checked after every operation that is applied.
+///
+/// The input is read and parsed on a separate thread so that I/O and parsing overlap with
+/// applying the lot operations to the lot collection. The thread is joined once the whole input is applied, failing
+/// with `TaxLotError::InputParserPanicked` if it panicked.
+fn process_input(
+    input: Input,
+    reader: InputReader,
+    lot_collection: &mut LotCollection,
+    mut checkpointer: Option<&mut Checkpointer>,
+    mut contracts: Option<&mut Section1256>,
+    alerts: &mut Alerts,
+) -> Result<(), TaxLotError> {
+    let (sender, receiver) = mpsc::sync_channel(PIPELINE_DEPTH);
+    let thread_reader = reader.clone();
+    let parser = thread::spawn(move || {
+        let mut batches = BatchSender::new(sender);
+        let parsed = match input {
+            Input::Stdin => parse_reader(io::stdin().lock(), &thread_reader, &mut batches),
+            #[cfg(feature = "https")]
+            Input::Remote(body) => parse_reader(io::BufReader::new(body), &thread_reader, &mut batches),
+            Input::File(mmap) => parse_mapped(&mmap, &thread_reader, &mut batches),
+            Input::Memory(bytes) => parse_mapped(&bytes, &thread_reader, &mut batches),
+        };
+        if let Err(e) = parsed {
+            batches.push(Err(e));
+        }
+        batches.finish();
+    });
+
+    let mut index = 0;
+    for batch in receiver {
+        for lot_operation in batch {
+            let lot_operation = lot_operation?;
+            reader.limits.check_operations(index)?;
+            let restored = checkpointer.as_ref().is_some_and(|checkpointer| checkpointer.is_restored(index));
+            index += 1;
+            if restored {
+                continue;
+            }
+            let Some(lot_operation) = reader.transform(lot_operation)? else {
+                continue;
+            };
+            if let Some(contracts) = contracts.as_mut().filter(|contracts| contracts.is_contract(&lot_operation)) {
+                contracts.apply(lot_operation)?;
+                continue;
+            }
+            lot_collection.apply_lot_operation(lot_operation)?;
+            alerts.check(lot_collection);
+            if let Some(checkpointer) = checkpointer.as_mut() {
+                checkpointer.applied(lot_collection)?;
+            }
         }
     }
+
+    // The channel is only drained once the parsing thread has dropped its sender, so this does not block. After an
+    // error above, the thread is not joined, as it may be blocked reading stdin until it sees the channel closed.
+    parser.join().map_err(|panic| {
+        let message = panic
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| panic.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        TaxLotError::InputParserPanicked(message)
+    })
 }
 
-impl PartialOrd for Lot {
-    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
-        Some(match &self.selection_algo {
-            SelectionAlgorithm::Fifo => self.date.cmp(&other.date),
-            // Reverse the comparison for `hifo` so that the lots are sorted from highest -> lowest.
-            SelectionAlgorithm::Hifo => other.price.cmp(&self.price),
-        })
-    }
+/// Represents a lot as a row of the lots output, with its id formatted according to `id_format`.
+struct LotRow<'a> {
+    lot: Cow<'a, Lot>,
+    id_format: &'a IdFormat,
 }
 
-impl PartialEq for Lot {
-    fn eq(&self, other: &Self) -> bool {
-        match self.selection_algo {
-            SelectionAlgorithm::Fifo => self.date == other.date,
-            SelectionAlgorithm::Hifo => self.price == other.price,
+impl Row for LotRow<'_> {
+    const OUTPUT: &'static str = "lots";
+
+    fn columns(schema_version: SchemaVersion) -> &'static [&'static str] {
+        match schema_version {
+            SchemaVersion::V1 => &["id", "date", "price", "quantity", "tags", "memo"],
+            SchemaVersion::V2 => &["id", "date", "price", "quantity", "tags", "memo", "purchases"],
         }
     }
+
+    fn values(&self, schema_version: SchemaVersion) -> Vec<Option<Value>> {
+        let LotRow { lot, id_format } = self;
+        let mut values = vec![
+            Some(Value::Text(id_format.format_id(lot.id()))),
+            Some(Value::Text(lot.date().to_string())),
+            Some(Value::Number(format!("{:.2}", lot.price()))),
+            Some(Value::Number(format!("{:.8}", lot.quantity()))),
+            Some(Value::List(lot.tags().map(str::to_string).collect())),
+            lot.memo().map(|memo| Value::Text(memo.to_string())),
+        ];
+        if schema_version >= SchemaVersion::V2 {
+            let purchases = lot.purchases().iter().map(|purchase| {
+                vec![
+                    ("date", Value::Text(purchase.date.to_string())),
+                    ("price", Value::Number(format!("{:.2}", purchase.price))),
+                    ("quantity", Value::Number(format!("{:.8}", purchase.quantity))),
+                ]
+            });
+            values.push(Some(Value::Records(purchases.collect())));
+        }
+        values
+    }
+
+    /// The CSV lines are the same in every version.
+    fn csv_columns(_: SchemaVersion) -> String {
+        "id,date,price,quantity[,tags=<tags>][,memo]".to_string()
+    }
+
+    /// Writes the lot as it is displayed, which only has the tags and memo if there are any.
+    fn write_csv(&self, _: SchemaVersion, out: &mut impl Write) -> io::Result<()> {
+        write!(out, "{}", self.lot.display(self.id_format))
+    }
 }
 
-impl Eq for Lot {}
+/// Represents the gains realized in a year as a row of the gains report.
+struct YearlyGainsRow {
+    year: i32,
+    gains: RealizedGains,
+}
 
-/// Represents a collection of tax lots. These lots can be sold, added to, or merged with an existing lot.
-/// 
-/// A `VecDeque` is used for efficient access to the "first" item, where the first item is dictated by the
-/// `selection_algorithm`. If we're using `fifo`, the `lot_queue` is sorted by date and the first item will
-/// be the oldest tax lot. If we're using `hifo`, the `lot_queue` is sorted by price and the first item will
-/// be the highest price tax lot.
-/// 
-/// Buy Operation: Worst case O(N) to find lot with the same date, when the `selection_algo` is `hifo`. When the `selection_algo` is `fifo`, this is improved to O(1).
-/// Sell Operation: Worst case (N) to sell all lots.
-struct LotCollection {
-    // Keeps a sorted queue according to the `selection_algorithm`.
-    lot_queue: VecDeque<Lot>,
+impl Row for YearlyGainsRow {
+    const OUTPUT: &'static str = "gains";
+    fn columns(_: SchemaVersion) -> &'static [&'static str] {
+        &["year", "short_term", "long_term", "total"]
+    }
 
-    // Generates ids for tax lots starting at 1.
-    id_generator: AtomicU64,
+    fn values(&self, _: SchemaVersion) -> Vec<Option<Value>> {
+        let YearlyGainsRow { year, gains } = self;
+        vec![
+            Some(Value::Number(year.to_string())),
+            Some(Value::Number(format!("{:.2}", gains.short_term))),
+            Some(Value::Number(format!("{:.2}", gains.long_term))),
+            Some(Value::Number(format!("{:.2}", gains.total()))),
+        ]
+    }
+}
 
-    // Determines how the tax lots are sorted in the `lot_queue`.
-    selection_algorithm: SelectionAlgorithm,
+/// Represents the gains realized in a quarter as a row of the quarterly report. `year_to_date` is the gains
+/// realized from the start of the year through the end of the quarter, e.g. to estimate the tax payments due
+/// for it.
+struct QuarterlyGainsRow {
+    year: i32,
+    quarter: u32,
+    gains: RealizedGains,
+    year_to_date: Decimal,
 }
 
-impl LotCollection {
-    fn new(selection_algorithm: SelectionAlgorithm) -> Self {
-        LotCollection {
-            lot_queue: VecDeque::new(),
-            id_generator: AtomicU64::new(INITIAL_TAX_LOT_ID),
-            selection_algorithm,
-        }
+impl Row for QuarterlyGainsRow {
+    const OUTPUT: &'static str = "quarterly-gains";
+    fn columns(_: SchemaVersion) -> &'static [&'static str] {
+        &["year", "quarter", "short_term", "long_term", "total", "year_to_date"]
     }
 
-    /// Applies a `buy` or `sell` lot operation to the lot collection.
-    fn apply_lot_operation(&mut self, lot_operation: LotOperation) -> Result<(), TaxLotError> {
-        match lot_operation.lot_type {
-            LotType::Buy => self.buy(lot_operation),
-            LotType::Sell => self.sell(lot_operation),
-        }
+    fn values(&self, _: SchemaVersion) -> Vec<Option<Value>> {
+        let QuarterlyGainsRow { year, quarter, gains, year_to_date } = self;
+        vec![
+            Some(Value::Number(year.to_string())),
+            Some(Value::Number(quarter.to_string())),
+            Some(Value::Number(format!("{:.2}", gains.short_term))),
+            Some(Value::Number(format!("{:.2}", gains.long_term))),
+            Some(Value::Number(format!("{:.2}", gains.total()))),
+            Some(Value::Number(format!("{year_to_date:.2}"))),
+        ]
     }
+}
 
-    /// Gets a lot from the lot collection according to the date.
-    /// If the lot collection is sorted by date, we can just check
-    /// the back of the queue to determine if a lot with the same date exists.
-    fn get_lot(&mut self, date: &NaiveDate) -> Option<&mut Lot> {
-        match self.selection_algorithm {
-            SelectionAlgorithm::Fifo => {
-                // If the selection algorithm is fifo, we can just check the back of the queue to
-                // determine if a lot with the same date already exists
-                if let Some(lot) = self.lot_queue.back_mut() {
-                    if &lot.date == date {
-                        return Some(lot);
-                    }
-                }
+/// Represents the total income of a type in a year as a row of the income report.
+struct IncomeRow {
+    year: i32,
+    income_type: IncomeType,
+    amount: Decimal,
+}
 
-                return None;
-            }
-            SelectionAlgorithm::Hifo => {
-                // We must search the whole queue to determine if a lot with the same date already exists
-                return self.lot_queue.iter_mut().find(|existing_lot| &existing_lot.date == date);
-            }
-        }
+impl Row for IncomeRow {
+    const OUTPUT: &'static str = "income";
+    fn columns(_: SchemaVersion) -> &'static [&'static str] {
+        &["year", "type", "amount"]
     }
 
-    /// Buy creates a new tax lot if there is no tax lot with the `lot_operation` date.
-    /// Buy merges `lot_operation` with an existing lot if the `lot_collection` already
-    /// has a `lot` with the specified date.
-    fn buy(&mut self, lot_operation: LotOperation) -> Result<(), TaxLotError> {
-        match self.get_lot(&lot_operation.date)
-        {
-            Some(existing_lot) => {
-                // merge with an existing lot since the `lot_collection` already has a lot
-                // for this date.
-                existing_lot.merge(lot_operation)?;
-            }
-            None => {
-                // create a new lot since `lot_collection` does not have a lot for this date.
-                let new_lot =
-                    lot_operation.create_new_lot(&self.id_generator, self.selection_algorithm);
-                self.lot_queue.push_back(new_lot);
-                self.lot_queue.make_contiguous().sort();
-            }
-        }
+    fn values(&self, _: SchemaVersion) -> Vec<Option<Value>> {
+        let IncomeRow { year, income_type, amount } = self;
+        vec![
+            Some(Value::Number(year.to_string())),
+            Some(Value::Text(income_type.to_string())),
+            Some(Value::Number(format!("{amount:.2}"))),
+        ]
+    }
+}
 
-        Ok(())
+/// Represents a part of a lot that was sold as a row of the disposals report, with the lot id formatted according
+/// to `id_format`.
+struct DisposalRow<'a> {
+    disposal: Disposal,
+    id_format: &'a IdFormat,
+}
+
+impl Row for DisposalRow<'_> {
+    const OUTPUT: &'static str = "disposals";
+    fn columns(_: SchemaVersion) -> &'static [&'static str] {
+        &["lot_id", "acquired", "sold", "quantity", "cost_basis", "proceeds", "gain", "term", "txn"]
     }
 
-    /// Sell deducts "shares" from tax lots according to the `selection_algorithm`. Since `lot_queue` is sorted
-    /// according to the `selection_algorithm`, this just needs to pop tax lots off of the queue and deduct
-    /// shares from each lot until there are no more tax lots or we have sold the number of shares specified.
-    fn sell(&mut self, lot_operation: LotOperation) -> Result<(), TaxLotError> {
-        let mut quantity_sold = lot_operation.quantity;
-
-        while quantity_sold > Decimal::ZERO {
-            if let Some(lot) = self.lot_queue.front_mut() {
-                let new_quantity = checked_sub(lot.quantity, quantity_sold)?;
-                if new_quantity > Decimal::ZERO {
-                    lot.quantity = new_quantity;
-                    quantity_sold = Decimal::ZERO;
-                } else {
-                    quantity_sold = checked_sub(quantity_sold, lot.quantity)?;
-                    self.lot_queue.pop_front();
-                }
-            } else {
-                // We have run out of lots to sell, break out of the loop.
-                break;
-            }
-        }
+    fn values(&self, _: SchemaVersion) -> Vec<Option<Value>> {
+        let DisposalRow { disposal, id_format } = self;
+        vec![
+            Some(Value::Text(id_format.format_id(disposal.lot_id))),
+            Some(Value::Text(disposal.acquired.to_string())),
+            Some(Value::Text(disposal.sold.to_string())),
+            Some(Value::Number(format!("{:.8}", disposal.quantity))),
+            Some(Value::Number(format!("{:.2}", disposal.cost_basis))),
+            Some(Value::Number(format!("{:.2}", disposal.proceeds))),
+            Some(Value::Number(format!("{:.2}", disposal.gain))),
+            Some(Value::Text(if disposal.long_term { "long" } else { "short" }.to_string())),
+            disposal.txn_id.clone().map(Value::Text),
+        ]
+    }
+}
 
-        Ok(())
+/// Represents a disposal as a row of the disposals report with the Australian rules, with the method of its gain.
+struct CgtDisposalRow<'a>(DisposalRow<'a>);
+
+impl Row for CgtDisposalRow<'_> {
+    const OUTPUT: &'static str = "disposals";
+    fn columns(_: SchemaVersion) -> &'static [&'static str] {
+        &["lot_id", "acquired", "sold", "quantity", "cost_basis", "proceeds", "gain", "term", "txn", "method"]
+    }
+
+    fn values(&self, schema_version: SchemaVersion) -> Vec<Option<Value>> {
+        let CgtDisposalRow(row) = self;
+        let mut values = row.values(schema_version);
+        values.push(Some(Value::Text(au::method(&row.disposal).to_string())));
+        values
     }
 }
 
-fn main() {
-    let TaxLotOpts { selection_algo } = TaxLotOpts::parse();
-
-    let mut lot_collection = LotCollection::new(selection_algo);
-
-    // Process each line from stdin
-    let lines = io::stdin().lines();
-    for line in lines {
-        match line {
-            Ok(line) => {
-                if let Err(e) = process_lot_operation(line.as_str(), &mut lot_collection) {
-                    eprintln!("{e}");
-                    process::exit(1);
-                }
-            }
-            Err(e) => {
-                eprintln!("Error reading from stdin: {e}");
-                process::exit(1);
-            }
-        }
+/// Represents the capital gains of an income year as a row of the gains report with the Australian rules.
+struct IncomeYearGainsRow(IncomeYearGains);
+
+impl Row for IncomeYearGainsRow {
+    const OUTPUT: &'static str = "gains";
+    fn columns(_: SchemaVersion) -> &'static [&'static str] {
+        &[
+            "year",
+            "other_gains",
+            "discountable_gains",
+            "losses",
+            "carried_losses",
+            "discount",
+            "net_gain",
+            "carried_forward",
+        ]
     }
 
-    while !lot_collection.lot_queue.is_empty() {
-        if let Some(lot) = lot_collection.lot_queue.pop_front() {
-            println!("{lot}");
-        }
+    fn values(&self, _: SchemaVersion) -> Vec<Option<Value>> {
+        let IncomeYearGainsRow(gains) = self;
+        let amount = |amount: Decimal| Some(Value::Number(format!("{amount:.2}")));
+        vec![
+            Some(Value::Number(gains.year.to_string())),
+            amount(gains.other_gains),
+            amount(gains.discountable_gains),
+            amount(gains.losses),
+            amount(gains.carried_losses),
+            amount(gains.discount),
+            amount(gains.net_gain),
+            amount(gains.carried_forward),
+        ]
     }
 }
 
-fn process_lot_operation(op: &str, lot_collection: &mut LotCollection) -> Result<(), TaxLotError> {
-    let lot_operation = LotOperation::from_str(op)?;
-    lot_collection.apply_lot_operation(lot_operation)
+/// Represents the gain or loss of selling a remaining lot at the mark price as a row of the unrealized gains
+/// report, with the lot id formatted according to `id_format`.
+struct UnrealizedRow<'a> {
+    lot: Cow<'a, Lot>,
+    mark: Quote,
+    gain: UnrealizedGain,
+    id_format: &'a IdFormat,
 }
 
-#[cfg(test)]
-mod tests {
-    use std::str::FromStr;
+impl Row for UnrealizedRow<'_> {
+    const OUTPUT: &'static str = "unrealized";
+    fn columns(_: SchemaVersion) -> &'static [&'static str] {
+        &["lot_id", "acquired", "quantity", "cost_basis", "marked", "mark_price", "market_value", "gain", "term"]
+    }
 
-    use chrono::NaiveDate;
-    use rust_decimal::{prelude::FromPrimitive, Decimal};
+    fn values(&self, _: SchemaVersion) -> Vec<Option<Value>> {
+        let UnrealizedRow { lot, mark, gain, id_format } = self;
+        vec![
+            Some(Value::Text(id_format.format_id(lot.id()))),
+            Some(Value::Text(lot.date().to_string())),
+            Some(Value::Number(format!("{:.8}", lot.quantity()))),
+            Some(Value::Number(format!("{:.2}", gain.cost_basis))),
+            Some(Value::Text(mark.date.to_string())),
+            Some(Value::Number(format!("{:.2}", mark.price))),
+            Some(Value::Number(format!("{:.2}", gain.market_value))),
+            Some(Value::Number(format!("{:.2}", gain.gain))),
+            Some(Value::Text(if gain.long_term { "long" } else { "short" }.to_string())),
+        ]
+    }
+}
 
-    use crate::{LotCollection, LotOperation, SelectionAlgorithm, TaxLotError, Lot};
+/// Represents the returns of the lots as the row of the performance report. The returns are fractions, e.g. `0.1`
+/// for 10%.
+struct PerformanceRow(Performance);
 
-    fn get_by_date<'a>(lot_collection: &'a LotCollection, date: &str) -> Result<&'a Lot, TaxLotError> {
-        let naive_date = NaiveDate::from_str(date)?;
-        Ok(lot_collection
-            .lot_queue
-            .iter()
-            .find(|lot| lot.date == naive_date)
-            .expect("No date found"))
+impl Row for PerformanceRow {
+    const OUTPUT: &'static str = "performance";
+    fn columns(_: SchemaVersion) -> &'static [&'static str] {
+        &["start", "end", "invested", "returned", "market_value", "irr", "twr"]
     }
 
-    #[test]
-    fn test_parse_lot_operation() -> Result<(), TaxLotError> {
-        LotOperation::from_str("2021-01-01,Buy,10000.00,1.00000000").expect("Failed to parse valid lot operation");
-        LotOperation::from_str("2021-01-01,sell,10000.00,1.00000000").expect("Failed to parse valid lot operation");
-        LotOperation::from_str("2021-01-01,sell,1,4").expect("Failed to parse valid lot operation");
+    fn values(&self, _: SchemaVersion) -> Vec<Option<Value>> {
+        let PerformanceRow(Performance { start, end, invested, returned, market_value, irr, twr }) = self;
+        vec![
+            Some(Value::Text(start.to_string())),
+            Some(Value::Text(end.to_string())),
+            Some(Value::Number(format!("{invested:.2}"))),
+            Some(Value::Number(format!("{returned:.2}"))),
+            Some(Value::Number(format!("{market_value:.2}"))),
+            irr.map(|irr| Value::Number(format!("{irr:.6}"))),
+            Some(Value::Number(format!("{twr:.6}"))),
+        ]
+    }
+}
+
+/// Represents a lot that differs between the old and new input of `diff` as a row of its output. `old` is `None` for a
+/// lot that was added, and `new` for a lot that was removed.
+struct DiffRow<'a> {
+    id: u64,
+    old: Option<&'a Lot>,
+    new: Option<&'a Lot>,
+    id_format: &'a IdFormat,
+}
 
-        // invalid date
-        LotOperation::from_str("2021-13-01,buy,10000.00,1.00000000").expect_err("Successfully parsed an invalid date");
+impl Row for DiffRow<'_> {
+    const OUTPUT: &'static str = "diff";
+    fn columns(_: SchemaVersion) -> &'static [&'static str] {
+        &["change", "id", "old_date", "new_date", "old_price", "new_price", "old_quantity", "new_quantity"]
+    }
 
-        // invalid lot type
-        LotOperation::from_str("2021-01-01,invalid,10000.00,1.00000000").expect_err("Successfully parsed an invalid lot type");
+    fn values(&self, _: SchemaVersion) -> Vec<Option<Value>> {
+        let DiffRow { id, old, new, id_format } = self;
+        let change = match (old, new) {
+            (None, _) => "added",
+            (_, None) => "removed",
+            _ => "changed",
+        };
+        let date = |lot: &Option<&Lot>| lot.map(|lot| Value::Text(lot.date().to_string()));
+        let price = |lot: &Option<&Lot>| lot.map(|lot| Value::Number(format!("{:.2}", lot.price())));
+        let quantity = |lot: &Option<&Lot>| lot.map(|lot| Value::Number(format!("{:.8}", lot.quantity())));
+        vec![
+            Some(Value::Text(change.to_string())),
+            Some(Value::Text(id_format.format_id(*id))),
+            date(old),
+            date(new),
+            price(old),
+            price(new),
+            quantity(old),
+            quantity(new),
+        ]
+    }
+}
 
-        // invalid price
-        LotOperation::from_str("2021-01-01,buy,-10000.00,1.00000000").expect_err("Successfully parsed an invalid price");
-        LotOperation::from_str("2021-01-01,buy,0.0,1.00000000").expect_err("Successfully parsed an invalid price");
-        LotOperation::from_str("2021-01-01,buy,invalid,1.00000000").expect_err("Successfully parsed an invalid price");
+/// Represents a lot or disposal that an operation inserted by `insert` changed.
+enum InsertChange<'a> {
+    Lot(&'a LotChange),
+    Disposal(&'a DisposalChange),
+}
 
-        // invalid quantity
-        LotOperation::from_str("2021-01-01,buy,10000.00,-1.00000000").expect_err("Successfully parsed an invalid quantity");
-        LotOperation::from_str("2021-01-01,buy,10000.00,invalid").expect_err("Successfully parsed an invalid quantity");
-        LotOperation::from_str("2021-01-01,buy,10000.00,0").expect_err("Successfully parsed an invalid quantity");
+/// Represents a change of an operation inserted by `insert` as a row of its output. A lot has a price, and a
+/// disposal the date of its sell and a gain; the dates are those of the lots.
+struct InsertChangeRow<'a> {
+    operation: &'a str,
+    change: InsertChange<'a>,
+    id_format: &'a IdFormat,
+}
 
-        // no quantity
-        LotOperation::from_str("2021-01-01,buy,10000.00").expect_err("Successfully parsed lot opration with no quantity");
+impl Row for InsertChangeRow<'_> {
+    const OUTPUT: &'static str = "insert";
+    fn columns(_: SchemaVersion) -> &'static [&'static str] {
+        &[
+            "operation",
+            "record",
+            "change",
+            "old_id",
+            "new_id",
+            "old_date",
+            "new_date",
+            "sold",
+            "old_price",
+            "new_price",
+            "old_quantity",
+            "new_quantity",
+            "old_gain",
+            "new_gain",
+        ]
+    }
 
-        // no price
-        LotOperation::from_str("2021-01-01,buy").expect_err("Successfully parsed lot operation with no price");
+    fn values(&self, _: SchemaVersion) -> Vec<Option<Value>> {
+        let InsertChangeRow { operation, change, id_format } = self;
+        let text = |text: &str| Some(Value::Text(text.to_string()));
+        let id = |id: u64| Value::Text(id_format.format_id(id));
+        let amount = |amount: Decimal| Value::Number(format!("{amount:.2}"));
+        let quantity = |quantity: Decimal| Value::Number(format!("{quantity:.8}"));
+        match change {
+            InsertChange::Lot(change) => {
+                let (change, old, new) = match change {
+                    LotChange::Added(lot) => ("added", None, Some(lot)),
+                    LotChange::Removed(lot) => ("removed", Some(lot), None),
+                    LotChange::Changed { before, after } => ("changed", Some(before), Some(after)),
+                };
+                let date = |lot: Option<&Lot>| lot.map(|lot| Value::Text(lot.date().to_string()));
+                vec![
+                    text(operation),
+                    text("lot"),
+                    text(change),
+                    old.map(|lot| id(lot.id())),
+                    new.map(|lot| id(lot.id())),
+                    date(old),
+                    date(new),
+                    None,
+                    old.map(|lot| amount(lot.price())),
+                    new.map(|lot| amount(lot.price())),
+                    old.map(|lot| quantity(lot.quantity())),
+                    new.map(|lot| quantity(lot.quantity())),
+                    None,
+                    None,
+                ]
+            }
+            InsertChange::Disposal(change) => {
+                let (change, old, new) = match change {
+                    DisposalChange::Added(disposal) => ("added", None, Some(disposal)),
+                    DisposalChange::Removed(disposal) => ("removed", Some(disposal), None),
+                    DisposalChange::Rematched { before, after } => ("rematched", Some(before), Some(after)),
+                };
+                let date = |disposal: Option<&Disposal>| {
+                    disposal.map(|disposal| Value::Text(disposal.acquired.to_string()))
+                };
+                // A re-matched disposal is of the same sell, so it was sold on the same date.
+                let sold = new.or(old).map(|disposal| Value::Text(disposal.sold.to_string()));
+                vec![
+                    text(operation),
+                    text("disposal"),
+                    text(change),
+                    old.map(|disposal| id(disposal.lot_id)),
+                    new.map(|disposal| id(disposal.lot_id)),
+                    date(old),
+                    date(new),
+                    sold,
+                    None,
+                    None,
+                    old.map(|disposal| quantity(disposal.quantity)),
+                    new.map(|disposal| quantity(disposal.quantity)),
+                    old.map(|disposal| amount(disposal.gain)),
+                    new.map(|disposal| amount(disposal.gain)),
+                ]
+            }
+        }
+    }
+}
 
-        // no type
-        LotOperation::from_str("2021-01-01").expect_err("Successfully parsed lot operation with no lot type");
+/// Represents the errors in a column of the input as a row of the `check schema` output.
+///
+/// `examples`: The first lines with errors in the column, with their line numbers.
+struct SchemaErrorsRow {
+    column: Column,
+    errors: u64,
+    examples: Vec<String>,
+}
 
-        // no date
-        LotOperation::from_str("").expect_err("Successfully parsed lot operation with no date");
-        Ok(())
+impl Row for SchemaErrorsRow {
+    const OUTPUT: &'static str = "schema-errors";
+    fn columns(_: SchemaVersion) -> &'static [&'static str] {
+        &["column", "errors", "examples"]
     }
 
-    #[test]
-    fn test_lot_displays_proper_formatting() -> Result<(), TaxLotError> {
-        let lot = Lot {
-            date: NaiveDate::from_str("2021-01-01")?,
-            id: 1,
-            price: Decimal::from_f64(10000.0).expect("Failed to parse price"),
-            quantity: Decimal::from_f64(1.0).expect("Failed to parse quantity"),
-            selection_algo: SelectionAlgorithm::Fifo,
-        };
+    fn values(&self, _: SchemaVersion) -> Vec<Option<Value>> {
+        let SchemaErrorsRow { column, errors, examples } = self;
+        vec![
+            Some(Value::Text(column.to_string())),
+            Some(Value::Number(errors.to_string())),
+            Some(Value::List(examples.clone())),
+        ]
+    }
+}
 
-        let lot_string = lot.to_string();
+/// Writes the remaining lots that match `print_filter`, one row per lot, in the order they would be sold.
+fn write_lots(
+    lot_collection: &LotCollection,
+    print_filter: &PrintFilter,
+    id_format: &IdFormat,
+    output: &Output,
+    out: impl Write,
+) -> io::Result<()> {
+    let lots = lot_collection.lots().filter(|lot| print_filter.matches(lot));
+    output.write(lots.map(|lot| LotRow { lot, id_format }), out)
+}
 
-        assert_eq!(lot_string, "1,2021-01-01,10000.00,1.00000000");
+/// Writes the lots that were added, removed or changed from `old` to `new`, one row per lot id in either of them
+/// whose date, exact price or exact quantity differs, by id.
+fn write_diff(
+    old: &LotCollection,
+    new: &LotCollection,
+    id_format: &IdFormat,
+    output: &Output,
+    out: impl Write,
+) -> io::Result<()> {
+    let old: BTreeMap<u64, Cow<Lot>> = old.lots().map(|lot| (lot.id(), lot)).collect();
+    let new: BTreeMap<u64, Cow<Lot>> = new.lots().map(|lot| (lot.id(), lot)).collect();
+    let ids: BTreeSet<u64> = old.keys().chain(new.keys()).copied().collect();
+    let rows = ids
+        .into_iter()
+        .map(|id| DiffRow { id, old: old.get(&id).map(Cow::as_ref), new: new.get(&id).map(Cow::as_ref), id_format })
+        .filter(|row| match (row.old, row.new) {
+            (Some(old), Some(new)) => {
+                (old.date(), old.price(), old.quantity()) != (new.date(), new.price(), new.quantity())
+            }
+            _ => true,
+        });
+    output.write(rows, out)
+}
 
-        Ok(())
+/// Writes the gains realized in each year, one row per year with sells, with the gains of the Section 1256 contracts
+/// split 60/40. With the Australian rules, the years are income years, worked out from the disposals.
+fn write_gains(
+    lot_collection: &LotCollection,
+    contracts: Option<&Section1256>,
+    jurisdiction: Jurisdiction,
+    output: &Output,
+    out: impl Write,
+) -> io::Result<()> {
+    match jurisdiction {
+        Jurisdiction::Us => {
+            let mut years: BTreeMap<i32, RealizedGains> = lot_collection.realized_gains_by_year().collect();
+            for (year, gain) in contracts.map(Section1256::gains_by_year).transpose()?.into_iter().flatten() {
+                let gains = years.entry(year).or_default();
+                let contract_gains = section1256::split(gain);
+                gains.short_term += contract_gains.short_term;
+                gains.long_term += contract_gains.long_term;
+            }
+            output.write(years.into_iter().map(|(year, gains)| YearlyGainsRow { year, gains }), out)
+        }
+        Jurisdiction::Au => {
+            let years = au::income_years(lot_collection.disposals())?;
+            output.write(years.into_iter().map(IncomeYearGainsRow), out)
+        }
     }
+}
 
-    #[test]
-    fn test_merge_uses_weighted_average() -> Result<(), TaxLotError> {
-        let mut lot = Lot {
-            date: NaiveDate::from_str("2021-01-01")?,
-            id: 1,
-            price: Decimal::from_f64(10000.0).expect("Failed to parse price"),
-            quantity: Decimal::from_f64(1.0).expect("Failed to parse quantity"),
-            selection_algo: SelectionAlgorithm::Fifo,
+/// Writes the gains realized in each quarter, one row per quarter with sells, with the running total of each year.
+fn write_quarterly_gains(lot_collection: &LotCollection, output: &Output, out: impl Write) -> io::Result<()> {
+    let mut year_to_date = None;
+    let rows = lot_collection.realized_gains_by_quarter().map(|(year, quarter, gains)| {
+        let running_total = match year_to_date {
+            Some((running_year, running_total)) if running_year == year => running_total + gains.total(),
+            _ => gains.total(),
         };
+        year_to_date = Some((year, running_total));
+        QuarterlyGainsRow { year, quarter, gains, year_to_date: running_total }
+    });
+    output.write(rows, out)
+}
 
-        let lot_operation = LotOperation {
-            date: NaiveDate::from_str("2021-01-01")?,
-            lot_type: crate::LotType::Buy,
-            price: Decimal::from_f64(20000.00).expect("Failed to parse price"),
-            quantity: Decimal::from_f64(3.00000000).expect("Failed to parse quantity"),
-        };
+/// Writes the total income of each type in each year, one row per total.
+fn write_income(lot_collection: &LotCollection, output: &Output, out: impl Write) -> io::Result<()> {
+    let rows = lot_collection
+        .income()
+        .map(|(year, income_type, amount)| IncomeRow { year, income_type, amount });
+    output.write(rows, out)
+}
 
-        lot.merge(lot_operation)?;
+/// Writes every part of a lot that was sold, one row per lot that each sell deducted from. The rows are written as
+/// the disposals are read, so that spilled disposals are not read back into memory all at once.
+fn write_disposals(
+    lot_collection: &LotCollection,
+    jurisdiction: Jurisdiction,
+    id_format: &IdFormat,
+    output: &Output,
+    out: impl Write,
+) -> io::Result<()> {
+    let mut error = None;
+    let rows = lot_collection.disposals().map_while(|disposal| match disposal {
+        Ok(disposal) => Some(DisposalRow { disposal, id_format }),
+        Err(e) => {
+            error = Some(e);
+            None
+        }
+    });
+    match jurisdiction {
+        Jurisdiction::Us => output.write(rows, out)?,
+        Jurisdiction::Au => output.write(rows.map(CgtDisposalRow), out)?,
+    }
+    error.map_or(Ok(()), Err)
+}
 
-        assert_eq!(lot.id, 1);
-        assert_eq!(lot.price, Decimal::from_f64(17500.0).expect("Failed to parse price"));
-        assert_eq!(lot.quantity, Decimal::from_f64(4.0).expect("Failed to parse quantity"));
+/// Writes the gain or loss of selling each remaining lot at the `mark` price, one row per lot in the order they
+/// would be sold.
+fn write_unrealized(
+    lot_collection: &LotCollection,
+    mark: Quote,
+    id_format: &IdFormat,
+    output: &Output,
+    out: impl Write,
+) -> Result<(), TaxLotError> {
+    let rows = lot_collection
+        .lots()
+        .map(|lot| Ok(UnrealizedRow { gain: lot.unrealized_gain(mark.price, mark.date)?, lot, mark, id_format }))
+        .collect::<Result<Vec<_>, TaxLotError>>()?;
+    Ok(output.write(rows.into_iter(), out)?)
+}
 
-        Ok(())
+/// Writes the lots and disposals that each of the `inserted` operations changed, one row per change.
+fn write_changes(
+    inserted: &[(&str, InsertChanges)],
+    id_format: &IdFormat,
+    output: &Output,
+    out: impl Write,
+) -> io::Result<()> {
+    let rows = inserted.iter().flat_map(|(operation, changes)| {
+        let lots = changes.lots.iter().map(InsertChange::Lot);
+        let disposals = changes.disposals.iter().map(InsertChange::Disposal);
+        lots.chain(disposals).map(|change| InsertChangeRow { operation, change, id_format })
+    });
+    output.write(rows, out)
+}
+
+/// Collects parsed lot operations into batches of `BATCH_SIZE` and sends them to the applying thread.
+struct BatchSender {
+    sender: SyncSender<Vec<Result<LotOperation, TaxLotError>>>,
+    batch: Vec<Result<LotOperation, TaxLotError>>,
+}
+
+impl BatchSender {
+    fn new(sender: SyncSender<Vec<Result<LotOperation, TaxLotError>>>) -> Self {
+        BatchSender {
+            sender,
+            batch: Vec::with_capacity(BATCH_SIZE),
+        }
     }
 
-    #[test]
-    fn test_buy_creates_new_lot() -> Result<(), TaxLotError> {
-        let selection_algo = SelectionAlgorithm::Fifo;
-        let mut lot_collection = LotCollection::new(selection_algo);
-        let lot_operation = LotOperation {
-            date: NaiveDate::from_str("2021-01-01")?,
-            lot_type: crate::LotType::Buy,
-            price: Decimal::from_f64(10000.00).expect("Failed to parse price"),
-            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
-        };
-        lot_collection.buy(lot_operation)?;
+    /// Adds a parsed lot operation to the current batch. Returns false if no more lines should be
+    /// parsed, either because this one failed or because the applying thread has stopped.
+    fn push(&mut self, lot_operation: Result<LotOperation, TaxLotError>) -> bool {
+        let failed = lot_operation.is_err();
+        self.batch.push(lot_operation);
 
-        let lot_operation = LotOperation {
-            date: NaiveDate::from_str("2021-01-02")?,
-            lot_type: crate::LotType::Buy,
-            price: Decimal::from_f64(20000.00).expect("Failed to parse price"),
-            quantity: Decimal::from_f64(2.00000000).expect("Failed to parse quantity"),
-        };
-        lot_collection.buy(lot_operation)?;
-
-        assert_eq!(lot_collection.lot_queue.len(), 2);
-        let lot1 = get_by_date(&lot_collection, "2021-01-01")?;
-        let lot2 = get_by_date(&lot_collection, "2021-01-02")?;
-        assert_eq!(lot1.id, 1);
-        assert_eq!(lot2.id, 2);
-        assert_eq!(lot1.price, Decimal::from_f64(10000.00).expect("Failed to parse price"));
-        assert_eq!(lot2.price, Decimal::from_f64(20000.00).expect("Failed to parse price"));
-        assert_eq!(lot1.quantity, Decimal::from_f64(1.00000000).expect("Failed to parse quantity"));
-        assert_eq!(lot2.quantity, Decimal::from_f64(2.00000000).expect("Failed to parse quantity"));
+        if failed || self.batch.len() == BATCH_SIZE {
+            let batch = std::mem::replace(&mut self.batch, Vec::with_capacity(BATCH_SIZE));
+            return self.sender.send(batch).is_ok() && !failed;
+        }
 
-        Ok(())
+        true
     }
 
-    #[test]
-    fn test_buy_merges_with_existing_lot() -> Result<(), TaxLotError> {
-        let selection_algo = SelectionAlgorithm::Fifo;
-        let mut lot_collection = LotCollection::new(selection_algo);
-        let lot_operation = LotOperation {
-            date: NaiveDate::from_str("2021-01-01")?,
-            lot_type: crate::LotType::Buy,
-            price: Decimal::from_f64(10000.00).expect("Failed to parse price"),
-            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
-        };
-        lot_collection.buy(lot_operation)?;
+    /// Sends the last, partially filled batch.
+    fn finish(self) {
+        if !self.batch.is_empty() {
+            // The applying thread only stops early after an error, so there is nothing to do if this fails.
+            let _ = self.sender.send(self.batch);
+        }
+    }
+}
 
-        let lot_operation = LotOperation {
-            date: NaiveDate::from_str("2021-01-01")?,
-            lot_type: crate::LotType::Buy,
-            price: Decimal::from_f64(20000.00).expect("Failed to parse price"),
-            quantity: Decimal::from_f64(3.00000000).expect("Failed to parse quantity"),
-        };
-        lot_collection.buy(lot_operation)?;
+/// Parses the lot operations read from stdin or a download, detecting their encoding from the start of it. Lines
+/// are parsed as they are read, unless the whole input is decoded at once, i.e. UTF-16 input or an XML document.
+fn parse_reader(mut input: impl BufRead, reader: &InputReader, batches: &mut BatchSender) -> Result<(), TaxLotError> {
+    let (encoding, bom) = reader.encoding.detect(input.fill_buf()?);
+    input.consume(bom);
+    if encoding == Encoding::Utf16le || matches!(reader.format, InputFormat::Xml(_)) {
+        let mut decoded = Vec::new();
+        input.read_to_end(&mut decoded)?;
+        return parse_encoded(&decoded, reader, encoding, batches);
+    }
+    parse_lines(input, reader, encoding, batches);
+    Ok(())
+}
 
-        assert_eq!(lot_collection.lot_queue.len(), 1);
-        let lot1 = get_by_date(&lot_collection, "2021-01-01")?;
-        assert_eq!(lot1.id, 1);
-        assert_eq!(lot1.price, Decimal::from_f64(17500.00).expect("Failed to parse price"));
-        assert_eq!(lot1.quantity, Decimal::from_f64(4.00000000).expect("Failed to parse quantity"));
+/// Parses the lot operations of an in-memory or memory-mapped input, detecting their encoding from the start of it.
+fn parse_mapped(input: &[u8], reader: &InputReader, batches: &mut BatchSender) -> Result<(), TaxLotError> {
+    let (encoding, bom) = reader.encoding.detect(input);
+    parse_encoded(&input[bom..], reader, encoding, batches)
+}
 
-        Ok(())
+/// Parses the lot operations of an input in `encoding` without its byte order mark. UTF-8 and latin-1 lines are
+/// decoded one at a time, while UTF-16 input and XML documents are decoded at once.
+fn parse_encoded(
+    input: &[u8],
+    reader: &InputReader,
+    encoding: Encoding,
+    batches: &mut BatchSender,
+) -> Result<(), TaxLotError> {
+    match &reader.format {
+        InputFormat::Xml(mapping) => parse_xml(&encoding.decode(input)?, mapping, batches),
+        _ if encoding == Encoding::Utf16le => {
+            let input = encoding.decode(input)?;
+            parse_mapped_lines(input.as_bytes(), reader, Encoding::Utf8, batches);
+        }
+        _ => parse_mapped_lines(input, reader, encoding, batches),
     }
+    Ok(())
+}
 
-    #[test]
-    fn test_sell_deducts_only_lot() -> Result<(), TaxLotError> {
-        let selection_algo = SelectionAlgorithm::Fifo;
-        let mut lot_collection = LotCollection::new(selection_algo);
-        let lot_operation = LotOperation {
-            date: NaiveDate::from_str("2021-01-01")?,
-            lot_type: crate::LotType::Buy,
-            price: Decimal::from_f64(10000.00).expect("Failed to parse price"),
-            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
+/// Parses each line of `input` in `encoding` into a `LotOperation`, reusing a single line buffer. No more of a
+/// line is read than the limits allow, so an overlong line fails before all of it is in memory.
+fn parse_lines(mut input: impl BufRead, reader: &InputReader, encoding: Encoding, batches: &mut BatchSender) {
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let lot_operation = match (&mut input).take(reader.limits.read_limit()).read_until(b'\n', &mut line) {
+            Ok(0) => return,
+            Ok(_) => parse_line(&line, reader, encoding),
+            Err(e) => Some(Err(e.into())),
         };
-        lot_collection.buy(lot_operation)?;
 
-        let lot_operation = LotOperation {
-            date: NaiveDate::from_str("2021-02-01")?,
-            lot_type: crate::LotType::Sell,
-            price: Decimal::from_f64(5000.00).expect("Failed to parse price"),
-            quantity: Decimal::from_f64(0.50000000).expect("Failed to parse quantity"),
-        };
-        lot_collection.sell(lot_operation)?;
+        if !lot_operation.is_none_or(|lot_operation| batches.push(lot_operation)) {
+            return;
+        }
+    }
+}
 
-        assert_eq!(lot_collection.lot_queue.len(), 1);
-        let lot1 = get_by_date(&lot_collection, "2021-01-01")?;
-        assert_eq!(lot1.price, Decimal::from_f64(10000.00).expect("Failed to parse price"));
-        assert_eq!(lot1.quantity, Decimal::from_f64(0.50000000).expect("Failed to parse quantity"));
+/// Parses each line of an in-memory or memory-mapped input in `encoding` into a `LotOperation` without copying
+/// the lines.
+fn parse_mapped_lines(input: &[u8], reader: &InputReader, encoding: Encoding, batches: &mut BatchSender) {
+    // A trailing newline does not start another line.
+    let input = input.strip_suffix(b"\n").unwrap_or(input);
+    if input.is_empty() {
+        return;
+    }
 
-        Ok(())
+    for line in input.split(|byte| *byte == b'\n') {
+        if !parse_line(line, reader, encoding).is_none_or(|lot_operation| batches.push(lot_operation)) {
+            return;
+        }
     }
+}
 
-    #[test]
-    fn test_sell_deducts_from_multiple_lots_fifo() -> Result<(), TaxLotError> {
-        let selection_algo = SelectionAlgorithm::Fifo;
-        let mut lot_collection = LotCollection::new(selection_algo);
-        let lot_operation = LotOperation {
-            date: NaiveDate::from_str("2021-01-01")?,
-            lot_type: crate::LotType::Buy,
-            price: Decimal::from_f64(10000.00).expect("Failed to parse price"),
-            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
-        };
-        lot_collection.buy(lot_operation)?;
+/// Parses the records of an XML document into lot operations according to `mapping`.
+fn parse_xml(document: &str, mapping: &XmlMapping, batches: &mut BatchSender) {
+    for lot_operation in mapping.records(document) {
+        if !batches.push(lot_operation) {
+            return;
+        }
+    }
+}
 
-        let lot_operation = LotOperation {
-            date: NaiveDate::from_str("2021-01-02")?,
-            lot_type: crate::LotType::Buy,
-            price: Decimal::from_f64(20000.00).expect("Failed to parse price"),
-            quantity: Decimal::from_f64(3.00000000).expect("Failed to parse quantity"),
-        };
-        lot_collection.buy(lot_operation)?;
+/// Parses a line of the input in `encoding` that may still have its line ending into a `LotOperation`, after
+/// checking it against the limits of the `reader`. Returns `None` for blank lines and comments.
+fn parse_line(line: &[u8], reader: &InputReader, encoding: Encoding) -> Option<Result<LotOperation, TaxLotError>> {
+    let line = trim_line_ending(line);
+    if let Err(e) = reader.limits.check_line(line) {
+        return Some(Err(e));
+    }
+    match encoding.decode(line) {
+        Ok(line) if reader.is_skipped(&line) => None,
+        Ok(line) => Some(reader.format.parse(&line)),
+        Err(e) => Some(Err(e)),
+    }
+}
 
-        let lot_operation = LotOperation {
-            date: NaiveDate::from_str("2021-01-03")?,
-            lot_type: crate::LotType::Buy,
-            price: Decimal::from_f64(15000.00).expect("Failed to parse price"),
-            quantity: Decimal::from_f64(10.00000000).expect("Failed to parse quantity"),
-        };
-        lot_collection.buy(lot_operation)?;
+/// Strips a trailing `\n` or `\r\n` from a line, like `BufRead::lines` does.
+fn trim_line_ending(line: &[u8]) -> &[u8] {
+    let line = line.strip_suffix(b"\n").unwrap_or(line);
+    line.strip_suffix(b"\r").unwrap_or(line)
+}
 
-        let lot_operation = LotOperation {
-            date: NaiveDate::from_str("2021-02-01")?,
-            lot_type: crate::LotType::Sell,
-            price: Decimal::from_f64(5000.00).expect("Failed to parse price"),
-            quantity: Decimal::from_f64(7.00000000).expect("Failed to parse quantity"),
-        };
-        lot_collection.sell(lot_operation)?;
+#[cfg(test)]
+mod tests {
+    use std::{fs, str::FromStr, sync::mpsc};
 
-        assert_eq!(lot_collection.lot_queue.len(), 1);
-        let lot1 = get_by_date(&lot_collection, "2021-01-03")?;
-        assert_eq!(lot1.price, Decimal::from_f64(15000.00).expect("Failed to parse price"));
-        assert_eq!(lot1.quantity, Decimal::from_f64(7.00000000).expect("Failed to parse quantity"));
+    use chrono::NaiveDate;
+    use rust_decimal::Decimal;
+    use taxlot::{
+        Encoding, IdFormat, InputDialect, LotCollection, LotOperation, LotType, SelectionAlgorithm, TaxLotError,
+        XmlMapping,
+    };
+
+    use super::{
+        check_schema, parse_line, parse_reader, process_input, write_changes, write_gains, BatchSender, Checkpointer,
+        Input, InputFormat, InputReader, Jurisdiction, PIPELINE_DEPTH,
+    };
+    use crate::{alerts::Alerts, limits::LimitOpts, output::Output, prices::Quote, section1256::Section1256};
+
+    const TRADES: &str = "2021-01-01,buy,100.00,1\n2021-01-02,buy,200.00,1\n2021-02-01,sell,300.00,1.5,memo\n";
+
+    fn input_reader(format: InputFormat, encoding: Encoding) -> InputReader {
+        InputReader {
+            format,
+            encoding,
+            comment_prefix: Some("//".to_string()),
+            limits: LimitOpts::default(),
+            #[cfg(feature = "script")]
+            script: None,
+        }
+    }
 
-        Ok(())
+    fn standard(encoding: Encoding) -> InputReader {
+        input_reader(InputFormat::Delimited(InputDialect::Standard), encoding)
     }
 
-    #[test]
-    fn test_sell_deducts_from_multiple_lots_hifo() -> Result<(), TaxLotError> {
-        let selection_algo = SelectionAlgorithm::Hifo;
-        let mut lot_collection = LotCollection::new(selection_algo);
-        let lot_operation = LotOperation {
-            date: NaiveDate::from_str("2021-01-01")?,
-            lot_type: crate::LotType::Buy,
-            price: Decimal::from_f64(10000.00).expect("Failed to parse price"),
-            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
-        };
-        lot_collection.buy(lot_operation)?;
+    /// Returns `text` in UTF-16LE, without a byte order mark.
+    fn utf16le(text: &str) -> Vec<u8> {
+        text.encode_utf16().flat_map(u16::to_le_bytes).collect()
+    }
 
-        let lot_operation = LotOperation {
-            date: NaiveDate::from_str("2021-01-02")?,
-            lot_type: crate::LotType::Buy,
-            price: Decimal::from_f64(20000.00).expect("Failed to parse price"),
-            quantity: Decimal::from_f64(3.00000000).expect("Failed to parse quantity"),
-        };
-        lot_collection.buy(lot_operation)?;
+    /// Applies the mapped `input` to a new collection, with a `checkpointer` if given.
+    fn apply(
+        input: &[u8],
+        reader: InputReader,
+        checkpointer: Option<&mut Checkpointer>,
+    ) -> Result<LotCollection, TaxLotError> {
+        let mut lot_collection = LotCollection::new(SelectionAlgorithm::Fifo);
+        let input = Input::Memory(input.into());
+        process_input(input, reader, &mut lot_collection, checkpointer, None, &mut Alerts::default())?;
+        Ok(lot_collection)
+    }
 
-        let lot_operation = LotOperation {
-            date: NaiveDate::from_str("2021-01-03")?,
-            lot_type: crate::LotType::Buy,
-            price: Decimal::from_f64(15000.00).expect("Failed to parse price"),
-            quantity: Decimal::from_f64(10.00000000).expect("Failed to parse quantity"),
-        };
-        lot_collection.buy(lot_operation)?;
+    /// Parses `input` as it is read from stdin, stopping at the first error like `process_input`.
+    fn read(input: &[u8], reader: &InputReader) -> Result<Vec<LotOperation>, TaxLotError> {
+        let (sender, receiver) = mpsc::sync_channel(PIPELINE_DEPTH);
+        let mut batches = BatchSender::new(sender);
+        if let Err(e) = parse_reader(input, reader, &mut batches) {
+            batches.push(Err(e));
+        }
+        batches.finish();
+        receiver.into_iter().flatten().collect()
+    }
 
-        let lot_operation = LotOperation {
-            date: NaiveDate::from_str("2021-02-01")?,
-            lot_type: crate::LotType::Sell,
-            price: Decimal::from_f64(5000.00).expect("Failed to parse price"),
-            quantity: Decimal::from_f64(7.00000000).expect("Failed to parse quantity"),
-        };
-        lot_collection.sell(lot_operation)?;
+    fn lots(lot_collection: &LotCollection) -> Vec<String> {
+        lot_collection.lots().map(|lot| lot.to_string()).collect()
+    }
 
-        assert_eq!(lot_collection.lot_queue.len(), 2);
+    #[test]
+    fn test_parse_line_skips_blank_lines_and_comments() -> Result<(), TaxLotError> {
+        let reader = standard(Encoding::Auto);
+        for line in ["\n", "  \r\n", "# comment\n", "  // comment"] {
+            assert!(parse_line(line.as_bytes(), &reader, Encoding::Auto).is_none(), "Parsed `{line}`");
+        }
+
+        let lot_operation = parse_line(b"2021-01-01,buy,100.00,1\r\n", &reader, Encoding::Utf8);
+        let lot_operation = lot_operation.expect("Skipped a buy")?;
+        assert_eq!(lot_operation.quantity(), Decimal::ONE);
+        let signed = InputFormat::Delimited(InputDialect::Signed);
+        let lot_operation = parse_line(b"2021-01-01,100.00,-1", &input_reader(signed, Encoding::Auto), Encoding::Auto)
+            .expect("Skipped a sell")?;
+        assert_eq!(lot_operation.lot_type(), LotType::Sell);
+        let layout = "date=0:10,type=11:4,price=15:10,quantity=25:14".parse()?;
+        let line = b"2021-01-01 buy   100.00    2";
+        let reader = input_reader(InputFormat::FixedWidth(layout), Encoding::Auto);
+        let lot_operation = parse_line(line, &reader, Encoding::Auto).expect("Skipped a fixed-width buy")?;
+        assert_eq!(lot_operation.quantity(), Decimal::TWO);
+        Ok(())
+    }
 
-        let lot1 = get_by_date(&lot_collection, "2021-01-03")?;
-        assert_eq!(lot1.price, Decimal::from_f64(15000.00).expect("Failed to parse price"));
-        assert_eq!(lot1.quantity, Decimal::from_f64(6.00000000).expect("Failed to parse quantity"));
+    #[test]
+    fn test_parse_line_errors() {
+        let mut reader = standard(Encoding::Auto);
+        let line = |line: &str, reader: &InputReader| parse_line(line.as_bytes(), reader, Encoding::Auto);
+        assert!(matches!(line("2021-01-01,hold,100.00,1", &reader), Some(Err(TaxLotError::ParseLotTypeError))));
+        assert!(matches!(line("2021-01-01,buy,100.00", &reader), Some(Err(TaxLotError::FieldDoesntExist(_)))));
+        let invalid_utf8 = parse_line(b"2021-01-01,buy,100.00,1,caf\xe9", &reader, Encoding::Utf8);
+        assert!(matches!(invalid_utf8, Some(Err(TaxLotError::InputReadError(_)))));
+
+        // The limits are checked before a line is decoded, so they also apply to comments.
+        reader.limits = LimitOpts { max_line_length: Some(24), max_fields: Some(4), ..LimitOpts::default() };
+        assert!(line("2021-01-01,buy,100.00,1\r\n", &reader).is_some_and(|lot_operation| lot_operation.is_ok()));
+        assert!(matches!(line("# a comment longer than the limit", &reader), Some(Err(TaxLotError::LimitExceeded(_)))));
+        assert!(matches!(line("2021-01-01,buy,1,1,memo", &reader), Some(Err(TaxLotError::LimitExceeded(_)))));
+
+        // A line read from stdin fails without reading more of it than the limit.
+        let long_line = format!("2021-01-01,buy,100.00,1,{}\n", "x".repeat(1000));
+        assert!(matches!(read(long_line.as_bytes(), &reader), Err(TaxLotError::LimitExceeded(_))));
+    }
 
-        let lot2 = get_by_date(&lot_collection, "2021-01-01")?;
-        assert_eq!(lot2.price, Decimal::from_f64(10000.00).expect("Failed to parse price"));
-        assert_eq!(lot2.quantity, Decimal::from_f64(1.00000000).expect("Failed to parse quantity"));
+    #[test]
+    fn test_input_encoding_is_detected() -> Result<(), TaxLotError> {
+        let trades = TRADES.replace("memo", "café");
+        let inputs = [
+            ("UTF-8", trades.clone().into_bytes()),
+            ("UTF-8 with a byte order mark", [&b"\xEF\xBB\xBF"[..], trades.as_bytes()].concat()),
+            ("UTF-16LE", utf16le(&trades)),
+            ("UTF-16LE with a byte order mark", [&b"\xFF\xFE"[..], &utf16le(&trades)].concat()),
+            ("latin-1", trades.chars().map(|c| c as u8).collect()),
+        ];
+        for (encoding, input) in inputs {
+            let expected = ["2,2021-01-02,200.00,0.50000000"];
+            assert_eq!(lots(&apply(&input, standard(Encoding::Auto), None)?), expected, "{encoding}");
+            let sell = read(&input, &standard(Encoding::Auto))?.pop().expect("No operations");
+            assert_eq!(sell.memo(), Some("café"), "{encoding}");
+        }
 
+        // An explicit encoding is not detected, so latin-1 is decoded as such even when it is valid UTF-8.
+        let sell = read(trades.as_bytes(), &standard(Encoding::Latin1))?.pop().expect("No operations");
+        assert_eq!(sell.memo(), Some("cafÃ©"));
         Ok(())
     }
 
     #[test]
-    fn test_sell_runs_out_of_lots() -> Result<(), TaxLotError> {
-        let selection_algo = SelectionAlgorithm::Hifo;
-        let mut lot_collection = LotCollection::new(selection_algo);
-        let lot_operation = LotOperation {
-            date: NaiveDate::from_str("2021-01-01")?,
-            lot_type: crate::LotType::Buy,
-            price: Decimal::from_f64(10000.00).expect("Failed to parse price"),
-            quantity: Decimal::from_f64(1.00000000).expect("Failed to parse quantity"),
-        };
-        lot_collection.buy(lot_operation)?;
+    fn test_input_that_is_not_in_its_encoding_fails() {
+        let latin1: Vec<u8> = TRADES.replace("memo", "café").chars().map(|c| c as u8).collect();
+        assert!(apply(&latin1, standard(Encoding::Utf8), None).is_err(), "Applied latin-1 input as UTF-8");
+        read(&latin1, &standard(Encoding::Utf8)).expect_err("Read latin-1 input as UTF-8");
+
+        let mut odd = [&b"\xFF\xFE"[..], &utf16le(TRADES)].concat();
+        odd.push(b'\n');
+        assert!(matches!(apply(&odd, standard(Encoding::Auto), None), Err(TaxLotError::InputReadError(_))));
+        assert!(matches!(read(&odd, &standard(Encoding::Auto)), Err(TaxLotError::InputReadError(_))));
+        let utf8 = apply(TRADES.as_bytes(), standard(Encoding::Utf16le), None);
+        assert!(utf8.is_err(), "Applied UTF-8 input as UTF-16LE");
+    }
 
-        let lot_operation = LotOperation {
-            date: NaiveDate::from_str("2021-01-02")?,
-            lot_type: crate::LotType::Buy,
-            price: Decimal::from_f64(20000.00).expect("Failed to parse price"),
-            quantity: Decimal::from_f64(3.00000000).expect("Failed to parse quantity"),
+    #[test]
+    fn test_check_schema_counts_errors_per_column() -> Result<(), TaxLotError> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("export.csv");
+        let check = |reader: &InputReader| {
+            let (mut out, mut status) = (Vec::new(), Vec::new());
+            let checked = check_schema(&path, reader, &Output::default(), &mut out, &mut status);
+            let status = String::from_utf8_lossy(&status).into_owned();
+            (checked, String::from_utf8_lossy(&out).into_owned(), status)
         };
-        lot_collection.buy(lot_operation)?;
 
-        let lot_operation = LotOperation {
-            date: NaiveDate::from_str("2021-01-03")?,
-            lot_type: crate::LotType::Buy,
-            price: Decimal::from_f64(15000.00).expect("Failed to parse price"),
-            quantity: Decimal::from_f64(10.00000000).expect("Failed to parse quantity"),
-        };
-        lot_collection.buy(lot_operation)?;
+        let export = "2021-01-01,buy,100.00,1\n// comment\n2021-13-01,buy,100.00,1\n\n2021-01-02,hold,abc\n";
+        fs::write(&path, export)?;
+        let (checked, out, status) = check(&standard(Encoding::Auto));
+        assert!(matches!(checked, Err(TaxLotError::SchemaErrors(3, 2))), "{checked:?}");
+        assert_eq!(status, "");
+        assert_eq!(
+            out,
+            "date,1,line 3: `2021-13-01` is not a date in the format YYYY-MM-DD\n\
+            type,1,line 5: `hold` is not an operation type\n\
+            price,1,line 5: `abc` is not a number\n\
+            quantity,1,line 5: missing\n\
+            fields,0,\n"
+        );
+
+        // The file is decoded as when it is processed.
+        fs::write(&path, [&b"\xFF\xFE"[..], &utf16le(TRADES)].concat())?;
+        let (checked, _, status) = check(&standard(Encoding::Auto));
+        checked?;
+        assert_eq!(status, "Checked 3 rows without errors\n");
+        fs::write(&path, [&b"\xFF\xFE"[..], &utf16le(TRADES), b"\n"].concat())?;
+        assert!(matches!(check(&standard(Encoding::Auto)).0, Err(TaxLotError::InputReadError(_))));
+
+        let mapping = XmlMapping::from_str("record=//trade,date=@date,type=side,price=price,quantity=quantity")?;
+        let xml = input_reader(InputFormat::Xml(mapping), Encoding::Auto);
+        fs::write(&path, "<t><trade date=\"2021-01-01\"><side>buy</side><price>1</price></trade></t>")?;
+        let (checked, out, _) = check(&xml);
+        assert!(matches!(checked, Err(TaxLotError::SchemaErrors(1, 1))), "{checked:?}");
+        assert_eq!(out, "date,0,\ntype,0,\nprice,0,\nquantity,1,record 1: missing\n");
+        fs::write(&path, "<t><trade date=\"2021-01-01\">")?;
+        assert!(matches!(check(&xml).0, Err(TaxLotError::XmlParseError(_))));
+
+        fs::remove_file(&path)?;
+        assert!(matches!(check(&standard(Encoding::Auto)).0, Err(TaxLotError::InputReadError(_))));
+        Ok(())
+    }
 
-        let lot_operation = LotOperation {
-            date: NaiveDate::from_str("2021-02-01")?,
-            lot_type: crate::LotType::Sell,
-            price: Decimal::from_f64(5000.00).expect("Failed to parse price"),
-            quantity: Decimal::from_f64(15.00000000).expect("Failed to parse quantity"),
-        };
-        lot_collection.sell(lot_operation)?;
+    #[test]
+    fn test_insert_writes_changed_lots_and_disposals() -> Result<(), TaxLotError> {
+        let mut lot_collection = LotCollection::new(SelectionAlgorithm::Fifo);
+        lot_collection.enable_journal();
+        lot_collection.enable_disposals();
+        for line in ["2021-01-10,buy,20000.00,1.00000000", "2021-02-01,sell,25000.00,0.50000000"] {
+            lot_collection.apply_lot_operation(LotOperation::from_str(line)?)?;
+        }
+        let operation = "2021-01-05,buy,10000.00,1.00000000";
+        let changes = lot_collection.insert_lot_operation(LotOperation::from_str(operation)?)?;
+
+        let mut out = Vec::new();
+        write_changes(&[(operation, changes)], &IdFormat::default(), &Output::default(), &mut out)?;
+        // The sell now takes the inserted lot, which was bought first, and the lot it sold from before is renumbered.
+        assert_eq!(
+            String::from_utf8_lossy(&out),
+            "\"2021-01-05,buy,10000.00,1.00000000\",lot,changed,1,1,2021-01-10,2021-01-05,,20000.00,10000.00,\
+            0.50000000,0.50000000,,\n\
+            \"2021-01-05,buy,10000.00,1.00000000\",lot,added,,2,,2021-01-10,,,20000.00,,1.00000000,,\n\
+            \"2021-01-05,buy,10000.00,1.00000000\",disposal,rematched,1,1,2021-01-10,2021-01-05,2021-02-01,,,\
+            0.50000000,0.50000000,2500.00,7500.00\n"
+        );
+        Ok(())
+    }
 
-        // We have sold all of the lots, because the sell specified 15 shares and we have only bought (1+3+10)=14 shares
-        assert_eq!(lot_collection.lot_queue.len(), 0);
+    #[test]
+    fn test_gains_report_splits_the_gains_of_section_1256_contracts_60_40() -> Result<(), TaxLotError> {
+        let quotes = [Quote { date: NaiveDate::from_ymd_opt(2021, 12, 31).unwrap(), price: Decimal::from(4800) }];
+        let mut contracts =
+            Section1256::new("futures".to_string(), &quotes, LotCollection::new(SelectionAlgorithm::Fifo));
+        let mut lot_collection = LotCollection::new(SelectionAlgorithm::Fifo);
+        let input = "2021-01-01,buy,100.00,1.00000000\n\
+            2021-06-01,sell,150.00,1.00000000\n\
+            2021-11-01,buy,4700.00,1.00000000,tags=futures\n";
+        process_input(
+            Input::Memory(input.as_bytes().into()),
+            standard(Encoding::Auto),
+            &mut lot_collection,
+            None,
+            Some(&mut contracts),
+            &mut Alerts::default(),
+        )?;
+        contracts.finish(NaiveDate::from_ymd_opt(2022, 1, 3).unwrap())?;
+        assert!(lots(&lot_collection).is_empty());
 
+        let mut out = Vec::new();
+        write_gains(&lot_collection, Some(&contracts), Jurisdiction::Us, &Output::default(), &mut out)?;
+        // The open contract is marked 100 up at the end of 2021, which adds 40 short-term and 60 long-term.
+        assert_eq!(String::from_utf8_lossy(&out), "2021,90.00,60.00,150.00\n");
         Ok(())
     }
 
     #[test]
-    fn test_sell_with_no_lots() -> Result<(), TaxLotError> {
-        let selection_algo = SelectionAlgorithm::Hifo;
-        let mut lot_collection = LotCollection::new(selection_algo);
-        let lot_operation = LotOperation {
-            date: NaiveDate::from_str("2021-02-01")?,
-            lot_type: crate::LotType::Sell,
-            price: Decimal::from_f64(5000.00).expect("Failed to parse price"),
-            quantity: Decimal::from_f64(15.00000000).expect("Failed to parse quantity"),
-        };
-
-        // The sell operation does not fail if there's no tax lots to sell, it will return success without changing the lot collection
-        lot_collection.sell(lot_operation)?;
-        assert_eq!(lot_collection.lot_queue.len(), 0);
+    fn test_checkpoint_resumes_after_the_restored_operations() -> Result<(), TaxLotError> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("checkpoint");
+        let (first_two, _) = TRADES.split_at(TRADES.match_indices('\n').nth(1).map_or(0, |(end, _)| end + 1));
+
+        // An interrupted run writes a checkpoint after every second operation.
+        let mut lot_collection = LotCollection::new(SelectionAlgorithm::Fifo);
+        let mut checkpointer = Checkpointer::restore(path.clone(), 2, None, &mut lot_collection)?;
+        assert!(!checkpointer.is_restored(0));
+        apply(first_two.as_bytes(), standard(Encoding::Auto), Some(&mut checkpointer))?;
+        assert!(path.exists());
+
+        // Resuming skips the operations in the checkpoint and applies the rest.
+        let mut lot_collection = LotCollection::new(SelectionAlgorithm::Fifo);
+        let mut checkpointer = Checkpointer::restore(path.clone(), 2, None, &mut lot_collection)?;
+        assert!(checkpointer.is_restored(1));
+        assert!(!checkpointer.is_restored(2));
+        assert_eq!(lots(&lot_collection), ["1,2021-01-01,100.00,1.00000000", "2,2021-01-02,200.00,1.00000000"]);
+        process_input(
+            Input::Memory(TRADES.as_bytes().into()),
+            standard(Encoding::Auto),
+            &mut lot_collection,
+            Some(&mut checkpointer),
+            None,
+            &mut Alerts::default(),
+        )?;
+        assert_eq!(lots(&lot_collection), lots(&apply(TRADES.as_bytes(), standard(Encoding::Auto), None)?));
+        Ok(())
+    }
 
+    #[test]
+    fn test_checkpoint_errors() -> Result<(), TaxLotError> {
+        let dir = tempfile::tempdir()?;
+        let mut lot_collection = LotCollection::new(SelectionAlgorithm::Fifo);
+
+        let path = dir.path().join("checkpoint");
+        fs::write(&path, "not a checkpoint\n")?;
+        let restored = Checkpointer::restore(path, 2, None, &mut lot_collection);
+        assert!(matches!(restored, Err(TaxLotError::InvalidCheckpoint(_))));
+
+        // Only a missing checkpoint starts from the beginning.
+        let restored = Checkpointer::restore(dir.path().to_path_buf(), 2, None, &mut lot_collection);
+        assert!(matches!(restored, Err(TaxLotError::InputReadError(_))));
+
+        // A checkpoint that cannot be written fails the run.
+        let path = dir.path().join("missing").join("checkpoint");
+        let mut checkpointer = Checkpointer::restore(path, 1, None, &mut lot_collection)?;
+        let applied = apply(TRADES.as_bytes(), standard(Encoding::Auto), Some(&mut checkpointer));
+        assert!(matches!(applied, Err(TaxLotError::InputReadError(_))));
         Ok(())
     }
 }
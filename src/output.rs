@@ -0,0 +1,386 @@
+//! Output formats of the lots and reports. Each output only defines its rows, and is written in every format by
+//! `Output::write`.
+
+use std::{
+    borrow::Cow,
+    io::{self, BufWriter, Write},
+};
+
+use clap::ValueEnum;
+
+#[cfg(feature = "arrow")]
+use crate::columnar;
+
+/// Represents the formats the lots and reports can be written in.
+///
+/// `csv`: One comma-separated line per row, the format the lots and reports have always been written in.
+/// `json`: A JSON object with the name and schema version of the output, and an array of the rows as objects.
+/// `table`: Aligned columns with a header row, to be read in a terminal.
+/// `arrow`: An Arrow IPC (Feather v2) file, e.g. for Polars or DuckDB. Requires the `arrow` feature.
+/// `parquet`: A Parquet file with the same columns as `arrow`, compressed with Snappy. Requires the `parquet`
+/// feature.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    #[default]
+    Csv,
+    Json,
+    Table,
+    #[cfg(feature = "arrow")]
+    Arrow,
+    #[cfg(feature = "parquet")]
+    Parquet,
+}
+
+/// Represents the versions of the columns of the lots and reports that are written. Columns are only added in a
+/// new version, and older versions can still be written with `--schema-version`, so that parsers of the output
+/// keep working when columns are added.
+///
+/// `1`: The lots, gains, quarterly gains and income.
+/// `2`: Adds the purchases that make up each lot to the lots.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+pub enum SchemaVersion {
+    #[value(name = "1")]
+    V1,
+    #[value(name = "2")]
+    V2,
+}
+
+impl SchemaVersion {
+    /// The version written when no version is asked for.
+    pub const LATEST: SchemaVersion = SchemaVersion::V2;
+
+    pub fn number(self) -> u32 {
+        match self {
+            SchemaVersion::V1 => 1,
+            SchemaVersion::V2 => 2,
+        }
+    }
+}
+
+/// Represents the value of a column of a row.
+pub enum Value {
+    Text(String),
+    /// A number that is already formatted, e.g. with two decimals, which JSON keeps as it is.
+    Number(String),
+    List(Vec<String>),
+    /// Nested rows, e.g. the purchases of a lot, which are objects in JSON. The other formats only have the
+    /// number of them, since a lot can be made of many purchases.
+    Records(Vec<Vec<(&'static str, Value)>>),
+}
+
+/// Represents a row of an output, e.g. a lot.
+pub trait Row {
+    /// Name of the output, e.g. `lots`.
+    const OUTPUT: &'static str;
+
+    /// Returns the names of the columns in `schema_version`, in the order of `values`.
+    fn columns(schema_version: SchemaVersion) -> &'static [&'static str];
+
+    /// Returns the value of each column in `schema_version`, or `None` if the row has no value for it.
+    fn values(&self, schema_version: SchemaVersion) -> Vec<Option<Value>>;
+
+    /// Returns the columns of the CSV lines in `schema_version`, as named in the schema header.
+    fn csv_columns(schema_version: SchemaVersion) -> String {
+        Self::columns(schema_version).join(",")
+    }
+
+    /// Writes the row as a CSV line without the line ending. By default every value is written as in a table,
+    /// with missing values left empty, and quoted as in RFC 4180 if it has a comma, quote or line ending.
+    fn write_csv(&self, schema_version: SchemaVersion, out: &mut impl Write) -> io::Result<()> {
+        for (i, value) in self.values(schema_version).into_iter().enumerate() {
+            if i > 0 {
+                write!(out, ",")?;
+            }
+            write!(out, "{}", csv_field(&plain_text(value).0))?;
+        }
+        Ok(())
+    }
+}
+
+/// Writes outputs in a format.
+///
+/// `format`: Format every output is written in.
+/// `schema_version`: If set, the CSV and table formats start with a header line naming the schema version and
+/// columns, and JSON has this version rather than the latest one.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Output {
+    pub format: OutputFormat,
+    pub schema_version: Option<SchemaVersion>,
+}
+
+impl Output {
+    /// Writes `rows` to `out` in the format of the output.
+    pub fn write<R: Row>(&self, rows: impl Iterator<Item = R>, out: impl Write) -> io::Result<()> {
+        let mut out = BufWriter::new(out);
+        let schema_version = self.schema_version.unwrap_or(SchemaVersion::LATEST);
+        match self.format {
+            OutputFormat::Csv => {
+                self.write_header::<R>(&R::csv_columns(schema_version), &mut out)?;
+                for row in rows {
+                    row.write_csv(schema_version, &mut out)?;
+                    writeln!(out)?;
+                }
+            }
+            OutputFormat::Json => {
+                let version = schema_version.number();
+                write!(out, "{{\"schema\":{},\"version\":{version},\"rows\":[", json_string(R::OUTPUT))?;
+                for (i, row) in rows.enumerate() {
+                    writeln!(out, "{}", if i > 0 { "," } else { "" })?;
+                    let columns = R::columns(schema_version).iter().copied();
+                    write_json_object(columns.zip(row.values(schema_version)), &mut out)?;
+                }
+                writeln!(out, "\n]}}")?;
+            }
+            OutputFormat::Table => {
+                self.write_header::<R>(&R::columns(schema_version).join(","), &mut out)?;
+                write_table::<R>(rows, schema_version, &mut out)?;
+            }
+            #[cfg(feature = "arrow")]
+            OutputFormat::Arrow => {
+                let batch = columnar::record_batch(rows, schema_version).map_err(io::Error::other)?;
+                columnar::write_ipc(&batch, &mut out)?;
+            }
+            #[cfg(feature = "parquet")]
+            OutputFormat::Parquet => {
+                let batch = columnar::record_batch(rows, schema_version).map_err(io::Error::other)?;
+                columnar::write_parquet(&batch, &mut out)?;
+            }
+        }
+        out.flush()
+    }
+
+    /// Writes a status message about the rows of `R`, e.g. how many were checked, to `out`, which is usually stderr
+    /// rather than where the rows are written. It is a JSON object with the name of the output in the JSON format,
+    /// so that it can be parsed like the rows, and a line of text in the other formats.
+    pub fn write_status<R: Row>(&self, message: &str, mut out: impl Write) -> io::Result<()> {
+        match self.format {
+            OutputFormat::Json => {
+                writeln!(out, "{{\"schema\":{},\"status\":{}}}", json_string(R::OUTPUT), json_string(message))?;
+            }
+            _ => writeln!(out, "{message}")?,
+        }
+        out.flush()
+    }
+
+    /// Writes the schema header line of the rows, e.g. `# lots schema 1: id,date,price,quantity`, if a schema
+    /// version was asked for.
+    fn write_header<R: Row>(&self, columns: &str, out: &mut impl Write) -> io::Result<()> {
+        match self.schema_version {
+            Some(schema_version) => writeln!(out, "# {} schema {}: {columns}", R::OUTPUT, schema_version.number()),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Writes `rows` as a table with a header row. Every column is as wide as its widest value, with numbers
+/// aligned to the right and the other values to the left.
+fn write_table<R: Row>(
+    rows: impl Iterator<Item = R>,
+    schema_version: SchemaVersion,
+    out: &mut impl Write,
+) -> io::Result<()> {
+    let columns = R::columns(schema_version);
+    let rows: Vec<Vec<(String, bool)>> = rows
+        .map(|row| row.values(schema_version).into_iter().map(plain_text).collect())
+        .collect();
+    let widths: Vec<usize> = columns
+        .iter()
+        .enumerate()
+        .map(|(i, column)| {
+            rows.iter()
+                .map(|row| row[i].0.chars().count())
+                .chain([column.len()])
+                .max()
+                .unwrap_or_default()
+        })
+        .collect();
+
+    // The header of a column is aligned like its values.
+    let header = columns
+        .iter()
+        .enumerate()
+        .map(|(i, column)| (column.to_string(), rows.first().is_some_and(|row| row[i].1)))
+        .collect();
+    for row in [header].iter().chain(&rows) {
+        let cells: Vec<_> = row
+            .iter()
+            .zip(&widths)
+            .map(|((value, is_number), &width)| match is_number {
+                true => format!("{value:>width$}"),
+                false => format!("{value:<width$}"),
+            })
+            .collect();
+        writeln!(out, "{}", cells.join("  ").trim_end())?;
+    }
+    Ok(())
+}
+
+/// Returns a value as it is written in a table or CSV line, and whether it is a number. Lists are separated by
+/// `;`, and missing values are empty.
+fn plain_text(value: Option<Value>) -> (String, bool) {
+    match value {
+        Some(Value::Text(text)) => (text, false),
+        Some(Value::Number(number)) => (number, true),
+        Some(Value::List(items)) => (items.join(";"), false),
+        Some(Value::Records(records)) => (records.len().to_string(), true),
+        None => (String::new(), false),
+    }
+}
+
+/// Returns `s` as a CSV field. A field with a comma, quote or line ending is quoted, with its quotes doubled.
+fn csv_field(s: &str) -> Cow<'_, str> {
+    if s.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", s.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(s)
+    }
+}
+
+/// Writes the values of `columns` as a JSON object, with missing values as `null`.
+pub fn write_json_object<'a, V>(columns: impl Iterator<Item = (&'a str, V)>, out: &mut impl Write) -> io::Result<()>
+where
+    V: Into<Option<Value>>,
+{
+    write!(out, "{{")?;
+    for (i, (column, value)) in columns.enumerate() {
+        write!(out, "{}{}:", if i > 0 { "," } else { "" }, json_string(column))?;
+        match value.into() {
+            Some(Value::Text(text)) => write!(out, "{}", json_string(&text))?,
+            Some(Value::Number(number)) => write!(out, "{number}")?,
+            Some(Value::List(items)) => {
+                let items: Vec<_> = items.iter().map(|item| json_string(item)).collect();
+                write!(out, "[{}]", items.join(","))?;
+            }
+            Some(Value::Records(records)) => {
+                write!(out, "[")?;
+                for (j, record) in records.into_iter().enumerate() {
+                    write!(out, "{}", if j > 0 { "," } else { "" })?;
+                    write_json_object(record.into_iter(), out)?;
+                }
+                write!(out, "]")?;
+            }
+            None => write!(out, "null")?,
+        }
+    }
+    write!(out, "}}")
+}
+
+/// Returns `s` as a quoted JSON string.
+pub(crate) fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            '\r' => quoted.push_str("\\r"),
+            '\t' => quoted.push_str("\\t"),
+            c if c.is_control() => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{json_string, Output, OutputFormat, Row, SchemaVersion, Value};
+
+    /// Represents a row with a value of every kind, and a column that only the second version has.
+    struct TestRow {
+        name: &'static str,
+        amount: &'static str,
+        tags: Vec<&'static str>,
+        memo: Option<&'static str>,
+    }
+
+    impl Row for TestRow {
+        const OUTPUT: &'static str = "test";
+        fn columns(schema_version: SchemaVersion) -> &'static [&'static str] {
+            match schema_version {
+                SchemaVersion::V1 => &["name", "amount", "tags"],
+                SchemaVersion::V2 => &["name", "amount", "tags", "memo", "parts"],
+            }
+        }
+
+        fn values(&self, schema_version: SchemaVersion) -> Vec<Option<Value>> {
+            let mut values = vec![
+                Some(Value::Text(self.name.to_string())),
+                Some(Value::Number(self.amount.to_string())),
+                Some(Value::List(self.tags.iter().map(|tag| tag.to_string()).collect())),
+            ];
+            if schema_version >= SchemaVersion::V2 {
+                values.push(self.memo.map(|memo| Value::Text(memo.to_string())));
+                let parts = vec![vec![("amount", Value::Number(self.amount.to_string()))]];
+                values.push(Some(Value::Records(parts)));
+            }
+            values
+        }
+    }
+
+    fn rows() -> Vec<TestRow> {
+        vec![
+            TestRow { name: "plain", amount: "1.50", tags: vec!["a", "b"], memo: None },
+            TestRow { name: "long name", amount: "-10.00", tags: vec![], memo: Some("says \"hi\", twice\nand more") },
+        ]
+    }
+
+    fn write(format: OutputFormat, schema_version: Option<SchemaVersion>) -> String {
+        let mut out = Vec::new();
+        Output { format, schema_version }.write(rows().into_iter(), &mut out).expect("Could not write the rows");
+        String::from_utf8(out).expect("Output is not UTF-8")
+    }
+
+    #[test]
+    fn test_csv_quotes_fields_with_commas_quotes_and_line_endings() {
+        assert_eq!(
+            write(OutputFormat::Csv, None),
+            "plain,1.50,a;b,,1\nlong name,-10.00,,\"says \"\"hi\"\", twice\nand more\",1\n"
+        );
+        assert_eq!(
+            write(OutputFormat::Csv, Some(SchemaVersion::V1)),
+            "# test schema 1: name,amount,tags\nplain,1.50,a;b\nlong name,-10.00,\n"
+        );
+    }
+
+    #[test]
+    fn test_json_escapes_strings_and_nests_records() {
+        assert_eq!(
+            write(OutputFormat::Json, None),
+            concat!(
+                "{\"schema\":\"test\",\"version\":2,\"rows\":[\n",
+                "{\"name\":\"plain\",\"amount\":1.50,\"tags\":[\"a\",\"b\"],\"memo\":null,",
+                "\"parts\":[{\"amount\":1.50}]},\n",
+                "{\"name\":\"long name\",\"amount\":-10.00,\"tags\":[],",
+                "\"memo\":\"says \\\"hi\\\", twice\\nand more\",\"parts\":[{\"amount\":-10.00}]}\n",
+                "]}\n"
+            )
+        );
+        assert_eq!(json_string("tab\tback\\slash\u{1}"), "\"tab\\tback\\\\slash\\u0001\"");
+    }
+
+    #[test]
+    fn test_status_is_a_json_object_in_json() -> std::io::Result<()> {
+        let mut out = Vec::new();
+        Output { format: OutputFormat::Csv, schema_version: None }.write_status::<TestRow>("Checked \"2\"", &mut out)?;
+        Output { format: OutputFormat::Json, schema_version: None }.write_status::<TestRow>("Checked \"2\"", &mut out)?;
+        let out = String::from_utf8(out).expect("Output is not UTF-8");
+        assert_eq!(out, "Checked \"2\"\n{\"schema\":\"test\",\"status\":\"Checked \\\"2\\\"\"}\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_table_aligns_numbers_to_the_right() {
+        assert_eq!(
+            write(OutputFormat::Table, Some(SchemaVersion::V1)),
+            concat!(
+                "# test schema 1: name,amount,tags\n",
+                "name       amount  tags\n",
+                "plain        1.50  a;b\n",
+                "long name  -10.00\n"
+            )
+        );
+    }
+}
@@ -0,0 +1,197 @@
+//! Performance of the lots as an investment: the money-weighted return (IRR) of the cash flows of the buys and
+//! sells, and the time-weighted return of the price over the periods the position was held.
+//!
+//! The cash flows are derived from the disposals and the remaining lots rather than from the operations, so the
+//! performance is the same however the lots were reached, e.g. after amends or a restored checkpoint. Each disposal
+//! is what was paid for its part of a lot on the date of the lot and what the sell brought in on the date of the
+//! sell, and each remaining lot is what was paid for it and what it is worth at the mark price. Income is not a cash
+//! flow. There is a single position, since operations have no symbol.
+
+use chrono::NaiveDate;
+use rust_decimal::{
+    prelude::{FromPrimitive, ToPrimitive},
+    Decimal,
+};
+use taxlot::{LotCollection, TaxLotError};
+
+use crate::prices::Quote;
+
+/// Number of bisection steps of the IRR, which narrow it far below a basis point.
+const IRR_STEPS: usize = 200;
+
+/// Represents the performance of the lots up to the date of the mark price.
+///
+/// `start`: Date of the first buy.
+/// `end`: Date of the mark price.
+/// `invested`: Total paid for the lots.
+/// `returned`: Total the sells brought in.
+/// `market_value`: What the remaining lots are worth at the mark price.
+/// `irr`: Annual rate that discounts the cash flows to zero, if there is one.
+/// `twr`: Return of the price over the periods in which anything was held, compounded.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Performance {
+    pub start: NaiveDate,
+    pub end: NaiveDate,
+    pub invested: Decimal,
+    pub returned: Decimal,
+    pub market_value: Decimal,
+    pub irr: Option<Decimal>,
+    pub twr: Decimal,
+}
+
+/// Represents a buy or sell of `quantity` at `price`, with a positive quantity for a buy. `cash_flow` is what the
+/// trade brought in, negative for a buy.
+struct Trade {
+    date: NaiveDate,
+    price: Decimal,
+    quantity: Decimal,
+    cash_flow: Decimal,
+}
+
+/// Returns the performance of the lots of `lot_collection`, which must record disposals, valued at `mark`. Returns
+/// `None` if nothing was bought.
+pub fn performance(lot_collection: &LotCollection, mark: Quote) -> Result<Option<Performance>, TaxLotError> {
+    let overflow = || TaxLotError::DecimalOverflow("totalling the cash flows".to_string());
+    let mut trades = Vec::new();
+    let (mut invested, mut returned, mut market_value) = (Decimal::ZERO, Decimal::ZERO, Decimal::ZERO);
+    for disposal in lot_collection.disposals() {
+        let disposal = disposal.map_err(TaxLotError::DisposalSpillError)?;
+        invested = invested.checked_add(disposal.cost_basis).ok_or_else(overflow)?;
+        returned = returned.checked_add(disposal.proceeds).ok_or_else(overflow)?;
+        let quantity = disposal.quantity;
+        trades.push(Trade {
+            date: disposal.acquired,
+            price: disposal.cost_basis / quantity,
+            quantity,
+            cash_flow: -disposal.cost_basis,
+        });
+        trades.push(Trade {
+            date: disposal.sold,
+            price: disposal.proceeds / quantity,
+            quantity: -quantity,
+            cash_flow: disposal.proceeds,
+        });
+    }
+    for lot in lot_collection.lots() {
+        let gain = lot.unrealized_gain(mark.price, mark.date)?;
+        invested = invested.checked_add(gain.cost_basis).ok_or_else(overflow)?;
+        market_value = market_value.checked_add(gain.market_value).ok_or_else(overflow)?;
+        let (date, price, quantity) = (lot.date(), lot.price(), lot.quantity());
+        trades.push(Trade { date, price, quantity, cash_flow: -gain.cost_basis });
+    }
+    let Some(start) = trades.iter().map(|trade| trade.date).min() else {
+        return Ok(None);
+    };
+
+    // The mark values what is left as if it were sold. Trades on the same date are in the order buys, sells and
+    // then the mark, so that a lot bought and sold on a date is held in between.
+    trades.push(Trade { date: mark.date, price: mark.price, quantity: Decimal::ZERO, cash_flow: market_value });
+    trades.sort_by_key(|trade| (trade.date, trade.quantity.is_zero(), trade.quantity.is_sign_negative()));
+    let flows: Vec<_> = trades.iter().map(|trade| (trade.date, trade.cash_flow)).collect();
+    Ok(Some(Performance {
+        start,
+        end: mark.date,
+        invested,
+        returned,
+        market_value,
+        irr: irr(&flows),
+        twr: twr(&trades).ok_or_else(overflow)?,
+    }))
+}
+
+/// Returns the annual rate at which the net present value of `flows` on the date of the first one is zero, found by
+/// bisection. Returns `None` if the flows have no such rate, e.g. if they span no time or never change sign.
+fn irr(flows: &[(NaiveDate, Decimal)]) -> Option<Decimal> {
+    let start = flows.iter().map(|(date, _)| *date).min()?;
+    let flows: Vec<(f64, f64)> = flows
+        .iter()
+        .map(|(date, amount)| ((*date - start).num_days() as f64 / 365.0, amount.to_f64().unwrap_or(0.0)))
+        .collect();
+    // The present value at a continuously compounded rate, which ranges over every annual rate above -100%.
+    let npv = |rate: f64| flows.iter().map(|(years, amount)| amount * (-rate * years).exp()).sum::<f64>();
+    let (mut low, mut high) = (-20.0, 20.0);
+    let low_sign = npv(low).is_sign_positive();
+    if low_sign == npv(high).is_sign_positive() {
+        return None;
+    }
+    for _ in 0..IRR_STEPS {
+        let middle = (low + high) / 2.0;
+        if npv(middle).is_sign_positive() == low_sign {
+            low = middle;
+        } else {
+            high = middle;
+        }
+    }
+    Decimal::from_f64((low.exp_m1() * 1e6).round() / 1e6)
+}
+
+/// Returns the compounded return of the price between each trade and the next in date order, over the periods in
+/// which a quantity was held. Returns `None` on overflow.
+fn twr(trades: &[Trade]) -> Option<Decimal> {
+    let mut growth = Decimal::ONE;
+    let mut held = Decimal::ZERO;
+    let mut last_price = None;
+    for trade in trades {
+        if let Some(last_price) = last_price.filter(|_| held > Decimal::ZERO) {
+            growth = growth.checked_mul(trade.price.checked_div(last_price)?)?;
+        }
+        held += trade.quantity;
+        last_price = Some(trade.price);
+    }
+    Some((growth - Decimal::ONE).round_dp(6))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chrono::NaiveDate;
+    use rust_decimal::Decimal;
+    use taxlot::{LotCollection, LotOperation, SelectionAlgorithm, TaxLotError};
+
+    use super::performance;
+    use crate::prices::Quote;
+
+    fn apply(lines: &[&str]) -> Result<LotCollection, TaxLotError> {
+        let mut lot_collection = LotCollection::new(SelectionAlgorithm::Fifo);
+        lot_collection.enable_disposals();
+        for line in lines {
+            lot_collection.apply_lot_operation(LotOperation::from_str(line)?)?;
+        }
+        Ok(lot_collection)
+    }
+
+    #[test]
+    fn test_returns_of_a_held_lot() -> Result<(), TaxLotError> {
+        let lot_collection = apply(&["2021-01-01,buy,100.00,10.00000000"])?;
+        let mark = Quote { date: NaiveDate::from_str("2022-01-01")?, price: Decimal::from(110) };
+        let performance = performance(&lot_collection, mark)?.expect("No performance");
+        assert_eq!(performance.invested, Decimal::from(1000));
+        assert_eq!(performance.market_value, Decimal::from(1100));
+        assert_eq!(performance.irr, Some(Decimal::from_str("0.1")?));
+        assert_eq!(performance.twr, Decimal::from_str("0.1")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_time_weighted_return_ignores_the_size_of_buys() -> Result<(), TaxLotError> {
+        // The price doubles while a little is held and halves after much more is bought, so the time-weighted
+        // return is zero while the money-weighted return is a loss.
+        let lot_collection = apply(&[
+            "2021-01-01,buy,100.00,1.00000000",
+            "2022-01-01,buy,200.00,9.00000000",
+            "2022-06-01,sell,150.00,5.00000000",
+        ])?;
+        let mark = Quote { date: NaiveDate::from_str("2023-01-01")?, price: Decimal::from(100) };
+        let returns = performance(&lot_collection, mark)?.expect("No performance");
+        assert_eq!(returns.start, NaiveDate::from_str("2021-01-01")?);
+        assert_eq!(returns.invested, Decimal::from(1900));
+        assert_eq!(returns.returned, Decimal::from(750));
+        assert_eq!(returns.market_value, Decimal::from(500));
+        assert_eq!(returns.twr, Decimal::ZERO);
+        assert!(returns.irr.expect("No IRR") < Decimal::ZERO);
+
+        assert_eq!(performance(&apply(&[])?, mark)?, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,146 @@
+//! Sell strategies compiled to WebAssembly, loaded with `--wasm-strategy`, so that a firm-specific policy for
+//! selecting lots can be used without rebuilding taxlot. The plugin is run with wasmi and given no imports, so it
+//! can only compute the order to sell in.
+//!
+//! A plugin is a module that exports its `memory` and these functions:
+//!
+//! `alloc(len: i32) -> i32`: Returns the offset in `memory` of `len` bytes to write a request to. It is called once
+//! for every sell, so a plugin can hand out the same bytes every time.
+//! `sell_order(offset: i32, len: i32) -> i64`: Returns the ids of the lots to sell from, in the order to sell from
+//! them, for the request of `len` bytes at `offset`. The request is a line `date,price,quantity` with the sell,
+//! followed by a line `id,date,price,quantity` for every lot it can sell from, in the order of the selection
+//! algorithm. The ids are returned one per line, at the offset in the upper 32 bits of the result, with the length
+//! in the lower 32 bits. Lots that are left out are not sold.
+//!
+//! Dates are `YYYY-MM-DD`, and prices and quantities are exact decimals. Each call is stopped once it has used up
+//! `FUEL`, roughly one unit per instruction, so that a plugin cannot hang a run.
+
+use std::{fmt::Write, fs, path::Path, str, sync::Mutex};
+
+use taxlot::{Lot, LotOperation, SellStrategy, TaxLotError};
+use wasmi::{Config, Engine, Linker, Memory, Module, Store, TypedFunc};
+
+/// Fuel that each call of a plugin function can use up.
+const FUEL: u64 = 100_000_000;
+
+/// Represents a loaded plugin. The store is locked for every sell, since calls change the memory of the plugin.
+/// `fuel` is what each call can use up.
+pub struct WasmStrategy {
+    fuel: u64,
+    store: Mutex<Store<()>>,
+    memory: Memory,
+    alloc: TypedFunc<i32, i32>,
+    sell_order: TypedFunc<(i32, i32), i64>,
+}
+
+impl WasmStrategy {
+    /// Loads the plugin at `path`, which is a WebAssembly module in the binary or text format.
+    pub fn load(path: &Path) -> Result<WasmStrategy, TaxLotError> {
+        let error = |e: wasmi::Error| TaxLotError::StrategyError(format!("{}: {e}", path.display()));
+        let wasm = fs::read(path)?;
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, wasm).map_err(error)?;
+        let mut store = Store::new(&engine, ());
+        store.set_fuel(FUEL).map_err(error)?;
+        let instance = Linker::<()>::new(&engine).instantiate_and_start(&mut store, &module).map_err(error)?;
+        let memory = instance
+            .get_memory(&store, "memory")
+            .ok_or_else(|| TaxLotError::StrategyError(format!("{} does not export `memory`", path.display())))?;
+        let alloc = instance.get_typed_func(&store, "alloc").map_err(error)?;
+        let sell_order = instance.get_typed_func(&store, "sell_order").map_err(error)?;
+        Ok(WasmStrategy { fuel: FUEL, store: Mutex::new(store), memory, alloc, sell_order })
+    }
+}
+
+impl SellStrategy for WasmStrategy {
+    fn sell_order(&self, sell: &LotOperation, lots: &[&Lot]) -> Result<Vec<u64>, TaxLotError> {
+        let error = |message: String| TaxLotError::StrategyError(message);
+        let mut request = format!("{},{},{}\n", sell.date(), sell.price(), sell.quantity());
+        for lot in lots {
+            writeln!(request, "{},{},{},{}", lot.id(), lot.date(), lot.price(), lot.quantity())
+                .map_err(|e| error(e.to_string()))?;
+        }
+        let len = i32::try_from(request.len()).map_err(|_| error("the request is too large".to_string()))?;
+
+        let mut store = self.store.lock().map_err(|_| error("an earlier call panicked".to_string()))?;
+        store.set_fuel(self.fuel).map_err(|e| error(e.to_string()))?;
+        let offset = self.alloc.call(&mut *store, len).map_err(|e| error(format!("`alloc`: {e}")))?;
+        self.memory
+            .write(&mut *store, offset as u32 as usize, request.as_bytes())
+            .map_err(|e| error(format!("`alloc` returned {offset}: {e}")))?;
+        let result = self.sell_order.call(&mut *store, (offset, len)).map_err(|e| error(format!("`sell_order`: {e}")))?;
+
+        let (offset, len) = ((result as u64 >> 32) as usize, (result as u64 & 0xffff_ffff) as usize);
+        let mut response = vec![0; len];
+        self.memory
+            .read(&*store, offset, &mut response)
+            .map_err(|e| error(format!("`sell_order` returned {len} bytes at {offset}: {e}")))?;
+        let response = str::from_utf8(&response).map_err(|_| error("the ids are not UTF-8".to_string()))?;
+        response
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty())
+            .map(|id| id.parse().map_err(|_| error(format!("`{id}` is not a lot id"))))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, str::FromStr};
+
+    use taxlot::{LotCollection, LotOperation, SelectionAlgorithm, TaxLotError};
+
+    use super::WasmStrategy;
+
+    /// Sells from lot 3 and then lot 1, whatever the request is.
+    const FIXED_ORDER: &str = r#"
+        (module
+            (memory (export "memory") 1)
+            (data (i32.const 0) "3\n1\n")
+            (func (export "alloc") (param i32) (result i32) i32.const 1024)
+            (func (export "sell_order") (param i32 i32) (result i64) i64.const 4))
+    "#;
+
+    /// Never returns.
+    const LOOPS: &str = r#"
+        (module
+            (memory (export "memory") 1)
+            (func (export "alloc") (param i32) (result i32) i32.const 0)
+            (func (export "sell_order") (param i32 i32) (result i64) (loop (br 0)) i64.const 0))
+    "#;
+
+    fn collection(plugin: &str) -> Result<LotCollection, TaxLotError> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("strategy.wat");
+        fs::write(&path, plugin)?;
+        let mut strategy = WasmStrategy::load(&path)?;
+        strategy.fuel = 1_000_000;
+        let mut lot_collection = LotCollection::new(SelectionAlgorithm::Fifo);
+        lot_collection.set_sell_strategy(Box::new(strategy));
+        for line in ["2021-01-01,buy,100.00,1", "2021-01-02,buy,200.00,1", "2021-01-03,buy,300.00,1"] {
+            lot_collection.apply_lot_operation(LotOperation::from_str(line)?)?;
+        }
+        Ok(lot_collection)
+    }
+
+    #[test]
+    fn test_plugin_orders_sells() -> Result<(), TaxLotError> {
+        let mut lot_collection = collection(FIXED_ORDER)?;
+        lot_collection.apply_lot_operation(LotOperation::from_str("2021-02-01,sell,250.00,1.5")?)?;
+        let lots: Vec<_> = lot_collection.lots().map(|lot| lot.to_string()).collect();
+        assert_eq!(lots, ["1,2021-01-01,100.00,0.50000000", "2,2021-01-02,200.00,1.00000000"]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_plugin_runs_out_of_fuel() -> Result<(), TaxLotError> {
+        let mut lot_collection = collection(LOOPS)?;
+        let sell = lot_collection.apply_lot_operation(LotOperation::from_str("2021-02-01,sell,250.00,1")?);
+        assert!(matches!(sell, Err(TaxLotError::StrategyError(_))));
+        assert_eq!(lot_collection.lots().count(), 3);
+        Ok(())
+    }
+}
@@ -0,0 +1,261 @@
+//! Mark prices to value the remaining lots at, e.g. for the unrealized gains report. The mark price is given on
+//! the command line, read from a file of quotes, or fetched from a price provider with the `https` feature.
+
+use std::{
+    env, fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use chrono::{Local, NaiveDate};
+use clap::Args;
+use rust_decimal::Decimal;
+use taxlot::TaxLotError;
+
+/// Represents where the mark price comes from. The first one given is used.
+///
+/// `mark_price`: Price to value the remaining lots at, as of today.
+/// `mark_prices`: File of `date,price` quotes, e.g. a downloaded price history. The quote with the latest date is
+/// the mark price.
+/// `price_provider`: Service to fetch today's quote from, e.g. `coingecko:bitcoin` or `yahoo:AAPL`, in US dollars.
+/// Requires the `https` feature.
+/// `price_cache`: Directory fetched quotes are cached in, so that a quote is fetched at most once a day. Defaults
+/// to `$XDG_CACHE_HOME/taxlot`, or `~/.cache/taxlot`.
+#[derive(Args, Debug, Clone, Default)]
+pub struct PriceOpts {
+    #[clap(long, value_name = "PRICE", env = "TAXLOT_MARK_PRICE", global = true)]
+    pub mark_price: Option<Decimal>,
+
+    #[clap(long, value_name = "FILE", env = "TAXLOT_MARK_PRICES", global = true)]
+    pub mark_prices: Option<PathBuf>,
+
+    #[clap(long, value_name = "PROVIDER:ID", env = "TAXLOT_PRICE_PROVIDER", global = true)]
+    pub price_provider: Option<PriceProvider>,
+
+    #[clap(long, value_name = "DIR", env = "TAXLOT_PRICE_CACHE", global = true)]
+    pub price_cache: Option<PathBuf>,
+}
+
+impl PriceOpts {
+    /// Returns true if any source of a mark price is given.
+    pub fn is_set(&self) -> bool {
+        self.mark_price.is_some() || self.mark_prices.is_some() || self.price_provider.is_some()
+    }
+
+    /// Returns the mark price from the first source that is given.
+    pub fn quote(&self) -> Result<Quote, TaxLotError> {
+        let today = Local::now().date_naive();
+        if let Some(price) = self.mark_price {
+            return Ok(Quote { date: today, price });
+        }
+        if let Some(path) = &self.mark_prices {
+            return latest(&read_quotes(path)?)
+                .ok_or_else(|| TaxLotError::PriceError(format!("{} has no quotes", path.display())));
+        }
+        match &self.price_provider {
+            Some(provider) => provider.quote(today, self.price_cache.clone().or_else(default_cache)),
+            None => Err(TaxLotError::PriceError("no mark price is given".to_string())),
+        }
+    }
+}
+
+/// Returns the quotes of the `date,price` file at `path`, in the order of the file.
+pub fn read_quotes(path: &Path) -> Result<Vec<Quote>, TaxLotError> {
+    let quotes = fs::read_to_string(path)
+        .map_err(|e| TaxLotError::PriceError(format!("could not read {}: {e}", path.display())))?;
+    parse_quotes(&quotes)
+}
+
+/// Represents the price of a unit on a date.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quote {
+    pub date: NaiveDate,
+    pub price: Decimal,
+}
+
+/// Represents a service to fetch quotes from, with the id of what to fetch the price of.
+///
+/// `CoinGecko`: The CoinGecko API, by the id of a coin, e.g. `bitcoin`.
+/// `Yahoo`: The Yahoo Finance chart API, by ticker symbol, e.g. `AAPL`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PriceProvider {
+    CoinGecko(String),
+    Yahoo(String),
+}
+
+impl FromStr for PriceProvider {
+    type Err = TaxLotError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || {
+            TaxLotError::PriceError(format!("`{s}` is not a price provider. Options: coingecko:<coin>, yahoo:<symbol>"))
+        };
+        let (provider, id) = s.split_once(':').ok_or_else(invalid)?;
+        // The id is part of the URL and the name of the cache file.
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '^' | '=')) {
+            return Err(invalid());
+        }
+        match provider {
+            "coingecko" => Ok(PriceProvider::CoinGecko(id.to_string())),
+            "yahoo" => Ok(PriceProvider::Yahoo(id.to_string())),
+            _ => Err(invalid()),
+        }
+    }
+}
+
+impl PriceProvider {
+    /// Returns the quote of `today`, from the file of the provider in `cache` if it was fetched before, or else
+    /// fetched and added to it. A cache that cannot be written is reported on stderr without failing.
+    fn quote(&self, today: NaiveDate, cache: Option<PathBuf>) -> Result<Quote, TaxLotError> {
+        let cache = cache.map(|dir| self.cache_file(&dir));
+        let cached = cache.as_ref().and_then(|path| fs::read_to_string(path).ok());
+        if let Some(quotes) = cached {
+            if let Some(quote) = parse_quotes(&quotes)?.into_iter().find(|quote| quote.date == today) {
+                return Ok(quote);
+            }
+        }
+
+        let quote = Quote { date: today, price: self.fetch()? };
+        if let Some(path) = cache {
+            if let Err(e) = append_quote(&path, quote) {
+                eprintln!("Could not cache the quote in {}: {e}", path.display());
+            }
+        }
+        Ok(quote)
+    }
+
+    /// Returns the file the quotes of the provider are cached in, in the directory `dir`.
+    fn cache_file(&self, dir: &Path) -> PathBuf {
+        match self {
+            PriceProvider::CoinGecko(coin) => dir.join(format!("coingecko-{coin}.csv")),
+            PriceProvider::Yahoo(symbol) => dir.join(format!("yahoo-{symbol}.csv")),
+        }
+    }
+
+    /// Fetches the current price in US dollars.
+    #[cfg(feature = "https")]
+    fn fetch(&self) -> Result<Decimal, TaxLotError> {
+        let (url, key) = match self {
+            PriceProvider::CoinGecko(coin) => (
+                format!("https://api.coingecko.com/api/v3/simple/price?ids={coin}&vs_currencies=usd"),
+                "usd",
+            ),
+            PriceProvider::Yahoo(symbol) => (
+                format!("https://query1.finance.yahoo.com/v8/finance/chart/{symbol}?range=1d&interval=1d"),
+                "regularMarketPrice",
+            ),
+        };
+        let error = |message: String| TaxLotError::PriceError(format!("{url}: {message}"));
+        let body = ureq::get(&url)
+            .call()
+            .map_err(|e| error(e.to_string()))?
+            .into_body()
+            .read_to_string()
+            .map_err(|e| error(e.to_string()))?;
+        json_number(&body, key).ok_or_else(|| error(format!("the response has no `{key}` price")))
+    }
+
+    #[cfg(not(feature = "https"))]
+    fn fetch(&self) -> Result<Decimal, TaxLotError> {
+        Err(TaxLotError::PriceError("fetching quotes requires the `https` feature".to_string()))
+    }
+}
+
+/// Returns the default directory of the quote cache, if there is a home directory.
+fn default_cache() -> Option<PathBuf> {
+    match env::var_os("XDG_CACHE_HOME") {
+        Some(dir) => Some(PathBuf::from(dir).join("taxlot")),
+        None => env::var_os("HOME").map(|home| PathBuf::from(home).join(".cache").join("taxlot")),
+    }
+}
+
+/// Parses `date,price` lines of quotes. Blank lines, lines starting with `#` and a `date,price` header are
+/// skipped.
+fn parse_quotes(quotes: &str) -> Result<Vec<Quote>, TaxLotError> {
+    let mut parsed = Vec::new();
+    for (number, line) in quotes.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') || line == "date,price" {
+            continue;
+        }
+        let invalid = || TaxLotError::PriceError(format!("line {}: expected `date,price`, got `{line}`", number + 1));
+        let (date, price) = line.split_once(',').ok_or_else(invalid)?;
+        let date = NaiveDate::from_str(date.trim()).map_err(|_| invalid())?;
+        let price = Decimal::from_str(price.trim()).map_err(|_| invalid())?;
+        if price.is_sign_negative() {
+            return Err(invalid());
+        }
+        parsed.push(Quote { date, price });
+    }
+    Ok(parsed)
+}
+
+/// Returns the quote with the latest date, the last one of them if several have it.
+fn latest(quotes: &[Quote]) -> Option<Quote> {
+    quotes.iter().rev().max_by_key(|quote| quote.date).copied()
+}
+
+/// Appends `quote` to the cache file at `path`, creating it and its directory if needed.
+fn append_quote(path: &Path, quote: Quote) -> io::Result<()> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let mut file = fs::OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{},{}", quote.date, quote.price)
+}
+
+/// Returns the first number that `key` has in a JSON document, e.g. `12.5` for `usd` in `{"usd":12.5}`.
+#[cfg_attr(not(feature = "https"), allow(dead_code))]
+fn json_number(document: &str, key: &str) -> Option<Decimal> {
+    let pattern = format!("\"{key}\"");
+    document.match_indices(&pattern).find_map(|(start, _)| {
+        let value = document[start + pattern.len()..].trim_start().strip_prefix(':')?.trim_start();
+        let end = value
+            .find(|c: char| !(c.is_ascii_digit() || matches!(c, '.' | '-' | '+' | 'e' | 'E')))
+            .unwrap_or(value.len());
+        let number = &value[..end];
+        Decimal::from_str(number).or_else(|_| Decimal::from_scientific(number)).ok()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, str::FromStr};
+
+    use chrono::NaiveDate;
+    use rust_decimal::Decimal;
+
+    use super::{json_number, latest, parse_quotes, PriceProvider, Quote};
+
+    #[test]
+    fn test_quotes_file_marks_at_latest_quote() {
+        let quotes = "date,price\n2021-03-01,120.5\n\n# the provider skipped a day\n2021-01-01,100\n";
+        let quotes = parse_quotes(quotes).expect("Could not parse the quotes");
+        let date = NaiveDate::from_str("2021-03-01").expect("Invalid date");
+        assert_eq!(latest(&quotes), Some(Quote { date, price: Decimal::from_str("120.5").expect("Invalid price") }));
+
+        parse_quotes("2021-03-01;120.5").expect_err("Parsed a quote without a comma");
+        parse_quotes("2021-03-01,-1").expect_err("Parsed a negative price");
+    }
+
+    #[test]
+    fn test_provider_quote_is_read_from_cache() {
+        let dir = tempfile::tempdir().expect("Could not create a directory");
+        fs::write(dir.path().join("coingecko-bitcoin.csv"), "2021-03-01,50000\n").expect("Could not write the cache");
+        let provider = PriceProvider::from_str("coingecko:bitcoin").expect("Invalid provider");
+        let date = NaiveDate::from_str("2021-03-01").expect("Invalid date");
+        let quote = provider.quote(date, Some(dir.path().to_path_buf())).expect("Could not read the cached quote");
+        assert_eq!(quote, Quote { date, price: Decimal::from(50000) });
+
+        PriceProvider::from_str("coingecko:../bitcoin").expect_err("Parsed an id that is not part of a URL");
+        PriceProvider::from_str("stooq:AAPL").expect_err("Parsed an unknown provider");
+    }
+
+    #[test]
+    fn test_json_number() {
+        assert_eq!(json_number(r#"{"bitcoin":{"usd":67187.34}}"#, "usd"), Decimal::from_str("67187.34").ok());
+        assert_eq!(json_number(r#"{"regularMarketPrice": 1.5e2}"#, "regularMarketPrice"), Some(Decimal::from(150)));
+        assert_eq!(json_number(r#"{"usd":null}"#, "usd"), None);
+    }
+}
@@ -0,0 +1,170 @@
+//! Validation of the columns of input lines and records, e.g. to find every problem in a third-party export before
+//! processing it, rather than stopping at the first line that fails to parse.
+
+use std::{fmt::Display, str::FromStr};
+
+use chrono::NaiveDate;
+use rust_decimal::Decimal;
+
+use crate::{InputDialect, LotOperation, LotType};
+
+/// Represents the columns of an input line that are validated. `Fields` are the optional fields after the quantity,
+/// e.g. `method=fifo`. The memo after them is not validated, since any text is valid in it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Column {
+    Date,
+    Type,
+    Price,
+    Quantity,
+    Fields,
+}
+
+impl Display for Column {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Column::Date => write!(f, "date"),
+            Column::Type => write!(f, "type"),
+            Column::Price => write!(f, "price"),
+            Column::Quantity => write!(f, "quantity"),
+            Column::Fields => write!(f, "fields"),
+        }
+    }
+}
+
+/// Represents a problem with a column of an input line, e.g. a date that is not in the `YYYY-MM-DD` format.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnError {
+    pub column: Column,
+    pub message: String,
+}
+
+impl InputDialect {
+    /// Returns the columns of lines in this format, in the order they are in.
+    pub fn columns(self) -> &'static [Column] {
+        match self {
+            InputDialect::Standard => &[Column::Date, Column::Type, Column::Price, Column::Quantity, Column::Fields],
+            InputDialect::Signed => &[Column::Date, Column::Price, Column::Quantity, Column::Fields],
+        }
+    }
+
+    /// Returns a problem with each column of a line in this format that keeps it from being parsed, in the order
+    /// of the columns. Unlike `parse`, which stops at the first problem, every column is checked, so a line
+    /// without problems parses.
+    pub fn check(self, line: &str) -> Vec<ColumnError> {
+        // The optional fields and memo are the rest of the line, so they may contain commas.
+        let columns = self.columns();
+        let mut parts = line.splitn(columns.len(), ',');
+        let values: Vec<_> = columns.iter().map(|&column| (column, parts.next())).collect();
+        let mut errors: Vec<_> = values
+            .iter()
+            .filter(|(column, value)| value.is_none() && *column != Column::Fields)
+            .map(|(column, _)| ColumnError { column: *column, message: "missing".to_string() })
+            .collect();
+        errors.extend(check_values(&values, self == InputDialect::Signed));
+        errors.sort_by_key(|error| error.column);
+        errors
+    }
+}
+
+/// Returns a problem with each column of a record whose fields are found by name rather than by position, e.g. a
+/// fixed-width line or an XML record, sorted by column. Values are `None` if they are missing or empty. Unlike in a
+/// delimited line, a cancel does not need a price or quantity.
+pub(crate) fn check_record(values: &[(Column, Option<&str>)]) -> Vec<ColumnError> {
+    let cancel = values.iter().any(|(column, value)| {
+        *column == Column::Type && value.is_some_and(|value| matches!(LotType::from_str(value), Ok(LotType::Cancel)))
+    });
+    let required = |column| match column {
+        Column::Date | Column::Type => true,
+        Column::Price | Column::Quantity => !cancel,
+        Column::Fields => false,
+    };
+    let mut errors: Vec<_> = values
+        .iter()
+        .filter(|(column, value)| value.is_none() && required(*column))
+        .map(|(column, _)| ColumnError { column: *column, message: "missing".to_string() })
+        .collect();
+    errors.extend(check_values(values, false));
+    errors.sort_by_key(|error| error.column);
+    errors
+}
+
+/// Returns a problem with each of the `values` of the columns of a lot operation that is given, unsorted. A signed
+/// quantity is negative for a sell, as in the signed dialect. Columns without a value are left to the caller, since
+/// which of them are required depends on the format.
+pub(crate) fn check_values(values: &[(Column, Option<&str>)], signed: bool) -> Vec<ColumnError> {
+    let value = |column| values.iter().find(|(other, _)| *other == column).and_then(|(_, value)| *value);
+    let mut errors = Vec::new();
+    let mut error = |column, message: String| errors.push(ColumnError { column, message });
+
+    if let Some(date) = value(Column::Date) {
+        if NaiveDate::parse_from_str(date, "%Y-%m-%d").is_err() {
+            error(Column::Date, format!("`{date}` is not a date in the format YYYY-MM-DD"));
+        }
+    }
+    let lot_type = value(Column::Type).and_then(|lot_type| match LotType::from_str(lot_type) {
+        Ok(lot_type) => Some(lot_type),
+        Err(_) => {
+            error(Column::Type, format!("`{lot_type}` is not an operation type"));
+            None
+        }
+    });
+    // A cancel only refers to an earlier operation, so its price and quantity are not used.
+    if lot_type != Some(LotType::Cancel) {
+        if let Some(price) = value(Column::Price) {
+            if let Err(message) = check_positive(price) {
+                error(Column::Price, message);
+            }
+        }
+        if let Some(quantity) = value(Column::Quantity) {
+            let unsigned = if signed { quantity.strip_prefix(['-', '+']).unwrap_or(quantity) } else { quantity };
+            if let Err(message) = check_positive(unsigned) {
+                error(Column::Quantity, message);
+            }
+        }
+    }
+
+    if let Some(fields) = value(Column::Fields) {
+        if let Err(e) = LotOperation::parse_fields_and_memo(fields) {
+            error(Column::Fields, e.to_string());
+        }
+    }
+    errors
+}
+
+/// Checks that `value` is a number greater than zero.
+fn check_positive(value: &str) -> Result<(), String> {
+    match Decimal::from_str(value) {
+        Ok(number) if number > Decimal::ZERO => Ok(()),
+        Ok(_) => Err(format!("`{value}` is not greater than zero")),
+        Err(_) => Err(format!("`{value}` is not a number")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use crate::{Column, InputDialect, LotOperation};
+
+    /// Returns the columns with a problem in `line`.
+    fn columns(dialect: InputDialect, line: &str) -> Vec<Column> {
+        dialect.check(line).into_iter().map(|error| error.column).collect()
+    }
+
+    #[test]
+    fn test_check_reports_every_column() {
+        use Column::*;
+        let standard = InputDialect::Standard;
+        assert_eq!(columns(standard, "2021-01-01,buy,10000.00,1.00000000,tags=ira,memo"), []);
+        assert_eq!(columns(standard, "2021-01-01,cancel,,,txn=1"), []);
+        assert_eq!(columns(standard, "2021-13-01,hold,-1,1"), [Date, Type, Price]);
+        assert_eq!(columns(standard, "2021-01-01,sell,10000.00"), [Quantity]);
+        assert_eq!(columns(standard, ""), [Date, Type, Price, Quantity]);
+        assert_eq!(columns(standard, "2021-01-01,sell,10000.00,1,tags=ira,method=lifo,memo"), [Fields]);
+        assert_eq!(columns(InputDialect::Signed, "2021-01-01,10000.00,-0.5"), []);
+        assert_eq!(columns(InputDialect::Signed, "2021-01-01,0,--0.5"), [Price, Quantity]);
+
+        // Lines without problems also parse.
+        LotOperation::from_str("2021-01-01,cancel,,,txn=1").expect("Failed to parse a checked line");
+    }
+}
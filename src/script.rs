@@ -0,0 +1,262 @@
+//! Scripts written in Rhai that customize how the input is processed, loaded with `--script`, for rules too
+//! specific to a firm or account to be built in. A script defines either or both of these functions:
+//!
+//! `transform(op)`: Called with every input operation before it is applied, as a map with its `date`, `type`,
+//! `price`, `quantity`, `tags` and `memo`. Returns the operation with its price, quantity, tags or memo changed,
+//! e.g. to add the fees of a fee schedule to the price of buys, or `()` to skip it.
+//! `compare(a, b, sell)`: Orders the lots that sells deduct from instead of the selection algorithm, given two lots
+//! as maps with their `id`, `date`, `price`, `quantity`, `tags` and `memo`, and the sell as a map with its `date`,
+//! `price` and `quantity`. Returns a negative number if `a` is sold before `b`, a positive number if it is sold
+//! after, or zero to keep the order of the selection algorithm.
+//!
+//! Prices and quantities are decimals, and dates are `YYYY-MM-DD` strings, so they compare in date order. Each call
+//! only sees its arguments, and is stopped after `MAX_OPERATIONS` operations so that a script cannot hang a run.
+
+use std::{path::Path, sync::Arc};
+
+use rhai::{Array, CallFnOptions, Dynamic, Engine, Map, Scope, AST, INT};
+use rust_decimal::Decimal;
+use taxlot::{Lot, LotOperation, SellStrategy, TaxLotError};
+
+/// Largest number of operations a single call of a script function can run.
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+/// Represents a loaded script and which of its functions are defined.
+pub struct Script {
+    engine: Engine,
+    ast: AST,
+    transforms: bool,
+    compares: bool,
+}
+
+impl Script {
+    /// Compiles the script at `path`, which must define `transform(op)`, `compare(a, b, sell)` or both.
+    pub fn load(path: &Path) -> Result<Arc<Script>, TaxLotError> {
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        let ast = engine
+            .compile_file(path.to_path_buf())
+            .map_err(|e| TaxLotError::ScriptError(format!("{}: {e}", path.display())))?;
+        let defines = |name: &str, params: usize| {
+            ast.iter_functions().any(|function| function.name == name && function.params.len() == params)
+        };
+        let (transforms, compares) = (defines("transform", 1), defines("compare", 3));
+        if !transforms && !compares {
+            let message = format!("{} defines neither `transform(op)` nor `compare(a, b, sell)`", path.display());
+            return Err(TaxLotError::ScriptError(message));
+        }
+        Ok(Arc::new(Script { engine, ast, transforms, compares }))
+    }
+
+    /// Returns the sell strategy of the `compare` function, if the script defines it.
+    pub fn sell_strategy(self: &Arc<Self>) -> Option<Box<dyn SellStrategy>> {
+        self.compares.then(|| Box::new(Comparator(Arc::clone(self))) as Box<dyn SellStrategy>)
+    }
+
+    /// Returns `lot_operation` as changed by the `transform` function, or `None` if it skips the operation. The
+    /// operation is returned unchanged if the script does not define `transform`.
+    pub fn transform(&self, mut lot_operation: LotOperation) -> Result<Option<LotOperation>, TaxLotError> {
+        if !self.transforms {
+            return Ok(Some(lot_operation));
+        }
+        let transformed = self.call("transform", (operation_map(&lot_operation),))?;
+        if transformed.is_unit() {
+            return Ok(None);
+        }
+        let mut map = transformed
+            .try_cast::<Map>()
+            .ok_or_else(|| TaxLotError::ScriptError("`transform` must return a map or ()".to_string()))?;
+
+        let unchanged = |field: &str, value: String| map.get(field).is_some_and(|v| v.to_string() == value);
+        if !unchanged("date", lot_operation.date().to_string()) || !unchanged("type", lot_operation.lot_type().to_string())
+        {
+            return Err(TaxLotError::ScriptError("`transform` cannot change the date or type".to_string()));
+        }
+        let price = decimal_field(&map, "price")?;
+        let quantity = decimal_field(&map, "quantity")?;
+        if (price, quantity) != (lot_operation.price(), lot_operation.quantity()) {
+            lot_operation.set_price_and_quantity(price, quantity)?;
+        }
+        let invalid_tags = || TaxLotError::ScriptError("`tags` must be an array of strings".to_string());
+        let tags = map
+            .remove("tags")
+            .and_then(|tags| tags.try_cast::<Array>())
+            .ok_or_else(invalid_tags)?
+            .into_iter()
+            .map(|tag| tag.into_string().map_err(|_| invalid_tags()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let memo = match map.remove("memo") {
+            Some(memo) if !memo.is_unit() => Some(
+                memo.into_string()
+                    .map_err(|_| TaxLotError::ScriptError("`memo` must be a string or ()".to_string()))?,
+            ),
+            _ => None,
+        };
+        lot_operation.set_tags_and_memo(tags, memo);
+        Ok(Some(lot_operation))
+    }
+
+    /// Calls the script function `name` with `args`, without running the statements outside of functions again.
+    fn call(&self, name: &str, args: impl rhai::FuncArgs) -> Result<Dynamic, TaxLotError> {
+        let options = CallFnOptions::new().eval_ast(false);
+        self.engine
+            .call_fn_with_options(options, &mut Scope::new(), &self.ast, name, args)
+            .map_err(|e| TaxLotError::ScriptError(format!("`{name}`: {e}")))
+    }
+}
+
+/// Sells lots in the order of the `compare` function of a script.
+struct Comparator(Arc<Script>);
+
+impl SellStrategy for Comparator {
+    fn sell_order(&self, sell: &LotOperation, lots: &[&Lot]) -> Result<Vec<u64>, TaxLotError> {
+        let mut sell_map = Map::new();
+        sell_map.insert("date".into(), sell.date().to_string().into());
+        sell_map.insert("price".into(), Dynamic::from_decimal(sell.price()));
+        sell_map.insert("quantity".into(), Dynamic::from_decimal(sell.quantity()));
+        let maps = lots.iter().map(|lot| lot_map(lot)).collect::<Result<Vec<_>, _>>()?;
+
+        let mut order: Vec<usize> = (0..lots.len()).collect();
+        merge_sort(&mut order, &mut |&a, &b| {
+            let ordering = self.0.call("compare", (maps[a].clone(), maps[b].clone(), sell_map.clone()))?;
+            ordering
+                .as_int()
+                .map(|ordering| ordering <= 0)
+                .map_err(|_| TaxLotError::ScriptError("`compare` must return a number".to_string()))
+        })?;
+        Ok(order.into_iter().map(|i| lots[i].id()).collect())
+    }
+}
+
+/// Sorts `items` stably with `before`, which returns true if its first argument can come before the second one. A
+/// merge sort is used rather than `sort_by`, since the comparison can fail, and a script whose comparison is not a
+/// total order must not make the sort panic.
+fn merge_sort<T: Copy>(
+    items: &mut [T],
+    before: &mut impl FnMut(&T, &T) -> Result<bool, TaxLotError>,
+) -> Result<(), TaxLotError> {
+    if items.len() < 2 {
+        return Ok(());
+    }
+    let middle = items.len() / 2;
+    merge_sort(&mut items[..middle], before)?;
+    merge_sort(&mut items[middle..], before)?;
+    let (left, right) = (items[..middle].to_vec(), items[middle..].to_vec());
+    let (mut i, mut j) = (0, 0);
+    for slot in items.iter_mut() {
+        if j == right.len() || (i < left.len() && before(&left[i], &right[j])?) {
+            *slot = left[i];
+            i += 1;
+        } else {
+            *slot = right[j];
+            j += 1;
+        }
+    }
+    Ok(())
+}
+
+/// Returns an operation as the map that `transform` is called with.
+fn operation_map(lot_operation: &LotOperation) -> Map {
+    let mut map = Map::new();
+    map.insert("date".into(), lot_operation.date().to_string().into());
+    map.insert("type".into(), lot_operation.lot_type().to_string().into());
+    map.insert("price".into(), Dynamic::from_decimal(lot_operation.price()));
+    map.insert("quantity".into(), Dynamic::from_decimal(lot_operation.quantity()));
+    map.insert("tags".into(), tags(lot_operation.tags()));
+    map.insert("memo".into(), lot_operation.memo().map_or(Dynamic::UNIT, |memo| memo.to_string().into()));
+    map
+}
+
+/// Returns a lot as the map that `compare` is called with.
+fn lot_map(lot: &Lot) -> Result<Map, TaxLotError> {
+    let id = INT::try_from(lot.id())
+        .map_err(|_| TaxLotError::ScriptError(format!("lot id {} is too large for a script", lot.id())))?;
+    let mut map = Map::new();
+    map.insert("id".into(), id.into());
+    map.insert("date".into(), lot.date().to_string().into());
+    map.insert("price".into(), Dynamic::from_decimal(lot.price()));
+    map.insert("quantity".into(), Dynamic::from_decimal(lot.quantity()));
+    map.insert("tags".into(), tags(lot.tags()));
+    map.insert("memo".into(), lot.memo().map_or(Dynamic::UNIT, |memo| memo.to_string().into()));
+    Ok(map)
+}
+
+/// Returns tags as an array of strings.
+fn tags<'a>(tags: impl Iterator<Item = &'a str>) -> Dynamic {
+    tags.map(|tag| Dynamic::from(tag.to_string())).collect::<Array>().into()
+}
+
+/// Returns the decimal `field` of a map returned by `transform`. Whole numbers are accepted too.
+fn decimal_field(map: &Map, field: &str) -> Result<Decimal, TaxLotError> {
+    let value = map.get(field);
+    value
+        .and_then(|value| value.as_decimal().ok().or_else(|| value.as_int().ok().map(Decimal::from)))
+        .ok_or_else(|| TaxLotError::ScriptError(format!("`{field}` must be a number")))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path, str::FromStr, sync::Arc};
+
+    use taxlot::{LotCollection, LotOperation, SelectionAlgorithm, TaxLotError};
+
+    use super::Script;
+
+    fn load(dir: &Path, script: &str) -> Result<Arc<Script>, TaxLotError> {
+        let path = dir.join("rules.rhai");
+        fs::write(&path, script)?;
+        Script::load(&path)
+    }
+
+    #[test]
+    fn test_transform_applies_fees_and_skips_operations() -> Result<(), TaxLotError> {
+        let dir = tempfile::tempdir()?;
+        let script = load(
+            dir.path(),
+            r#"
+                fn transform(op) {
+                    if op.tags.contains("ignore") { return (); }
+                    if op.type == "buy" { op.price += 0.5; op.tags.push("fee"); }
+                    op
+                }
+            "#,
+        )?;
+        let buy = script.transform(LotOperation::from_str("2021-01-01,buy,100.00,2,tags=ira,dca")?)?;
+        let buy = buy.expect("Skipped the buy");
+        assert_eq!(buy.price(), rust_decimal::Decimal::from_str("100.50")?);
+        assert_eq!(buy.tags().collect::<Vec<_>>(), ["fee", "ira"]);
+        assert_eq!(buy.memo(), Some("dca"));
+        let ignored = script.transform(LotOperation::from_str("2021-01-01,sell,100.00,2,tags=ignore")?)?;
+        assert!(ignored.is_none());
+
+        let script = load(dir.path(), "fn transform(op) { op.price = -1; op }")?;
+        script.transform(LotOperation::from_str("2021-01-01,buy,100.00,2")?).expect_err("Applied a negative price");
+        load(dir.path(), "fn other() { 1 }").err().expect("Loaded a script without functions");
+        Ok(())
+    }
+
+    #[test]
+    fn test_compare_orders_sells() -> Result<(), TaxLotError> {
+        let dir = tempfile::tempdir()?;
+        // Sells the smallest lots first, even with an inconsistent tie-break.
+        let script = load(dir.path(), "fn compare(a, b, sell) { if a.quantity < b.quantity { -1 } else { 1 } }")?;
+        let mut lot_collection = LotCollection::new(SelectionAlgorithm::Hifo);
+        lot_collection.set_sell_strategy(script.sell_strategy().expect("No sell strategy"));
+        for line in [
+            "2021-01-01,buy,300.00,3",
+            "2021-01-02,buy,100.00,1",
+            "2021-01-03,buy,200.00,2",
+            "2021-02-01,sell,250.00,2",
+        ] {
+            lot_collection.apply_lot_operation(LotOperation::from_str(line)?)?;
+        }
+        let lots: Vec<_> = lot_collection.lots().map(|lot| lot.to_string()).collect();
+        assert_eq!(lots, ["1,2021-01-01,300.00,3.00000000", "3,2021-01-03,200.00,1.00000000"]);
+
+        let script = load(dir.path(), r#"fn compare(a, b, sell) { "first" }"#)?;
+        lot_collection.set_sell_strategy(script.sell_strategy().expect("No sell strategy"));
+        let sell = lot_collection.apply_lot_operation(LotOperation::from_str("2021-02-02,sell,250.00,1")?);
+        assert!(matches!(sell, Err(TaxLotError::ScriptError(_))));
+        Ok(())
+    }
+}
@@ -0,0 +1,220 @@
+//! Section 1256 contracts with `--section-1256-tag`, e.g. regulated futures and broad-based index options. The
+//! operations with the tag are matched against lots of their own, apart from the rest of the input, and the contracts
+//! still open at the end of each year are marked to market at the last quote of the year in `--mark-prices`. The gain
+//! of a year, from its sells and from marking the open contracts, is split 60% long-term and 40% short-term in the
+//! gains report, however long the contracts were held.
+//!
+//! Marking a contract to market treats it as sold and bought back at the mark price, so the contracts open at the end
+//! of a year have the mark price of the year as their cost basis in the next one. A year is only marked once it has
+//! ended, so the gain of the current year is that of its sells.
+
+use std::{collections::BTreeMap, io};
+
+use chrono::{Datelike, NaiveDate};
+use rust_decimal::Decimal;
+use taxlot::{LotCollection, LotOperation, RealizedGains, TaxLotError};
+
+use crate::prices::Quote;
+
+/// Represents the Section 1256 contracts of the input, with the gains of marking them to market.
+///
+/// `tag`: Tag of the operations on the contracts.
+/// `year_ends`: Last quote of each year with quotes, by year.
+/// `lot_collection`: Lots of the contracts, which keep the price they were bought at.
+/// `year`: Latest year of the operations applied, the first one not marked yet.
+/// `marks`: Price that the open contracts were marked at in each marked year.
+/// `marked_gains`: Gain of marking the open contracts in each marked year, from their cost basis in the year.
+pub struct Section1256 {
+    tag: String,
+    year_ends: BTreeMap<i32, Decimal>,
+    lot_collection: LotCollection,
+    year: Option<i32>,
+    marks: BTreeMap<i32, Decimal>,
+    marked_gains: BTreeMap<i32, Decimal>,
+}
+
+impl Section1256 {
+    /// Creates the contracts of the operations tagged with `tag`, marked at `quotes` and matched in `lot_collection`.
+    pub fn new(tag: String, quotes: &[Quote], mut lot_collection: LotCollection) -> Self {
+        let mut year_ends = BTreeMap::<i32, Quote>::new();
+        for &quote in quotes {
+            let year_end = year_ends.entry(quote.date.year()).or_insert(quote);
+            if quote.date >= year_end.date {
+                *year_end = quote;
+            }
+        }
+        lot_collection.enable_disposals();
+        Section1256 {
+            tag,
+            year_ends: year_ends.into_iter().map(|(year, quote)| (year, quote.price)).collect(),
+            lot_collection,
+            year: None,
+            marks: BTreeMap::new(),
+            marked_gains: BTreeMap::new(),
+        }
+    }
+
+    /// Returns true if `lot_operation` is on a contract.
+    pub fn is_contract(&self, lot_operation: &LotOperation) -> bool {
+        lot_operation.tags().any(|tag| tag == self.tag)
+    }
+
+    /// Marks the years that ended before `lot_operation`, then applies it to the lots of the contracts.
+    pub fn apply(&mut self, lot_operation: LotOperation) -> Result<(), TaxLotError> {
+        self.mark_until(lot_operation.date().year())?;
+        self.lot_collection.apply_lot_operation(lot_operation)
+    }
+
+    /// Marks the years that ended before `today` that are not marked yet, after the last operation is applied.
+    pub fn finish(&mut self, today: NaiveDate) -> Result<(), TaxLotError> {
+        self.mark_until(today.year())
+    }
+
+    /// Marks the open contracts at the end of every year from the first one not marked yet to the one before
+    /// `year`.
+    fn mark_until(&mut self, year: i32) -> Result<(), TaxLotError> {
+        let first = self.year.unwrap_or(year);
+        for marked in first..year {
+            self.mark(marked)?;
+        }
+        self.year = Some(first.max(year));
+        Ok(())
+    }
+
+    fn mark(&mut self, year: i32) -> Result<(), TaxLotError> {
+        if self.lot_collection.lots().next().is_none() {
+            return Ok(());
+        }
+        let mark = *self.year_ends.get(&year).ok_or_else(|| {
+            TaxLotError::PriceError(format!("no quote of {year} to mark the open Section 1256 contracts at"))
+        })?;
+        let mut gain = Decimal::ZERO;
+        for lot in self.lot_collection.lots() {
+            let cost_basis = self.marked_price(lot.date(), year).unwrap_or(lot.price());
+            gain += (mark - cost_basis) * lot.quantity();
+        }
+        self.marks.insert(year, mark);
+        *self.marked_gains.entry(year).or_default() += gain;
+        Ok(())
+    }
+
+    /// Returns the price that a contract bought on `acquired` was marked at before `year`, if it was open at the end
+    /// of the year before.
+    fn marked_price(&self, acquired: NaiveDate, year: i32) -> Option<Decimal> {
+        match acquired.year() < year {
+            true => self.marks.get(&(year - 1)).copied(),
+            false => None,
+        }
+    }
+
+    /// Returns the gain of the contracts in each year with sells or marks, by year.
+    pub fn gains_by_year(&self) -> io::Result<BTreeMap<i32, Decimal>> {
+        let mut gains = self.marked_gains.clone();
+        for disposal in self.lot_collection.disposals() {
+            let disposal = disposal?;
+            let year = disposal.sold.year();
+            let cost_basis = match self.marked_price(disposal.acquired, year) {
+                Some(mark) => mark * disposal.quantity,
+                None => disposal.cost_basis,
+            };
+            *gains.entry(year).or_default() += disposal.proceeds - cost_basis;
+        }
+        Ok(gains)
+    }
+}
+
+/// Returns `gain` split 60% long-term and 40% short-term.
+pub fn split(gain: Decimal) -> RealizedGains {
+    let long_term = gain * Decimal::new(6, 1);
+    RealizedGains { short_term: gain - long_term, long_term }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chrono::NaiveDate;
+    use rust_decimal::Decimal;
+    use taxlot::{LotCollection, LotOperation, RealizedGains, SelectionAlgorithm, TaxLotError};
+
+    use super::{split, Section1256};
+    use crate::prices::Quote;
+
+    fn contracts(quotes: &[(&str, i64)]) -> Section1256 {
+        let quotes: Vec<_> = quotes
+            .iter()
+            .map(|&(date, price)| Quote {
+                date: NaiveDate::from_str(date).expect("Invalid date"),
+                price: Decimal::from(price),
+            })
+            .collect();
+        Section1256::new("futures".to_string(), &quotes, LotCollection::new(SelectionAlgorithm::Fifo))
+    }
+
+    fn apply(contracts: &mut Section1256, lines: &[&str]) -> Result<(), TaxLotError> {
+        for line in lines {
+            let lot_operation = LotOperation::from_str(line)?;
+            assert!(contracts.is_contract(&lot_operation));
+            contracts.apply(lot_operation)?;
+        }
+        Ok(())
+    }
+
+    fn gains(contracts: &Section1256) -> Vec<(i32, Decimal)> {
+        let gains = contracts.gains_by_year().expect("Could not read the disposals");
+        gains.into_iter().map(|(year, gain)| (year, gain.normalize())).collect()
+    }
+
+    #[test]
+    fn test_open_contracts_are_marked_at_the_last_quote_of_each_year() -> Result<(), TaxLotError> {
+        let mut contracts = contracts(&[("2022-12-29", 110), ("2022-12-30", 120), ("2023-06-30", 90)]);
+        let lines = [
+            "2022-03-01,buy,100,10,tags=futures",
+            "2023-02-01,sell,130,4,tags=futures",
+            "2023-03-01,sell,80,6,tags=futures",
+        ];
+        apply(&mut contracts, &lines)?;
+        contracts.finish(NaiveDate::from_ymd_opt(2024, 1, 2).unwrap())?;
+        // 2022 is marked at 120. The sells of 2023 are against that, and nothing is left to mark at its end.
+        assert_eq!(gains(&contracts), [(2022, Decimal::from(200)), (2023, Decimal::from(-200))]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_contracts_bought_in_a_year_are_marked_from_their_price() -> Result<(), TaxLotError> {
+        let mut contracts = contracts(&[("2022-12-30", 120), ("2023-12-29", 90)]);
+        apply(&mut contracts, &["2022-11-01,buy,100,1,tags=futures", "2023-05-01,buy,95,1,tags=futures"])?;
+        contracts.finish(NaiveDate::from_ymd_opt(2024, 3, 1).unwrap())?;
+        // In 2023, the first contract goes from its 2022 mark to 90, and the second from its price.
+        assert_eq!(gains(&contracts), [(2022, Decimal::from(20)), (2023, Decimal::from(-35))]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_the_current_year_is_not_marked() -> Result<(), TaxLotError> {
+        let mut contracts = contracts(&[("2023-06-30", 120)]);
+        apply(&mut contracts, &["2023-03-01,buy,100,10,tags=futures", "2023-05-01,sell,110,5,tags=futures"])?;
+        contracts.finish(NaiveDate::from_ymd_opt(2023, 7, 1).unwrap())?;
+        assert_eq!(gains(&contracts), [(2023, Decimal::from(50))]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_marking_fails_without_a_quote_of_the_year() -> Result<(), TaxLotError> {
+        let mut contracts = contracts(&[("2023-12-29", 120)]);
+        apply(&mut contracts, &["2022-03-01,buy,100,10,tags=futures"])?;
+        assert!(matches!(
+            contracts.apply(LotOperation::from_str("2023-02-01,sell,130,4,tags=futures")?),
+            Err(TaxLotError::PriceError(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_gains_are_split_60_40() {
+        assert_eq!(
+            split(Decimal::from(-250)),
+            RealizedGains { short_term: Decimal::from(-100), long_term: Decimal::from(-150) }
+        );
+    }
+}
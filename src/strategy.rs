@@ -0,0 +1,89 @@
+//! Custom orders to sell lots in, e.g. a firm-specific policy loaded from a script or plugin at run time, for rules
+//! that no selection algorithm covers.
+
+use std::sync::Arc;
+
+use crate::{Lot, LotOperation, TaxLotError};
+
+/// Decides which lots a sell deducts from, and in which order, instead of the selection algorithm of a
+/// `LotCollection`, see `LotCollection::set_sell_strategy`.
+pub trait SellStrategy: Send {
+    /// Returns the ids of the lots that `sell` deducts from, in the order it deducts from them. `lots` are the
+    /// unlocked lots, in the order of the selection algorithm. Lots that are left out are not sold, and lots that
+    /// have not been held for the minimum holding period are still skipped.
+    fn sell_order(&self, sell: &LotOperation, lots: &[&Lot]) -> Result<Vec<u64>, TaxLotError>;
+}
+
+/// A strategy shared by several collections, e.g. a plugin that is loaded once.
+impl<S: SellStrategy + Sync> SellStrategy for Arc<S> {
+    fn sell_order(&self, sell: &LotOperation, lots: &[&Lot]) -> Result<Vec<u64>, TaxLotError> {
+        self.as_ref().sell_order(sell, lots)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use super::SellStrategy;
+    use crate::{
+        test_helpers::{apply, lots},
+        Lot, LotCollection, LotOperation, SelectionAlgorithm, TaxLotError,
+    };
+
+    /// Sells the lots with the most shares first, skipping lots tagged `hold`.
+    struct LargestFirst;
+
+    impl SellStrategy for LargestFirst {
+        fn sell_order(&self, _: &LotOperation, lots: &[&Lot]) -> Result<Vec<u64>, TaxLotError> {
+            let mut lots: Vec<_> = lots.iter().filter(|lot| !lot.tags().any(|tag| tag == "hold")).collect();
+            lots.sort_by_key(|lot| std::cmp::Reverse(lot.quantity()));
+            Ok(lots.into_iter().map(|lot| lot.id()).collect())
+        }
+    }
+
+    /// Returns the same lot twice.
+    struct Repeats;
+
+    impl SellStrategy for Repeats {
+        fn sell_order(&self, _: &LotOperation, lots: &[&Lot]) -> Result<Vec<u64>, TaxLotError> {
+            Ok(lots.iter().take(1).chain(lots).map(|lot| lot.id()).collect())
+        }
+    }
+
+    #[test]
+    fn test_sell_strategy_orders_lots() -> Result<(), TaxLotError> {
+        let mut lot_collection = LotCollection::new(SelectionAlgorithm::Fifo);
+        lot_collection.set_sell_strategy(Box::new(LargestFirst));
+        apply(
+            &mut lot_collection,
+            &[
+                "2021-01-01,buy,100.00,1.00000000",
+                "2021-01-02,buy,100.00,3.00000000,tags=hold",
+                "2021-01-03,buy,100.00,2.00000000",
+                "2021-02-01,sell,100.00,2.50000000",
+            ],
+        )?;
+        assert_eq!(
+            lots(&lot_collection),
+            ["1,2021-01-01,100.00,0.50000000", "2,2021-01-02,100.00,3.00000000,tags=hold"]
+        );
+
+        // A sell method still overrides the strategy.
+        apply(&mut lot_collection, &["2021-02-02,sell,100.00,1.00000000,method=fifo"])?;
+        assert_eq!(lots(&lot_collection), ["2,2021-01-02,100.00,2.50000000,tags=hold"]);
+        assert_eq!(lot_collection.oversold(), Decimal::ZERO);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sell_strategy_must_return_each_lot_once() -> Result<(), TaxLotError> {
+        let mut lot_collection = LotCollection::new(SelectionAlgorithm::Fifo);
+        lot_collection.set_sell_strategy(Box::new(Repeats));
+        apply(&mut lot_collection, &["2021-01-01,buy,100.00,1.00000000"])?;
+        let sell = apply(&mut lot_collection, &["2021-02-01,sell,100.00,1.00000000"]);
+        assert!(matches!(sell, Err(TaxLotError::StrategyError(_))));
+        assert_eq!(lots(&lot_collection), ["1,2021-01-01,100.00,1.00000000"]);
+        Ok(())
+    }
+}
@@ -0,0 +1,57 @@
+//! Async ingestion of lot operations, enabled with the `async` feature.
+
+use std::pin::pin;
+
+use tokio_stream::{Stream, StreamExt};
+
+use crate::{LotCollection, LotOperation, TaxLotError};
+
+impl LotCollection {
+    /// Applies each lot operation from `lot_operations` as it arrives, stopping at the first error.
+    /// Operations before the error stay applied.
+    ///
+    /// Applying an operation never blocks, so a task can feed the collection from a socket or
+    /// channel without tying up a thread while it waits for the next operation.
+    pub async fn apply_stream(
+        &mut self,
+        lot_operations: impl Stream<Item = LotOperation>,
+    ) -> Result<(), TaxLotError> {
+        let mut lot_operations = pin!(lot_operations);
+        while let Some(lot_operation) = lot_operations.next().await {
+            self.apply_lot_operation(lot_operation)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use rust_decimal::{prelude::FromPrimitive, Decimal};
+
+    use crate::{LotCollection, LotOperation, SelectionAlgorithm, TaxLotError};
+
+    #[tokio::test]
+    async fn test_apply_stream_applies_every_operation() -> Result<(), TaxLotError> {
+        let lot_operations = [
+            "2021-01-01,buy,10000.00,1.00000000",
+            "2021-01-02,buy,20000.00,1.00000000",
+            "2021-02-01,sell,15000.00,1.50000000",
+        ]
+        .map(LotOperation::from_str)
+        .into_iter()
+        .collect::<Result<Vec<_>, _>>()?;
+
+        let mut lot_collection = LotCollection::new(SelectionAlgorithm::Hifo);
+        lot_collection.apply_stream(tokio_stream::iter(lot_operations)).await?;
+
+        let lots: Vec<_> = lot_collection.lots().collect();
+        assert_eq!(lots.len(), 1);
+        assert_eq!(lots[0].id(), 1);
+        assert_eq!(lots[0].quantity(), Decimal::from_f64(0.5).expect("Failed to parse quantity"));
+
+        Ok(())
+    }
+}
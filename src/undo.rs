@@ -0,0 +1,267 @@
+//! Undo log of the buys and sells applied to a `LotCollection`, which lets the last operations be
+//! reverted, e.g. after a mistaken sell.
+
+use std::{collections::VecDeque, sync::atomic::Ordering};
+
+use rust_decimal::Decimal;
+
+use crate::{IncomeType, Lot, LotCollection, LotKey, RealizedGains};
+
+/// Represents how to revert a single buy or sell.
+///
+/// Created: the buy created the lot with `key`, when the next sequential id was `next_id`.
+/// Merged: the buy merged into a lot, which now has `key` and was `previous` before.
+/// Sold: the sell changed the realized gains from `realized_gains`, the oversold quantity from `oversold` and the
+/// gains of `quarter` from `quarter_gains`, deducted a quantity from each lot in `deducted`, and removed the lots in
+/// `removed`, which were sold completely.
+/// If disposals are recorded, there were `disposals` of them before the sell.
+/// Income: the dividend or interest operation added `amount` to the income total with `key`.
+/// BoughtWithIncome: the drip or vest operation bought a lot, reverted by `bought`, and added `amount` to the
+/// income total with `key`.
+#[derive(Debug)]
+pub(crate) enum Undo {
+    Created {
+        key: LotKey,
+        next_id: u64,
+    },
+    Merged {
+        key: LotKey,
+        previous: Lot,
+    },
+    Sold {
+        realized_gains: Decimal,
+        oversold: Decimal,
+        quarter: (i32, u32),
+        quarter_gains: Option<RealizedGains>,
+        deducted: Vec<(LotKey, Decimal)>,
+        removed: Vec<Lot>,
+        disposals: usize,
+        compensation: Option<((i32, IncomeType), Decimal)>,
+    },
+    Income {
+        key: (i32, IncomeType),
+        amount: Decimal,
+    },
+    BoughtWithIncome {
+        bought: Box<Undo>,
+        key: (i32, IncomeType),
+        amount: Decimal,
+    },
+}
+
+/// The last buys and sells applied to a `LotCollection`, up to `limit` of them, most recent last.
+#[derive(Debug)]
+pub(crate) struct UndoLog {
+    entries: VecDeque<Undo>,
+    limit: usize,
+}
+
+impl UndoLog {
+    /// Returns an empty undo log with the same limit.
+    pub(crate) fn cleared(&self) -> UndoLog {
+        UndoLog {
+            entries: VecDeque::new(),
+            limit: self.limit,
+        }
+    }
+
+    /// Adds how to revert the last operation, dropping the oldest entry if the log is full.
+    pub(crate) fn push(&mut self, undo: Undo) {
+        if self.limit == 0 {
+            return;
+        }
+        if self.entries.len() == self.limit {
+            self.entries.pop_front();
+        }
+        self.entries.push_back(undo);
+    }
+}
+
+impl LotCollection {
+    /// Keeps how to revert the last `limit` buys and sells applied from now on, so that `undo` can revert
+    /// them. Amend and cancel operations clear the undo log.
+    pub fn enable_undo(&mut self, limit: usize) {
+        self.undo_log = Some(UndoLog {
+            entries: VecDeque::new(),
+            limit,
+        });
+    }
+
+    /// Reverts the last `n` buys and sells, most recent first, leaving the lots and realized gains as they
+    /// were before those operations were applied. Returns the number of operations reverted, which is less
+    /// than `n` if the undo log runs out.
+    pub fn undo(&mut self, n: usize) -> usize {
+        let mut undone = 0;
+        while undone < n {
+            let Some(undo) = self.undo_log.as_mut().and_then(|undo_log| undo_log.entries.pop_back()) else {
+                break;
+            };
+            self.revert(undo);
+            if let Some(journal) = &mut self.journal {
+                journal.pop();
+            }
+            undone += 1;
+        }
+        undone
+    }
+
+    /// Reverts a single buy or sell. Operations are reverted in the reverse order they were applied, so
+    /// every lot is in the same state as right after the operation.
+    fn revert(&mut self, undo: Undo) {
+        match undo {
+            Undo::Created { key, next_id } => {
+                self.remove_lot(&key);
+                self.id_generator.store(next_id, Ordering::SeqCst);
+            }
+            Undo::Merged { key, previous } => {
+                self.remove_lot(&key);
+                self.insert_lot(previous);
+            }
+            Undo::Sold {
+                realized_gains,
+                oversold,
+                quarter,
+                quarter_gains,
+                deducted,
+                removed,
+                disposals,
+                compensation,
+            } => {
+                self.realized_gains = realized_gains;
+                self.oversold = oversold;
+                match quarter_gains {
+                    Some(quarter_gains) => self.quarterly_gains.insert(quarter, quarter_gains),
+                    None => self.quarterly_gains.remove(&quarter),
+                };
+                for (key, quantity) in deducted {
+                    if let Some(lot) = self.lots.get_mut(&key) {
+                        lot.quantity += quantity;
+                    }
+                }
+                for lot in removed {
+                    self.insert_lot(lot);
+                }
+                if let Some(recorded) = &mut self.disposals {
+                    recorded.truncate(disposals);
+                }
+                if let Some((key, amount)) = compensation {
+                    self.revert_income(key, amount);
+                }
+            }
+            Undo::Income { key, amount } => self.revert_income(key, amount),
+            Undo::BoughtWithIncome { bought, key, amount } => {
+                self.revert(*bought);
+                self.revert_income(key, amount);
+            }
+        }
+    }
+
+    /// Subtracts `amount` from the income total with `key`, removing the total once it is zero.
+    fn revert_income(&mut self, key: (i32, IncomeType), amount: Decimal) {
+        if let Some(total) = self.income.get_mut(&key) {
+            // The amount was added to the total, so this cannot underflow.
+            *total -= amount;
+            if total.is_zero() {
+                self.income.remove(&key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rust_decimal::Decimal;
+
+    use crate::{
+        test_helpers::{apply, lots},
+        LotCollection, SelectionAlgorithm, TaxLotError,
+    };
+
+    #[test]
+    fn test_undo_reverts_operations() -> Result<(), TaxLotError> {
+        let mut lot_collection = LotCollection::new(SelectionAlgorithm::Hifo);
+        lot_collection.enable_undo(10);
+        apply(
+            &mut lot_collection,
+            &[
+                "2021-01-01,buy,10000.00,1.00000000",
+                "2021-01-02,buy,20000.00,1.00000000",
+            ],
+        )?;
+        let before = lots(&lot_collection);
+
+        apply(
+            &mut lot_collection,
+            &[
+                "2021-01-02,buy,30000.00,1.00000000,dca",
+                "2021-02-01,sell,25000.00,2.50000000",
+                "2021-02-02,dividend,0.25,0.50000000",
+                "2021-02-03,drip,25000.00,0.00001000",
+            ],
+        )?;
+        assert_eq!(
+            lots(&lot_collection),
+            ["3,2021-02-03,25000.00,0.00001000", "1,2021-01-01,10000.00,0.50000000"]
+        );
+
+        // Undoing the sell restores the lots it sold, and undoing the merge restores the lot's price.
+        assert_eq!(lot_collection.undo(4), 4);
+        assert_eq!(lots(&lot_collection), before);
+        assert_eq!(lot_collection.realized_gains(), Decimal::ZERO);
+        assert_eq!(lot_collection.realized_gains_by_quarter().count(), 0);
+        assert_eq!(lot_collection.income().count(), 0);
+
+        // Ids of reverted lots are handed out again.
+        assert_eq!(lot_collection.undo(5), 2);
+        apply(&mut lot_collection, &["2021-01-03,buy,10000.00,1.00000000"])?;
+        assert_eq!(lots(&lot_collection), ["1,2021-01-03,10000.00,1.00000000"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_undo_log_is_limited() -> Result<(), TaxLotError> {
+        let mut lot_collection = LotCollection::new(SelectionAlgorithm::Fifo);
+        lot_collection.enable_undo(1);
+        apply(
+            &mut lot_collection,
+            &[
+                "2021-01-01,buy,10000.00,1.00000000",
+                "2021-01-02,buy,20000.00,1.00000000",
+            ],
+        )?;
+
+        assert_eq!(lot_collection.undo(2), 1);
+        assert_eq!(lots(&lot_collection), ["1,2021-01-01,10000.00,1.00000000"]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_failed_operation_leaves_lots_unchanged() -> Result<(), TaxLotError> {
+        let mut lot_collection = LotCollection::new(SelectionAlgorithm::Fifo);
+        apply(
+            &mut lot_collection,
+            &[
+                "2021-01-01,buy,79228162514264337593543950335,1",
+                "2021-01-02,buy,1,1.00000000",
+                "2021-01-03,buy,1,1.00000000",
+            ],
+        )?;
+        let before = lots(&lot_collection);
+
+        // Merging overflows the total cost of the lot.
+        apply(&mut lot_collection, &["2021-01-01,buy,79228162514264337593543950335,1"])
+            .expect_err("Merged a lot with an overflowing cost");
+
+        // The gain of the first lot sold fits, but adding the gain of the second one overflows.
+        lot_collection.lock_lot(1);
+        apply(&mut lot_collection, &["2021-02-01,sell,79228162514264337593543950335,2"])
+            .expect_err("Sold lots with an overflowing gain");
+
+        assert_eq!(lots(&lot_collection), before);
+        assert_eq!(lot_collection.realized_gains(), Decimal::ZERO);
+
+        Ok(())
+    }
+}
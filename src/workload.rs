@@ -0,0 +1,130 @@
+//! Synthetic workload generation for the `gen` and `bench` subcommands.
+
+use std::io::{self, Write};
+
+use chrono::{Days, NaiveDate};
+use clap::Args;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// Price of the asset on the first day of a generated workload.
+const INITIAL_PRICE: f64 = 10000.0;
+
+/// Lowest price the random walk can reach, so that every generated price is valid.
+const MIN_PRICE: f64 = 1.0;
+
+/// Largest relative change of the price from one day to the next.
+const MAX_DAILY_PRICE_CHANGE: f64 = 0.04;
+
+/// Largest relative difference between the price of an operation and the price of its day.
+const MAX_INTRADAY_PRICE_SPREAD: f64 = 0.005;
+
+/// Represents the options for generating a synthetic workload.
+///
+/// `ops`: Number of lot operations to generate.
+/// `days`: Number of days the operations are spread across.
+/// `sell_ratio`: Fraction of the operations that are sells, between 0 and 1.
+/// `seed`: Seed for the random number generator, so that workloads are reproducible.
+/// `start_date`: Date of the first operation.
+#[derive(Args, Debug, Clone)]
+pub struct WorkloadOpts {
+    #[clap(long, default_value_t = 100_000, env = "TAXLOT_OPS")]
+    pub ops: u64,
+
+    #[clap(long, default_value_t = 365, value_parser = clap::value_parser!(u64).range(1..), env = "TAXLOT_DAYS")]
+    pub days: u64,
+
+    #[clap(long, default_value_t = 0.3, value_parser = parse_ratio, env = "TAXLOT_SELL_RATIO")]
+    pub sell_ratio: f64,
+
+    #[clap(long, default_value_t = 0, env = "TAXLOT_SEED")]
+    pub seed: u64,
+
+    #[clap(long, default_value = "2021-01-01", env = "TAXLOT_START_DATE")]
+    pub start_date: NaiveDate,
+}
+
+/// Parses a ratio, which must be between 0 and 1.
+fn parse_ratio(s: &str) -> Result<f64, String> {
+    let ratio: f64 = s.parse().map_err(|_| format!("`{s}` is not a number"))?;
+    if !(0.0..=1.0).contains(&ratio) {
+        return Err(format!("`{s}` is not between 0 and 1"));
+    }
+    Ok(ratio)
+}
+
+/// Writes `opts.ops` lot operations to `out`, in the same format as the CLI input.
+///
+/// The operations are spread evenly across `opts.days` days in date order. The price follows a
+/// random walk from day to day, with a small spread between the operations on the same day, and
+/// each operation is a sell with probability `opts.sell_ratio`.
+pub fn generate(opts: &WorkloadOpts, out: &mut impl Write) -> io::Result<()> {
+    let mut rng = StdRng::seed_from_u64(opts.seed);
+    let mut day_price = INITIAL_PRICE;
+    let mut day = 0;
+
+    for op in 0..opts.ops {
+        let op_day = (u128::from(op) * u128::from(opts.days) / u128::from(opts.ops)) as u64;
+        while day < op_day {
+            day_price *= 1.0 + rng.gen_range(-MAX_DAILY_PRICE_CHANGE..=MAX_DAILY_PRICE_CHANGE);
+            day_price = day_price.max(MIN_PRICE);
+            day += 1;
+        }
+
+        let date = opts
+            .start_date
+            .checked_add_days(Days::new(day))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Generated date is out of range"))?;
+        let lot_type = if rng.gen_bool(opts.sell_ratio) { "sell" } else { "buy" };
+        let price = day_price * (1.0 + rng.gen_range(-MAX_INTRADAY_PRICE_SPREAD..=MAX_INTRADAY_PRICE_SPREAD));
+        let quantity = rng.gen_range(0.01..2.0);
+
+        writeln!(out, "{date},{lot_type},{:.2},{quantity:.8}", price.max(MIN_PRICE))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use chrono::{Days, NaiveDate};
+    use taxlot::{LotOperation, LotType, TaxLotError};
+
+    use super::{generate, WorkloadOpts};
+
+    fn workload(seed: u64) -> String {
+        let opts = WorkloadOpts {
+            ops: 1000,
+            days: 30,
+            sell_ratio: 0.3,
+            seed,
+            start_date: NaiveDate::from_ymd_opt(2021, 1, 1).expect("Invalid date"),
+        };
+        let mut out = Vec::new();
+        generate(&opts, &mut out).expect("Could not generate the workload");
+        String::from_utf8(out).expect("Workload is not UTF-8")
+    }
+
+    #[test]
+    fn test_same_seed_generates_same_operations() {
+        assert_eq!(workload(7), workload(7));
+        assert_ne!(workload(7), workload(8));
+    }
+
+    #[test]
+    fn test_generated_operations_parse() -> Result<(), TaxLotError> {
+        let workload = workload(0);
+        let operations = workload.lines().map(LotOperation::from_str).collect::<Result<Vec<_>, _>>()?;
+        assert_eq!(operations.len(), 1000);
+
+        let start_date = NaiveDate::from_ymd_opt(2021, 1, 1).expect("Invalid date");
+        let last_date = start_date + Days::new(29);
+        assert_eq!(operations.first().map(|operation| operation.date()), Some(start_date));
+        assert_eq!(operations.last().map(|operation| operation.date()), Some(last_date));
+        assert!(operations.windows(2).all(|pair| pair[0].date() <= pair[1].date()));
+        assert!(operations.iter().any(|operation| operation.lot_type() == LotType::Sell));
+        assert!(operations.iter().any(|operation| operation.lot_type() == LotType::Buy));
+        Ok(())
+    }
+}
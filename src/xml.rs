@@ -0,0 +1,501 @@
+//! Mapping of XML exports to lot operations, so that a broker's XML can be read without a bespoke importer.
+//!
+//! Only the parts of XML that exports use are supported: elements, attributes, text with the predefined and
+//! numeric entities, CDATA sections and comments. Processing instructions and doctypes are skipped, and
+//! namespace prefixes are part of the element name.
+
+use std::{collections::BTreeSet, str::FromStr};
+
+use chrono::NaiveDate;
+
+use crate::{schema::check_record, Column, ColumnError, LotOperation, LotType, TaxLotError};
+
+/// Represents which elements of an XML document are lot operations, and where their fields are.
+///
+/// A mapping is written as comma-separated `field=path` entries, e.g.
+/// `record=//trade,date=@date,type=side,price=price,quantity=quantity/@amount`. The `record` path selects
+/// the elements that are lot operations: `/a/b` from the root element, or `//b` for `b` elements anywhere.
+/// The paths of the other fields are relative to a record, `child/grandchild` for the text of an element,
+/// optionally followed by `/@attribute` for an attribute instead. The `date`, `type`, `price` and `quantity`
+/// fields are required. `tags` (separated by `;`), `txn` and `memo` are optional.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct XmlMapping {
+    record: Vec<String>,
+    record_anywhere: bool,
+    fields: Vec<(XmlField, XmlPath)>,
+}
+
+/// Represents the fields of a lot operation that can be mapped.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum XmlField {
+    Date,
+    LotType,
+    Price,
+    Quantity,
+    Tags,
+    TxnId,
+    Memo,
+}
+
+/// Represents the path of a field relative to a record: the names of the nested elements, and the
+/// attribute of the innermost one if the value is an attribute rather than text.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct XmlPath {
+    elements: Vec<String>,
+    attribute: Option<String>,
+}
+
+impl FromStr for XmlMapping {
+    type Err = TaxLotError;
+
+    fn from_str(s: &str) -> Result<Self, TaxLotError> {
+        let mut record = None;
+        let mut fields = Vec::new();
+        for entry in s.split(',') {
+            let invalid = || TaxLotError::InvalidXmlMapping(entry.to_string());
+            let (name, path) = entry.split_once('=').ok_or_else(invalid)?;
+            let path = path.trim();
+            let field = match name.trim() {
+                "record" => {
+                    record = Some(path);
+                    continue;
+                }
+                "date" => XmlField::Date,
+                "type" => XmlField::LotType,
+                "price" => XmlField::Price,
+                "quantity" => XmlField::Quantity,
+                "tags" => XmlField::Tags,
+                "txn" => XmlField::TxnId,
+                "memo" => XmlField::Memo,
+                _ => return Err(invalid()),
+            };
+            let mut elements: Vec<String> = path.split('/').map(str::to_string).collect();
+            let attribute = match elements.last().and_then(|last| last.strip_prefix('@')) {
+                Some(attribute) => {
+                    let attribute = attribute.to_string();
+                    elements.pop();
+                    Some(attribute)
+                }
+                None => None,
+            };
+            if elements.iter().any(String::is_empty) || attribute.as_deref() == Some("") {
+                return Err(invalid());
+            }
+            fields.push((field, XmlPath { elements, attribute }));
+        }
+
+        let record = record.ok_or_else(|| TaxLotError::InvalidXmlMapping("missing record path".to_string()))?;
+        let (record_anywhere, record) = match record.strip_prefix("//") {
+            Some(record) => (true, record),
+            None => (false, record.strip_prefix('/').unwrap_or(record)),
+        };
+        let record: Vec<String> = record.split('/').map(str::to_string).collect();
+        if record.iter().any(String::is_empty) {
+            return Err(TaxLotError::InvalidXmlMapping(format!("record={}", record.join("/"))));
+        }
+        for (field, name) in [
+            (XmlField::Date, "date"),
+            (XmlField::LotType, "type"),
+            (XmlField::Price, "price"),
+            (XmlField::Quantity, "quantity"),
+        ] {
+            if !fields.iter().any(|(mapped, _)| *mapped == field) {
+                return Err(TaxLotError::InvalidXmlMapping(format!("missing {name} field")));
+            }
+        }
+
+        Ok(XmlMapping {
+            record,
+            record_anywhere,
+            fields,
+        })
+    }
+}
+
+impl XmlMapping {
+    /// Returns the lot operations of the records in `document`, in document order. Iteration stops after
+    /// the first error.
+    pub fn records<'a>(&'a self, document: &'a str) -> impl Iterator<Item = Result<LotOperation, TaxLotError>> + 'a {
+        let mut failed = false;
+        self.record_values(document).map_while(move |values| {
+            if failed {
+                return None;
+            }
+            let lot_operation = values.and_then(|values| self.lot_operation(values));
+            failed = lot_operation.is_err();
+            Some(lot_operation)
+        })
+    }
+
+    /// Returns the columns of records, which are validated by `check`. Tags, transaction ids and memos can be any
+    /// text.
+    pub fn columns(&self) -> &'static [Column] {
+        &[Column::Date, Column::Type, Column::Price, Column::Quantity]
+    }
+
+    /// Returns a problem with each column of each record in `document` that keeps it from being parsed, in document
+    /// order, like `InputDialect::check`. Iteration stops after an error in the document itself, e.g. an element
+    /// that is not closed.
+    pub fn check<'a>(&'a self, document: &'a str) -> impl Iterator<Item = Result<Vec<ColumnError>, TaxLotError>> + 'a {
+        self.record_values(document).map(|values| values.map(|values| self.check_values(&values)))
+    }
+
+    /// Returns the values of the fields of the records in `document`, in document order and in the order of the
+    /// fields of the mapping. Iteration stops after the first error.
+    fn record_values<'a>(
+        &'a self,
+        document: &'a str,
+    ) -> impl Iterator<Item = Result<Vec<Option<String>>, TaxLotError>> + 'a {
+        let mut tokenizer = Tokenizer { rest: document };
+        let mut stack: Vec<&str> = Vec::new();
+        // Depth of the stack at the record element, and the values of the fields of the record so far.
+        let mut record: Option<(usize, Vec<Option<String>>)> = None;
+        let mut failed = false;
+
+        std::iter::from_fn(move || {
+            while !failed {
+                let token = match tokenizer.next_token() {
+                    Ok(Some(token)) => token,
+                    Ok(None) => {
+                        failed = true;
+                        let name = stack.last()?;
+                        return Some(Err(TaxLotError::XmlParseError(format!("<{name}> is not closed"))));
+                    }
+                    Err(e) => {
+                        failed = true;
+                        return Some(Err(e));
+                    }
+                };
+
+                match token {
+                    Token::Start { name, attributes, empty } => {
+                        stack.push(name);
+                        if record.is_none() && self.is_record(&stack) {
+                            record = Some((stack.len(), vec![None; self.fields.len()]));
+                        }
+                        if let Some((depth, values)) = &mut record {
+                            for ((_, path), value) in self.fields.iter().zip(values.iter_mut()) {
+                                let Some(attribute) = &path.attribute else {
+                                    continue;
+                                };
+                                if path.elements != stack[*depth..] {
+                                    continue;
+                                }
+                                if let Some((_, attribute_value)) =
+                                    attributes.iter().find(|(attribute_name, _)| attribute_name == attribute)
+                                {
+                                    *value = Some(attribute_value.clone());
+                                }
+                            }
+                        }
+                        if empty {
+                            if let Some(values) = self.end_element(&mut stack, &mut record) {
+                                return Some(Ok(values));
+                            }
+                        }
+                    }
+                    Token::End(name) => {
+                        if stack.last() != Some(&name) {
+                            failed = true;
+                            return Some(Err(TaxLotError::XmlParseError(format!("unexpected closing tag </{name}>"))));
+                        }
+                        if let Some(values) = self.end_element(&mut stack, &mut record) {
+                            return Some(Ok(values));
+                        }
+                    }
+                    Token::Text(text) => {
+                        if let Some((depth, values)) = &mut record {
+                            for ((_, path), value) in self.fields.iter().zip(values.iter_mut()) {
+                                if path.attribute.is_none() && path.elements == stack[*depth..] {
+                                    value.get_or_insert_with(String::new).push_str(&text);
+                                }
+                            }
+                        }
+                    }
+                }
+            }
+            None
+        })
+    }
+
+    /// Returns true if the elements in `stack` are a record.
+    fn is_record(&self, stack: &[&str]) -> bool {
+        let matches = |elements: &[&str]| {
+            elements.len() == self.record.len()
+                && elements.iter().zip(&self.record).all(|(element, record)| *element == record.as_str())
+        };
+        if self.record_anywhere {
+            stack.len() >= self.record.len() && matches(&stack[stack.len() - self.record.len()..])
+        } else {
+            matches(stack)
+        }
+    }
+
+    /// Pops the innermost element from `stack`. Returns the values of the fields of the record if that element
+    /// was the record.
+    fn end_element(
+        &self,
+        stack: &mut Vec<&str>,
+        record: &mut Option<(usize, Vec<Option<String>>)>,
+    ) -> Option<Vec<Option<String>>> {
+        let closes_record = record.as_ref().is_some_and(|(depth, _)| *depth == stack.len());
+        stack.pop();
+        if !closes_record {
+            return None;
+        }
+        let (_, values) = record.take()?;
+        Some(values)
+    }
+
+    /// Returns a problem with each column of a record with the values of its fields, as they are parsed by
+    /// `lot_operation`.
+    fn check_values(&self, values: &[Option<String>]) -> Vec<ColumnError> {
+        let value = |field| {
+            self.fields
+                .iter()
+                .zip(values)
+                .filter(|((mapped, _), _)| *mapped == field)
+                .filter_map(|(_, value)| value.as_deref().map(str::trim).filter(|value| !value.is_empty()))
+                .next_back()
+        };
+        let date = value(XmlField::Date).map(|date| date.split('T').next().unwrap_or_default());
+        check_record(&[
+            (Column::Date, date),
+            (Column::Type, value(XmlField::LotType)),
+            (Column::Price, value(XmlField::Price)),
+            (Column::Quantity, value(XmlField::Quantity)),
+        ])
+    }
+
+    /// Creates the lot operation of a record from the values of its fields.
+    fn lot_operation(&self, values: Vec<Option<String>>) -> Result<LotOperation, TaxLotError> {
+        let mut date = None;
+        let mut lot_type = None;
+        let mut price = None;
+        let mut quantity = None;
+        let mut tags = BTreeSet::new();
+        let mut txn_id = None;
+        let mut memo = None;
+        for ((field, _), value) in self.fields.iter().zip(values) {
+            let Some(value) = value.map(|value| value.trim().to_string()).filter(|value| !value.is_empty()) else {
+                continue;
+            };
+            match field {
+                XmlField::Date => date = Some(value),
+                XmlField::LotType => lot_type = Some(value),
+                XmlField::Price => price = Some(value),
+                XmlField::Quantity => quantity = Some(value),
+                XmlField::Tags => {
+                    let values = value.split(';').map(str::trim).filter(|tag| !tag.is_empty());
+                    tags.extend(values.map(str::to_string));
+                }
+                XmlField::TxnId => txn_id = Some(value),
+                XmlField::Memo => memo = Some(value),
+            }
+        }
+
+        let required =
+            |value: Option<String>, name: &str| value.ok_or_else(|| TaxLotError::FieldDoesntExist(name.to_string()));
+        let date = required(date, "Date")?;
+        // Exports often have timestamps, of which only the date is used.
+        let date = NaiveDate::parse_from_str(date.split('T').next().unwrap_or_default(), "%Y-%m-%d")?;
+        let lot_type = LotType::from_str(&required(lot_type, "Lot Type")?)?;
+        // Like in a delimited line, a cancel only needs the transaction id.
+        let (price, quantity) = if lot_type == LotType::Cancel {
+            (String::new(), String::new())
+        } else {
+            (required(price, "Price")?, required(quantity, "Quantity")?)
+        };
+        let mut lot_operation = LotOperation::from_fields(date, lot_type, &price, &quantity, "")?;
+        lot_operation.tags = tags;
+        lot_operation.txn_id = txn_id;
+        lot_operation.memo = memo;
+        Ok(lot_operation)
+    }
+}
+
+/// Represents a part of an XML document that records are extracted from.
+enum Token<'a> {
+    Start {
+        name: &'a str,
+        attributes: Vec<(&'a str, String)>,
+        empty: bool,
+    },
+    End(&'a str),
+    Text(String),
+}
+
+/// Splits an XML document into tokens.
+struct Tokenizer<'a> {
+    rest: &'a str,
+}
+
+impl<'a> Tokenizer<'a> {
+    /// Returns the next token, or `None` at the end of the document.
+    fn next_token(&mut self) -> Result<Option<Token<'a>>, TaxLotError> {
+        loop {
+            if self.rest.is_empty() {
+                return Ok(None);
+            }
+            if let Some(rest) = self.rest.strip_prefix("<!--") {
+                self.rest = skip_past(rest, "-->")?;
+            } else if let Some(rest) = self.rest.strip_prefix("<![CDATA[") {
+                let (text, rest) = rest.split_once("]]>").ok_or_else(|| unterminated("]]>"))?;
+                self.rest = rest;
+                return Ok(Some(Token::Text(text.to_string())));
+            } else if self.rest.starts_with("<?") || self.rest.starts_with("<!") {
+                self.rest = skip_past(self.rest, ">")?;
+            } else if let Some(rest) = self.rest.strip_prefix("</") {
+                let (name, rest) = rest.split_once('>').ok_or_else(|| unterminated(">"))?;
+                self.rest = rest;
+                return Ok(Some(Token::End(name.trim())));
+            } else if let Some(rest) = self.rest.strip_prefix('<') {
+                return self.start_tag(rest).map(Some);
+            } else {
+                let end = self.rest.find('<').unwrap_or(self.rest.len());
+                let (text, rest) = self.rest.split_at(end);
+                self.rest = rest;
+                return Ok(Some(Token::Text(unescape(text)?)));
+            }
+        }
+    }
+
+    /// Parses a start tag, after its `<`.
+    fn start_tag(&mut self, mut rest: &'a str) -> Result<Token<'a>, TaxLotError> {
+        let name_end = rest
+            .find(|c: char| c.is_whitespace() || c == '>' || c == '/')
+            .ok_or_else(|| unterminated(">"))?;
+        let name = &rest[..name_end];
+        rest = &rest[name_end..];
+
+        let mut attributes = Vec::new();
+        loop {
+            rest = rest.trim_start();
+            if let Some(after) = rest.strip_prefix("/>") {
+                self.rest = after;
+                return Ok(Token::Start { name, attributes, empty: true });
+            }
+            if let Some(after) = rest.strip_prefix('>') {
+                self.rest = after;
+                return Ok(Token::Start { name, attributes, empty: false });
+            }
+
+            let (attribute, after) = rest.split_once('=').ok_or_else(|| unterminated(">"))?;
+            let after = after.trim_start();
+            let quote = after
+                .chars()
+                .next()
+                .filter(|quote| *quote == '"' || *quote == '\'')
+                .ok_or_else(|| TaxLotError::XmlParseError(format!("unquoted value of attribute {}", attribute.trim())))?;
+            let (value, after) = after[1..].split_once(quote).ok_or_else(|| unterminated("quote"))?;
+            attributes.push((attribute.trim(), unescape(value)?));
+            rest = after;
+        }
+    }
+}
+
+/// Returns the rest of `s` after the first `delimiter`.
+fn skip_past<'a>(s: &'a str, delimiter: &str) -> Result<&'a str, TaxLotError> {
+    s.split_once(delimiter).map(|(_, rest)| rest).ok_or_else(|| unterminated(delimiter))
+}
+
+fn unterminated(expected: &str) -> TaxLotError {
+    TaxLotError::XmlParseError(format!("expected {expected} before the end of the document"))
+}
+
+/// Replaces the predefined and numeric entities in `text` with the characters they stand for.
+fn unescape(text: &str) -> Result<String, TaxLotError> {
+    let mut unescaped = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+        let (entity, after) = rest[start + 1..].split_once(';').ok_or_else(|| unterminated(";"))?;
+        let character = match entity {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => match entity.strip_prefix("#x").or_else(|| entity.strip_prefix("#X")) {
+                Some(hex) => u32::from_str_radix(hex, 16).ok().and_then(char::from_u32),
+                None => entity.strip_prefix('#').and_then(|decimal| decimal.parse().ok()).and_then(char::from_u32),
+            },
+        };
+        unescaped.push(character.ok_or_else(|| TaxLotError::XmlParseError(format!("unknown entity &{entity};")))?);
+        rest = after;
+    }
+    unescaped.push_str(rest);
+    Ok(unescaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use rust_decimal::{prelude::FromPrimitive, Decimal};
+
+    use crate::{Column, ColumnError, LotOperation, LotType, TaxLotError, XmlMapping};
+
+    const DOCUMENT: &str = r#"<?xml version="1.0" encoding="UTF-8"?>
+<!-- Exported statement -->
+<statement>
+  <trades>
+    <trade date="2021-01-01T09:30:00" id="a1">
+      <side>BUY</side>
+      <price>10000.00</price>
+      <quantity amount="1.5"/>
+      <note><![CDATA[first <buy>]]> &amp; more</note>
+    </trade>
+    <trade date="2021-02-01" id="a2"><side>SELL</side><price>12000</price><quantity amount="0.5"/></trade>
+  </trades>
+</statement>"#;
+
+    fn records(mapping: &str, document: &str) -> Result<Vec<LotOperation>, TaxLotError> {
+        XmlMapping::from_str(mapping)?.records(document).collect()
+    }
+
+    #[test]
+    fn test_xml_records_are_mapped_to_lot_operations() -> Result<(), TaxLotError> {
+        let mapping = "record=/statement/trades/trade,date=@date,type=side,price=price,quantity=quantity/@amount,txn=@id,memo=note";
+        let lot_operations = records(mapping, DOCUMENT)?;
+        assert_eq!(lot_operations.len(), 2);
+
+        assert_eq!(lot_operations[0].lot_type, LotType::Buy);
+        assert_eq!(lot_operations[0].quantity, Decimal::from_f64(1.5).expect("Failed to parse quantity"));
+        assert_eq!(lot_operations[0].txn_id.as_deref(), Some("a1"));
+        assert_eq!(lot_operations[0].memo.as_deref(), Some("first <buy> & more"));
+
+        assert_eq!(lot_operations[1].lot_type, LotType::Sell);
+        assert_eq!(lot_operations[1].price, Decimal::from(12000));
+        assert_eq!(lot_operations[1].memo, None);
+
+        // Records can also be matched anywhere in the document.
+        let mapping = "record=//trade,date=@date,type=side,price=price,quantity=quantity/@amount";
+        assert_eq!(records(mapping, DOCUMENT)?.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_xml_is_rejected() {
+        let mapping = "record=//trade,date=@date,type=side,price=price,quantity=quantity/@amount";
+        records(mapping, "<trade date=\"2021-01-01\"><side>BUY</side><price>1</price></trade>")
+            .expect_err("Parsed a record without a quantity");
+        records(mapping, "<trades><trade></trades>").expect_err("Parsed mismatched tags");
+        records(mapping, "<trade date=2021-01-01>").expect_err("Parsed an unquoted attribute");
+        records(mapping, "<trades>").expect_err("Parsed an unclosed element");
+
+        // Checking reports every record, and stops at an error in the document itself.
+        let mapping: XmlMapping = mapping.parse().expect("Invalid mapping");
+        let document = "<t><trade date=\"2021-01-01\"><side>hold</side></trade><trade><side>cancel</side></trade><t>";
+        let checked: Vec<_> = mapping.check(document).collect();
+        let columns = |errors: &Vec<ColumnError>| errors.iter().map(|error| error.column).collect::<Vec<_>>();
+        assert_eq!(checked.len(), 3);
+        assert_eq!(checked[0].as_ref().map(columns).ok(), Some(vec![Column::Type, Column::Price, Column::Quantity]));
+        assert_eq!(checked[1].as_ref().map(columns).ok(), Some(vec![Column::Date]));
+        checked[2].as_ref().expect_err("Checked an unclosed element");
+
+        XmlMapping::from_str("date=@date,type=side,price=price,quantity=quantity").expect_err("Parsed a mapping without records");
+        XmlMapping::from_str("record=//trade,type=side,price=price,quantity=quantity")
+            .expect_err("Parsed a mapping without a date");
+    }
+}